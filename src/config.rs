@@ -0,0 +1,144 @@
+use crate::error::{Error, Result};
+use crate::raft_engine::{raft_node::RaftConfig, raft_server::TlsConfig};
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Server configuration, loaded from a TOML file.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The unique ID of the node.
+    pub id: String,
+    /// The ID of the cluster. Peers reject connections from nodes of other clusters.
+    pub cluster_id: String,
+    /// The address to listen on for Raft peers.
+    pub listen_raft: String,
+    /// The address to listen on for clients.
+    pub listen_client: String,
+    /// The log level, e.g. info or debug.
+    pub log_level: String,
+    /// The directory to store data in.
+    pub data_dir: String,
+    /// Whether to fsync the Raft log on every write.
+    pub sync: bool,
+    /// The Raft peers, as a map of node ID to Raft address.
+    pub peers: HashMap<String, String>,
+    /// The Raft log storage engine: hybrid or memory.
+    pub storage_raft: String,
+    /// The state machine storage engine: bitcask or memory.
+    pub storage_state: String,
+    /// The fraction of garbage in the bitcask state machine storage that triggers compaction.
+    pub compact_threshold: f64,
+    /// Raft timing and tuning parameters.
+    pub raft: RaftConfig,
+    /// TLS settings for Raft peer connections. If unset, peers communicate via plaintext TCP.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            id: "boula".into(),
+            cluster_id: "boula".into(),
+            listen_raft: "0.0.0.0:9705".into(),
+            listen_client: "0.0.0.0:9605".into(),
+            log_level: "info".into(),
+            data_dir: "data".into(),
+            sync: true,
+            peers: HashMap::new(),
+            storage_raft: "hybrid".into(),
+            storage_state: "bitcask".into(),
+            compact_threshold: 0.2,
+            raft: RaftConfig::default(),
+            tls: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from a TOML file, using defaults for missing settings.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| Error::Config(format!("Failed to read {}: {}", path.display(), err)))?;
+        Self::parse(&content)
+    }
+
+    /// Parses the configuration from a TOML string.
+    pub fn parse(content: &str) -> Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        if config.id.is_empty() {
+            return Err(Error::Config("Node ID can't be empty".into()));
+        }
+        if config.cluster_id.is_empty() {
+            return Err(Error::Config("Cluster ID can't be empty".into()));
+        }
+        if config.peers.contains_key(&config.id) {
+            return Err(Error::Config(format!("Node {} can't be its own peer", config.id)));
+        }
+        config.raft.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            id = "a"
+            cluster_id = "prod"
+            data_dir = "/var/lib/boula"
+            storage_state = "memory"
+
+            [peers]
+            b = "10.0.0.2:9705"
+
+            [raft]
+            tick_ms = 10
+            election_timeout_max = 20
+            lease_reads = true
+
+            [tls]
+            cert = "a.pem"
+            key = "a.key"
+            ca = "ca.pem"
+            "#,
+        )?;
+        assert_eq!(
+            config,
+            Config {
+                id: "a".into(),
+                cluster_id: "prod".into(),
+                data_dir: "/var/lib/boula".into(),
+                storage_state: "memory".into(),
+                peers: vec![("b".to_string(), "10.0.0.2:9705".to_string())].into_iter().collect(),
+                raft: RaftConfig {
+                    tick_ms: 10,
+                    election_timeout_max: 20,
+                    lease_reads: true,
+                    ..RaftConfig::default()
+                },
+                tls: Some(TlsConfig {
+                    cert: "a.pem".into(),
+                    key: "a.key".into(),
+                    ca: "ca.pem".into(),
+                }),
+                ..Config::default()
+            }
+        );
+
+        assert!(matches!(Config::parse("id = 1"), Err(Error::Config(_))));
+        let own_peer = Config::parse("id = \"a\"\npeers = { a = \"x\" }");
+        assert!(matches!(own_peer, Err(Error::Config(_))));
+        assert!(matches!(Config::parse("cluster_id = \"\""), Err(Error::Config(_))));
+        let invalid_raft = Config::parse("[raft]\nelection_timeout_max = 1");
+        assert!(matches!(invalid_raft, Err(Error::Config(_))));
+        Ok(())
+    }
+}
@@ -13,6 +13,8 @@ pub enum Error {
     Parse(String),
     ReadOnly,
     Serialization,
+    /// The client session with the given ID was not found, e.g. because it expired.
+    SessionExpired(u64),
     Value(String),
 }
 
@@ -27,6 +29,7 @@ impl Display for Error {
             Error::Abort => write!(f, "Operation aborted"),
             Error::Serialization => write!(f, "Serialization failure, retry transaction"),
             Error::ReadOnly => write!(f, "Read-only transaction"),
+            Error::SessionExpired(id) => write!(f, "Session {} not found", id),
         }
     }
 }
@@ -37,9 +40,8 @@ impl From<Box<bincode::ErrorKind>> for Error {
     }
 }
 
-/*
-impl From<config::ConfigError> for Error {
-    fn from(err: config::ConfigError) -> Self {
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
         Error::Config(err.to_string())
     }
 }
@@ -56,6 +58,7 @@ impl From<log::SetLoggerError> for Error {
     }
 }
 
+/*
 impl From<regex::Error> for Error {
     fn from(err: regex::Error) -> Self {
         Error::Value(err.to_string())
@@ -1,8 +1,82 @@
+pub mod config;
 pub mod data_types;
 pub mod raft_engine;
 pub mod storage_engine;
 pub mod error;
 
-fn main() {
-    println!("Hello, world!");
+use config::Config;
+use error::{Error, Result};
+use raft_engine::{
+    machine_state::{KvState, MachineState},
+    messaging::Handshake,
+    raft_log::RaftLog,
+    raft_server::{Server, TcpTransport, TlsTransport, Transport}
+};
+use storage_engine::{
+    key_value_storage::{KvBitCask, KvMemory},
+    log_storage::{Hybrid, LogMemory, LogStore}
+};
+
+use std::path::Path;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// The default configuration file, if none is given via -c or --config.
+const DEFAULT_CONFIG: &str = "config/boula.toml";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let mut config_path = DEFAULT_CONFIG.to_string();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                config_path = args
+                    .next()
+                    .ok_or_else(|| Error::Config(format!("No path given for {}", arg)))?
+            }
+            arg => return Err(Error::Config(format!("Unknown argument {}", arg))),
+        }
+    }
+    let config = Config::load(Path::new(&config_path))?;
+
+    let log_level = config.log_level.parse::<simplelog::LevelFilter>()?;
+    let mut log_config = simplelog::ConfigBuilder::new();
+    if log_level != simplelog::LevelFilter::Debug {
+        log_config.add_filter_allow_str("boula");
+    }
+    simplelog::SimpleLogger::init(log_level, log_config.build())?;
+
+    let path = Path::new(&config.data_dir);
+    let log_store: Box<dyn LogStore> = match config.storage_raft.as_str() {
+        "hybrid" => Box::new(Hybrid::new(path, config.sync)?),
+        "memory" => Box::new(LogMemory::new()),
+        name => return Err(Error::Config(format!("Unknown Raft storage engine {}", name))),
+    };
+    let state: Box<dyn MachineState> = match config.storage_state.as_str() {
+        "bitcask" => Box::new(KvState::new(Box::new(KvBitCask::new_compact(
+            &path.join("state"),
+            config.compact_threshold,
+        )?))?),
+        "memory" => Box::new(KvState::new(Box::new(KvMemory::new()))?),
+        name => return Err(Error::Config(format!("Unknown state storage engine {}", name))),
+    };
+
+    let log = RaftLog::new(log_store)?;
+    let server = Server::new(&config.id, config.peers, log, state, config.raft).await?;
+    let raft_listener = TcpListener::bind(&config.listen_raft).await?;
+    let handshake = Handshake::new(&config.cluster_id, &config.id);
+    let transport: Box<dyn Transport> = match &config.tls {
+        Some(tls) => Box::new(TlsTransport::new(raft_listener, handshake, tls)?),
+        None => Box::new(TcpTransport::new(raft_listener, handshake)),
+    };
+    let client_listener = TcpListener::bind(&config.listen_client).await?;
+    let (client_tx, client_rx) = mpsc::unbounded_channel();
+    log::info!("Node {} listening for clients on {}", config.id, config.listen_client);
+
+    tokio::try_join!(
+        server.serve(transport, client_rx),
+        Server::serve_clients(client_listener, client_tx),
+    )?;
+    Ok(())
 }
@@ -3,25 +3,74 @@ use crate::{
     error::{Error, Result},
     raft_engine::{
         messaging::{Address, Event, Message, Response},
-        raft_log::{Entry, Scan},
-        machine_state::{Instruction, Query, MachineState}
+        raft_log::{Entry, Scan, Snapshot},
+        machine_state::{Instruction, Query, MachineState, SessionEntry, Sessions, StateView}
     }
 };
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, RwLock};
 use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tokio_stream::wrappers::UnboundedReceiverStream;
+use futures::FutureExt as _;
 use tokio_stream::StreamExt as _;
 
+/// The number of applied entries after which the state machine is snapshotted, allowing the node
+/// to compact its log.
+pub const SNAPSHOT_THRESHOLD: u64 = 1000;
+
+/// The maximum number of queued log entries applied to the state machine as a single batch.
+pub const MAX_APPLY_BATCH: usize = 256;
+
+/// The maximum number of state machine queries executing concurrently. Further ready queries
+/// are queued until a query completes.
+pub const MAX_QUERY_WORKERS: usize = 16;
 
 /// Drives a state machine, taking operations from state_rx and sending results via node_tx.
 pub struct Driver {
     pub state_rx: UnboundedReceiverStream<Instruction>,
     pub node_tx: mpsc::UnboundedSender<Message>,
     pub applied_index: u64,
+    /// The index of the last state machine snapshot.
+    pub snapshot_index: u64,
     /// Notify clients when their mutation is applied. <index, (client, id)>
     pub notify: HashMap<u64, (Address, Vec<u8>)>,
     /// Execute client queries when they receive a quorum. <index, <id, query>>
     pub queries: BTreeMap<u64, BTreeMap<Vec<u8>, Query>>,
+    /// Client sessions, used to apply session mutations exactly once. They are included in
+    /// snapshots along with the state machine data.
+    pub sessions: Sessions,
+    /// Queries executing on worker threads.
+    workers: JoinSet<Result<()>>,
+    /// Queries waiting for a worker thread.
+    queued: VecDeque<QueuedQuery>,
+}
+
+/// A state machine shared by the driver and its query workers.
+pub type SharedState = Arc<RwLock<Box<dyn MachineState>>>;
+
+/// A read-only state machine view, shared by the queries executing against it.
+type SharedView = Arc<dyn StateView>;
+
+/// A state machine query waiting for a worker thread.
+struct QueuedQuery {
+    /// The state machine view to execute against, if supported by the state machine.
+    view: Option<SharedView>,
+    id: Vec<u8>,
+    address: Address,
+    command: Vec<u8>,
+}
+
+/// A state machine command pending in a batch of applied log entries.
+struct PendingCommand {
+    /// The position of the entry's response in the batch.
+    slot: usize,
+    /// The log index of the entry.
+    index: u64,
+    /// The state machine command.
+    command: Vec<u8>,
+    /// The session mutation's ID, sequence number, and time, if any.
+    session: Option<(u64, u64, u64)>,
 }
 
 impl Driver {
@@ -34,40 +83,249 @@ impl Driver {
             state_rx: UnboundedReceiverStream::new(state_rx),
             node_tx,
             applied_index: 0,
+            snapshot_index: 0,
             notify: HashMap::new(),
             queries: BTreeMap::new(),
+            sessions: Sessions::new(),
+            workers: JoinSet::new(),
+            queued: VecDeque::new(),
         }
     }
 
     /// Drives a state machine.
-    pub async fn drive(mut self, mut state: Box<dyn MachineState>) -> Result<()> {
+    pub async fn drive(mut self, state: Box<dyn MachineState>) -> Result<()> {
         debug!("Starting state machine driver");
-        while let Some(instruction) = self.state_rx.next().await {
-            if let Err(error) = self.execute(instruction, &mut *state).await {
-                error!("Halting state machine due to error: {}", error);
-                return Err(error);
-            }
+        let state: SharedState = Arc::new(RwLock::new(state));
+        if let Err(error) = self.run(&state).await {
+            error!("Halting state machine due to error: {}", error);
+            return Err(error);
         }
         debug!("Stopping state machine driver");
         Ok(())
     }
 
+    /// Executes instructions until state_rx is closed and all queries have completed.
+    async fn run(&mut self, state: &SharedState) -> Result<()> {
+        loop {
+            let instruction = tokio::select! {
+                Some(joined) = self.workers.join_next() => {
+                    joined??;
+                    if let Some(query) = self.queued.pop_front() {
+                        self.query_spawn(state, query);
+                    }
+                    continue;
+                }
+                instruction = self.state_rx.next() => match instruction {
+                    Some(instruction) => instruction,
+                    None => break,
+                },
+            };
+            // Batch any further log entries that are already queued. An instruction following
+            // them is executed after the batch, to preserve ordering.
+            match instruction {
+                Instruction::Apply { entry } => {
+                    let mut entries = vec![entry];
+                    let mut next = None;
+                    while entries.len() < MAX_APPLY_BATCH {
+                        match self.state_rx.next().now_or_never() {
+                            Some(Some(Instruction::Apply { entry })) => entries.push(entry),
+                            Some(Some(instruction)) => {
+                                next = Some(instruction);
+                                break;
+                            }
+                            Some(None) | None => break,
+                        }
+                    }
+                    self.apply_entries(state, entries).await?;
+                    if let Some(instruction) = next {
+                        self.execute(instruction, state).await?;
+                    }
+                }
+                instruction => self.execute(instruction, state).await?,
+            }
+        }
+        while let Some(joined) = self.workers.join_next().await {
+            joined??;
+            if let Some(query) = self.queued.pop_front() {
+                self.query_spawn(state, query);
+            }
+        }
+        Ok(())
+    }
+
     /// Synchronously (re)plays a set of log entries, for initial sync.
     pub fn replay<'a>(&mut self, state: &mut dyn MachineState, mut scan: Scan<'a>) -> Result<()> {
+        let mut entries = Vec::new();
         while let Some(entry) = scan.next().transpose()? {
             debug!("Replaying {:?}", entry);
-            if let Some(command) = entry.command {
-                match state.mutate(entry.index, command) {
-                    Err(error @ Error::Internal(_)) => return Err(error),
-                    _ => self.applied_index = entry.index,
+            entries.push(entry);
+            if entries.len() >= MAX_APPLY_BATCH {
+                self.replay_batch(state, std::mem::take(&mut entries))?;
+            }
+        }
+        self.replay_batch(state, entries)
+    }
+
+    /// Replays a batch of log entries, discarding the responses.
+    fn replay_batch(&mut self, state: &mut dyn MachineState, entries: Vec<Entry>) -> Result<()> {
+        if let Some(index) = entries.last().map(|entry| entry.index) {
+            self.apply(state, entries)?;
+            self.applied_index = index;
+        }
+        Ok(())
+    }
+
+    /// Loads the client sessions on startup, before replaying any unapplied entries. They are
+    /// taken from the state machine if it saved them at or after the snapshot, otherwise from
+    /// the snapshot. Returns the index they were saved at, after which any entries that have
+    /// already been applied to the state machine must be passed to replay_sessions().
+    pub fn load_sessions(
+        &mut self,
+        state: &dyn MachineState,
+        snapshot: Option<Snapshot>,
+    ) -> Result<u64> {
+        let snapshot_index = snapshot.as_ref().map_or(0, |snapshot| snapshot.index);
+        if let Some((index, sessions)) = state.load_sessions()? {
+            if index >= snapshot_index && index <= state.applied_index() {
+                self.sessions = bincode::deserialize(&sessions)?;
+                return Ok(index);
+            }
+        }
+        if let Some(snapshot) = snapshot {
+            self.sessions = bincode::deserialize::<(Sessions, Vec<u8>)>(&snapshot.data)?.0;
+        }
+        Ok(snapshot_index)
+    }
+
+    /// Rebuilds the client sessions from log entries that have already been applied to the
+    /// state machine, following the ones loaded by load_sessions(). The responses of these
+    /// mutations are unknown, which only happens if the node crashed before saving them.
+    pub fn replay_sessions<'a>(&mut self, mut scan: Scan<'a>) -> Result<()> {
+        while let Some(entry) = scan.next().transpose()? {
+            if let Some(session) = &entry.session {
+                self.sessions.replay(entry.index, session);
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the state machine and client sessions from snapshot data.
+    pub fn restore(
+        &mut self,
+        state: &mut dyn MachineState,
+        index: u64,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let (sessions, data): (Sessions, Vec<u8>) = bincode::deserialize(&data)?;
+        state.restore(index, data)?;
+        state.save_sessions(index, bincode::serialize(&sessions)?)?;
+        self.sessions = sessions;
+        Ok(())
+    }
+
+    /// Applies a batch of log entries to the state machine, returning the responses for the
+    /// clients that submitted them by index. Commands are applied via a single mutate_batch()
+    /// call, except that a session mutation is only checked for duplicates once any previous
+    /// mutation in the same session has been applied. If any session changed, the sessions are
+    /// saved once the batch has been applied.
+    fn apply(
+        &mut self,
+        state: &mut dyn MachineState,
+        entries: Vec<Entry>,
+    ) -> Result<Vec<(u64, Result<Response>)>> {
+        let last_index = entries.last().map_or(0, |entry| entry.index);
+        let has_sessions = entries.iter().any(|entry| entry.session.is_some());
+        let mut responses = Vec::with_capacity(entries.len());
+        let mut pending = Vec::new();
+        for Entry { index, command, membership, session, .. } in entries {
+            if let Some(SessionEntry::Mutate { id, .. }) = &session {
+                if pending.iter().any(|p: &PendingCommand| p.session.is_some_and(|s| s.0 == *id)) {
+                    self.mutate(state, std::mem::take(&mut pending), &mut responses)?;
+                }
+            }
+            if let Some(session) = &session {
+                self.sessions.expire(session.time());
+            }
+            let response = match (command, session) {
+                (None, Some(SessionEntry::Open { time })) => {
+                    self.sessions.open(index, time);
+                    Some(Ok(Response::Session(index)))
+                }
+                (Some(command), Some(SessionEntry::Mutate { id, sequence, time })) => {
+                    match self.sessions.check(id, sequence) {
+                        Some(result) => {
+                            debug!("Skipping session {} mutation {} at {}", id, sequence, index);
+                            Some(result.map(Response::State))
+                        }
+                        None => {
+                            // Record the mutation right away, such that later entries in the
+                            // batch see the same sessions as if it was applied on its own.
+                            self.sessions.record(id, sequence, time, None);
+                            let session = Some((id, sequence, time));
+                            let slot = responses.len();
+                            pending.push(PendingCommand { slot, index, command, session });
+                            None
+                        }
+                    }
+                }
+                (Some(command), _) => {
+                    let slot = responses.len();
+                    pending.push(PendingCommand { slot, index, command, session: None });
+                    None
                 }
+                // Membership changes don't affect the state machine, but may have a client
+                // waiting for them to commit.
+                (None, _) => membership.map(|membership| Ok(Response::Membership(membership))),
+            };
+            responses.push((index, response));
+        }
+        self.mutate(state, pending, &mut responses)?;
+        if has_sessions {
+            state.save_sessions(last_index, bincode::serialize(&self.sessions)?)?;
+        }
+        Ok(responses
+            .into_iter()
+            .filter_map(|(index, response)| Some((index, response?)))
+            .collect())
+    }
+
+    /// Applies pending state machine commands, filling in their responses and recording session
+    /// mutation results. Internal errors halt the node, while other errors are returned to the
+    /// client.
+    fn mutate(
+        &mut self,
+        state: &mut dyn MachineState,
+        pending: Vec<PendingCommand>,
+        responses: &mut [(u64, Option<Result<Response>>)],
+    ) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let (commands, pending): (Vec<_>, Vec<_>) =
+            pending.into_iter().map(|p| ((p.index, p.command), (p.slot, p.session))).unzip();
+        debug!("Applying state machine commands {:?}", commands);
+        let results = state.mutate_batch(commands)?;
+        if results.len() != pending.len() {
+            return Err(Error::Internal(format!(
+                "State machine returned {} results for {} commands",
+                results.len(),
+                pending.len()
+            )));
+        }
+        for ((slot, session), result) in pending.into_iter().zip(results) {
+            if let Err(error @ Error::Internal(_)) = result {
+                return Err(error);
+            }
+            if let Some((id, sequence, time)) = session {
+                self.sessions.record(id, sequence, time, Some(result.clone()));
             }
+            responses[slot].1 = Some(result.map(Response::State));
         }
         Ok(())
     }
 
     /// Executes a state machine instruction.
-    pub async fn execute(&mut self, i: Instruction, state: &mut dyn MachineState) -> Result<()> {
+    pub async fn execute(&mut self, i: Instruction, state: &SharedState) -> Result<()> {
         debug!("Executing {:?}", i);
         match i {
             Instruction::Abort => {
@@ -75,24 +333,10 @@ impl Driver {
                 self.query_abort()?;
             }
 
-            Instruction::Apply { entry: Entry { index, command, .. } } => {
-                if let Some(command) = command {
-                    debug!("Applying state machine command {}: {:?}", index, command);
-                    match tokio::task::block_in_place(|| state.mutate(index, command)) {
-                        Err(error @ Error::Internal(_)) => return Err(error),
-                        result => self.notify_applied(index, result)?,
-                    };
-                }
-                // We have to track applied_index here, separately from the state machine, because
-                // no-op log entries are significant for whether a query should be executed.
-                self.applied_index = index;
-                // Try to execute any pending queries, since they may have been submitted for a
-                // commit_index which hadn't been applied yet.
-                self.query_execute(state)?;
-            }
+            Instruction::Apply { entry } => self.apply_entries(state, vec![entry]).await?,
 
             Instruction::Notify { id, address, index } => {
-                if index > state.applied_index() {
+                if index > state.read()?.applied_index() {
                     self.notify.insert(index, (address, id));
                 } else {
                     self.send(address, Event::ClientResponse { id, response: Err(Error::Abort) })?;
@@ -100,14 +344,49 @@ impl Driver {
             }
 
             Instruction::Query { id, address, command, index, term, quorum } => {
+                let command = Some(command);
                 self.queries.entry(index).or_default().insert(
                     id.clone(),
-                    Query { id, term, address, command, quorum, votes: HashSet::new() },
+                    Query { id, term, address, command, index, quorum, votes: HashSet::new() },
                 );
+                // Queries without a quorum, e.g. under a leader lease, may be ready right away.
+                self.query_execute(state)?;
+            }
+
+            Instruction::ReadIndex { id, address, term, index, quorum } => {
+                let command = None;
+                self.queries.entry(index).or_default().insert(
+                    id.clone(),
+                    Query { id, term, address, command, index, quorum, votes: HashSet::new() },
+                );
+                self.query_execute(state)?;
+            }
+
+            Instruction::StaleQuery { id, address, command, index } => {
+                if self.applied_index >= index {
+                    let view = Self::view(state)?;
+                    self.query_spawn(state, QueuedQuery { view, id, address, command });
+                } else {
+                    let response = Err(Error::Value(format!(
+                        "Applied index {} is behind index {} required by max staleness",
+                        self.applied_index, index
+                    )));
+                    self.send(address, Event::ClientResponse { id, response })?;
+                }
+            }
+
+            Instruction::Restore { snapshot } => {
+                debug!("Restoring state machine snapshot at index {}", snapshot.index);
+                let (index, data) = (snapshot.index, snapshot.data);
+                tokio::task::block_in_place(|| self.restore(&mut **state.write()?, index, data))?;
+                self.applied_index = snapshot.index;
+                self.snapshot_index = snapshot.index;
+                self.notify_abort_applied(snapshot.index)?;
+                self.query_execute(state)?;
             }
 
             Instruction::Status { id, address, mut status } => {
-                status.apply_index = state.applied_index();
+                status.apply_index = state.read()?.applied_index();
                 self.send(
                     address,
                     Event::ClientResponse { id, response: Ok(Response::Status(*status)) },
@@ -122,6 +401,30 @@ impl Driver {
         Ok(())
     }
 
+    /// Applies a batch of log entries, notifying clients of their results. The state machine is
+    /// locked exclusively, but queries execute against views, so only queries against state
+    /// machines without view support can hold it up.
+    async fn apply_entries(&mut self, state: &SharedState, entries: Vec<Entry>) -> Result<()> {
+        let Some(index) = entries.last().map(|entry| entry.index) else {
+            return Ok(());
+        };
+        let responses =
+            tokio::task::block_in_place(|| self.apply(&mut **state.write()?, entries))?;
+        for (index, response) in responses {
+            self.notify_applied(index, response)?;
+        }
+        // We have to track applied_index here, separately from the state machine, because
+        // no-op log entries are significant for whether a query should be executed.
+        self.applied_index = index;
+        // Try to execute any pending queries, since they may have been submitted for a
+        // commit_index which hadn't been applied yet.
+        self.query_execute(state)?;
+        if self.applied_index >= self.snapshot_index + SNAPSHOT_THRESHOLD {
+            self.snapshot(state)?;
+        }
+        Ok(())
+    }
+
     /// Aborts all pending notifications.
     fn notify_abort(&mut self) -> Result<()> {
         for (_, (address, id)) in std::mem::take(&mut self.notify) {
@@ -130,10 +433,22 @@ impl Driver {
         Ok(())
     }
 
+    /// Aborts pending notifications up to and including the given index, e.g. when their entries
+    /// were replaced by a snapshot and their results are unknown.
+    fn notify_abort_applied(&mut self, index: u64) -> Result<()> {
+        let indexes: Vec<u64> = self.notify.keys().filter(|i| **i <= index).copied().collect();
+        for index in indexes {
+            if let Some((address, id)) = self.notify.remove(&index) {
+                self.send(address, Event::ClientResponse { id, response: Err(Error::Abort) })?;
+            }
+        }
+        Ok(())
+    }
+
     /// Notifies a client about an applied log entry, if any.
-    fn notify_applied(&mut self, index: u64, result: Result<Vec<u8>>) -> Result<()> {
+    fn notify_applied(&mut self, index: u64, response: Result<Response>) -> Result<()> {
         if let Some((to, id)) = self.notify.remove(&index) {
-            self.send(to, Event::ClientResponse { id, response: result.map(Response::State) })?;
+            self.send(to, Event::ClientResponse { id, response })?;
         }
         Ok(())
     }
@@ -151,30 +466,72 @@ impl Driver {
         Ok(())
     }
 
-    /// Executes any queries that are ready.
-    fn query_execute(&mut self, state: &mut dyn MachineState) -> Result<()> {
-        for query in self.query_ready(self.applied_index) {
-            debug!("Executing query {:?}", query.command);
-            let result = state.query(query.command);
-            if let Err(error @ Error::Internal(_)) = result {
-                return Err(error);
+    /// Executes any queries that are ready. They share a view of the state machine at the
+    /// applied index, if supported.
+    fn query_execute(&mut self, state: &SharedState) -> Result<()> {
+        let queries = self.query_ready(self.applied_index);
+        let view = match queries.iter().any(|query| query.command.is_some()) {
+            true => Self::view(state)?,
+            false => None,
+        };
+        for query in queries {
+            match query.command {
+                Some(command) => {
+                    let (view, id, address) = (view.clone(), query.id, query.address);
+                    self.query_spawn(state, QueuedQuery { view, id, address, command });
+                }
+                None => {
+                    let response = Ok(Response::ReadIndex(query.index));
+                    self.send(query.address, Event::ClientResponse { id: query.id, response })?
+                }
             }
-            self.send(
-                query.address,
-                Event::ClientResponse { id: query.id, response: result.map(Response::State) },
-            )?
         }
         Ok(())
     }
 
-    /// Fetches and removes any ready queries, where index <= applied_index.
+    /// Returns a read-only view of the state machine at the applied index for queries to
+    /// execute against, if the state machine supports it.
+    fn view(state: &SharedState) -> Result<Option<SharedView>> {
+        Ok(state.read()?.view()?.map(Arc::from))
+    }
+
+    /// Executes a state machine query on a worker thread, which responds to the client. The
+    /// query executes against its view if any, concurrently with other queries and with
+    /// applies. Otherwise, it executes against the state machine itself, and applies wait for
+    /// it. If MAX_QUERY_WORKERS queries are already executing, the query is queued until one of
+    /// them completes. Internal query errors halt the driver.
+    fn query_spawn(&mut self, state: &SharedState, query: QueuedQuery) {
+        if self.workers.len() >= MAX_QUERY_WORKERS {
+            self.queued.push_back(query);
+            return;
+        }
+        let (state, node_tx) = (state.clone(), self.node_tx.clone());
+        self.workers.spawn_blocking(move || {
+            let QueuedQuery { view, id, address, command } = query;
+            debug!("Executing query {:?}", command);
+            let result = match view {
+                Some(view) => view.query(command),
+                None => state.read()?.query(command),
+            };
+            if let Err(error @ Error::Internal(_)) = result {
+                return Err(error);
+            }
+            let event = Event::ClientResponse { id, response: result.map(Response::State) };
+            Self::send_via(&node_tx, address, event)
+        });
+    }
+
+    /// Fetches and removes any ready queries, where index <= applied_index. Read index requests
+    /// don't have to wait for the index to be applied.
     fn query_ready(&mut self, applied_index: u64) -> Vec<Query> {
         let mut ready = Vec::new();
         let mut empty = Vec::new();
-        for (index, queries) in self.queries.range_mut(..=applied_index) {
+        for (index, queries) in self.queries.iter_mut() {
             let mut ready_ids = Vec::new();
             for (id, query) in queries.iter_mut() {
-                if query.votes.len() as u64 >= query.quorum {
+                let applied = *index <= applied_index || query.command.is_none();
+                let confirmed = query.quorum.as_ref().is_none_or(|m| m.has_quorum(&query.votes));
+                if applied && confirmed {
                     ready_ids.push(id.clone());
                 }
             }
@@ -193,21 +550,37 @@ impl Driver {
         ready
     }
 
-    /// Votes for queries up to and including a given commit index for a term by an address.
+    /// Votes for queries up to and including a given commit index for a term by a node.
     fn query_vote(&mut self, term: u64, commit_index: u64, address: Address) {
+        let Address::Peer(id) = address else { return };
         for (_, queries) in self.queries.range_mut(..=commit_index) {
             for (_, query) in queries.iter_mut() {
                 if term >= query.term {
-                    query.votes.insert(address.clone());
+                    query.votes.insert(id.clone());
                 }
             }
         }
     }
 
+    /// Snapshots the state machine and hands the snapshot to the local node, which uses it to
+    /// compact its log.
+    fn snapshot(&mut self, state: &SharedState) -> Result<()> {
+        debug!("Taking state machine snapshot at index {}", self.applied_index);
+        let data = tokio::task::block_in_place(|| state.read()?.snapshot())?;
+        let data = bincode::serialize(&(&self.sessions, data))?;
+        self.snapshot_index = self.applied_index;
+        self.send(Address::Local, Event::Snapshot { index: self.applied_index, data })
+    }
+
     /// Sends a message.
     fn send(&self, to: Address, event: Event) -> Result<()> {
-        let msg = Message { from: Address::Local, to, term: 0, event };
+        Self::send_via(&self.node_tx, to, event)
+    }
+
+    /// Sends a message via the given node channel.
+    fn send_via(node_tx: &mpsc::UnboundedSender<Message>, to: Address, event: Event) -> Result<()> {
+        let msg = Message { group: 0, from: Address::Local, to, term: 0, event };
         debug!("Sending {:?}", msg);
-        Ok(self.node_tx.send(msg)?)
+        Ok(node_tx.send(msg)?)
     }
 }
\ No newline at end of file
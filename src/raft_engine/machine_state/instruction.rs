@@ -1,8 +1,8 @@
 use crate::{
     raft_engine::{
-        raft_log::Entry,
+        raft_log::{Entry, Snapshot},
         messaging::Address,
-        raft_node::Status
+        raft_node::{Membership, Status}
     },
 };
 
@@ -16,10 +16,26 @@ pub enum Instruction {
     Apply { entry: Entry },
     /// Notify the given address with the result of applying the entry at the given index.
     Notify { id: Vec<u8>, address: Address, index: u64 },
-    /// Query the state machine when the given term and index has been confirmed by vote.
-    Query { id: Vec<u8>, address: Address, command: Vec<u8>, term: u64, index: u64, quorum: u64 },
+    /// Query the state machine when the given term and index has been confirmed by a quorum of
+    /// the given membership, or right away if None.
+    Query {
+        id: Vec<u8>,
+        address: Address,
+        command: Vec<u8>,
+        term: u64,
+        index: u64,
+        quorum: Option<Membership>,
+    },
+    /// Respond with the given index when the given term and index has been confirmed by a quorum
+    /// of the given membership, without waiting for it to be applied.
+    ReadIndex { id: Vec<u8>, address: Address, term: u64, index: u64, quorum: Option<Membership> },
+    /// Query the state machine right away, if it has applied at least the given index.
+    StaleQuery { id: Vec<u8>, address: Address, command: Vec<u8>, index: u64 },
+    /// Replace the state machine with a snapshot received from the leader.
+    Restore { snapshot: Snapshot },
     /// Extend the given server status and return it to the given address.
     Status { id: Vec<u8>, address: Address, status: Box<Status> },
-    /// Votes for queries at the given term and commit index.
+    /// Votes for queries at the given term and commit index. Only votes from Address::Peer
+    /// count, so the leader votes with its own node ID.
     Vote { term: u64, index: u64, address: Address },
 }
\ No newline at end of file
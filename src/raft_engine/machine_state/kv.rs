@@ -0,0 +1,246 @@
+use crate::{
+    error::{Error, Result},
+    raft_engine::machine_state::{MachineState, ReadOnlyState, StateView},
+    storage_engine::key_value_storage::{KvCow, KvStore, Range}
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The key under which the applied index is stored.
+const APPLIED_INDEX_KEY: &[u8] = &[0x00];
+/// The key under which the driver's client sessions are stored, along with their index.
+const SESSIONS_KEY: &[u8] = &[0x00, 0x01];
+/// The prefix of user keys, to keep them separate from the applied index.
+const DATA_PREFIX: u8 = 0x01;
+
+/// A key/value state machine mutation, the command of a Raft mutate request.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum KvMutation {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+/// A key/value Raft state machine backed by a key/value store. Mutate commands are serialized
+/// KvMutations, and query commands are raw keys which return a serialized Option of the value.
+/// The store is wrapped in a copy-on-write store, whose snapshots back the read-only views.
+pub struct KvState {
+    store: Box<dyn KvStore>,
+    applied_index: u64,
+}
+
+impl KvState {
+    /// Creates a new key/value state machine, resuming from the store's applied index.
+    pub fn new(store: Box<dyn KvStore>) -> Result<Self> {
+        let applied_index = match store.get(APPLIED_INDEX_KEY)? {
+            Some(v) => bincode::deserialize(&v)?,
+            None => 0,
+        };
+        Ok(Self { store: Box::new(KvCow::new(store)), applied_index })
+    }
+
+    /// Returns the storage key of a user key.
+    fn data_key(key: &[u8]) -> Vec<u8> {
+        let mut data_key = Vec::with_capacity(key.len() + 1);
+        data_key.push(DATA_PREFIX);
+        data_key.extend_from_slice(key);
+        data_key
+    }
+
+    /// Returns the range of all user keys.
+    fn data_range() -> Range {
+        Range::from(vec![DATA_PREFIX]..vec![DATA_PREFIX + 1])
+    }
+
+    /// Applies a mutation without flushing the store. The outer result is an error if the
+    /// store failed, and the inner result is returned to the client.
+    fn apply(&mut self, index: u64, command: Vec<u8>) -> Result<Result<Vec<u8>>> {
+        // Invalid commands are still applied, returning the error to the client.
+        let result = match bincode::deserialize(&command) {
+            Ok(KvMutation::Set { key, value }) => self.store.set(&Self::data_key(&key), value),
+            Ok(KvMutation::Delete { key }) => self.store.delete(&Self::data_key(&key)),
+            Err(err) => Err(Error::Value(format!("Invalid key/value mutation: {}", err))),
+        };
+        self.set_applied_index(index)?;
+        Ok(result.map(|_| Vec::new()))
+    }
+
+    /// Records the applied index in the store.
+    fn set_applied_index(&mut self, index: u64) -> Result<()> {
+        self.store.set(APPLIED_INDEX_KEY, bincode::serialize(&index)?)?;
+        self.applied_index = index;
+        Ok(())
+    }
+}
+
+impl MachineState for KvState {
+    fn applied_index(&self) -> u64 {
+        self.applied_index
+    }
+
+    fn mutate(&mut self, index: u64, command: Vec<u8>) -> Result<Vec<u8>> {
+        let result = self.apply(index, command)?;
+        self.store.flush()?;
+        result
+    }
+
+    fn mutate_batch(&mut self, commands: Vec<(u64, Vec<u8>)>) -> Result<Vec<Result<Vec<u8>>>> {
+        let results = commands
+            .into_iter()
+            .map(|(index, command)| self.apply(index, command))
+            .collect::<Result<Vec<_>>>()?;
+        self.store.flush()?;
+        Ok(results)
+    }
+
+    fn query(&self, command: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self.store.get(&Self::data_key(&command))?)?)
+    }
+
+    fn view(&self) -> Result<Option<Box<dyn StateView>>> {
+        let Some(store) = self.store.snapshot()? else { return Ok(None) };
+        Ok(Some(Box::new(ReadOnlyState(Self { store, applied_index: self.applied_index }))))
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        let pairs = self
+            .store
+            .scan(Self::data_range())
+            .map(|r| r.map(|(key, value)| (key[1..].to_vec(), value)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(bincode::serialize(&pairs)?)
+    }
+
+    fn restore(&mut self, index: u64, snapshot: Vec<u8>) -> Result<()> {
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = bincode::deserialize(&snapshot)?;
+        // Clear the applied index before touching the data, and only write it back once the
+        // snapshot has been fully restored. If we crash halfway, the node will find the state
+        // machine behind the log snapshot on restart and restore the snapshot again, instead of
+        // trusting a partially restored state.
+        self.store.delete(APPLIED_INDEX_KEY)?;
+        self.store.flush()?;
+        self.applied_index = 0;
+        let keys = self
+            .store
+            .scan(Self::data_range())
+            .map(|r| r.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        for key in keys {
+            self.store.delete(&key)?;
+        }
+        for (key, value) in pairs {
+            self.store.set(&Self::data_key(&key), value)?;
+        }
+        self.set_applied_index(index)?;
+        self.store.flush()
+    }
+
+    fn save_sessions(&mut self, index: u64, sessions: Vec<u8>) -> Result<()> {
+        self.store.set(SESSIONS_KEY, bincode::serialize(&(index, sessions))?)?;
+        self.store.flush()
+    }
+
+    fn load_sessions(&self) -> Result<Option<(u64, Vec<u8>)>> {
+        self.store.get(SESSIONS_KEY)?.map(|v| Ok(bincode::deserialize(&v)?)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_engine::key_value_storage::KvMemory;
+    use pretty_assertions::assert_eq;
+
+    fn get(state: &KvState, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(bincode::deserialize(&state.query(key.to_vec())?)?)
+    }
+
+    #[test]
+    fn mutate_query() -> Result<()> {
+        let mut state = KvState::new(Box::new(KvMemory::new()))?;
+        assert_eq!(state.applied_index(), 0);
+
+        let set = KvMutation::Set { key: b"a".to_vec(), value: vec![0x01] };
+        state.mutate(1, bincode::serialize(&set)?)?;
+        assert_eq!(get(&state, b"a")?, Some(vec![0x01]));
+        assert_eq!(get(&state, b"b")?, None);
+
+        state.mutate(2, bincode::serialize(&KvMutation::Delete { key: b"a".to_vec() })?)?;
+        assert_eq!(get(&state, b"a")?, None);
+
+        // Invalid mutations are applied, but return an error.
+        assert!(matches!(state.mutate(3, vec![0xff]), Err(Error::Value(_))));
+        assert_eq!(state.applied_index(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn mutate_batch() -> Result<()> {
+        let mut state = KvState::new(Box::new(KvMemory::new()))?;
+        let set = KvMutation::Set { key: b"a".to_vec(), value: vec![0x01] };
+        let delete = KvMutation::Delete { key: b"b".to_vec() };
+        let results = state.mutate_batch(vec![
+            (1, bincode::serialize(&set)?),
+            (2, vec![0xff]),
+            (3, bincode::serialize(&delete)?),
+        ])?;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], Ok(Vec::new()));
+        assert!(matches!(results[1], Err(Error::Value(_))));
+        assert_eq!(results[2], Ok(Vec::new()));
+        assert_eq!(get(&state, b"a")?, Some(vec![0x01]));
+        assert_eq!(state.applied_index(), 3);
+        Ok(())
+    }
+
+    #[test]
+    // Views see the state as of when they were taken.
+    fn view() -> Result<()> {
+        let mut state = KvState::new(Box::new(KvMemory::new()))?;
+        let set = KvMutation::Set { key: b"a".to_vec(), value: vec![0x01] };
+        state.mutate(1, bincode::serialize(&set)?)?;
+        let view = state.view()?.expect("view");
+
+        let set = KvMutation::Set { key: b"a".to_vec(), value: vec![0x02] };
+        state.mutate(2, bincode::serialize(&set)?)?;
+        assert_eq!(get(&state, b"a")?, Some(vec![0x02]));
+        let value: Option<Vec<u8>> = bincode::deserialize(&view.query(b"a".to_vec())?)?;
+        assert_eq!(value, Some(vec![0x01]));
+        assert_eq!(view.applied_index(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_restore() -> Result<()> {
+        let mut state = KvState::new(Box::new(KvMemory::new()))?;
+        let set = KvMutation::Set { key: b"a".to_vec(), value: vec![0x01] };
+        state.mutate(1, bincode::serialize(&set)?)?;
+        let snapshot = state.snapshot()?;
+
+        let mut other = KvState::new(Box::new(KvMemory::new()))?;
+        let set = KvMutation::Set { key: b"b".to_vec(), value: vec![0x02] };
+        other.mutate(1, bincode::serialize(&set)?)?;
+        other.restore(5, snapshot)?;
+        assert_eq!(other.applied_index(), 5);
+        assert_eq!(get(&other, b"a")?, Some(vec![0x01]));
+        assert_eq!(get(&other, b"b")?, None);
+
+        // The restored applied index is persisted.
+        let other = KvState::new(other.store)?;
+        assert_eq!(other.applied_index(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn sessions() -> Result<()> {
+        let mut state = KvState::new(Box::new(KvMemory::new()))?;
+        assert_eq!(state.load_sessions()?, None);
+        state.save_sessions(3, vec![0x01])?;
+        state.save_sessions(4, vec![0x02])?;
+        assert_eq!(state.load_sessions()?, Some((4, vec![0x02])));
+
+        // Sessions are not user data, so they're not included in the state machine snapshot.
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = bincode::deserialize(&state.snapshot()?)?;
+        assert!(pairs.is_empty());
+        Ok(())
+    }
+}
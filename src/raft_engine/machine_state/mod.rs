@@ -1,28 +1,44 @@
 mod driver;
 mod instruction;
+mod kv;
 mod query;
+mod session;
 mod state;
 
 
 pub use driver::*;
 pub use instruction::*;
+pub use kv::*;
 pub use query::*;
+pub use session::*;
 pub use state::*;
 
 
 
 
 
-/*
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::{
+        error::{Error, Result},
+        raft_engine::{
+            messaging::{Address, Event, Message, Response},
+            raft_log::{Entry, RaftLog, Snapshot},
+            raft_node::Membership
+        },
+        storage_engine::{key_value_storage::KvMemory, log_storage::LogTest}
+    };
     use pretty_assertions::assert_eq;
     use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tokio_stream::StreamExt as _;
 
     #[derive(Clone, Debug)]
     pub struct TestState {
         commands: Arc<Mutex<Vec<Vec<u8>>>>,
+        batches: Arc<Mutex<Vec<usize>>>,
         applied_index: Arc<Mutex<u64>>,
     }
 
@@ -30,6 +46,7 @@ pub mod tests {
         pub fn new(applied_index: u64) -> Self {
             Self {
                 commands: Arc::new(Mutex::new(Vec::new())),
+                batches: Arc::new(Mutex::new(Vec::new())),
                 applied_index: Arc::new(Mutex::new(applied_index)),
             }
         }
@@ -37,9 +54,13 @@ pub mod tests {
         pub fn list(&self) -> Vec<Vec<u8>> {
             self.commands.lock().unwrap().clone()
         }
+
+        pub fn batches(&self) -> Vec<usize> {
+            self.batches.lock().unwrap().clone()
+        }
     }
 
-    impl State for TestState {
+    impl MachineState for TestState {
         fn applied_index(&self) -> u64 {
             *self.applied_index.lock().unwrap()
         }
@@ -51,11 +72,37 @@ pub mod tests {
             Ok(command)
         }
 
+        // Records the batch size, and applies the commands via mutate().
+        fn mutate_batch(
+            &mut self,
+            commands: Vec<(u64, Vec<u8>)>,
+        ) -> Result<Vec<Result<Vec<u8>>>> {
+            self.batches.lock()?.push(commands.len());
+            commands.into_iter().map(|(index, command)| Ok(self.mutate(index, command))).collect()
+        }
+
         // Appends the command to the internal commands list.
         fn query(&self, command: Vec<u8>) -> Result<Vec<u8>> {
             self.commands.lock()?.push(command.clone());
             Ok(command)
         }
+
+        // Serializes the internal commands list.
+        fn snapshot(&self) -> Result<Vec<u8>> {
+            Ok(bincode::serialize(&*self.commands.lock()?)?)
+        }
+
+        // Replaces the internal commands list.
+        fn restore(&mut self, index: u64, snapshot: Vec<u8>) -> Result<()> {
+            *self.commands.lock()? = bincode::deserialize(&snapshot)?;
+            *self.applied_index.lock()? = index;
+            Ok(())
+        }
+    }
+
+    /// A query quorum of voters a, b and c.
+    fn quorum() -> Option<Membership> {
+        Some(Membership::new(["a", "b", "c"].map(String::from)))
     }
 
     async fn setup() -> Result<(
@@ -85,9 +132,9 @@ pub mod tests {
             command: vec![0xf0],
             term: 1,
             index: 1,
-            quorum: 2,
+            quorum: quorum(),
         })?;
-        state_tx.send(Instruction::Vote { term: 1, index: 1, address: Address::Local })?;
+        state_tx.send(Instruction::Vote { term: 1, index: 1, address: Address::Peer("a".into()) })?;
         state_tx.send(Instruction::Abort)?;
         std::mem::drop(state_tx);
 
@@ -96,12 +143,14 @@ pub mod tests {
             node_rx.collect::<Vec<_>>().await,
             vec![
                 Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer("a".into()),
                     term: 0,
                     event: Event::ClientResponse { id: vec![0x01], response: Err(Error::Abort) }
                 },
                 Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Client,
                     term: 0,
@@ -124,9 +173,17 @@ pub mod tests {
             index: 2,
             address: Address::Client,
         })?;
-        state_tx.send(Instruction::Apply { entry: Entry { index: 1, term: 1, command: None } })?;
         state_tx.send(Instruction::Apply {
-            entry: Entry { index: 2, term: 1, command: Some(vec![0xaf]) },
+            entry: Entry { index: 1, term: 1, command: None, membership: None, session: None },
+        })?;
+        state_tx.send(Instruction::Apply {
+            entry: Entry {
+                index: 2,
+                term: 1,
+                command: Some(vec![0xaf]),
+                membership: None,
+                session: None,
+            },
         })?;
         std::mem::drop(state_tx);
 
@@ -134,6 +191,7 @@ pub mod tests {
         assert_eq!(
             node_rx.collect::<Vec<_>>().await,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Client,
                 term: 0,
@@ -159,16 +217,22 @@ pub mod tests {
             command: vec![0xf0],
             term: 2,
             index: 1,
-            quorum: 2,
+            quorum: quorum(),
         })?;
         state_tx.send(Instruction::Apply {
-            entry: Entry { index: 1, term: 2, command: Some(vec![0xaf]) },
+            entry: Entry {
+                index: 1,
+                term: 2,
+                command: Some(vec![0xaf]),
+                membership: None,
+                session: None,
+            },
         })?;
-        state_tx.send(Instruction::Vote { term: 2, index: 1, address: Address::Local })?;
+        state_tx.send(Instruction::Vote { term: 2, index: 1, address: Address::Peer("a".into()) })?;
         state_tx.send(Instruction::Vote {
             term: 2,
             index: 1,
-            address: Address::Peer("a".into()),
+            address: Address::Peer("b".into()),
         })?;
         std::mem::drop(state_tx);
 
@@ -176,6 +240,7 @@ pub mod tests {
         assert_eq!(
             node_rx.collect::<Vec<_>>().await,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Client,
                 term: 0,
@@ -189,6 +254,436 @@ pub mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn driver_read_index() -> Result<()> {
+        let (_, state_tx, node_rx) = setup().await?;
+
+        // Read index requests respond once confirmed by a quorum, even if not yet applied.
+        state_tx.send(Instruction::ReadIndex {
+            id: vec![0x01],
+            address: Address::Peer("b".into()),
+            term: 2,
+            index: 3,
+            quorum: quorum(),
+        })?;
+        state_tx.send(Instruction::Vote { term: 2, index: 3, address: Address::Peer("a".into()) })?;
+        state_tx.send(Instruction::Vote {
+            term: 2,
+            index: 3,
+            address: Address::Peer("c".into()),
+        })?;
+        std::mem::drop(state_tx);
+
+        let node_rx = UnboundedReceiverStream::new(node_rx);
+        assert_eq!(
+            node_rx.collect::<Vec<_>>().await,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("b".into()),
+                term: 0,
+                event: Event::ClientResponse {
+                    id: vec![0x01],
+                    response: Ok(Response::ReadIndex(3))
+                }
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn driver_stale_query() -> Result<()> {
+        let (_, state_tx, node_rx) = setup().await?;
+
+        state_tx.send(Instruction::Apply {
+            entry: Entry {
+                index: 1,
+                term: 1,
+                command: Some(vec![0xaf]),
+                membership: None,
+                session: None,
+            },
+        })?;
+        for (id, index) in [(0x01, 1), (0x02, 2)] {
+            state_tx.send(Instruction::StaleQuery {
+                id: vec![id],
+                address: Address::Client,
+                command: vec![0xf0],
+                index,
+            })?;
+        }
+        std::mem::drop(state_tx);
+
+        // Queries execute concurrently, so responses may arrive in any order.
+        let node_rx = UnboundedReceiverStream::new(node_rx);
+        let mut messages = node_rx.collect::<Vec<_>>().await;
+        messages.sort_by_key(|msg| match &msg.event {
+            Event::ClientResponse { id, .. } => id.clone(),
+            _ => Vec::new(),
+        });
+        assert_eq!(
+            messages,
+            vec![
+                Message {
+                    group: 0,
+                    from: Address::Local,
+                    to: Address::Client,
+                    term: 0,
+                    event: Event::ClientResponse {
+                        id: vec![0x01],
+                        response: Ok(Response::State(vec![0xf0]))
+                    }
+                },
+                Message {
+                    group: 0,
+                    from: Address::Local,
+                    to: Address::Client,
+                    term: 0,
+                    event: Event::ClientResponse {
+                        id: vec![0x02],
+                        response: Err(Error::Value(
+                            "Applied index 1 is behind index 2 required by max staleness".into()
+                        ))
+                    }
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn driver_restore() -> Result<()> {
+        let (state, state_tx, node_rx) = setup().await?;
+        let data = bincode::serialize(&vec![vec![0xaa_u8]])?;
+
+        state_tx.send(Instruction::Notify {
+            id: vec![0x01],
+            index: 2,
+            address: Address::Client,
+        })?;
+        state_tx.send(Instruction::Restore {
+            snapshot: Snapshot {
+                index: 3,
+                term: 1,
+                data: bincode::serialize(&(Sessions::new(), data))?,
+                membership: None,
+            },
+        })?;
+        std::mem::drop(state_tx);
+
+        let node_rx = UnboundedReceiverStream::new(node_rx);
+        assert_eq!(
+            node_rx.collect::<Vec<_>>().await,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Client,
+                term: 0,
+                event: Event::ClientResponse { id: vec![0x01], response: Err(Error::Abort) }
+            }]
+        );
+        assert_eq!(state.list(), vec![vec![0xaa]]);
+        assert_eq!(state.applied_index(), 3);
+
+        Ok(())
+    }
+
+    // Session mutations are applied once, and retries return the cached response.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn driver_session() -> Result<()> {
+        let (state, state_tx, node_rx) = setup().await?;
+
+        let entries = vec![
+            (None, SessionEntry::Open { time: 1000 }),
+            (Some(vec![0xaf]), SessionEntry::Mutate { id: 1, sequence: 1, time: 1000 }),
+            (Some(vec![0xaf]), SessionEntry::Mutate { id: 1, sequence: 1, time: 1000 }),
+            (Some(vec![0xbf]), SessionEntry::Mutate { id: 2, sequence: 1, time: 1000 }),
+        ];
+        for (i, (command, session)) in entries.into_iter().enumerate() {
+            let index = i as u64 + 1;
+            let address = Address::Client;
+            state_tx.send(Instruction::Notify { id: vec![index as u8], index, address })?;
+            state_tx.send(Instruction::Apply {
+                entry: Entry { index, term: 1, command, membership: None, session: Some(session) },
+            })?;
+        }
+        std::mem::drop(state_tx);
+
+        let node_rx = UnboundedReceiverStream::new(node_rx);
+        let responses: Vec<_> = node_rx
+            .map(|msg| match msg.event {
+                Event::ClientResponse { response, .. } => response,
+                event => panic!("Unexpected event {:?}", event),
+            })
+            .collect()
+            .await;
+        assert_eq!(
+            responses,
+            vec![
+                Ok(Response::Session(1)),
+                Ok(Response::State(vec![0xaf])),
+                Ok(Response::State(vec![0xaf])),
+                Err(Error::SessionExpired(2)),
+            ]
+        );
+        assert_eq!(state.list(), vec![vec![0xaf]]);
+        Ok(())
+    }
+
+    // Session responses are saved by the state machine, so a mutation that is retried after a
+    // restart gets the original response.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn driver_session_restart() -> Result<()> {
+        let set = bincode::serialize(&KvMutation::Set { key: vec![0x01], value: vec![0x02] })?;
+        let mutate = SessionEntry::Mutate { id: 1, sequence: 1, time: 1000 };
+        let mut log = RaftLog::new(Box::new(LogTest::new()))?;
+        log.append_session(1, None, SessionEntry::Open { time: 1000 })?;
+        log.append_session(1, Some(set.clone()), mutate.clone())?;
+        log.commit(2)?;
+
+        let mut state = KvState::new(Box::new(KvMemory::new()))?;
+        let (_, state_rx) = mpsc::unbounded_channel();
+        let (node_tx, _) = mpsc::unbounded_channel();
+        Driver::new(state_rx, node_tx).replay(&mut state, log.scan(..))?;
+
+        // Restart the driver and retry the mutation.
+        let (state_tx, state_rx) = mpsc::unbounded_channel();
+        let (node_tx, node_rx) = mpsc::unbounded_channel();
+        let mut driver = Driver::new(state_rx, node_tx);
+        assert_eq!(driver.load_sessions(&state, None)?, 2);
+        driver.applied_index = 2;
+
+        let address = Address::Client;
+        state_tx.send(Instruction::Notify { id: vec![0x03], index: 3, address })?;
+        let session = Some(mutate);
+        state_tx.send(Instruction::Apply {
+            entry: Entry { index: 3, term: 1, command: Some(set), membership: None, session },
+        })?;
+        std::mem::drop(state_tx);
+        driver.drive(Box::new(state)).await?;
+
+        let node_rx = UnboundedReceiverStream::new(node_rx);
+        assert_eq!(
+            node_rx.map(|msg| msg.event).collect::<Vec<_>>().await,
+            vec![Event::ClientResponse { id: vec![0x03], response: Ok(Response::State(vec![])) }]
+        );
+        Ok(())
+    }
+
+    // Queries execute concurrently, so a slow query doesn't hold up other queries.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn driver_query_concurrent() -> Result<()> {
+        // A state machine whose 0xff query blocks until released.
+        struct BlockingState {
+            release: Mutex<std::sync::mpsc::Receiver<()>>,
+        }
+
+        impl MachineState for BlockingState {
+            fn applied_index(&self) -> u64 {
+                0
+            }
+
+            fn mutate(&mut self, _: u64, command: Vec<u8>) -> Result<Vec<u8>> {
+                Ok(command)
+            }
+
+            fn query(&self, command: Vec<u8>) -> Result<Vec<u8>> {
+                if command == [0xff] {
+                    self.release.lock()?.recv().map_err(|e| Error::Internal(e.to_string()))?;
+                }
+                Ok(command)
+            }
+
+            fn snapshot(&self) -> Result<Vec<u8>> {
+                Ok(Vec::new())
+            }
+
+            fn restore(&mut self, _: u64, _: Vec<u8>) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let (release_tx, release_rx) = std::sync::mpsc::channel();
+        let state = Box::new(BlockingState { release: Mutex::new(release_rx) });
+        let (state_tx, state_rx) = mpsc::unbounded_channel();
+        let (node_tx, mut node_rx) = mpsc::unbounded_channel();
+        tokio::spawn(Driver::new(state_rx, node_tx).drive(state));
+
+        for (id, command) in [(0x01, 0xff), (0x02, 0x02)] {
+            state_tx.send(Instruction::StaleQuery {
+                id: vec![id],
+                address: Address::Client,
+                command: vec![command],
+                index: 0,
+            })?;
+        }
+        let response = |msg: Option<Message>| match msg.map(|msg| msg.event) {
+            Some(Event::ClientResponse { id, response }) => (id, response),
+            event => panic!("Unexpected event {:?}", event),
+        };
+        assert_eq!(response(node_rx.recv().await), (vec![0x02], Ok(Response::State(vec![0x02]))));
+
+        release_tx.send(()).unwrap();
+        assert_eq!(response(node_rx.recv().await), (vec![0x01], Ok(Response::State(vec![0xff]))));
+
+        std::mem::drop(state_tx);
+        assert_eq!(node_rx.recv().await, None);
+        Ok(())
+    }
+
+    // Queries execute against a view of the state machine, so a slow query doesn't hold up
+    // applies, and it sees the state as of when it was ready.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn driver_query_view() -> Result<()> {
+        // A state machine holding the last applied command, whose queries block until released.
+        struct ViewState {
+            last: Vec<u8>,
+            applied_index: u64,
+            release: Arc<Mutex<std::sync::mpsc::Receiver<()>>>,
+        }
+
+        impl MachineState for ViewState {
+            fn applied_index(&self) -> u64 {
+                self.applied_index
+            }
+
+            fn mutate(&mut self, index: u64, command: Vec<u8>) -> Result<Vec<u8>> {
+                self.last = command.clone();
+                self.applied_index = index;
+                Ok(command)
+            }
+
+            fn query(&self, _: Vec<u8>) -> Result<Vec<u8>> {
+                self.release.lock()?.recv().map_err(|e| Error::Internal(e.to_string()))?;
+                Ok(self.last.clone())
+            }
+
+            fn view(&self) -> Result<Option<Box<dyn StateView>>> {
+                Ok(Some(Box::new(ReadOnlyState(ViewState {
+                    last: self.last.clone(),
+                    applied_index: self.applied_index,
+                    release: self.release.clone(),
+                }))))
+            }
+
+            fn snapshot(&self) -> Result<Vec<u8>> {
+                Ok(Vec::new())
+            }
+
+            fn restore(&mut self, _: u64, _: Vec<u8>) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let (release_tx, release_rx) = std::sync::mpsc::channel();
+        let release = Arc::new(Mutex::new(release_rx));
+        let state = Box::new(ViewState { last: Vec::new(), applied_index: 0, release });
+        let (state_tx, state_rx) = mpsc::unbounded_channel();
+        let (node_tx, mut node_rx) = mpsc::unbounded_channel();
+        tokio::spawn(Driver::new(state_rx, node_tx).drive(state));
+
+        let entry = |index: u64| Entry {
+            index,
+            term: 1,
+            command: Some(vec![index as u8]),
+            membership: None,
+            session: None,
+        };
+        state_tx.send(Instruction::Apply { entry: entry(1) })?;
+        state_tx.send(Instruction::StaleQuery {
+            id: vec![0x01],
+            address: Address::Client,
+            command: vec![],
+            index: 1,
+        })?;
+
+        // The blocked query doesn't hold up further applies.
+        let mut response = async || {
+            let timeout = std::time::Duration::from_secs(5);
+            match tokio::time::timeout(timeout, node_rx.recv()).await {
+                Ok(Some(Message { event: Event::ClientResponse { id, response }, .. })) => {
+                    (id, response)
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+        };
+        for index in 2..=3 {
+            state_tx.send(Instruction::Notify {
+                id: vec![index as u8],
+                index,
+                address: Address::Client,
+            })?;
+            state_tx.send(Instruction::Apply { entry: entry(index) })?;
+            let expect = (vec![index as u8], Ok(Response::State(vec![index as u8])));
+            assert_eq!(response().await, expect);
+        }
+
+        // Once released, the query responds with the state as of index 1.
+        release_tx.send(()).unwrap();
+        assert_eq!(response().await, (vec![0x01], Ok(Response::State(vec![0x01]))));
+
+        std::mem::drop(state_tx);
+        assert_eq!(node_rx.recv().await, None);
+        Ok(())
+    }
+
+    // Queued entries are applied in batches, which are split when a session mutation follows
+    // another mutation in the same session, and each client gets its own response.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn driver_batch() -> Result<()> {
+        let state = Box::new(TestState::new(0));
+        let (state_tx, state_rx) = mpsc::unbounded_channel();
+        let (node_tx, node_rx) = mpsc::unbounded_channel();
+
+        let mutate = |sequence| Some(SessionEntry::Mutate { id: 1, sequence, time: 1000 });
+        let entries = vec![
+            (None, Some(SessionEntry::Open { time: 1000 })),
+            (Some(vec![0x01]), None),
+            (Some(vec![0x02]), mutate(1)),
+            (Some(vec![0x03]), None),
+            (Some(vec![0x02]), mutate(1)),
+            (Some(vec![0x04]), mutate(2)),
+        ];
+        for index in 1..=entries.len() as u64 {
+            let address = Address::Client;
+            state_tx.send(Instruction::Notify { id: vec![index as u8], index, address })?;
+        }
+        for (i, (command, session)) in entries.into_iter().enumerate() {
+            let index = i as u64 + 1;
+            state_tx.send(Instruction::Apply {
+                entry: Entry { index, term: 1, command, membership: None, session },
+            })?;
+        }
+        std::mem::drop(state_tx);
+        Driver::new(state_rx, node_tx).drive(state.clone()).await?;
+
+        let node_rx = UnboundedReceiverStream::new(node_rx);
+        let responses: Vec<_> = node_rx
+            .map(|msg| match msg.event {
+                Event::ClientResponse { id, response } => (id, response),
+                event => panic!("Unexpected event {:?}", event),
+            })
+            .collect()
+            .await;
+        assert_eq!(
+            responses,
+            vec![
+                (vec![0x01], Ok(Response::Session(1))),
+                (vec![0x02], Ok(Response::State(vec![0x01]))),
+                (vec![0x03], Ok(Response::State(vec![0x02]))),
+                (vec![0x04], Ok(Response::State(vec![0x03]))),
+                (vec![0x05], Ok(Response::State(vec![0x02]))),
+                (vec![0x06], Ok(Response::State(vec![0x04]))),
+            ]
+        );
+        assert_eq!(state.list(), vec![vec![0x01], vec![0x02], vec![0x03], vec![0x04]]);
+        assert_eq!(state.batches(), vec![3, 1]);
+        assert_eq!(state.applied_index(), 6);
+        Ok(())
+    }
+
     // A query for an index submitted in a given term cannot be satisfied by votes below that term.
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn driver_query_noterm() -> Result<()> {
@@ -200,12 +695,18 @@ pub mod tests {
             command: vec![0xf0],
             term: 2,
             index: 1,
-            quorum: 2,
+            quorum: quorum(),
         })?;
         state_tx.send(Instruction::Apply {
-            entry: Entry { index: 1, term: 1, command: Some(vec![0xaf]) },
+            entry: Entry {
+                index: 1,
+                term: 1,
+                command: Some(vec![0xaf]),
+                membership: None,
+                session: None,
+            },
         })?;
-        state_tx.send(Instruction::Vote { term: 2, index: 1, address: Address::Local })?;
+        state_tx.send(Instruction::Vote { term: 2, index: 1, address: Address::Peer("a".into()) })?;
         state_tx.send(Instruction::Vote {
             term: 1,
             index: 1,
@@ -228,12 +729,22 @@ pub mod tests {
             command: vec![0xf0],
             term: 1,
             index: 1,
-            quorum: 2,
+            quorum: quorum(),
         })?;
         state_tx.send(Instruction::Apply {
-            entry: Entry { index: 1, term: 1, command: Some(vec![0xaf]) },
+            entry: Entry {
+                index: 1,
+                term: 1,
+                command: Some(vec![0xaf]),
+                membership: None,
+                session: None,
+            },
         })?;
-        state_tx.send(Instruction::Vote { term: 1, index: 1, address: Address::Local })?;
+        // Duplicate votes from the same node, and votes from non-voters, don't form a quorum.
+        for address in [Address::Peer("a".into()), Address::Peer("a".into()), Address::Local] {
+            state_tx.send(Instruction::Vote { term: 1, index: 1, address })?;
+        }
+        state_tx.send(Instruction::Vote { term: 1, index: 1, address: Address::Peer("x".into()) })?;
         std::mem::drop(state_tx);
 
         let node_rx = UnboundedReceiverStream::new(node_rx);
@@ -242,4 +753,3 @@ pub mod tests {
         Ok(())
     }
 }
-*/
\ No newline at end of file
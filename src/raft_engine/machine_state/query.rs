@@ -1,4 +1,4 @@
-use crate::raft_engine::messaging::Address;
+use crate::raft_engine::{messaging::Address, raft_node::Membership};
 use std::collections::HashSet;
 
 /// A driver query.
@@ -6,7 +6,12 @@ pub struct Query {
     pub id: Vec<u8>,
     pub term: u64,
     pub address: Address,
-    pub command: Vec<u8>,
-    pub quorum: u64,
-    pub votes: HashSet<Address>,
-}
\ No newline at end of file
+    /// The state machine query, or None for a read index request.
+    pub command: Option<Vec<u8>>,
+    /// The commit index the query was submitted at.
+    pub index: u64,
+    /// The membership whose quorum must confirm the query, or None if it's already confirmed.
+    pub quorum: Option<Membership>,
+    /// The node IDs that have confirmed the query.
+    pub votes: HashSet<String>,
+}
@@ -0,0 +1,162 @@
+use crate::error::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The time in milliseconds after which an inactive client session expires.
+pub const SESSION_TIMEOUT: u64 = 10 * 60 * 1000;
+
+/// A client session operation carried by a log entry. Each operation records the leader's wall
+/// clock time when it was appended, which is used to expire inactive sessions deterministically
+/// on all nodes as entries are applied.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SessionEntry {
+    /// Opens a new session, identified by the index of the log entry.
+    Open { time: u64 },
+    /// A mutation in a session. The entry command is only applied if the sequence number is
+    /// higher than that of the session's previous mutation.
+    Mutate { id: u64, sequence: u64, time: u64 },
+}
+
+impl SessionEntry {
+    /// Returns the time the entry was appended.
+    pub fn time(&self) -> u64 {
+        match self {
+            SessionEntry::Open { time } | SessionEntry::Mutate { time, .. } => *time,
+        }
+    }
+}
+
+/// A client session.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Session {
+    /// The time of the last operation in the session.
+    last_active: u64,
+    /// The sequence number of the last applied mutation, or 0 if none.
+    sequence: u64,
+    /// The result of the last applied mutation, or None if unknown.
+    response: Option<Result<Vec<u8>>>,
+}
+
+/// Client sessions, used to deduplicate retried mutations so that each is applied exactly once.
+/// Clients issue mutations in a session one at a time with increasing sequence numbers, so only
+/// the result of the last mutation is retained.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Sessions {
+    sessions: BTreeMap<u64, Session>,
+}
+
+impl Sessions {
+    /// Creates an empty session table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expires sessions that have been inactive for SESSION_TIMEOUT as of the given time.
+    pub fn expire(&mut self, time: u64) {
+        self.sessions.retain(|_, s| s.last_active.saturating_add(SESSION_TIMEOUT) > time);
+    }
+
+    /// Opens a new session.
+    pub fn open(&mut self, id: u64, time: u64) {
+        self.sessions.insert(id, Session { last_active: time, sequence: 0, response: None });
+    }
+
+    /// Checks whether a session mutation should be applied, returning the response to use
+    /// instead if it should not: the cached result of an already applied mutation, or an error.
+    pub fn check(&self, id: u64, sequence: u64) -> Option<Result<Vec<u8>>> {
+        let session = match self.sessions.get(&id) {
+            Some(session) => session,
+            None => return Some(Err(Error::SessionExpired(id))),
+        };
+        if sequence > session.sequence {
+            None
+        } else if sequence == session.sequence {
+            Some(session.response.clone().unwrap_or_else(|| {
+                Err(Error::Value(format!("Mutation {} was already applied", sequence)))
+            }))
+        } else {
+            Some(Err(Error::Value(format!("Mutation {} is outdated", sequence))))
+        }
+    }
+
+    /// Records an applied session mutation and its result, if known.
+    pub fn record(
+        &mut self,
+        id: u64,
+        sequence: u64,
+        time: u64,
+        response: Option<Result<Vec<u8>>>,
+    ) {
+        if let Some(session) = self.sessions.get_mut(&id) {
+            session.last_active = time;
+            session.sequence = sequence;
+            session.response = response;
+        }
+    }
+
+    /// Applies a session entry without its command, e.g. when rebuilding the session table for
+    /// entries that were already applied to the state machine. Mutation results are then unknown.
+    pub fn replay(&mut self, index: u64, entry: &SessionEntry) {
+        self.expire(entry.time());
+        match entry {
+            SessionEntry::Open { time } => self.open(index, *time),
+            SessionEntry::Mutate { id, sequence, time } => {
+                if self.check(*id, *sequence).is_none() {
+                    self.record(*id, *sequence, *time, None)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn check_record() {
+        let mut sessions = Sessions::new();
+        assert_eq!(sessions.check(1, 1), Some(Err(Error::SessionExpired(1))));
+
+        sessions.open(1, 1000);
+        assert_eq!(sessions.check(1, 1), None);
+        sessions.record(1, 1, 1000, Some(Ok(vec![0x01])));
+        assert_eq!(sessions.check(1, 1), Some(Ok(vec![0x01])));
+        assert_eq!(sessions.check(1, 2), None);
+        sessions.record(1, 2, 1000, Some(Err(Error::Value("failed".into()))));
+        assert_eq!(sessions.check(1, 2), Some(Err(Error::Value("failed".into()))));
+        assert_eq!(
+            sessions.check(1, 1),
+            Some(Err(Error::Value("Mutation 1 is outdated".into())))
+        );
+    }
+
+    #[test]
+    fn expire() {
+        let mut sessions = Sessions::new();
+        sessions.open(1, 1000);
+        sessions.open(2, 2000);
+        sessions.expire(1000 + SESSION_TIMEOUT);
+        assert!(sessions.check(1, 1).is_some());
+        assert_eq!(sessions.check(2, 1), None);
+
+        // Activity extends the session.
+        sessions.record(2, 1, 3000, None);
+        sessions.expire(2000 + SESSION_TIMEOUT);
+        assert!(sessions.check(2, 2).is_none());
+    }
+
+    #[test]
+    fn replay() {
+        let mut sessions = Sessions::new();
+        sessions.replay(5, &SessionEntry::Open { time: 1000 });
+        sessions.replay(6, &SessionEntry::Mutate { id: 5, sequence: 1, time: 1000 });
+        assert_eq!(
+            sessions.check(5, 1),
+            Some(Err(Error::Value("Mutation 1 was already applied".into())))
+        );
+        assert_eq!(sessions.check(5, 2), None);
+    }
+}
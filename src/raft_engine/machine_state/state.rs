@@ -1,7 +1,7 @@
-use crate::error::Result;
+use crate::error::{Error, Result};
 
-/// A Raft-managed state machine.
-pub trait MachineState: Send {
+/// A Raft-managed state machine. Queries may execute concurrently on other threads.
+pub trait MachineState: Send + Sync {
     /// Returns the last applied index from the state machine, used when initializing the driver.
     fn applied_index(&self) -> u64;
 
@@ -9,6 +9,74 @@ pub trait MachineState: Send {
     /// halts. For any other error, the state is applied and the error propagated to the caller.
     fn mutate(&mut self, index: u64, command: Vec<u8>) -> Result<Vec<u8>>;
 
+    /// Mutates the state machine with the commands of several log entries, returning each
+    /// command's result in order. The driver uses this when several committed entries are queued,
+    /// allowing implementations to apply and flush the whole batch at once. Internal errors halt
+    /// the node, either as the batch result or as a command result, in which case the batch may
+    /// have been partially applied. The default implementation applies each command via mutate().
+    fn mutate_batch(&mut self, commands: Vec<(u64, Vec<u8>)>) -> Result<Vec<Result<Vec<u8>>>> {
+        let mut results = Vec::with_capacity(commands.len());
+        for (index, command) in commands {
+            match self.mutate(index, command) {
+                Err(error @ Error::Internal(_)) => return Err(error),
+                result => results.push(result),
+            }
+        }
+        Ok(results)
+    }
+
     /// Queries the state machine. All errors are propagated to the caller.
     fn query(&self, command: Vec<u8>) -> Result<Vec<u8>>;
-}
\ No newline at end of file
+
+    /// Returns a read-only view of the state machine as of the current applied index, which
+    /// doesn't see later mutations. The driver executes queries against views, such that they
+    /// don't hold up applies. Returns None if views aren't supported, in which case queries
+    /// execute against the state machine itself, and applies wait for them to complete.
+    fn view(&self) -> Result<Option<Box<dyn StateView>>> {
+        Ok(None)
+    }
+
+    /// Takes a snapshot of the state machine, covering all entries up to applied_index(). It is
+    /// used to compact the Raft log, and to catch up followers whose log is behind the compaction.
+    fn snapshot(&self) -> Result<Vec<u8>>;
+
+    /// Replaces the entire state machine with a snapshot covering all entries up to and including
+    /// the given index, which becomes the applied index.
+    fn restore(&mut self, index: u64, snapshot: Vec<u8>) -> Result<()>;
+
+    /// Durably saves the driver's client sessions as of the given applied index, including the
+    /// responses of applied session mutations, such that retries after a restart still get the
+    /// original response. The default implementation doesn't save them, in which case sessions
+    /// are rebuilt from the log without responses.
+    fn save_sessions(&mut self, _index: u64, _sessions: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Loads the client sessions last saved via save_sessions(), along with their index.
+    fn load_sessions(&self) -> Result<Option<(u64, Vec<u8>)>> {
+        Ok(None)
+    }
+}
+
+/// A read-only view of a state machine as of an applied index, which only allows queries.
+pub trait StateView: Send + Sync {
+    /// Returns the applied index the view was taken at.
+    fn applied_index(&self) -> u64;
+
+    /// Queries the view. All errors are propagated to the caller.
+    fn query(&self, command: Vec<u8>) -> Result<Vec<u8>>;
+}
+
+/// A state view backed by a copy of a state machine, e.g. one over a store snapshot, exposing
+/// only its queries.
+pub struct ReadOnlyState<S: MachineState>(pub S);
+
+impl<S: MachineState> StateView for ReadOnlyState<S> {
+    fn applied_index(&self) -> u64 {
+        self.0.applied_index()
+    }
+
+    fn query(&self, command: Vec<u8>) -> Result<Vec<u8>> {
+        self.0.query(command)
+    }
+}
@@ -2,13 +2,20 @@ use serde::{Deserialize, Serialize};
 use crate::{
     error::Result,
     raft_engine::{
-        raft_log::Entry,
+        raft_log::{Entry, Snapshot},
         messaging::{Request, Response}
     }
 };
 
 
 /// An event contained within messages.
+///
+/// Events are encoded on the wire as a stable tag and their fields, such that peers of different
+/// versions can interoperate during rolling upgrades. Tags must never be changed or reused, and
+/// new events get the next unused tag regardless of their position in the enum. Nodes drop events
+/// with unknown tags, i.e. new events from newer peers. New fields may only be appended to an
+/// event as Option fields, which older nodes ignore, and which must decode as None when absent.
+/// Nested types such as Entry or Request can't be changed, since their fields are not tagged.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Event {
     /// Leaders send periodic heartbeats to its followers.
@@ -17,6 +24,9 @@ pub enum Event {
         commit_index: u64,
         /// The term of the leader's last committed log entry.
         commit_term: u64,
+        /// The leader's heartbeat round, echoed in ConfirmLeader so the leader knows when the
+        /// confirmed heartbeat was sent, for leader leases.
+        round: u64,
     },
     /// Followers confirm loyalty to leader after heartbeats.
     ConfirmLeader {
@@ -26,6 +36,8 @@ pub enum Event {
         /// If false, the follower does not have the entry at commit_index
         /// and would like the leader to replicate it.
         has_committed: bool,
+        /// The round of the original leader heartbeat.
+        round: u64,
     },
     /// Candidates solicit votes from all peers.
     SolicitVote {
@@ -36,6 +48,16 @@ pub enum Event {
     },
     /// Followers may grant votes to candidates.
     GrantVote,
+    /// Candidates solicit pre-votes from all peers before starting an election, to check whether
+    /// they could win it without incrementing their term. The message term is the proposed term.
+    PreVote {
+        // The index of the candidate's last stored log entry
+        last_index: u64,
+        // The term of the candidate's last stored log entry
+        last_term: u64,
+    },
+    /// Peers may grant pre-votes to candidates. The message term is the proposed term.
+    PreVoteGranted,
     /// Leaders replicate a set of log entries to followers.
     ReplicateEntries {
         /// The index of the log entry immediately preceding the submitted commands.
@@ -50,8 +72,34 @@ pub enum Event {
         /// The index of the last log entry.
         last_index: u64,
     },
-    /// Followers may also reject a set of log entries from a leader.
-    RejectEntries,
+    /// Followers may also reject a set of log entries from a leader, with a hint of where their
+    /// logs diverge so that the leader can skip past conflicting terms.
+    RejectEntries {
+        /// The base index of the rejected entries.
+        base_index: u64,
+        /// The term of the follower's conflicting entry at the base index, or None if the
+        /// follower's log is shorter than the base index.
+        conflict_term: Option<u64>,
+        /// The first index of the conflicting term in the follower's log, or the follower's last
+        /// index + 1 if its log is shorter than the base index.
+        conflict_index: u64,
+    },
+    /// Leaders send a snapshot to followers whose next entry has been compacted from the log.
+    /// Followers respond with AcceptEntries once it is installed.
+    InstallSnapshot {
+        /// The snapshot to install.
+        snapshot: Snapshot,
+    },
+    /// Leaders transferring leadership tell the caught-up target to start an election
+    /// immediately, without waiting for its election timeout.
+    TimeoutNow,
+    /// The local state machine driver has taken a snapshot, allowing the log to be compacted.
+    Snapshot {
+        /// The index of the last entry covered by the snapshot.
+        index: u64,
+        /// The serialized state machine data.
+        data: Vec<u8>,
+    },
     /// A client request.
     ClientRequest {
         /// The request ID.
@@ -66,4 +114,160 @@ pub enum Event {
         /// The response.
         response: Result<Response>,
     },
-}
\ No newline at end of file
+    /// Heartbeats of several Raft groups, coalesced by the server into a single message per peer
+    /// and tick. The receiving server fans them out to its groups as Heartbeat events, so they
+    /// are never seen by Raft nodes.
+    Heartbeats {
+        /// The groups' heartbeats.
+        heartbeats: Vec<GroupHeartbeat>,
+    },
+}
+
+/// A Raft group's heartbeat, carried by a Heartbeats event.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GroupHeartbeat {
+    /// The Raft group.
+    pub group: u64,
+    /// The leader's term in the group.
+    pub term: u64,
+    /// The index of the leader's last committed log entry.
+    pub commit_index: u64,
+    /// The term of the leader's last committed log entry.
+    pub commit_term: u64,
+    /// The leader's heartbeat round.
+    pub round: u64,
+}
+impl Event {
+    /// Encodes the event for the wire, as its tag and fields.
+    pub fn encode(&self) -> Result<(u32, Vec<u8>)> {
+        Ok(match self {
+            Event::Heartbeat { commit_index, commit_term, round } => {
+                (0, bincode::serialize(&(commit_index, commit_term, round))?)
+            }
+            Event::ConfirmLeader { commit_index, has_committed, round } => {
+                (1, bincode::serialize(&(commit_index, has_committed, round))?)
+            }
+            Event::SolicitVote { last_index, last_term } => {
+                (2, bincode::serialize(&(last_index, last_term))?)
+            }
+            Event::GrantVote => (3, Vec::new()),
+            Event::ReplicateEntries { base_index, base_term, entries } => {
+                (4, bincode::serialize(&(base_index, base_term, entries))?)
+            }
+            Event::AcceptEntries { last_index } => (5, bincode::serialize(&(last_index,))?),
+            Event::RejectEntries { base_index, conflict_term, conflict_index } => {
+                (6, bincode::serialize(&(base_index, conflict_term, conflict_index))?)
+            }
+            Event::ClientRequest { id, request } => (7, bincode::serialize(&(id, request))?),
+            Event::ClientResponse { id, response } => (8, bincode::serialize(&(id, response))?),
+            Event::InstallSnapshot { snapshot } => (9, bincode::serialize(&(snapshot,))?),
+            Event::Snapshot { index, data } => (10, bincode::serialize(&(index, data))?),
+            Event::TimeoutNow => (11, Vec::new()),
+            Event::PreVote { last_index, last_term } => {
+                (12, bincode::serialize(&(last_index, last_term))?)
+            }
+            Event::PreVoteGranted => (13, Vec::new()),
+            Event::Heartbeats { heartbeats } => (14, bincode::serialize(&(heartbeats,))?),
+        })
+    }
+
+    /// Decodes an event from its wire tag and fields. Returns None if the tag is unknown. Any
+    /// trailing fields, appended by newer peers, are ignored.
+    pub fn decode(tag: u32, fields: &[u8]) -> Result<Option<Self>> {
+        Ok(Some(match tag {
+            0 => {
+                let (commit_index, commit_term, round) = bincode::deserialize(fields)?;
+                Event::Heartbeat { commit_index, commit_term, round }
+            }
+            1 => {
+                let (commit_index, has_committed, round) = bincode::deserialize(fields)?;
+                Event::ConfirmLeader { commit_index, has_committed, round }
+            }
+            2 => {
+                let (last_index, last_term) = bincode::deserialize(fields)?;
+                Event::SolicitVote { last_index, last_term }
+            }
+            3 => Event::GrantVote,
+            4 => {
+                let (base_index, base_term, entries) = bincode::deserialize(fields)?;
+                Event::ReplicateEntries { base_index, base_term, entries }
+            }
+            5 => {
+                let (last_index,) = bincode::deserialize(fields)?;
+                Event::AcceptEntries { last_index }
+            }
+            6 => {
+                let (base_index, conflict_term, conflict_index) = bincode::deserialize(fields)?;
+                Event::RejectEntries { base_index, conflict_term, conflict_index }
+            }
+            7 => {
+                let (id, request) = bincode::deserialize(fields)?;
+                Event::ClientRequest { id, request }
+            }
+            8 => {
+                let (id, response) = bincode::deserialize(fields)?;
+                Event::ClientResponse { id, response }
+            }
+            9 => {
+                let (snapshot,) = bincode::deserialize(fields)?;
+                Event::InstallSnapshot { snapshot }
+            }
+            10 => {
+                let (index, data) = bincode::deserialize(fields)?;
+                Event::Snapshot { index, data }
+            }
+            11 => Event::TimeoutNow,
+            12 => {
+                let (last_index, last_term) = bincode::deserialize(fields)?;
+                Event::PreVote { last_index, last_term }
+            }
+            13 => Event::PreVoteGranted,
+            14 => {
+                let (heartbeats,) = bincode::deserialize(fields)?;
+                Event::Heartbeats { heartbeats }
+            }
+            _ => return Ok(None),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    // Events are encoded with distinct tags and round-trip through their wire encoding.
+    fn encode_decode() -> Result<()> {
+        let entry =
+            Entry { index: 1, term: 1, command: Some(vec![1]), membership: None, session: None };
+        let snapshot = Snapshot { index: 1, term: 1, data: vec![1], membership: None };
+        let heartbeat =
+            GroupHeartbeat { group: 1, term: 2, commit_index: 3, commit_term: 2, round: 4 };
+        let events = vec![
+            Event::Heartbeat { commit_index: 1, commit_term: 2, round: 3 },
+            Event::ConfirmLeader { commit_index: 1, has_committed: true, round: 3 },
+            Event::SolicitVote { last_index: 1, last_term: 2 },
+            Event::GrantVote,
+            Event::ReplicateEntries { base_index: 1, base_term: 2, entries: vec![entry] },
+            Event::AcceptEntries { last_index: 1 },
+            Event::RejectEntries { base_index: 1, conflict_term: Some(2), conflict_index: 3 },
+            Event::ClientRequest { id: vec![1], request: Request::Query(vec![2]) },
+            Event::ClientResponse { id: vec![1], response: Err(Error::Abort) },
+            Event::InstallSnapshot { snapshot },
+            Event::Snapshot { index: 1, data: vec![2] },
+            Event::TimeoutNow,
+            Event::PreVote { last_index: 1, last_term: 2 },
+            Event::PreVoteGranted,
+            Event::Heartbeats { heartbeats: vec![heartbeat] },
+        ];
+        for (i, event) in events.into_iter().enumerate() {
+            let (tag, fields) = event.encode()?;
+            assert_eq!(tag, i as u32);
+            assert_eq!(Event::decode(tag, &fields)?, Some(event));
+        }
+        assert_eq!(Event::decode(15, &[])?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use crate::error::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+
+/// The Raft peer protocol version. Events are tagged, so new events and appended event fields
+/// don't require a new version, since older nodes skip them. It must be incremented on
+/// incompatible wire format changes, and when adding events that older nodes can't safely skip.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest protocol version this node can communicate with. Nodes of adjacent versions must
+/// interoperate during a rolling upgrade.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// A handshake exchanged by both sides when a peer connection is opened, before any messages.
+/// Its encoding must remain the same across protocol versions.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Handshake {
+    /// The sender's protocol version.
+    pub version: u32,
+    /// The oldest protocol version the sender can communicate with.
+    pub min_version: u32,
+    /// The ID of the sender's cluster.
+    pub cluster_id: String,
+    /// The sender's node ID.
+    pub node_id: String,
+}
+
+impl Handshake {
+    /// Creates a handshake for the given cluster and node, at the current protocol version.
+    pub fn new(cluster_id: &str, node_id: &str) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            cluster_id: cluster_id.to_string(),
+            node_id: node_id.to_string(),
+        }
+    }
+
+    /// Checks a peer's handshake, optionally expecting a given node ID. Returns the protocol
+    /// version to use for the connection, i.e. the lower of the two versions.
+    pub fn check(&self, peer: &Handshake, node_id: Option<&str>) -> Result<u32> {
+        if peer.cluster_id != self.cluster_id {
+            return Err(Error::Value(format!(
+                "Peer {} belongs to cluster {}, expected {}",
+                peer.node_id, peer.cluster_id, self.cluster_id
+            )));
+        }
+        if let Some(node_id) = node_id {
+            if peer.node_id != node_id {
+                return Err(Error::Value(format!(
+                    "Peer identified as node {}, expected {}",
+                    peer.node_id, node_id
+                )));
+            }
+        }
+        if peer.node_id == self.node_id {
+            return Err(Error::Value(format!("Peer has the same node ID {}", peer.node_id)));
+        }
+        if peer.version < self.min_version || self.version < peer.min_version {
+            return Err(Error::Value(format!(
+                "Peer {} protocol version {} is incompatible with version {}",
+                peer.node_id, peer.version, self.version
+            )));
+        }
+        Ok(self.version.min(peer.version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn check() {
+        let local = Handshake::new("test", "a");
+        assert_eq!(local.check(&Handshake::new("test", "b"), Some("b")), Ok(PROTOCOL_VERSION));
+        assert_eq!(local.check(&Handshake::new("test", "b"), None), Ok(PROTOCOL_VERSION));
+
+        assert!(local.check(&Handshake::new("other", "b"), None).is_err());
+        assert!(local.check(&Handshake::new("test", "c"), Some("b")).is_err());
+        assert!(local.check(&Handshake::new("test", "a"), None).is_err());
+
+        // Versions are compatible if each side supports the other's version.
+        let newer = Handshake { version: PROTOCOL_VERSION + 1, ..Handshake::new("test", "b") };
+        assert_eq!(local.check(&newer, None), Ok(PROTOCOL_VERSION));
+        let newer = Handshake { min_version: PROTOCOL_VERSION + 1, ..newer };
+        assert!(local.check(&newer, None).is_err());
+        let older = Handshake { version: MIN_PROTOCOL_VERSION, ..Handshake::new("test", "b") };
+        assert_eq!(local.check(&older, None), Ok(MIN_PROTOCOL_VERSION));
+        assert_eq!(older.check(&local, None), Ok(MIN_PROTOCOL_VERSION));
+        let older = Handshake { version: MIN_PROTOCOL_VERSION - 1, ..older };
+        assert!(local.check(&older, None).is_err());
+    }
+}
@@ -6,7 +6,10 @@ use crate::raft_engine::{
 /// A message passed between Raft nodes.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Message {
-    /// The current term of the sender.
+    /// The Raft group the message belongs to, when a server hosts several groups. Nodes send
+    /// messages for group 0, and the server replaces it with the node's group.
+    pub group: u64,
+    /// The current term of the sender, or the proposed term for pre-votes.
     pub term: u64,
     /// The sender address.
     pub from: Address,
@@ -14,4 +17,11 @@ pub struct Message {
     pub to: Address,
     /// The message event.
     pub event: Event,
-}
\ No newline at end of file
+}
+impl Message {
+    /// Checks if this is a pre-vote message, whose term is the proposed election term rather than
+    /// the sender's current term.
+    pub fn is_pre_vote(&self) -> bool {
+        matches!(self.event, Event::PreVote { .. } | Event::PreVoteGranted)
+    }
+}
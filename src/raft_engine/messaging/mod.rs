@@ -1,5 +1,6 @@
 mod address;
 mod event;
+mod handshake;
 mod message;
 mod request;
 mod response;
@@ -7,6 +8,7 @@ mod response;
 
 pub use address::*;
 pub use event::*;
+pub use handshake::*;
 pub use message::*;
 pub use request::*;
 pub use response::*;
\ No newline at end of file
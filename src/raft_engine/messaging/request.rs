@@ -6,4 +6,34 @@ pub enum Request {
     Query(Vec<u8>),
     Mutate(Vec<u8>),
     Status,
+    /// Opens a client session, used to apply mutations exactly once.
+    OpenSession,
+    /// Mutates the state machine in a client session. Sequence numbers must increase with each
+    /// mutation, and a retried mutation with the same sequence number is only applied once.
+    SessionMutate { session: u64, sequence: u64, command: Vec<u8> },
+    /// Adds a voter to the cluster, reachable at the given network address, or promotes a learner.
+    AddNode { id: String, address: String },
+    /// Adds a non-voting learner to the cluster, reachable at the given network address. Learners
+    /// receive the replicated log, and can be promoted to voters via AddNode.
+    AddLearner { id: String, address: String },
+    /// Removes a voter or learner from the cluster.
+    RemoveNode { id: String },
+    /// Transfers leadership to the given node.
+    TransferLeadership { to: String },
+    /// Confirms the leader's leadership and returns its commit index, once confirmed. Followers
+    /// use this to serve linearizable queries locally, once they have applied up to the index.
+    ReadIndex,
+    /// Queries the state machine on the receiving node without confirming leadership, as long
+    /// as its state is within the given staleness bound.
+    StaleQuery { command: Vec<u8>, staleness: Staleness },
+}
+
+/// The maximum staleness of a stale query.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Staleness {
+    /// The maximum number of entries the applied index may lag behind the leader's last known
+    /// commit index.
+    Index(u64),
+    /// The maximum number of ticks since the node last heard from the leader.
+    Ticks(u64),
 }
\ No newline at end of file
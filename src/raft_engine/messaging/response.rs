@@ -1,9 +1,15 @@
 use serde::{Deserialize, Serialize};
-use crate::raft_engine::raft_node::Status;
+use crate::raft_engine::raft_node::{Membership, Status};
 
 /// A client response.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Response {
     State(Vec<u8>),
     Status(Status),
+    Membership(Membership),
+    TransferLeadership,
+    /// The ID of an opened client session.
+    Session(u64),
+    /// The leader's confirmed commit index.
+    ReadIndex(u64),
 }
\ No newline at end of file
@@ -3,4 +3,5 @@ pub mod messaging;
 pub mod raft_client;
 pub mod raft_log;
 pub mod raft_node;
-pub mod raft_server;
\ No newline at end of file
+pub mod raft_server;
+pub mod sharding;
\ No newline at end of file
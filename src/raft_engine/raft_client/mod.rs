@@ -1,32 +1,83 @@
 use crate::error::{Error, Result};
 use crate::{
     raft_engine::{
-        messaging::{Request, Response},
-        raft_node::Status
+        messaging::{Request, Response, Staleness},
+        raft_node::{Membership, Status}
     }
 };
 
-use tokio::sync::{mpsc, oneshot};
+mod remote;
 
-/// A client for a local Raft server.
+pub use remote::*;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+/// The transport used to reach the Raft server.
+#[derive(Clone)]
+enum Transport {
+    /// An in-process server, via its client request channel, and the Raft group to use.
+    Local(mpsc::UnboundedSender<(u64, Request, oneshot::Sender<Result<Response>>)>, u64),
+    /// A remote cluster, via a TCP connection. Requests on a connection are serialized.
+    Remote(Arc<Mutex<Remote>>),
+}
+
+/// A client for a local server or a remote cluster. Remote clients use Raft group 0.
 #[derive(Clone)]
 pub struct Client {
-    pub request_tx: mpsc::UnboundedSender<(Request, oneshot::Sender<Result<Response>>)>,
+    transport: Transport,
 }
 
 impl Client {
-    /// Creates a new Raft client.
+    /// Creates a new Raft client for Raft group 0 of a local server.
     pub fn new(
-        request_tx: mpsc::UnboundedSender<(Request, oneshot::Sender<Result<Response>>)>,
+        request_tx: mpsc::UnboundedSender<(u64, Request, oneshot::Sender<Result<Response>>)>,
     ) -> Self {
-        Self { request_tx }
+        Self::new_group(request_tx, 0)
+    }
+
+    /// Creates a new Raft client for the given Raft group of a local server.
+    pub fn new_group(
+        request_tx: mpsc::UnboundedSender<(u64, Request, oneshot::Sender<Result<Response>>)>,
+        group: u64,
+    ) -> Self {
+        Self { transport: Transport::Local(request_tx, group) }
+    }
+
+    /// Connects to a remote Raft server at the given client address, with the default retry
+    /// policy.
+    pub async fn connect(addr: &str) -> Result<Self> {
+        let remote = Remote::connect(addr.to_string(), HashMap::new(), RetryPolicy::default());
+        Ok(Self { transport: Transport::Remote(Arc::new(Mutex::new(remote.await?))) })
+    }
+
+    /// Connects to a remote Raft cluster, given the client addresses of its nodes by node ID.
+    /// Requests are sent to the current leader, which is tracked across leadership changes.
+    pub async fn connect_cluster(
+        nodes: HashMap<String, String>,
+        retry: RetryPolicy,
+    ) -> Result<Self> {
+        let mut addrs: Vec<&String> = nodes.values().collect();
+        addrs.sort();
+        let addr = match addrs.first() {
+            Some(addr) => addr.to_string(),
+            None => return Err(Error::Value("No cluster nodes given".into())),
+        };
+        let remote = Remote::connect(addr, nodes, retry).await?;
+        Ok(Self { transport: Transport::Remote(Arc::new(Mutex::new(remote))) })
     }
 
     /// Executes a request against the Raft cluster.
     async fn request(&self, request: Request) -> Result<Response> {
-        let (response_tx, response_rx) = oneshot::channel();
-        self.request_tx.send((request, response_tx))?;
-        response_rx.await?
+        match &self.transport {
+            Transport::Local(request_tx, group) => {
+                let (response_tx, response_rx) = oneshot::channel();
+                request_tx.send((*group, request, response_tx))?;
+                response_rx.await?
+            }
+            Transport::Remote(remote) => remote.lock().await.request(request).await,
+        }
     }
 
     /// Mutates the Raft state machine.
@@ -37,6 +88,28 @@ impl Client {
         }
     }
 
+    /// Opens a client session, returning its ID. Mutations in a session are applied exactly once.
+    pub async fn open_session(&self) -> Result<u64> {
+        match self.request(Request::OpenSession).await? {
+            Response::Session(session) => Ok(session),
+            resp => Err(Error::Internal(format!("Unexpected Raft session response {:?}", resp))),
+        }
+    }
+
+    /// Mutates the Raft state machine in a client session. The sequence number must be higher
+    /// than that of the previous mutation in the session, unless retrying it.
+    pub async fn session_mutate(
+        &self,
+        session: u64,
+        sequence: u64,
+        command: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        match self.request(Request::SessionMutate { session, sequence, command }).await? {
+            Response::State(response) => Ok(response),
+            resp => Err(Error::Internal(format!("Unexpected Raft mutate response {:?}", resp))),
+        }
+    }
+
     /// Queries the Raft state machine.
     pub async fn query(&self, command: Vec<u8>) -> Result<Vec<u8>> {
         match self.request(Request::Query(command)).await? {
@@ -45,6 +118,15 @@ impl Client {
         }
     }
 
+    /// Queries the Raft state machine on the connected node, allowing stale results within the
+    /// given bound.
+    pub async fn stale_query(&self, command: Vec<u8>, staleness: Staleness) -> Result<Vec<u8>> {
+        match self.request(Request::StaleQuery { command, staleness }).await? {
+            Response::State(response) => Ok(response),
+            resp => Err(Error::Internal(format!("Unexpected Raft query response {:?}", resp))),
+        }
+    }
+
     /// Fetches Raft node status.
     pub async fn status(&self) -> Result<Status> {
         match self.request(Request::Status).await? {
@@ -52,4 +134,44 @@ impl Client {
             resp => Err(Error::Internal(format!("Unexpected Raft status response {:?}", resp))),
         }
     }
+
+    /// Adds a voter to the cluster, returning the new membership once it is committed.
+    pub async fn add_node(&self, id: &str, address: &str) -> Result<Membership> {
+        let request = Request::AddNode { id: id.to_string(), address: address.to_string() };
+        match self.request(request).await? {
+            Response::Membership(membership) => Ok(membership),
+            resp => Err(Error::Internal(format!("Unexpected Raft add node response {:?}", resp))),
+        }
+    }
+
+    /// Adds a non-voting learner to the cluster, returning the new membership once it is
+    /// committed.
+    pub async fn add_learner(&self, id: &str, address: &str) -> Result<Membership> {
+        let request = Request::AddLearner { id: id.to_string(), address: address.to_string() };
+        match self.request(request).await? {
+            Response::Membership(membership) => Ok(membership),
+            resp => {
+                Err(Error::Internal(format!("Unexpected Raft add learner response {:?}", resp)))
+            }
+        }
+    }
+
+    /// Removes a voter or learner from the cluster, returning the new membership once it is
+    /// committed.
+    pub async fn remove_node(&self, id: &str) -> Result<Membership> {
+        match self.request(Request::RemoveNode { id: id.to_string() }).await? {
+            Response::Membership(membership) => Ok(membership),
+            resp => {
+                Err(Error::Internal(format!("Unexpected Raft remove node response {:?}", resp)))
+            }
+        }
+    }
+
+    /// Transfers leadership to the given node, returning once the current leader has stepped down.
+    pub async fn transfer_leadership(&self, to: &str) -> Result<()> {
+        match self.request(Request::TransferLeadership { to: to.to_string() }).await? {
+            Response::TransferLeadership => Ok(()),
+            resp => Err(Error::Internal(format!("Unexpected Raft transfer response {:?}", resp))),
+        }
+    }
 }
@@ -0,0 +1,298 @@
+use crate::error::{Error, Result};
+use crate::raft_engine::messaging::{Request, Response};
+
+use futures::sink::SinkExt as _;
+use log::debug;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_stream::StreamExt as _;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// A client connection to a remote Raft server, sending bincode-encoded requests and receiving
+/// responses over a length-delimited TCP stream.
+type Connection = tokio_serde::Framed<
+    Framed<TcpStream, LengthDelimitedCodec>,
+    Result<Response>,
+    Request,
+    tokio_serde::formats::Bincode<Result<Response>, Request>,
+>;
+
+/// The retry policy for remote requests that fail with Error::Abort, e.g. due to a leadership
+/// change, or due to a lost connection. Before each retry, the client backs off and then looks
+/// up the current leader to send the request to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of retries of a request.
+    pub retries: u32,
+    /// The delay before the first retry, doubled for each subsequent retry.
+    pub backoff: Duration,
+    /// The maximum delay between retries.
+    pub max_backoff: Duration,
+    /// Whether to retry mutations. Mutations are then sent in a client session, so that a retried
+    /// mutation is applied exactly once even if the aborted attempt was committed.
+    pub mutations: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            mutations: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which never retries requests.
+    pub fn none() -> Self {
+        Self { retries: 0, ..Self::default() }
+    }
+
+    /// Returns the backoff delay before the given retry, counting from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.backoff.saturating_mul(2_u32.saturating_pow(retry)).min(self.max_backoff)
+    }
+
+    /// Checks if a request may be retried. Queries, status requests, and session requests are
+    /// idempotent, while mutations outside of a session and membership changes are not.
+    fn is_retryable(&self, request: &Request) -> bool {
+        match request {
+            Request::Query(_)
+            | Request::Status
+            | Request::OpenSession
+            | Request::SessionMutate { .. }
+            | Request::ReadIndex
+            | Request::StaleQuery { .. } => true,
+            Request::Mutate(_) => false,
+            Request::AddNode { .. }
+            | Request::AddLearner { .. }
+            | Request::RemoveNode { .. }
+            | Request::TransferLeadership { .. } => false,
+        }
+    }
+}
+
+/// A connection to a remote Raft cluster. It tracks the cluster leader and sends requests to it
+/// when its address is known, otherwise to any reachable node which proxies them to the leader.
+pub struct Remote {
+    /// The client addresses of cluster nodes, by node ID. May be empty.
+    nodes: HashMap<String, String>,
+    /// The address of the current or last connected node.
+    addr: String,
+    /// The last known leader.
+    leader: Option<String>,
+    /// The current connection, if any.
+    connection: Option<Connection>,
+    /// The retry policy.
+    retry: RetryPolicy,
+    /// The client session used for retried mutations, as the session ID and last sequence number.
+    session: Option<(u64, u64)>,
+}
+
+impl Remote {
+    /// Connects to the node at the given address, or any of the given cluster nodes.
+    pub async fn connect(
+        addr: String,
+        nodes: HashMap<String, String>,
+        retry: RetryPolicy,
+    ) -> Result<Self> {
+        let mut remote =
+            Self { nodes, addr, leader: None, connection: None, retry, session: None };
+        remote.reconnect().await?;
+        Ok(remote)
+    }
+
+    /// Connects to the leader if known, otherwise the last connected node, otherwise any node.
+    async fn reconnect(&mut self) -> Result<()> {
+        let mut addrs = Vec::new();
+        if let Some(addr) = self.leader.as_ref().and_then(|id| self.nodes.get(id)) {
+            addrs.push(addr.clone());
+        }
+        addrs.push(self.addr.clone());
+        let mut nodes: Vec<_> = self.nodes.values().cloned().collect();
+        nodes.sort();
+        for addr in nodes {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+
+        let mut error = Error::Internal("No nodes to connect to".into());
+        for addr in addrs {
+            match TcpStream::connect(&addr).await {
+                Ok(socket) => {
+                    debug!("Connected to Raft node {}", addr);
+                    self.connection = Some(tokio_serde::Framed::new(
+                        Framed::new(socket, LengthDelimitedCodec::new()),
+                        tokio_serde::formats::Bincode::default(),
+                    ));
+                    self.addr = addr;
+                    return Ok(());
+                }
+                Err(err) => {
+                    debug!("Failed connecting to Raft node {}: {}", addr, err);
+                    error = err.into();
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Sends a request on the current connection, connecting first if necessary. The outer result
+    /// is a connection error, after which the connection is dropped, and the inner result is the
+    /// server's response. A server disconnect is reported as Error::Abort, since the request may
+    /// or may not have been executed.
+    async fn send(&mut self, request: Request) -> Result<Result<Response>> {
+        if self.connection.is_none() {
+            self.reconnect().await?;
+        }
+        let connection = self.connection.as_mut().expect("no connection");
+        let response = match connection.send(request).await {
+            Ok(()) => connection.try_next().await,
+            Err(err) => Err(err),
+        };
+        match response {
+            Ok(Some(response)) => {
+                if let Ok(Response::Status(status)) = &response {
+                    if !status.leader.is_empty() {
+                        self.leader = Some(status.leader.clone());
+                    }
+                }
+                Ok(response)
+            }
+            Ok(None) => {
+                self.connection = None;
+                Err(Error::Abort)
+            }
+            Err(err) => {
+                self.connection = None;
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Looks up the current leader, and reconnects to it if its address is known.
+    async fn discover_leader(&mut self) -> Result<()> {
+        let server = match self.send(Request::Status).await?? {
+            Response::Status(status) => status.server,
+            resp => return Err(Error::Internal(format!("Unexpected status response {:?}", resp))),
+        };
+        if let Some(leader) = &self.leader {
+            if leader != &server && self.nodes.contains_key(leader) {
+                debug!("Reconnecting to Raft leader {}", leader);
+                self.connection = None;
+                self.reconnect().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes a request. If mutations are retried, they are sent in a client session.
+    pub async fn request(&mut self, request: Request) -> Result<Response> {
+        match request {
+            Request::Mutate(command) if self.retry.mutations => {
+                let request = self.session_mutate(command).await?;
+                let response = self.execute(request).await;
+                // Keep the session when the outcome is unknown, e.g. after a connection error,
+                // since a later mutation must not be applied before a retry of this one. Only
+                // start a new session once the server no longer knows this one.
+                if let Err(Error::SessionExpired(_)) = response {
+                    self.session = None;
+                }
+                response
+            }
+            request => self.execute(request).await,
+        }
+    }
+
+    /// Builds a session mutation with the next sequence number, opening a session if needed.
+    async fn session_mutate(&mut self, command: Vec<u8>) -> Result<Request> {
+        let (session, sequence) = match self.session {
+            Some((session, sequence)) => (session, sequence + 1),
+            None => match self.execute(Request::OpenSession).await? {
+                Response::Session(session) => (session, 1),
+                resp => {
+                    return Err(Error::Internal(format!("Unexpected session response {:?}", resp)))
+                }
+            },
+        };
+        self.session = Some((session, sequence));
+        Ok(Request::SessionMutate { session, sequence, command })
+    }
+
+    /// Executes a request, retrying it according to the retry policy.
+    async fn execute(&mut self, request: Request) -> Result<Response> {
+        let is_retryable = self.retry.is_retryable(&request);
+        let mut retry = 0;
+        loop {
+            match self.send(request.clone()).await {
+                Ok(Err(Error::Abort)) | Err(_) if is_retryable && retry < self.retry.retries => {}
+                Ok(response) => return response,
+                Err(err) => return Err(err),
+            }
+            tokio::time::sleep(self.retry.backoff(retry)).await;
+            retry += 1;
+            // The leader lookup is bounded by the backoff, since it blocks until there is a
+            // leader. Failures are retried with the request.
+            let timeout = self.retry.max_backoff;
+            match tokio::time::timeout(timeout, self.discover_leader()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => debug!("Failed to discover Raft leader: {}", err),
+                Err(_) => {
+                    debug!("Timed out discovering Raft leader");
+                    self.connection = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn backoff() {
+        let retry = RetryPolicy {
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(retry.backoff(0), Duration::from_millis(100));
+        assert_eq!(retry.backoff(1), Duration::from_millis(200));
+        assert_eq!(retry.backoff(2), Duration::from_millis(400));
+        assert_eq!(retry.backoff(3), Duration::from_millis(500));
+        assert_eq!(retry.backoff(100), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn is_retryable() {
+        let retry = RetryPolicy::default();
+        assert!(retry.is_retryable(&Request::Query(vec![])));
+        assert!(retry.is_retryable(&Request::Status));
+        assert!(retry.is_retryable(&Request::OpenSession));
+        assert!(!retry.is_retryable(&Request::Mutate(vec![])));
+        assert!(!retry.is_retryable(&Request::RemoveNode { id: "a".into() }));
+        let request = Request::SessionMutate { session: 1, sequence: 1, command: vec![] };
+        assert!(retry.is_retryable(&request));
+    }
+
+    #[tokio::test]
+    // A server that disconnects without responding aborts the request.
+    async fn disconnect() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut [0; 1024]).await;
+            }
+        });
+        let mut remote = Remote::connect(addr, HashMap::new(), RetryPolicy::none()).await?;
+        assert_eq!(remote.request(Request::Status).await, Err(Error::Abort));
+        Ok(())
+    }
+}
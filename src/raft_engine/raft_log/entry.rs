@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use crate::raft_engine::{machine_state::SessionEntry, raft_node::Membership};
 
 /// A replicated log entry
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -9,4 +10,8 @@ pub struct Entry {
     pub term: u64,
     /// The state machine command. None is used to commit noops during leader election.
     pub command: Option<Vec<u8>>,
+    /// A cluster membership change, which takes effect as soon as the entry is appended.
+    pub membership: Option<Membership>,
+    /// A client session operation, used to apply the command exactly once.
+    pub session: Option<SessionEntry>,
 }
\ No newline at end of file
@@ -5,7 +5,9 @@ use crate::{
         log_storage::{LogStore, Range}
     },
     raft_engine::{
-        raft_log::{Entry, Scan, Key}
+        machine_state::SessionEntry,
+        raft_log::{Entry, Scan, Key, Snapshot},
+        raft_node::Membership
     }
 };
 use std::ops::RangeBounds;
@@ -24,39 +26,86 @@ pub struct RaftLog {
     pub commit_index: u64,
     /// The term of the last committed entry.
     pub commit_term: u64,
+    /// The index of the last entry covered by the latest snapshot, i.e. compacted from the log.
+    pub snapshot_index: u64,
+    /// The term of the last entry covered by the latest snapshot.
+    pub snapshot_term: u64,
+    /// The latest cluster membership in the log, committed or not, if it was ever changed.
+    pub membership: Option<Membership>,
+    /// The index of the entry containing the latest membership.
+    pub membership_index: u64,
 }
 
 impl RaftLog {
     /// Creates a new log, using a LogStore for storage.
     pub fn new(store: Box<dyn LogStore>) -> Result<Self> {
-        let (commit_index, commit_term) = match store.committed() {
-            0 => (0, 0),
-            index => store
-                .get(index)?
-                .map(|v| Self::deserialize::<Entry>(&v))
-                .transpose()?
-                .map(|e| (e.index, e.term))
-                .ok_or_else(|| Error::Internal("Committed entry not found".into()))?,
-        };
-        let (last_index, last_term) = match store.len() {
-            0 => (0, 0),
-            index => store
-                .get(index)?
-                .map(|v| Self::deserialize::<Entry>(&v))
-                .transpose()?
-                .map(|e| (e.index, e.term))
-                .ok_or_else(|| Error::Internal("Last entry not found".into()))?,
+        let (snapshot_index, snapshot_term) = store
+            .get_snapshot()?
+            .map(|v| Self::deserialize::<Snapshot>(&v))
+            .transpose()?
+            .map(|s| (s.index, s.term))
+            .unwrap_or((0, 0));
+        let mut log = Self {
+            store,
+            last_index: 0,
+            last_term: 0,
+            commit_index: 0,
+            commit_term: 0,
+            snapshot_index,
+            snapshot_term,
+            membership: None,
+            membership_index: 0,
         };
-        Ok(Self { store, last_index, last_term, commit_index, commit_term })
+        log.commit_index = log.store.committed();
+        log.commit_term = log
+            .term(log.commit_index)?
+            .ok_or_else(|| Error::Internal("Committed entry not found".into()))?;
+        log.last_index = log.store.len();
+        log.last_term =
+            log.term(log.last_index)?.ok_or_else(|| Error::Internal("Last entry not found".into()))?;
+        log.load_membership()?;
+        Ok(log)
     }
 
     /// Appends a command to the log, returning the entry.
     pub fn append(&mut self, term: u64, command: Option<Vec<u8>>) -> Result<Entry> {
-        let entry = Entry { index: self.last_index + 1, term, command };
+        self.append_entry(term, command, None, None)
+    }
+
+    /// Appends a client session operation to the log, with an optional command, returning the
+    /// entry.
+    pub fn append_session(
+        &mut self,
+        term: u64,
+        command: Option<Vec<u8>>,
+        session: SessionEntry,
+    ) -> Result<Entry> {
+        self.append_entry(term, command, None, Some(session))
+    }
+
+    /// Appends a cluster membership change to the log, returning the entry. The membership takes
+    /// effect immediately, without waiting for the entry to be committed.
+    pub fn append_membership(&mut self, term: u64, membership: Membership) -> Result<Entry> {
+        self.append_entry(term, None, Some(membership), None)
+    }
+
+    /// Appends an entry to the log.
+    fn append_entry(
+        &mut self,
+        term: u64,
+        command: Option<Vec<u8>>,
+        membership: Option<Membership>,
+        session: Option<SessionEntry>,
+    ) -> Result<Entry> {
+        let entry = Entry { index: self.last_index + 1, term, command, membership, session };
         debug!("Appending log entry {}: {:?}", entry.index, entry);
         self.store.append(Self::serialize(&entry)?)?;
         self.last_index = entry.index;
         self.last_term = entry.term;
+        if let Some(membership) = &entry.membership {
+            self.membership = Some(membership.clone());
+            self.membership_index = entry.index;
+        }
         Ok(entry)
     }
 
@@ -76,40 +125,72 @@ impl RaftLog {
         self.store.get(index)?.map(|v| Self::deserialize(&v)).transpose()
     }
 
+    /// Fetches the term of the entry at an index, if it exists. This also covers the last entry
+    /// of the snapshot, since it may be the base of replicated entries.
+    pub fn term(&self, index: u64) -> Result<Option<u64>> {
+        match index {
+            0 => Ok(Some(0)),
+            i if i == self.snapshot_index => Ok(Some(self.snapshot_term)),
+            i => Ok(self.get(i)?.map(|e| e.term)),
+        }
+    }
+
     /// Checks if the log contains an entry
     pub fn has(&self, index: u64, term: u64) -> Result<bool> {
-        match self.get(index)? {
-            Some(entry) => Ok(entry.term == term),
-            None if index == 0 && term == 0 => Ok(true),
-            None => Ok(false),
+        // Entries below the snapshot are committed, and by the Raft log matching property any
+        // committed entry claimed by the leader must match ours.
+        if index < self.snapshot_index {
+            return Ok(true);
         }
+        Ok(self.term(index)? == Some(term))
+    }
+
+    /// Finds the first index in the inclusive range from..=to whose entry has a term of at least
+    /// the given term, or to+1 if there is none. Since terms never decrease along the log, this
+    /// uses a binary search. The range must be within the snapshot index and last index.
+    pub fn search_term(&self, term: u64, from: u64, to: u64) -> Result<u64> {
+        let (mut lo, mut hi) = (from, to + 1);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let mid_term = self
+                .term(mid)?
+                .ok_or_else(|| Error::Internal(format!("Entry {} not found", mid)))?;
+            if mid_term >= term {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        Ok(lo)
     }
 
     /// Iterates over log entries
-    pub fn scan(&self, range: impl RangeBounds<u64>) -> Scan {
+    pub fn scan(&self, range: impl RangeBounds<u64>) -> Scan<'_> {
         Box::new(self.store.scan(Range::from(range)).map(|r| r.and_then(|v| Self::deserialize(&v))))
     }
 
     /// Splices a set of entries onto an offset. The entries must be contiguous, and the first entry
     /// must be at most last_index+1. If an entry does not exist, append it. If an existing entry
-    /// has a term mismatch, replace it and all following entries.
+    /// has a term mismatch, replace it and all following entries. Entries covered by the snapshot
+    /// are skipped.
     pub fn splice(&mut self, entries: Vec<Entry>) -> Result<u64> {
         for i in 0..entries.len() {
             if i == 0 && entries.get(i).unwrap().index > self.last_index + 1 {
                 return Err(Error::Internal("Spliced entries cannot begin past last index".into()));
             }
-            if entries.get(i).unwrap().index != entries.get(0).unwrap().index + i as u64 {
+            if entries.get(i).unwrap().index != entries.first().unwrap().index + i as u64 {
                 return Err(Error::Internal("Spliced entries must be contiguous".into()));
             }
         }
-        for entry in entries {
+        let snapshot_index = self.snapshot_index;
+        for entry in entries.into_iter().filter(|e| e.index > snapshot_index) {
             if let Some(ref current) = self.get(entry.index)? {
                 if current.term == entry.term {
                     continue;
                 }
                 self.truncate(entry.index - 1)?;
             }
-            self.append(entry.term, entry.command)?;
+            self.append_entry(entry.term, entry.command, entry.membership, entry.session)?;
         }
         Ok(self.last_index)
     }
@@ -118,21 +199,103 @@ impl RaftLog {
     /// Refuses to remove entries that have been applied or committed.
     pub fn truncate(&mut self, index: u64) -> Result<u64> {
         debug!("Truncating log from entry {}", index);
-        let (index, term) = match self.store.truncate(index)? {
-            0 => (0, 0),
-            i => self
-                .store
-                .get(i)?
-                .map(|v| Self::deserialize::<Entry>(&v))
-                .transpose()?
-                .map(|e| (e.index, e.term))
-                .ok_or_else(|| Error::Internal(format!("Entry {} not found", index)))?,
-        };
+        let index = self.store.truncate(index)?;
         self.last_index = index;
-        self.last_term = term;
+        self.last_term =
+            self.term(index)?.ok_or_else(|| Error::Internal(format!("Entry {} not found", index)))?;
+        if self.membership_index > index {
+            self.load_membership()?;
+        }
         Ok(index)
     }
 
+    /// Compacts the log by discarding all entries up to and including a committed index, storing
+    /// the given state machine snapshot data in their place.
+    pub fn compact(&mut self, index: u64, data: Vec<u8>) -> Result<()> {
+        if index <= self.snapshot_index {
+            return Ok(());
+        }
+        if index > self.commit_index {
+            return Err(Error::Internal(format!("Cannot compact uncommitted entry {}", index)));
+        }
+        let term =
+            self.term(index)?.ok_or_else(|| Error::Internal(format!("Entry {} not found", index)))?;
+        let membership = match self.membership_index {
+            i if i <= index => self.membership.clone(),
+            _ => self.find_membership(index)?.1,
+        };
+        debug!("Compacting log up to entry {}", index);
+        // The snapshot must be saved before discarding entries, so a crash in between is harmless.
+        self.save_snapshot(&Snapshot { index, term, data, membership })?;
+        self.store.compact(index)?;
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        Ok(())
+    }
+
+    /// Installs a snapshot received from the leader, replacing all log entries it covers. If the
+    /// log does not contain the snapshot's last entry, the entire log is discarded.
+    pub fn install_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        if snapshot.index <= self.commit_index {
+            return Err(Error::Internal(format!(
+                "Cannot install snapshot at or below committed index {}",
+                self.commit_index
+            )));
+        }
+        debug!("Installing snapshot at entry {}", snapshot.index);
+        let (index, term) = (snapshot.index, snapshot.term);
+        let keep_tail = self.has(index, term)?;
+        self.save_snapshot(&snapshot)?;
+        if !keep_tail {
+            self.store.truncate(self.store.committed())?;
+        }
+        self.store.compact(index)?;
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.commit_index = index;
+        self.commit_term = term;
+        self.last_index = self.store.len();
+        self.last_term = self
+            .term(self.last_index)?
+            .ok_or_else(|| Error::Internal("Last entry not found".into()))?;
+        self.load_membership()?;
+        Ok(())
+    }
+
+    /// Loads the latest membership from the snapshot and log entries.
+    fn load_membership(&mut self) -> Result<()> {
+        let (index, membership) = self.find_membership(self.last_index)?;
+        self.membership_index = index;
+        self.membership = membership;
+        Ok(())
+    }
+
+    /// Finds the latest membership at or below an index, along with the index it was set at.
+    fn find_membership(&self, index: u64) -> Result<(u64, Option<Membership>)> {
+        let (mut found, mut membership) = match self.load_snapshot()? {
+            Some(snapshot) => (snapshot.index, snapshot.membership),
+            None => (0, None),
+        };
+        let mut scan = self.scan((self.snapshot_index + 1)..=index);
+        while let Some(entry) = scan.next().transpose()? {
+            if entry.membership.is_some() {
+                found = entry.index;
+                membership = entry.membership;
+            }
+        }
+        Ok((found, membership))
+    }
+
+    /// Loads the latest snapshot, if any.
+    pub fn load_snapshot(&self) -> Result<Option<Snapshot>> {
+        self.store.get_snapshot()?.map(|v| Self::deserialize(&v)).transpose()
+    }
+
+    /// Saves a snapshot.
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.store.set_snapshot(Self::serialize(snapshot)?)
+    }
+
     /// Loads information about the most recent term known by the log, containing the term number (0
     /// if none) and candidate voted for in current term (if any).
     pub fn load_term(&self) -> Result<(u64, Option<String>)> {
@@ -2,23 +2,29 @@ mod entry;
 mod key;
 mod log;
 mod scan;
+mod snapshot;
 
 
 pub use entry::*;
 pub use key::*;
 pub use self::log::*;
 pub use scan::*;
+pub use snapshot::*;
 
-/*
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        error::{Error, Result},
+        raft_engine::raft_node::Membership,
+        storage_engine::log_storage::LogTest
+    };
     use pretty_assertions::assert_eq;
 
-    fn setup() -> Result<(Log, Box<log::Test>)> {
-        let store = Box::new(log::Test::new());
-        let log = Log::new(store.clone())?;
+    fn setup() -> Result<(RaftLog, Box<LogTest>)> {
+        let store = Box::new(LogTest::new());
+        let log = RaftLog::new(store.clone())?;
         Ok((log, store))
     }
 
@@ -39,10 +45,19 @@ mod tests {
         assert_eq!(Ok(None), l.get(1));
 
         assert_eq!(
-            Entry { index: 1, term: 3, command: Some(vec![0x01]) },
+            Entry { index: 1, term: 3, command: Some(vec![0x01]), membership: None, session: None },
             l.append(3, Some(vec![0x01]))?
         );
-        assert_eq!(Some(Entry { index: 1, term: 3, command: Some(vec![0x01]) }), l.get(1)?);
+        assert_eq!(
+            Some(Entry {
+                index: 1,
+                term: 3,
+                command: Some(vec![0x01]),
+                membership: None,
+                session: None,
+            }),
+            l.get(1)?
+        );
         assert_eq!(None, l.get(2)?);
 
         assert_eq!(1, l.last_index);
@@ -55,8 +70,14 @@ mod tests {
     #[test]
     fn append_none() -> Result<()> {
         let (mut l, _) = setup()?;
-        assert_eq!(Entry { index: 1, term: 3, command: None }, l.append(3, None)?);
-        assert_eq!(Some(Entry { index: 1, term: 3, command: None }), l.get(1)?);
+        assert_eq!(
+            Entry { index: 1, term: 3, command: None, membership: None, session: None },
+            l.append(3, None)?
+        );
+        assert_eq!(
+            Some(Entry { index: 1, term: 3, command: None, membership: None, session: None }),
+            l.get(1)?
+        );
         Ok(())
     }
 
@@ -67,10 +88,31 @@ mod tests {
         l.append(2, None)?;
         l.append(2, Some(vec![0x03]))?;
 
-        let l = Log::new(store)?;
-        assert_eq!(Some(Entry { index: 1, term: 1, command: Some(vec![0x01]) }), l.get(1)?);
-        assert_eq!(Some(Entry { index: 2, term: 2, command: None }), l.get(2)?);
-        assert_eq!(Some(Entry { index: 3, term: 2, command: Some(vec![0x03]) }), l.get(3)?);
+        let l = RaftLog::new(store)?;
+        assert_eq!(
+            Some(Entry {
+                index: 1,
+                term: 1,
+                command: Some(vec![0x01]),
+                membership: None,
+                session: None,
+            }),
+            l.get(1)?
+        );
+        assert_eq!(
+            Some(Entry { index: 2, term: 2, command: None, membership: None, session: None }),
+            l.get(2)?
+        );
+        assert_eq!(
+            Some(Entry {
+                index: 3,
+                term: 2,
+                command: Some(vec![0x03]),
+                membership: None,
+                session: None,
+            }),
+            l.get(3)?
+        );
         Ok(())
     }
 
@@ -85,7 +127,7 @@ mod tests {
         assert_eq!(2, l.commit_term);
 
         // The last committed entry must be persisted, to sync with state machine
-        let l = Log::new(store)?;
+        let l = RaftLog::new(store)?;
         assert_eq!(3, l.commit_index);
         assert_eq!(2, l.commit_term);
         Ok(())
@@ -120,7 +162,16 @@ mod tests {
         assert_eq!(None, l.get(1)?);
 
         l.append(3, Some(vec![0x01]))?;
-        assert_eq!(Some(Entry { index: 1, term: 3, command: Some(vec![0x01]) }), l.get(1)?);
+        assert_eq!(
+            Some(Entry {
+                index: 1,
+                term: 3,
+                command: Some(vec![0x01]),
+                membership: None,
+                session: None,
+            }),
+            l.get(1)?
+        );
         assert_eq!(None, l.get(2)?);
         Ok(())
     }
@@ -140,6 +191,23 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn search_term() -> Result<()> {
+        let (mut l, _) = setup()?;
+        for term in [1, 1, 3, 3, 3, 4] {
+            l.append(term, None)?;
+        }
+
+        assert_eq!(l.search_term(1, 1, 6)?, 1);
+        assert_eq!(l.search_term(2, 1, 6)?, 3);
+        assert_eq!(l.search_term(3, 1, 6)?, 3);
+        assert_eq!(l.search_term(4, 1, 6)?, 6);
+        assert_eq!(l.search_term(5, 1, 6)?, 7);
+        assert_eq!(l.search_term(3, 4, 5)?, 4);
+        assert_eq!(l.search_term(0, 0, 6)?, 0);
+        Ok(())
+    }
+
     #[test]
     fn scan() -> Result<()> {
         let (mut l, _) = setup()?;
@@ -149,16 +217,46 @@ mod tests {
 
         assert_eq!(
             vec![
-                Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                Entry { index: 2, term: 1, command: Some(vec![0x02]) },
-                Entry { index: 3, term: 1, command: Some(vec![0x03]) },
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 1,
+                    command: Some(vec![0x02]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 1,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(0..).collect::<Result<Vec<_>>>()?
         );
         assert_eq!(
             vec![
-                Entry { index: 2, term: 1, command: Some(vec![0x02]) },
-                Entry { index: 3, term: 1, command: Some(vec![0x03]) },
+                Entry {
+                    index: 2,
+                    term: 1,
+                    command: Some(vec![0x02]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 1,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(2..).collect::<Result<Vec<_>>>()?
         );
@@ -175,7 +273,7 @@ mod tests {
         // Test loading saved term
         let (mut l, store) = setup()?;
         l.save_term(1, Some("a"))?;
-        let l = Log::new(store)?;
+        let l = RaftLog::new(store)?;
         assert_eq!((1, Some("a".into())), l.load_term()?);
 
         // Test replacing saved term with none
@@ -197,16 +295,52 @@ mod tests {
         assert_eq!(
             4,
             l.splice(vec![
-                Entry { index: 3, term: 3, command: Some(vec![0x03]) },
-                Entry { index: 4, term: 4, command: Some(vec![0x04]) },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 4,
+                    term: 4,
+                    command: Some(vec![0x04]),
+                    membership: None,
+                    session: None,
+                },
             ])?
         );
         assert_eq!(
             vec![
-                Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                Entry { index: 2, term: 2, command: Some(vec![0x02]) },
-                Entry { index: 3, term: 3, command: Some(vec![0x03]) },
-                Entry { index: 4, term: 4, command: Some(vec![0x04]) },
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 2,
+                    command: Some(vec![0x02]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 4,
+                    term: 4,
+                    command: Some(vec![0x04]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(..).collect::<Result<Vec<_>>>()?
         );
@@ -225,14 +359,38 @@ mod tests {
         assert_eq!(
             2,
             l.splice(vec![
-                Entry { index: 1, term: 4, command: Some(vec![0x0a]) },
-                Entry { index: 2, term: 4, command: Some(vec![0x0b]) },
+                Entry {
+                    index: 1,
+                    term: 4,
+                    command: Some(vec![0x0a]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 4,
+                    command: Some(vec![0x0b]),
+                    membership: None,
+                    session: None,
+                },
             ])?
         );
         assert_eq!(
             vec![
-                Entry { index: 1, term: 4, command: Some(vec![0x0a]) },
-                Entry { index: 2, term: 4, command: Some(vec![0x0b]) },
+                Entry {
+                    index: 1,
+                    term: 4,
+                    command: Some(vec![0x0a]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 4,
+                    command: Some(vec![0x0b]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(..).collect::<Result<Vec<_>>>()?
         );
@@ -250,16 +408,52 @@ mod tests {
         assert_eq!(
             4,
             l.splice(vec![
-                Entry { index: 3, term: 3, command: Some(vec![0x03]) },
-                Entry { index: 4, term: 4, command: Some(vec![0x04]) },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 4,
+                    term: 4,
+                    command: Some(vec![0x04]),
+                    membership: None,
+                    session: None,
+                },
             ])?
         );
         assert_eq!(
             vec![
-                Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                Entry { index: 2, term: 2, command: Some(vec![0x02]) },
-                Entry { index: 3, term: 3, command: Some(vec![0x03]) },
-                Entry { index: 4, term: 4, command: Some(vec![0x04]) },
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 2,
+                    command: Some(vec![0x02]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 4,
+                    term: 4,
+                    command: Some(vec![0x04]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(..).collect::<Result<Vec<_>>>()?
         );
@@ -279,15 +473,45 @@ mod tests {
         assert_eq!(
             3,
             l.splice(vec![
-                Entry { index: 2, term: 3, command: Some(vec![0x0b]) },
-                Entry { index: 3, term: 3, command: Some(vec![0x0c]) }
+                Entry {
+                    index: 2,
+                    term: 3,
+                    command: Some(vec![0x0b]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x0c]),
+                    membership: None,
+                    session: None,
+                }
             ])?
         );
         assert_eq!(
             vec![
-                Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                Entry { index: 2, term: 3, command: Some(vec![0x0b]) },
-                Entry { index: 3, term: 3, command: Some(vec![0x0c]) },
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 3,
+                    command: Some(vec![0x0b]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x0c]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(..).collect::<Result<Vec<_>>>()?
         );
@@ -306,15 +530,45 @@ mod tests {
         assert_eq!(
             Err(Error::Internal("Spliced entries must be contiguous".into())),
             l.splice(vec![
-                Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                Entry { index: 3, term: 3, command: Some(vec![0x03]) },
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             ])
         );
         assert_eq!(
             vec![
-                Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                Entry { index: 2, term: 2, command: Some(vec![0x02]) },
-                Entry { index: 3, term: 3, command: Some(vec![0x03]) },
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 2,
+                    command: Some(vec![0x02]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(..).collect::<Result<Vec<_>>>()?
         );
@@ -331,15 +585,45 @@ mod tests {
         assert_eq!(
             Err(Error::Internal("Spliced entries cannot begin past last index".into())),
             l.splice(vec![
-                Entry { index: 5, term: 3, command: Some(vec![0x05]) },
-                Entry { index: 6, term: 3, command: Some(vec![0x06]) },
+                Entry {
+                    index: 5,
+                    term: 3,
+                    command: Some(vec![0x05]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 6,
+                    term: 3,
+                    command: Some(vec![0x06]),
+                    membership: None,
+                    session: None,
+                },
             ])
         );
         assert_eq!(
             vec![
-                Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                Entry { index: 2, term: 2, command: Some(vec![0x02]) },
-                Entry { index: 3, term: 3, command: Some(vec![0x03]) },
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 2,
+                    command: Some(vec![0x02]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(..).collect::<Result<Vec<_>>>()?
         );
@@ -353,12 +637,39 @@ mod tests {
         l.append(2, Some(vec![0x02]))?;
         l.append(3, Some(vec![0x03]))?;
 
-        assert_eq!(3, l.splice(vec![Entry { index: 2, term: 2, command: Some(vec![0x02]) },])?);
+        assert_eq!(
+            3,
+            l.splice(vec![Entry {
+                index: 2,
+                term: 2,
+                command: Some(vec![0x02]),
+                membership: None,
+                session: None
+            }])?
+        );
         assert_eq!(
             vec![
-                Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                Entry { index: 2, term: 2, command: Some(vec![0x02]) },
-                Entry { index: 3, term: 3, command: Some(vec![0x03]) },
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 2,
+                    command: Some(vec![0x02]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(..).collect::<Result<Vec<_>>>()?
         );
@@ -375,8 +686,20 @@ mod tests {
         assert_eq!(2, l.truncate(2)?);
         assert_eq!(
             vec![
-                Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                Entry { index: 2, term: 2, command: Some(vec![0x02]) },
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 2,
+                    command: Some(vec![0x02]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(..).collect::<Result<Vec<_>>>()?
         );
@@ -393,9 +716,27 @@ mod tests {
         assert_eq!(3, l.truncate(4)?);
         assert_eq!(
             vec![
-                Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                Entry { index: 2, term: 2, command: Some(vec![0x02]) },
-                Entry { index: 3, term: 3, command: Some(vec![0x03]) },
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 2,
+                    command: Some(vec![0x02]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 3,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             ],
             l.scan(..).collect::<Result<Vec<_>>>()?
         );
@@ -429,6 +770,249 @@ mod tests {
         assert!(l.scan(..).collect::<Result<Vec<_>>>()?.is_empty());
         Ok(())
     }
-}
 
-*/
\ No newline at end of file
+    #[test]
+    fn compact() -> Result<()> {
+        let (mut l, store) = setup()?;
+        l.append(1, Some(vec![0x01]))?;
+        l.append(2, Some(vec![0x02]))?;
+        l.append(2, Some(vec![0x03]))?;
+        l.commit(2)?;
+
+        assert_eq!(
+            Err(Error::Internal("Cannot compact uncommitted entry 3".into())),
+            l.compact(3, vec![0xff])
+        );
+
+        l.compact(2, vec![0xff])?;
+        assert_eq!(2, l.snapshot_index);
+        assert_eq!(2, l.snapshot_term);
+        assert_eq!(None, l.get(1)?);
+        assert_eq!(None, l.get(2)?);
+        assert_eq!(Some(2), l.term(2)?);
+        assert!(l.has(1, 1)?);
+        assert!(l.has(2, 2)?);
+        assert!(!l.has(2, 1)?);
+        assert_eq!(
+            vec![Entry {
+                index: 3,
+                term: 2,
+                command: Some(vec![0x03]),
+                membership: None,
+                session: None,
+            }],
+            l.scan(..).collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(
+            Some(Snapshot { index: 2, term: 2, data: vec![0xff], membership: None }),
+            l.load_snapshot()?
+        );
+
+        // Compacting at or below the snapshot is a noop.
+        l.compact(1, vec![0xee])?;
+        assert_eq!(2, l.snapshot_index);
+
+        // The snapshot and compaction should be persisted.
+        let l = RaftLog::new(store)?;
+        assert_eq!(2, l.snapshot_index);
+        assert_eq!(2, l.snapshot_term);
+        assert_eq!(2, l.commit_index);
+        assert_eq!(2, l.commit_term);
+        assert_eq!(3, l.last_index);
+        assert_eq!(2, l.last_term);
+        Ok(())
+    }
+
+    #[test]
+    fn compact_all() -> Result<()> {
+        let (mut l, store) = setup()?;
+        l.append(1, Some(vec![0x01]))?;
+        l.append(2, Some(vec![0x02]))?;
+        l.commit(2)?;
+        l.compact(2, vec![0xff])?;
+
+        let mut l = RaftLog::new(store)?;
+        assert_eq!(2, l.last_index);
+        assert_eq!(2, l.last_term);
+        assert_eq!(2, l.commit_index);
+        assert_eq!(2, l.commit_term);
+        assert_eq!(
+            Entry { index: 3, term: 3, command: None, membership: None, session: None },
+            l.append(3, None)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn install_snapshot_keeps_tail() -> Result<()> {
+        let (mut l, _) = setup()?;
+        l.append(1, Some(vec![0x01]))?;
+        l.append(2, Some(vec![0x02]))?;
+        l.append(2, Some(vec![0x03]))?;
+        l.commit(1)?;
+
+        l.install_snapshot(Snapshot { index: 2, term: 2, data: vec![0xff], membership: None })?;
+        assert_eq!(2, l.snapshot_index);
+        assert_eq!(2, l.commit_index);
+        assert_eq!(2, l.commit_term);
+        assert_eq!(3, l.last_index);
+        assert_eq!(2, l.last_term);
+        assert_eq!(
+            vec![Entry {
+                index: 3,
+                term: 2,
+                command: Some(vec![0x03]),
+                membership: None,
+                session: None,
+            }],
+            l.scan(..).collect::<Result<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn install_snapshot_discards_conflict() -> Result<()> {
+        let (mut l, _) = setup()?;
+        l.append(1, Some(vec![0x01]))?;
+        l.append(1, Some(vec![0x02]))?;
+        l.append(1, Some(vec![0x03]))?;
+        l.commit(1)?;
+
+        l.install_snapshot(Snapshot { index: 4, term: 3, data: vec![0xff], membership: None })?;
+        assert_eq!(4, l.snapshot_index);
+        assert_eq!(4, l.commit_index);
+        assert_eq!(3, l.commit_term);
+        assert_eq!(4, l.last_index);
+        assert_eq!(3, l.last_term);
+        assert!(l.scan(..).collect::<Result<Vec<_>>>()?.is_empty());
+        assert_eq!(
+            Entry { index: 5, term: 3, command: None, membership: None, session: None },
+            l.append(3, None)?
+        );
+
+        assert_eq!(
+            Err(Error::Internal("Cannot install snapshot at or below committed index 4".into())),
+            l.install_snapshot(Snapshot { index: 4, term: 3, data: vec![0xff], membership: None })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn splice_skips_snapshot() -> Result<()> {
+        let (mut l, _) = setup()?;
+        l.append(1, Some(vec![0x01]))?;
+        l.append(1, Some(vec![0x02]))?;
+        l.commit(2)?;
+        l.compact(2, vec![0xff])?;
+
+        assert_eq!(
+            3,
+            l.splice(vec![
+                Entry {
+                    index: 1,
+                    term: 1,
+                    command: Some(vec![0x01]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 2,
+                    term: 1,
+                    command: Some(vec![0x02]),
+                    membership: None,
+                    session: None,
+                },
+                Entry {
+                    index: 3,
+                    term: 2,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
+            ])?
+        );
+        assert_eq!(
+            vec![Entry {
+                index: 3,
+                term: 2,
+                command: Some(vec![0x03]),
+                membership: None,
+                session: None,
+            }],
+            l.scan(..).collect::<Result<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn membership() -> Result<()> {
+        let (mut l, store) = setup()?;
+        let old = Membership::new(vec!["a".to_string(), "b".to_string()]);
+        let voters = vec!["a".to_string(), "c".to_string()].into_iter().collect();
+        let joint = old.transition(voters, Default::default());
+        assert_eq!(None, l.membership);
+
+        l.append(1, Some(vec![0x01]))?;
+        l.append_membership(1, old.clone())?;
+        l.commit(2)?;
+        assert_eq!(
+            Entry {
+                index: 3,
+                term: 2,
+                command: None,
+                membership: Some(joint.clone()),
+                session: None,
+            },
+            l.append_membership(2, joint.clone())?
+        );
+        assert_eq!(Some(&joint), l.membership.as_ref());
+        assert_eq!(3, l.membership_index);
+
+        // The membership is recovered on startup.
+        let mut l = RaftLog::new(store.clone())?;
+        assert_eq!(Some(&joint), l.membership.as_ref());
+        assert_eq!(3, l.membership_index);
+
+        // Truncating an uncommitted membership reverts to the previous one.
+        l.truncate(2)?;
+        assert_eq!(Some(&old), l.membership.as_ref());
+        assert_eq!(2, l.membership_index);
+
+        // Spliced entries also change the membership.
+        let entry = Entry {
+            index: 3,
+            term: 3,
+            command: None,
+            membership: Some(joint.clone()),
+            session: None,
+        };
+        l.splice(vec![entry])?;
+        assert_eq!(Some(&joint), l.membership.as_ref());
+        assert_eq!(3, l.membership_index);
+        Ok(())
+    }
+
+    #[test]
+    fn compact_membership() -> Result<()> {
+        let (mut l, store) = setup()?;
+        let old = Membership::new(vec!["a".to_string(), "b".to_string()]);
+        let voters = vec!["a".to_string(), "c".to_string()].into_iter().collect();
+        let joint = old.transition(voters, Default::default());
+        l.append_membership(1, old.clone())?;
+        l.append(1, Some(vec![0x02]))?;
+        l.append_membership(1, joint.clone())?;
+        l.commit(3)?;
+
+        // The snapshot contains the membership as of its index, not the latest one.
+        l.compact(2, vec![0xff])?;
+        assert_eq!(Some(old.clone()), l.load_snapshot()?.and_then(|s| s.membership));
+        assert_eq!(Some(&joint), l.membership.as_ref());
+
+        l.compact(3, vec![0xff])?;
+        assert_eq!(Some(joint.clone()), l.load_snapshot()?.and_then(|s| s.membership));
+        let l = RaftLog::new(store)?;
+        assert_eq!(Some(&joint), l.membership.as_ref());
+        assert_eq!(3, l.membership_index);
+        Ok(())
+    }
+}
@@ -0,0 +1,15 @@
+use serde::{Serialize, Deserialize};
+use crate::raft_engine::raft_node::Membership;
+
+/// A state machine snapshot, covering all log entries up to and including index.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The index of the last log entry covered by the snapshot.
+    pub index: u64,
+    /// The term of the last log entry covered by the snapshot.
+    pub term: u64,
+    /// The serialized state machine data.
+    pub data: Vec<u8>,
+    /// The cluster membership as of the snapshot, if it was changed from the initial peers.
+    pub membership: Option<Membership>,
+}
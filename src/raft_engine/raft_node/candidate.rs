@@ -1,13 +1,13 @@
 use crate::{
-    error::Result,
+    error::{Error, Result},
     raft_engine::{
-        messaging::{Address, Event, Message, Response},
-        raft_node::{Follower, Leader, Node, RoleNode, ELECTION_TIMEOUT_MAX, ELECTION_TIMEOUT_MIN}
+        messaging::{Address, Event, Message, Request},
+        raft_node::{random_election_timeout, Follower, Leader, Node, RoleNode}
     }
 };
 
 use log::{debug, info, warn};
-use rand::Rng as _;
+use std::collections::HashSet;
 
 /// A candidate is campaigning to become a leader.
 #[derive(Debug)]
@@ -16,29 +16,69 @@ pub struct Candidate {
     pub election_ticks: u64,
     /// Election timeout, in ticks.
     pub election_timeout: u64,
-    /// Votes received (including ourself).
-    pub votes: u64,
+    /// Voters that granted us their vote (including ourself). Tracked by node ID, so duplicate
+    /// votes from the same node are only counted once.
+    pub votes: HashSet<String>,
+    /// Whether this is a pre-vote round, where we check that we could win an election for the next
+    /// term before actually incrementing our term and campaigning for it.
+    pub pre_vote: bool,
 }
 
 impl Candidate {
-    /// Creates a new candidate role.
-    pub fn new() -> Self {
+    /// Creates a new candidate role for the given node ID, with the given election timeout.
+    pub fn new(id: &str, election_timeout: u64) -> Self {
         Self {
-            votes: 1, // We always start with a vote for ourselves.
+            votes: HashSet::from([id.to_string()]), // We always start with a vote for ourselves.
             election_ticks: 0,
-            election_timeout: rand::thread_rng()
-                .gen_range(ELECTION_TIMEOUT_MIN..=ELECTION_TIMEOUT_MAX),
+            election_timeout,
+            pre_vote: false,
         }
     }
+
+    /// Creates a new candidate role in the pre-vote round.
+    pub fn new_pre_vote(id: &str, election_timeout: u64) -> Self {
+        Self { pre_vote: true, ..Self::new(id, election_timeout) }
+    }
 }
 
 impl RoleNode<Candidate> {
+    /// Starts a pre-vote round for the next term, without incrementing our term.
+    pub fn pre_campaign(&mut self) -> Result<()> {
+        info!("Starting pre-vote for term {}", self.term + 1);
+        self.role = Candidate::new_pre_vote(&self.id, random_election_timeout(&self.config));
+        self.send_term(
+            Address::Peers,
+            self.term + 1,
+            Event::PreVote { last_index: self.log.last_index, last_term: self.log.last_term },
+        )
+    }
+
+    /// Starts an election for the next term.
+    pub fn campaign(&mut self) -> Result<()> {
+        info!("Starting election for term {}", self.term + 1);
+        self.term += 1;
+        self.log.save_term(self.term, None)?;
+        self.role = Candidate::new(&self.id, random_election_timeout(&self.config));
+        self.send(
+            Address::Peers,
+            Event::SolicitVote { last_index: self.log.last_index, last_term: self.log.last_term },
+        )
+    }
+
     /// Transition to follower role.
     fn become_follower(mut self, term: u64, leader: &str) -> Result<RoleNode<Follower>> {
         info!("Discovered leader {} for term {}, following", leader, term);
-        self.term = term;
-        self.log.save_term(term, None)?;
-        let mut node = self.become_role(Follower::new(Some(leader), None))?;
+        // A pre-vote doesn't change our term, so we may have voted in this term as a follower.
+        let mut voted_for = None;
+        if term > self.term {
+            self.term = term;
+            self.log.save_term(term, None)?;
+        } else {
+            voted_for = self.log.load_term()?.1;
+        }
+        let election_timeout = random_election_timeout(&self.config);
+        let mut node =
+            self.become_role(Follower::new(Some(leader), voted_for.as_deref(), election_timeout))?;
         node.abort_proxied()?;
         node.forward_queued(Address::Peer(leader.to_string()))?;
         Ok(node)
@@ -50,13 +90,7 @@ impl RoleNode<Candidate> {
         let peers = self.peers.clone();
         let last_index = self.log.last_index;
         let mut node = self.become_role(Leader::new(peers, last_index))?;
-        node.send(
-            Address::Peers,
-            Event::Heartbeat {
-                commit_index: node.log.commit_index,
-                commit_term: node.log.commit_term,
-            },
-        )?;
+        node.heartbeat()?;
         node.append(None)?;
         node.abort_proxied()?;
         Ok(node)
@@ -68,69 +102,93 @@ impl RoleNode<Candidate> {
             warn!("Ignoring invalid message: {}", err);
             return Ok(self.into());
         }
-        if msg.term > self.term {
+        if msg.term > self.term && !msg.is_pre_vote() {
             if let Address::Peer(from) = &msg.from {
                 return self.become_follower(msg.term, from)?.step(msg);
             }
         }
 
         match msg.event {
-            Event::Heartbeat { .. } => {
+            // A leader in our term has won the election, which is also the case when we're
+            // holding a pre-vote at the leader's term, so we follow it.
+            Event::Heartbeat { .. }
+            | Event::ReplicateEntries { .. }
+            | Event::InstallSnapshot { .. } => {
                 if let Address::Peer(from) = &msg.from {
                     return self.become_follower(msg.term, from)?.step(msg);
                 }
             }
 
-            Event::GrantVote => {
+            Event::GrantVote if !self.role.pre_vote => {
                 debug!("Received term {} vote from {:?}", self.term, msg.from);
-                self.role.votes += 1;
-                if self.role.votes >= self.quorum() {
+                let Address::Peer(from) = msg.from else { return Ok(self.into()) };
+                if !self.is_voter(&from) {
+                    return Ok(self.into());
+                }
+                self.role.votes.insert(from);
+                if self.has_quorum(&self.role.votes) {
                     let queued = std::mem::take(&mut self.queued_reqs);
                     let mut node: Node = self.become_leader()?.into();
                     for (from, event) in queued {
-                        node = node.step(Message { from, to: Address::Local, term: 0, event })?;
+                        let msg = Message { group: 0, from, to: Address::Local, term: 0, event };
+                        node = node.step(msg)?;
                     }
                     return Ok(node);
                 }
             }
 
-            Event::ClientRequest { .. } => self.queued_reqs.push((msg.from, msg.event)),
+            Event::PreVoteGranted if self.role.pre_vote && msg.term == self.term + 1 => {
+                debug!("Received term {} pre-vote from {:?}", msg.term, msg.from);
+                let Address::Peer(from) = msg.from else { return Ok(self.into()) };
+                if !self.is_voter(&from) {
+                    return Ok(self.into());
+                }
+                self.role.votes.insert(from);
+                if self.has_quorum(&self.role.votes) {
+                    self.campaign()?;
+                }
+            }
 
-            Event::ClientResponse { id, mut response } => {
-                if let Ok(Response::Status(ref mut status)) = response {
-                    status.server = self.id.clone();
+            // We grant pre-votes to other candidates with an up-to-date log, since we haven't
+            // heard from a leader either. This does not affect our own vote.
+            Event::PreVote { last_index, last_term } => {
+                if msg.term > self.term && self.is_log_current(last_index, last_term) {
+                    self.send_term(msg.from, msg.term, Event::PreVoteGranted)?;
                 }
-                self.proxied_reqs.remove(&id);
-                self.send(Address::Client, Event::ClientResponse { id, response })?;
             }
 
-            // Ignore other candidates when we're also campaigning
-            Event::SolicitVote { .. } => {}
+            // Stale queries can't wait for an election, since we don't know how stale we are.
+            Event::ClientRequest { id, request: Request::StaleQuery { .. } } => {
+                let response = Err(Error::Value("No known leader to bound staleness".into()));
+                self.send(msg.from, Event::ClientResponse { id, response })?;
+            }
+
+            Event::ClientRequest { .. } => self.queued_reqs.push((msg.from, msg.event)),
+
+            Event::ClientResponse { id, response } => self.proxy_response(id, response)?,
+
+            Event::Snapshot { index, data } => self.log.compact(index, data)?,
+
+            // Ignore other candidates when we're also campaigning, and stray votes from a
+            // previous round.
+            Event::SolicitVote { .. } | Event::GrantVote | Event::PreVoteGranted => {}
 
             Event::ConfirmLeader { .. }
-            | Event::ReplicateEntries { .. }
             | Event::AcceptEntries { .. }
-            | Event::RejectEntries { .. } => warn!("Received unexpected message {:?}", msg),
+            | Event::RejectEntries { .. }
+            | Event::TimeoutNow
+            | Event::Heartbeats { .. } => warn!("Received unexpected message {:?}", msg),
         }
         Ok(self.into())
     }
 
     /// Processes a logical clock tick.
     pub fn tick(mut self) -> Result<Node> {
-        // If the election times out, start a new one for the next term.
+        // If the election times out, start a new pre-vote for the next term.
         self.role.election_ticks += 1;
         if self.role.election_ticks >= self.role.election_timeout {
-            info!("Election timed out, starting new election for term {}", self.term + 1);
-            self.term += 1;
-            self.log.save_term(self.term, None)?;
-            self.role = Candidate::new();
-            self.send(
-                Address::Peers,
-                Event::SolicitVote {
-                    last_index: self.log.last_index,
-                    last_term: self.log.last_term,
-                },
-            )?;
+            info!("Election timed out");
+            self.pre_campaign()?;
         }
         Ok(self.into())
     }
@@ -138,10 +196,11 @@ impl RoleNode<Candidate> {
 
 #[cfg(test)]
 mod tests {
-    use super::super::super::{Entry, Instruction, Log, Request};
+    use crate::raft_engine::{machine_state::Instruction, messaging::Request, raft_log::{Entry, RaftLog}};
     use super::super::tests::{assert_messages, assert_node};
     use super::*;
-    use crate::storage::log;
+    use crate::raft_engine::raft_node::{RaftConfig, ELECTION_TIMEOUT_MIN};
+    use crate::storage_engine::log_storage::LogTest;
     use futures::FutureExt;
     use std::collections::HashMap;
     use tokio::sync::mpsc;
@@ -154,7 +213,7 @@ mod tests {
     )> {
         let (node_tx, mut node_rx) = mpsc::unbounded_channel();
         let (state_tx, state_rx) = mpsc::unbounded_channel();
-        let mut log = Log::new(Box::new(log::Test::new()))?;
+        let mut log = RaftLog::new(Box::new(LogTest::new()))?;
         log.append(1, Some(vec![0x01]))?;
         log.append(1, Some(vec![0x02]))?;
         log.append(2, Some(vec![0x03]))?;
@@ -170,9 +229,12 @@ mod tests {
             state_tx,
             queued_reqs: Vec::new(),
             proxied_reqs: HashMap::new(),
-            role: Candidate::new(),
+            read_reqs: HashMap::new(),
+            config: RaftConfig::default(),
+            role: Candidate::new("a", ELECTION_TIMEOUT_MIN),
         };
         node = match node.step(Message {
+            group: 0,
             from: Address::Client,
             to: Address::Local,
             term: 0,
@@ -191,16 +253,18 @@ mod tests {
     fn step_heartbeat_current_term() -> Result<()> {
         let (candidate, mut node_rx, mut state_rx) = setup()?;
         let node = candidate.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::Heartbeat { commit_index: 2, commit_term: 1 },
+            event: Event::Heartbeat { commit_index: 2, commit_term: 1, round: 0 },
         })?;
         assert_node(&node).is_follower().term(3);
         assert_messages(
             &mut node_rx,
             vec![
                 Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer("b".into()),
                     term: 0,
@@ -210,10 +274,11 @@ mod tests {
                     },
                 },
                 Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer("b".into()),
                     term: 3,
-                    event: Event::ConfirmLeader { commit_index: 2, has_committed: true },
+                    event: Event::ConfirmLeader { commit_index: 2, has_committed: true, round: 0 },
                 },
             ],
         );
@@ -227,16 +292,18 @@ mod tests {
     fn step_heartbeat_future_term() -> Result<()> {
         let (candidate, mut node_rx, mut state_rx) = setup()?;
         let node = candidate.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 4,
-            event: Event::Heartbeat { commit_index: 2, commit_term: 1 },
+            event: Event::Heartbeat { commit_index: 2, commit_term: 1, round: 0 },
         })?;
         assert_node(&node).is_follower().term(4);
         assert_messages(
             &mut node_rx,
             vec![
                 Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer("b".into()),
                     term: 0,
@@ -246,10 +313,58 @@ mod tests {
                     },
                 },
                 Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer("b".into()),
                     term: 4,
-                    event: Event::ConfirmLeader { commit_index: 2, has_committed: true },
+                    event: Event::ConfirmLeader { commit_index: 2, has_committed: true, round: 0 },
+                },
+            ],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // ReplicateEntries for the current term, e.g. while holding a pre-vote, converts to
+    // follower, forwards the queued request, and accepts the entries.
+    fn step_replicateentries_current_term() -> Result<()> {
+        let (mut candidate, mut node_rx, mut state_rx) = setup()?;
+        candidate.role = Candidate::new_pre_vote("a", ELECTION_TIMEOUT_MIN);
+        let entry = Entry {
+            index: 4,
+            term: 3,
+            command: Some(vec![0x04]),
+            membership: None,
+            session: None,
+        };
+        let node = candidate.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::ReplicateEntries { base_index: 3, base_term: 2, entries: vec![entry] },
+        })?;
+        assert_node(&node).is_follower().term(3).leader(Some("b")).last(4);
+        assert_messages(
+            &mut node_rx,
+            vec![
+                Message {
+                    group: 0,
+                    from: Address::Local,
+                    to: Address::Peer("b".into()),
+                    term: 0,
+                    event: Event::ClientRequest {
+                        id: vec![0xaf],
+                        request: Request::Query(vec![0xf0]),
+                    },
+                },
+                Message {
+                    group: 0,
+                    from: Address::Local,
+                    to: Address::Peer("b".into()),
+                    term: 3,
+                    event: Event::AcceptEntries { last_index: 4 },
                 },
             ],
         );
@@ -262,10 +377,11 @@ mod tests {
     fn step_heartbeat_past_term() -> Result<()> {
         let (candidate, mut node_rx, mut state_rx) = setup()?;
         let node = candidate.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 2,
-            event: Event::Heartbeat { commit_index: 1, commit_term: 1 },
+            event: Event::Heartbeat { commit_index: 1, commit_term: 1, round: 0 },
         })?;
         assert_node(&node).is_candidate().term(3);
         assert_messages(&mut node_rx, vec![]);
@@ -277,21 +393,27 @@ mod tests {
     fn step_grantvote() -> Result<()> {
         let (candidate, mut node_rx, mut state_rx) = setup()?;
         let peers = candidate.peers.clone();
+        let quorum = Some(candidate.membership());
         let mut node = Node::Candidate(candidate);
 
-        // The first vote is not sufficient for a quorum (3 votes including self)
-        node = node.step(Message {
-            from: Address::Peer("c".into()),
-            to: Address::Peer("a".into()),
-            term: 3,
-            event: Event::GrantVote,
-        })?;
-        assert_node(&node).is_candidate().term(3);
+        // The first vote is not sufficient for a quorum (3 votes including self), nor is a
+        // duplicate vote from the same peer.
+        for _ in 0..2 {
+            node = node.step(Message {
+                group: 0,
+                from: Address::Peer("c".into()),
+                to: Address::Peer("a".into()),
+                term: 3,
+                event: Event::GrantVote,
+            })?;
+            assert_node(&node).is_candidate().term(3);
+        }
         assert_messages(&mut node_rx, vec![]);
         assert_messages(&mut state_rx, vec![]);
 
         // However, the second external vote makes us leader
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("e".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -302,10 +424,11 @@ mod tests {
         assert_eq!(
             node_rx.recv().now_or_never(),
             Some(Some(Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peers,
                 term: 3,
-                event: Event::Heartbeat { commit_index: 2, commit_term: 1 },
+                event: Event::Heartbeat { commit_index: 2, commit_term: 1, round: 0 },
             })),
         );
 
@@ -313,13 +436,20 @@ mod tests {
             assert_eq!(
                 node_rx.recv().now_or_never(),
                 Some(Some(Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer(to),
                     term: 3,
                     event: Event::ReplicateEntries {
                         base_index: 3,
                         base_term: 2,
-                        entries: vec![Entry { index: 4, term: 3, command: None }],
+                        entries: vec![Entry {
+                            index: 4,
+                            term: 3,
+                            command: None,
+                            membership: None,
+                            session: None,
+                        }],
                     },
                 }))
             )
@@ -329,10 +459,11 @@ mod tests {
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peers,
                 term: 3,
-                event: Event::Heartbeat { commit_index: 2, commit_term: 1 },
+                event: Event::Heartbeat { commit_index: 2, commit_term: 1, round: 0 },
             }],
         );
         assert_messages(
@@ -344,14 +475,71 @@ mod tests {
                     command: vec![0xf0],
                     term: 3,
                     index: 2,
-                    quorum: 3,
+                    quorum,
                 },
-                Instruction::Vote { term: 3, index: 2, address: Address::Local },
+                Instruction::Vote { term: 3, index: 2, address: Address::Peer("a".into()) },
             ],
         );
         Ok(())
     }
 
+    #[test]
+    // A quorum of pre-votes starts an election for the next term, but votes for the current term
+    // are ignored during the pre-vote.
+    fn step_prevotegranted() -> Result<()> {
+        let (mut candidate, mut node_rx, mut state_rx) = setup()?;
+        candidate.pre_campaign()?;
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peers,
+                term: 4,
+                event: Event::PreVote { last_index: 3, last_term: 2 },
+            }],
+        );
+        let mut node = Node::Candidate(candidate);
+
+        for (from, term, event) in [
+            ("b", 3, Event::GrantVote),
+            ("c", 3, Event::GrantVote),
+            ("d", 3, Event::PreVoteGranted),
+            ("e", 4, Event::PreVoteGranted),
+        ] {
+            node = node.step(Message {
+                group: 0,
+                from: Address::Peer(from.into()),
+                to: Address::Peer("a".into()),
+                term,
+                event,
+            })?;
+            assert_node(&node).is_candidate().term(3);
+        }
+        assert_messages(&mut node_rx, vec![]);
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("c".into()),
+            to: Address::Peer("a".into()),
+            term: 4,
+            event: Event::PreVoteGranted,
+        })?;
+        assert_node(&node).is_candidate().term(4);
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peers,
+                term: 4,
+                event: Event::SolicitVote { last_index: 3, last_term: 2 },
+            }],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
     #[test]
     fn tick() -> Result<()> {
         let (candidate, mut node_rx, mut state_rx) = setup()?;
@@ -363,15 +551,16 @@ mod tests {
             assert_node(&node).is_candidate().term(3);
             node = node.tick()?;
         }
-        assert_node(&node).is_candidate().term(4);
+        assert_node(&node).is_candidate().term(3);
 
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peers,
                 term: 4,
-                event: Event::SolicitVote { last_index: 3, last_term: 2 },
+                event: Event::PreVote { last_index: 3, last_term: 2 },
             }],
         );
         assert_messages(&mut state_rx, vec![]);
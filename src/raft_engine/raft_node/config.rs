@@ -0,0 +1,166 @@
+use super::{
+    ELECTION_TIMEOUT_MAX, ELECTION_TIMEOUT_MIN, HEARTBEAT_INTERVAL, MAX_APPEND_BYTES,
+    MAX_APPEND_ENTRIES, MAX_INFLIGHT, PEER_BUFFER, RECONNECT_BACKOFF_MAX, RECONNECT_BACKOFF_MIN,
+    REPLICATE_TIMEOUT, TICK,
+};
+use crate::error::{Error, Result};
+
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Raft timing and tuning parameters. Heartbeats and elections are measured in logical clock
+/// ticks, whose duration is given by the tick setting.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RaftConfig {
+    /// The duration of a logical clock tick, in milliseconds.
+    pub tick_ms: u64,
+    /// The interval between leader heartbeats, in ticks.
+    pub heartbeat_interval: u64,
+    /// The minimum election timeout, in ticks.
+    pub election_timeout_min: u64,
+    /// The maximum election timeout, in ticks.
+    pub election_timeout_max: u64,
+    /// The delay before reconnecting to an unreachable peer, in milliseconds. It is doubled after
+    /// every failed attempt, up to reconnect_backoff_max_ms.
+    pub reconnect_backoff_min_ms: u64,
+    /// The maximum delay before reconnecting to an unreachable peer, in milliseconds.
+    pub reconnect_backoff_max_ms: u64,
+    /// The number of outbound messages buffered per peer. Messages are dropped when it is full.
+    pub peer_buffer: usize,
+    /// The maximum number of entries in a single ReplicateEntries message.
+    pub max_append_entries: usize,
+    /// The maximum size of the entries in a single ReplicateEntries message, in bytes.
+    pub max_append_bytes: u64,
+    /// The maximum number of ReplicateEntries messages in flight to a peer.
+    pub max_inflight: usize,
+    /// The number of ticks without a response from a peer after which the ReplicateEntries
+    /// messages in flight to it are considered lost, and resent.
+    pub replicate_timeout: u64,
+    /// Whether the leader serves queries locally while it holds a leader lease, instead of
+    /// confirming its leadership with a quorum for every query. This relies on bounded clock
+    /// drift between nodes.
+    pub lease_reads: bool,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            tick_ms: TICK.as_millis() as u64,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            election_timeout_min: ELECTION_TIMEOUT_MIN,
+            election_timeout_max: ELECTION_TIMEOUT_MAX,
+            reconnect_backoff_min_ms: RECONNECT_BACKOFF_MIN.as_millis() as u64,
+            reconnect_backoff_max_ms: RECONNECT_BACKOFF_MAX.as_millis() as u64,
+            peer_buffer: PEER_BUFFER,
+            max_append_entries: MAX_APPEND_ENTRIES,
+            max_append_bytes: MAX_APPEND_BYTES,
+            max_inflight: MAX_INFLIGHT,
+            replicate_timeout: REPLICATE_TIMEOUT,
+            lease_reads: false,
+        }
+    }
+}
+
+impl RaftConfig {
+    /// Checks that the settings are valid and consistent with each other.
+    pub fn validate(&self) -> Result<()> {
+        let nonzero = [
+            ("tick_ms", self.tick_ms),
+            ("heartbeat_interval", self.heartbeat_interval),
+            ("reconnect_backoff_min_ms", self.reconnect_backoff_min_ms),
+            ("peer_buffer", self.peer_buffer as u64),
+            ("max_append_entries", self.max_append_entries as u64),
+            ("max_append_bytes", self.max_append_bytes),
+            ("max_inflight", self.max_inflight as u64),
+            ("replicate_timeout", self.replicate_timeout),
+        ];
+        if let Some((name, _)) = nonzero.iter().find(|(_, value)| *value == 0) {
+            return Err(Error::Config(format!("Raft setting {} must be greater than 0", name)));
+        }
+        // Followers must hear from the leader at least once per election timeout, with some
+        // margin for lost heartbeats, otherwise they will keep calling elections.
+        if self.election_timeout_min < 2 * self.heartbeat_interval {
+            return Err(Error::Config(format!(
+                "Minimum election timeout {} must be at least twice the heartbeat interval {}",
+                self.election_timeout_min, self.heartbeat_interval
+            )));
+        }
+        if self.election_timeout_max < self.election_timeout_min {
+            return Err(Error::Config(format!(
+                "Maximum election timeout {} is below minimum election timeout {}",
+                self.election_timeout_max, self.election_timeout_min
+            )));
+        }
+        if self.reconnect_backoff_max_ms < self.reconnect_backoff_min_ms {
+            return Err(Error::Config(format!(
+                "Maximum reconnect backoff {}ms is below minimum reconnect backoff {}ms",
+                self.reconnect_backoff_max_ms, self.reconnect_backoff_min_ms
+            )));
+        }
+        // A lease must outlast the heartbeat interval, otherwise it expires before the next
+        // heartbeat round can renew it.
+        if self.lease_reads && self.lease_timeout() <= self.heartbeat_interval {
+            return Err(Error::Config(format!(
+                "Lease timeout {} (half the minimum election timeout) must be above the \
+                 heartbeat interval {} for lease reads",
+                self.lease_timeout(),
+                self.heartbeat_interval
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the duration of a logical clock tick.
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+
+    /// Returns the duration of a leader lease, in ticks. It is shorter than the minimum election
+    /// timeout to allow for clock drift between nodes.
+    pub fn lease_timeout(&self) -> u64 {
+        self.election_timeout_min / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() -> Result<()> {
+        RaftConfig::default().validate()?;
+        let fast = RaftConfig {
+            tick_ms: 1,
+            election_timeout_min: 2,
+            election_timeout_max: 2,
+            ..Default::default()
+        };
+        fast.validate()?;
+        RaftConfig { lease_reads: true, ..Default::default() }.validate()?;
+
+        let invalid = [
+            RaftConfig { tick_ms: 0, ..Default::default() },
+            RaftConfig { peer_buffer: 0, ..Default::default() },
+            RaftConfig { max_inflight: 0, ..Default::default() },
+            RaftConfig { replicate_timeout: 0, ..Default::default() },
+            RaftConfig { heartbeat_interval: 5, election_timeout_min: 9, ..Default::default() },
+            RaftConfig { election_timeout_min: 10, election_timeout_max: 9, ..Default::default() },
+            RaftConfig {
+                heartbeat_interval: 2,
+                election_timeout_min: 4,
+                lease_reads: true,
+                ..Default::default()
+            },
+            RaftConfig {
+                reconnect_backoff_min_ms: 1000,
+                reconnect_backoff_max_ms: 500,
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(matches!(config.validate(), Err(Error::Config(_))), "{:?}", config);
+        }
+        Ok(())
+    }
+}
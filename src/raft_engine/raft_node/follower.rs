@@ -1,14 +1,13 @@
 use crate::{
-    error::Result,
+    error::{Error, Result},
     raft_engine::{
         machine_state::Instruction,
-        messaging::{Address, Event, Message, Response},
-        raft_node::{Candidate, Node, RoleNode, ELECTION_TIMEOUT_MAX, ELECTION_TIMEOUT_MIN}
+        messaging::{Address, Event, Message, Request, Staleness},
+        raft_node::{random_election_timeout, Candidate, Node, RoleNode}
     }
 };
 
 use log::{debug, info, warn};
-use rand::Rng as _;
 
 // A follower replicates state from a leader.
 #[derive(Debug)]
@@ -17,6 +16,8 @@ pub struct Follower {
     pub leader: Option<String>,
     /// The number of ticks since the last message from the leader.
     pub leader_seen_ticks: u64,
+    /// The leader's commit index, as of its last heartbeat.
+    pub leader_commit_index: u64,
     /// The timeout before triggering an election.
     pub leader_seen_timeout: u64,
     /// The node we voted for in the current term, if any.
@@ -24,29 +25,29 @@ pub struct Follower {
 }
 
 impl Follower {
-    /// Creates a new follower role.
-    pub fn new(leader: Option<&str>, voted_for: Option<&str>) -> Self {
+    /// Creates a new follower role, with the given election timeout.
+    pub fn new(leader: Option<&str>, voted_for: Option<&str>, election_timeout: u64) -> Self {
         Self {
             leader: leader.map(String::from),
             voted_for: voted_for.map(String::from),
             leader_seen_ticks: 0,
-            leader_seen_timeout: rand::thread_rng()
-                .gen_range(ELECTION_TIMEOUT_MIN..=ELECTION_TIMEOUT_MAX),
+            leader_commit_index: 0,
+            leader_seen_timeout: election_timeout,
         }
     }
 }
 
 impl RoleNode<Follower> {
-    /// Transforms the node into a candidate.
-    fn become_candidate(self) -> Result<RoleNode<Candidate>> {
-        info!("Starting election for term {}", self.term + 1);
-        let mut node = self.become_role(Candidate::new())?;
-        node.term += 1;
-        node.log.save_term(node.term, None)?;
-        node.send(
-            Address::Peers,
-            Event::SolicitVote { last_index: node.log.last_index, last_term: node.log.last_term },
-        )?;
+    /// Transforms the node into a candidate, either starting a pre-vote round or an election.
+    fn become_candidate(self, pre_vote: bool) -> Result<RoleNode<Candidate>> {
+        let election_timeout = random_election_timeout(&self.config);
+        let id = self.id.clone();
+        let mut node = self.become_role(Candidate::new_pre_vote(&id, election_timeout))?;
+        if pre_vote {
+            node.pre_campaign()?;
+        } else {
+            node.campaign()?;
+        }
         Ok(node)
     }
 
@@ -61,12 +62,25 @@ impl RoleNode<Follower> {
             info!("Discovered leader {}, following", leader);
             voted_for = self.role.voted_for;
         };
-        self.role = Follower::new(Some(leader), voted_for.as_deref());
+        let election_timeout = random_election_timeout(&self.config);
+        self.role = Follower::new(Some(leader), voted_for.as_deref(), election_timeout);
         self.abort_proxied()?;
         self.forward_queued(Address::Peer(leader.to_string()))?;
         Ok(self)
     }
 
+    /// Finds the conflict hint for a rejected base index: the term of our entry at the base index
+    /// and the first index of that term, or None and our last index + 1 if we don't have it.
+    fn conflict(&self, base_index: u64) -> Result<(Option<u64>, u64)> {
+        match self.log.term(base_index)? {
+            Some(term) if base_index > self.log.snapshot_index => {
+                let from = self.log.snapshot_index + 1;
+                Ok((Some(term), self.log.search_term(term, from, base_index)?))
+            }
+            _ => Ok((None, self.log.last_index + 1)),
+        }
+    }
+
     /// Checks if an address is the current leader
     fn is_leader(&self, from: &Address) -> bool {
         matches!((&self.role.leader, from), (Some(leader), Address::Peer(from)) if leader == from)
@@ -79,7 +93,7 @@ impl RoleNode<Follower> {
             return Ok(self.into());
         }
         if let Address::Peer(from) = &msg.from {
-            if msg.term > self.term || self.role.leader.is_none() {
+            if !msg.is_pre_vote() && (msg.term > self.term || self.role.leader.is_none()) {
                 return self.become_follower(from, msg.term)?.step(msg);
             }
         }
@@ -88,8 +102,9 @@ impl RoleNode<Follower> {
         }
 
         match msg.event {
-            Event::Heartbeat { commit_index, commit_term } => {
+            Event::Heartbeat { commit_index, commit_term, round } => {
                 if self.is_leader(&msg.from) {
+                    self.role.leader_commit_index = commit_index;
                     let has_committed = self.log.has(commit_index, commit_term)?;
                     if has_committed && commit_index > self.log.commit_index {
                         let old_commit_index = self.log.commit_index;
@@ -99,7 +114,10 @@ impl RoleNode<Follower> {
                             self.state_tx.send(Instruction::Apply { entry })?;
                         }
                     }
-                    self.send(msg.from, Event::ConfirmLeader { commit_index, has_committed })?;
+                    self.send(
+                        msg.from,
+                        Event::ConfirmLeader { commit_index, has_committed, round },
+                    )?;
                 }
             }
 
@@ -123,18 +141,96 @@ impl RoleNode<Follower> {
                 }
             }
 
+            // Pre-votes are only granted if we haven't heard from a leader recently, to avoid
+            // disrupting a healthy cluster, and if the candidate's log is up-to-date. They don't
+            // change our term or vote. This also applies when we don't know of a leader, e.g.
+            // after a restart, since the leader may still hold a lease that relies on us.
+            Event::PreVote { last_index, last_term } => {
+                if self.role.leader_seen_ticks < self.config.election_timeout_min {
+                    return Ok(self.into());
+                }
+                if msg.term > self.term && self.is_log_current(last_index, last_term) {
+                    debug!("Granting pre-vote for term {} to {:?}", msg.term, msg.from);
+                    self.send_term(msg.from, msg.term, Event::PreVoteGranted)?;
+                }
+            }
+
             Event::ReplicateEntries { base_index, base_term, entries } => {
                 if self.is_leader(&msg.from) {
                     if base_index > 0 && !self.log.has(base_index, base_term)? {
                         debug!("Rejecting log entries at base {}", base_index);
-                        self.send(msg.from, Event::RejectEntries)?
+                        let (conflict_term, conflict_index) = self.conflict(base_index)?;
+                        self.send(
+                            msg.from,
+                            Event::RejectEntries { base_index, conflict_term, conflict_index },
+                        )?
                     } else {
                         let last_index = self.log.splice(entries)?;
+                        self.update_peers();
                         self.send(msg.from, Event::AcceptEntries { last_index })?
                     }
                 }
             }
 
+            Event::InstallSnapshot { snapshot } => {
+                if self.is_leader(&msg.from) {
+                    let last_index = snapshot.index;
+                    if snapshot.index > self.log.commit_index {
+                        self.log.install_snapshot(snapshot.clone())?;
+                        self.update_peers();
+                        self.state_tx.send(Instruction::Restore { snapshot })?;
+                    }
+                    self.send(msg.from, Event::AcceptEntries { last_index })?
+                }
+            }
+
+            Event::TimeoutNow => {
+                if self.is_leader(&msg.from) && self.is_voter(&self.id) {
+                    info!("Leader is transferring leadership to us, starting election");
+                    return Ok(self.become_candidate(false)?.into());
+                }
+            }
+
+            Event::Snapshot { index, data } => self.log.compact(index, data)?,
+
+            // Queries are executed locally, at the leader's confirmed read index.
+            Event::ClientRequest { id, request: Request::Query(command) }
+                if self.role.leader.is_some() =>
+            {
+                let leader = self.role.leader.clone().expect("no leader");
+                self.read_index(&leader, id, msg.from, command)?;
+            }
+
+            Event::ClientRequest {
+                id,
+                request: Request::StaleQuery { command, staleness },
+            } => {
+                let index = match (&self.role.leader, staleness) {
+                    (None, _) => Err(Error::Value("No known leader to bound staleness".into())),
+                    (Some(_), Staleness::Index(lag)) => {
+                        Ok(self.role.leader_commit_index.saturating_sub(lag))
+                    }
+                    (Some(_), Staleness::Ticks(ticks)) if self.role.leader_seen_ticks > ticks => {
+                        Err(Error::Value(format!(
+                            "Leader last seen {} ticks ago, exceeding max staleness of {} ticks",
+                            self.role.leader_seen_ticks, ticks
+                        )))
+                    }
+                    (Some(_), Staleness::Ticks(_)) => Ok(0),
+                };
+                match index {
+                    Ok(index) => self.state_tx.send(Instruction::StaleQuery {
+                        id,
+                        address: msg.from,
+                        command,
+                        index,
+                    })?,
+                    Err(error) => {
+                        self.send(msg.from, Event::ClientResponse { id, response: Err(error) })?
+                    }
+                }
+            }
+
             Event::ClientRequest { ref id, .. } => {
                 if let Some(leader) = self.role.leader.as_deref() {
                     self.proxied_reqs.insert(id.clone(), msg.from);
@@ -144,29 +240,29 @@ impl RoleNode<Follower> {
                 }
             }
 
-            Event::ClientResponse { id, mut response } => {
-                if let Ok(Response::Status(ref mut status)) = response {
-                    status.server = self.id.clone();
-                }
-                self.proxied_reqs.remove(&id);
-                self.send(Address::Client, Event::ClientResponse { id, response })?;
-            }
+            Event::ClientResponse { id, response } => self.proxy_response(id, response)?,
 
             // Ignore votes which are usually strays from the previous election that we lost.
-            Event::GrantVote => {}
+            Event::GrantVote | Event::PreVoteGranted => {}
 
             Event::ConfirmLeader { .. }
             | Event::AcceptEntries { .. }
-            | Event::RejectEntries { .. } => warn!("Received unexpected message {:?}", msg),
+            | Event::RejectEntries { .. }
+            | Event::Heartbeats { .. } => warn!("Received unexpected message {:?}", msg),
         };
         Ok(self.into())
     }
 
     /// Processes a logical clock tick.
     pub fn tick(mut self) -> Result<Node> {
+        // Learners and nodes that have been removed from the cluster never campaign, and must not
+        // disrupt it with elections.
+        if !self.is_voter(&self.id) {
+            return Ok(self.into());
+        }
         self.role.leader_seen_ticks += 1;
         if self.role.leader_seen_ticks >= self.role.leader_seen_timeout {
-            Ok(self.become_candidate()?.into())
+            Ok(self.become_candidate(true)?.into())
         } else {
             Ok(self.into())
         }
@@ -175,11 +271,14 @@ impl RoleNode<Follower> {
 
 #[cfg(test)]
 pub mod tests {
-    use super::super::super::{Entry, Log, Request};
+    use crate::raft_engine::{messaging::Response, raft_log::{Entry, RaftLog, Snapshot}};
     use super::super::tests::{assert_messages, assert_node};
     use super::*;
     use crate::error::Error;
-    use crate::storage::log;
+    use crate::raft_engine::raft_node::{
+        Membership, RaftConfig, ELECTION_TIMEOUT_MAX, ELECTION_TIMEOUT_MIN,
+    };
+    use crate::storage_engine::log_storage::LogTest;
     use std::collections::HashMap;
     use tokio::sync::mpsc;
 
@@ -199,7 +298,7 @@ pub mod tests {
     )> {
         let (node_tx, node_rx) = mpsc::unbounded_channel();
         let (state_tx, state_rx) = mpsc::unbounded_channel();
-        let mut log = Log::new(Box::new(log::Test::new()))?;
+        let mut log = RaftLog::new(Box::new(LogTest::new()))?;
         log.append(1, Some(vec![0x01]))?;
         log.append(1, Some(vec![0x02]))?;
         log.append(2, Some(vec![0x03]))?;
@@ -214,36 +313,137 @@ pub mod tests {
             node_tx,
             state_tx,
             proxied_reqs: HashMap::new(),
+            read_reqs: HashMap::new(),
             queued_reqs: Vec::new(),
-            role: Follower::new(Some("b"), None),
+            config: RaftConfig::default(),
+            role: Follower::new(Some("b"), None, ELECTION_TIMEOUT_MIN),
         };
         Ok((node, node_rx, state_rx))
     }
 
+    #[test]
+    // InstallSnapshot from the leader replaces the log prefix and restores the state machine.
+    fn step_installsnapshot() -> Result<()> {
+        let (follower, mut node_rx, mut state_rx) = setup()?;
+        let snapshot = Snapshot { index: 5, term: 3, data: vec![0xaa], membership: None };
+        let node = follower.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::InstallSnapshot { snapshot: snapshot.clone() },
+        })?;
+        assert_node(&node).is_follower().term(3).leader(Some("b")).committed(5).last(5);
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("b".into()),
+                term: 3,
+                event: Event::AcceptEntries { last_index: 5 },
+            }],
+        );
+        assert_messages(&mut state_rx, vec![Instruction::Restore { snapshot }]);
+        Ok(())
+    }
+
+    #[test]
+    // InstallSnapshot below the commit index is acknowledged but ignored.
+    fn step_installsnapshot_stale() -> Result<()> {
+        let (follower, mut node_rx, mut state_rx) = setup()?;
+        let node = follower.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::InstallSnapshot {
+                snapshot: Snapshot { index: 1, term: 1, data: vec![0xaa], membership: None },
+            },
+        })?;
+        assert_node(&node).is_follower().term(3).leader(Some("b")).committed(2).last(3);
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("b".into()),
+                term: 3,
+                event: Event::AcceptEntries { last_index: 1 },
+            }],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // TimeoutNow from the leader starts an election immediately, but is ignored from others.
+    fn step_timeoutnow() -> Result<()> {
+        let (follower, mut node_rx, mut state_rx) = setup()?;
+        let mut node = follower.step(Message {
+            group: 0,
+            from: Address::Peer("c".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::TimeoutNow,
+        })?;
+        assert_node(&node).is_follower().term(3).leader(Some("b"));
+        assert_messages(&mut node_rx, vec![]);
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::TimeoutNow,
+        })?;
+        assert_node(&node).is_candidate().term(4);
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peers,
+                term: 4,
+                event: Event::SolicitVote { last_index: 3, last_term: 2 },
+            }],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
     #[test]
     // Heartbeat from current leader should commit and apply
     fn step_heartbeat() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::Heartbeat { commit_index: 3, commit_term: 2 },
+            event: Event::Heartbeat { commit_index: 3, commit_term: 2, round: 0 },
         })?;
         assert_node(&node).is_follower().term(3).leader(Some("b")).voted_for(None).committed(3);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
-                event: Event::ConfirmLeader { commit_index: 3, has_committed: true },
+                event: Event::ConfirmLeader { commit_index: 3, has_committed: true, round: 0 },
             }],
         );
         assert_messages(
             &mut state_rx,
             vec![Instruction::Apply {
-                entry: Entry { index: 3, term: 2, command: Some(vec![0x03]) },
+                entry: Entry {
+                    index: 3,
+                    term: 2,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             }],
         );
         Ok(())
@@ -254,19 +454,21 @@ pub mod tests {
     fn step_heartbeat_conflict_commit_term() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::Heartbeat { commit_index: 3, commit_term: 3 },
+            event: Event::Heartbeat { commit_index: 3, commit_term: 3, round: 0 },
         })?;
         assert_node(&node).is_follower().term(3).leader(Some("b")).voted_for(None).committed(2);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
-                event: Event::ConfirmLeader { commit_index: 3, has_committed: false },
+                event: Event::ConfirmLeader { commit_index: 3, has_committed: false, round: 0 },
             }],
         );
         assert_messages(&mut state_rx, vec![]);
@@ -278,19 +480,21 @@ pub mod tests {
     fn step_heartbeat_missing_commit_entry() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::Heartbeat { commit_index: 5, commit_term: 3 },
+            event: Event::Heartbeat { commit_index: 5, commit_term: 3, round: 0 },
         })?;
         assert_node(&node).is_follower().term(3).leader(Some("b")).voted_for(None).committed(2);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
-                event: Event::ConfirmLeader { commit_index: 5, has_committed: false },
+                event: Event::ConfirmLeader { commit_index: 5, has_committed: false, round: 0 },
             }],
         );
         assert_messages(&mut state_rx, vec![]);
@@ -302,10 +506,11 @@ pub mod tests {
     fn step_heartbeat_fake_leader() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("c".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::Heartbeat { commit_index: 5, commit_term: 3 },
+            event: Event::Heartbeat { commit_index: 5, commit_term: 3, round: 0 },
         })?;
         assert_node(&node).is_follower().term(3).leader(Some("b")).voted_for(None).committed(2);
         assert_messages(&mut node_rx, vec![]);
@@ -317,27 +522,35 @@ pub mod tests {
     // Heartbeat when no current leader makes us follow the leader
     fn step_heartbeat_no_leader() -> Result<()> {
         let (mut follower, mut node_rx, mut state_rx) = setup()?;
-        follower.role = Follower::new(None, None);
+        follower.role = Follower::new(None, None, ELECTION_TIMEOUT_MIN);
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("c".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::Heartbeat { commit_index: 3, commit_term: 2 },
+            event: Event::Heartbeat { commit_index: 3, commit_term: 2, round: 0 },
         })?;
         assert_node(&node).is_follower().term(3).leader(Some("c")).voted_for(None).committed(3);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("c".into()),
                 term: 3,
-                event: Event::ConfirmLeader { commit_index: 3, has_committed: true },
+                event: Event::ConfirmLeader { commit_index: 3, has_committed: true, round: 0 },
             }],
         );
         assert_messages(
             &mut state_rx,
             vec![Instruction::Apply {
-                entry: Entry { index: 3, term: 2, command: Some(vec![0x03]) },
+                entry: Entry {
+                    index: 3,
+                    term: 2,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             }],
         );
         Ok(())
@@ -348,19 +561,21 @@ pub mod tests {
     fn step_heartbeat_old_commit_index() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::Heartbeat { commit_index: 1, commit_term: 1 },
+            event: Event::Heartbeat { commit_index: 1, commit_term: 1, round: 0 },
         })?;
         assert_node(&node).is_follower().term(3).leader(Some("b")).voted_for(None).committed(2);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
-                event: Event::ConfirmLeader { commit_index: 1, has_committed: true },
+                event: Event::ConfirmLeader { commit_index: 1, has_committed: true, round: 0 },
             }],
         );
         assert_messages(&mut state_rx, vec![]);
@@ -372,25 +587,33 @@ pub mod tests {
     fn step_heartbeat_future_term() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("c".into()),
             to: Address::Peer("a".into()),
             term: 4,
-            event: Event::Heartbeat { commit_index: 3, commit_term: 2 },
+            event: Event::Heartbeat { commit_index: 3, commit_term: 2, round: 0 },
         })?;
         assert_node(&node).is_follower().term(4).leader(Some("c")).voted_for(None);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("c".into()),
                 term: 4,
-                event: Event::ConfirmLeader { commit_index: 3, has_committed: true },
+                event: Event::ConfirmLeader { commit_index: 3, has_committed: true, round: 0 },
             }],
         );
         assert_messages(
             &mut state_rx,
             vec![Instruction::Apply {
-                entry: Entry { index: 3, term: 2, command: Some(vec![0x03]) },
+                entry: Entry {
+                    index: 3,
+                    term: 2,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             }],
         );
         Ok(())
@@ -401,10 +624,11 @@ pub mod tests {
     fn step_heartbeat_past_term() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 2,
-            event: Event::Heartbeat { commit_index: 3, commit_term: 2 },
+            event: Event::Heartbeat { commit_index: 3, commit_term: 2, round: 0 },
         })?;
         assert_node(&node).is_follower().term(3).leader(Some("b")).voted_for(None).committed(2);
         assert_messages(&mut node_rx, vec![]);
@@ -419,6 +643,7 @@ pub mod tests {
 
         // The first vote request in this term yields a vote response.
         let mut node = follower.step(Message {
+            group: 0,
             from: Address::Peer("c".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -428,6 +653,7 @@ pub mod tests {
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("c".into()),
                 term: 3,
@@ -438,6 +664,7 @@ pub mod tests {
 
         // Another vote request from the same sender is granted.
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("c".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -447,6 +674,7 @@ pub mod tests {
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("c".into()),
                 term: 3,
@@ -457,6 +685,7 @@ pub mod tests {
 
         // But a vote request from a different node is ignored.
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("d".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -473,6 +702,7 @@ pub mod tests {
     fn step_grantvote_noop() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -489,6 +719,7 @@ pub mod tests {
     fn step_solicitvote_last_index_outdated() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("c".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -505,6 +736,7 @@ pub mod tests {
     fn step_solicitvote_last_term_outdated() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("c".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -516,11 +748,110 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    // PreVote is rejected while the leader is alive, and granted once it's been silent for the
+    // minimum election timeout, without changing the term or vote.
+    fn step_prevote() -> Result<()> {
+        let (follower, mut node_rx, mut state_rx) = setup()?;
+        let prevote = Message {
+            group: 0,
+            from: Address::Peer("c".into()),
+            to: Address::Peer("a".into()),
+            term: 4,
+            event: Event::PreVote { last_index: 3, last_term: 2 },
+        };
+        let mut node = follower.step(prevote.clone())?;
+        assert_node(&node).is_follower().term(3).leader(Some("b")).voted_for(None);
+        assert_messages(&mut node_rx, vec![]);
+
+        for _ in 0..ELECTION_TIMEOUT_MIN {
+            node = match node {
+                Node::Follower(mut f) => {
+                    f.role.leader_seen_ticks += 1;
+                    f.into()
+                }
+                _ => panic!("Unexpected node type"),
+            };
+        }
+        node = node.step(prevote)?;
+        assert_node(&node).is_follower().term(3).leader(Some("b")).voted_for(None);
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("c".into()),
+                term: 4,
+                event: Event::PreVoteGranted,
+            }],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // After a restart, PreVote is rejected for the minimum election timeout even though we don't
+    // know of a leader, since a leader may still hold a lease confirmed by us before the restart.
+    fn step_prevote_restart() -> Result<()> {
+        let (mut follower, mut node_rx, mut state_rx) = setup()?;
+        follower.role = Follower::new(None, None, ELECTION_TIMEOUT_MAX);
+        let prevote = Message {
+            group: 0,
+            from: Address::Peer("c".into()),
+            to: Address::Peer("a".into()),
+            term: 4,
+            event: Event::PreVote { last_index: 3, last_term: 2 },
+        };
+        let mut node = follower.step(prevote.clone())?;
+        assert_messages(&mut node_rx, vec![]);
+
+        for _ in 0..ELECTION_TIMEOUT_MIN {
+            node = node.tick()?;
+        }
+        node = node.step(prevote)?;
+        assert_node(&node).is_follower().term(3).leader(None).voted_for(None);
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("c".into()),
+                term: 4,
+                event: Event::PreVoteGranted,
+            }],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // PreVote is rejected if the log is outdated or the proposed term isn't ahead of ours.
+    fn step_prevote_outdated() -> Result<()> {
+        let (mut follower, mut node_rx, mut state_rx) = setup()?;
+        follower.role = Follower::new(None, None, ELECTION_TIMEOUT_MIN);
+        follower.role.leader_seen_ticks = ELECTION_TIMEOUT_MAX;
+        let mut node: Node = follower.into();
+        for (term, last_index, last_term) in [(4, 2, 2), (4, 3, 1), (3, 3, 2)] {
+            node = node.step(Message {
+                group: 0,
+                from: Address::Peer("c".into()),
+                to: Address::Peer("a".into()),
+                term,
+                event: Event::PreVote { last_index, last_term },
+            })?;
+            assert_node(&node).is_follower().term(3).leader(None).voted_for(None);
+        }
+        assert_messages(&mut node_rx, vec![]);
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
     #[test]
     // ReplicateEntries accepts some entries at base 0 without changes
     fn step_replicateentries_base0() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -528,19 +859,32 @@ pub mod tests {
                 base_index: 0,
                 base_term: 0,
                 entries: vec![
-                    Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-                    Entry { index: 2, term: 1, command: Some(vec![0x02]) },
+                    Entry {
+                        index: 1,
+                        term: 1,
+                        command: Some(vec![0x01]),
+                        membership: None,
+                        session: None,
+                    },
+                    Entry {
+                        index: 2,
+                        term: 1,
+                        command: Some(vec![0x02]),
+                        membership: None,
+                        session: None,
+                    },
                 ],
             },
         })?;
         assert_node(&node).is_follower().term(3).entries(vec![
-            Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-            Entry { index: 2, term: 1, command: Some(vec![0x02]) },
-            Entry { index: 3, term: 2, command: Some(vec![0x03]) },
+            Entry { index: 1, term: 1, command: Some(vec![0x01]), membership: None, session: None },
+            Entry { index: 2, term: 1, command: Some(vec![0x02]), membership: None, session: None },
+            Entry { index: 3, term: 2, command: Some(vec![0x03]), membership: None, session: None },
         ]);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
@@ -556,6 +900,7 @@ pub mod tests {
     fn step_replicateentries_append() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -563,21 +908,34 @@ pub mod tests {
                 base_index: 3,
                 base_term: 2,
                 entries: vec![
-                    Entry { index: 4, term: 3, command: Some(vec![0x04]) },
-                    Entry { index: 5, term: 3, command: Some(vec![0x05]) },
+                    Entry {
+                        index: 4,
+                        term: 3,
+                        command: Some(vec![0x04]),
+                        membership: None,
+                        session: None,
+                    },
+                    Entry {
+                        index: 5,
+                        term: 3,
+                        command: Some(vec![0x05]),
+                        membership: None,
+                        session: None,
+                    },
                 ],
             },
         })?;
         assert_node(&node).is_follower().term(3).entries(vec![
-            Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-            Entry { index: 2, term: 1, command: Some(vec![0x02]) },
-            Entry { index: 3, term: 2, command: Some(vec![0x03]) },
-            Entry { index: 4, term: 3, command: Some(vec![0x04]) },
-            Entry { index: 5, term: 3, command: Some(vec![0x05]) },
+            Entry { index: 1, term: 1, command: Some(vec![0x01]), membership: None, session: None },
+            Entry { index: 2, term: 1, command: Some(vec![0x02]), membership: None, session: None },
+            Entry { index: 3, term: 2, command: Some(vec![0x03]), membership: None, session: None },
+            Entry { index: 4, term: 3, command: Some(vec![0x04]), membership: None, session: None },
+            Entry { index: 5, term: 3, command: Some(vec![0x05]), membership: None, session: None },
         ]);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
@@ -588,11 +946,64 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    // ReplicateEntries with a membership change updates the peers immediately
+    fn step_replicateentries_membership() -> Result<()> {
+        let (follower, mut node_rx, mut state_rx) = setup()?;
+        let voters = vec!["a", "b", "c", "d", "e"].into_iter().map(String::from);
+        let membership = Membership::new(voters).transition(
+            vec!["a", "b", "c", "f"].into_iter().map(String::from).collect(),
+            Default::default(),
+        );
+        let node = follower.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::ReplicateEntries {
+                base_index: 3,
+                base_term: 2,
+                entries: vec![Entry {
+                    index: 4,
+                    term: 3,
+                    command: None,
+                    membership: Some(membership.clone()),
+                    session: None,
+                }],
+            },
+        })?;
+        assert_node(&node).is_follower().term(3).last(4);
+        match node {
+            Node::Follower(n) => {
+                assert_eq!(n.peers, vec!["b", "c", "d", "e", "f"]);
+                assert_eq!(n.membership(), membership);
+                // A quorum needs a majority of both a-e and a,b,c,f.
+                let votes = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+                assert!(n.has_quorum(&votes(&["a", "b", "c"])));
+                assert!(!n.has_quorum(&votes(&["a", "d", "e", "f"])));
+            }
+            _ => unreachable!(),
+        }
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("b".into()),
+                term: 3,
+                event: Event::AcceptEntries { last_index: 4 },
+            }],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
     #[test]
     // ReplicateEntries accepts partially overlapping entries
     fn step_replicateentries_partial_overlap() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -600,21 +1011,40 @@ pub mod tests {
                 base_index: 1,
                 base_term: 1,
                 entries: vec![
-                    Entry { index: 2, term: 1, command: Some(vec![0x02]) },
-                    Entry { index: 3, term: 2, command: Some(vec![0x03]) },
-                    Entry { index: 4, term: 3, command: Some(vec![0x04]) },
+                    Entry {
+                        index: 2,
+                        term: 1,
+                        command: Some(vec![0x02]),
+                        membership: None,
+                        session: None,
+                    },
+                    Entry {
+                        index: 3,
+                        term: 2,
+                        command: Some(vec![0x03]),
+                        membership: None,
+                        session: None,
+                    },
+                    Entry {
+                        index: 4,
+                        term: 3,
+                        command: Some(vec![0x04]),
+                        membership: None,
+                        session: None,
+                    },
                 ],
             },
         })?;
         assert_node(&node).is_follower().term(3).entries(vec![
-            Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-            Entry { index: 2, term: 1, command: Some(vec![0x02]) },
-            Entry { index: 3, term: 2, command: Some(vec![0x03]) },
-            Entry { index: 4, term: 3, command: Some(vec![0x04]) },
+            Entry { index: 1, term: 1, command: Some(vec![0x01]), membership: None, session: None },
+            Entry { index: 2, term: 1, command: Some(vec![0x02]), membership: None, session: None },
+            Entry { index: 3, term: 2, command: Some(vec![0x03]), membership: None, session: None },
+            Entry { index: 4, term: 3, command: Some(vec![0x04]), membership: None, session: None },
         ]);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
@@ -630,6 +1060,7 @@ pub mod tests {
     fn step_replicateentries_replace() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -637,20 +1068,33 @@ pub mod tests {
                 base_index: 2,
                 base_term: 1,
                 entries: vec![
-                    Entry { index: 3, term: 3, command: Some(vec![0x04]) },
-                    Entry { index: 4, term: 3, command: Some(vec![0x05]) },
+                    Entry {
+                        index: 3,
+                        term: 3,
+                        command: Some(vec![0x04]),
+                        membership: None,
+                        session: None,
+                    },
+                    Entry {
+                        index: 4,
+                        term: 3,
+                        command: Some(vec![0x05]),
+                        membership: None,
+                        session: None,
+                    },
                 ],
             },
         })?;
         assert_node(&node).is_follower().term(3).entries(vec![
-            Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-            Entry { index: 2, term: 1, command: Some(vec![0x02]) },
-            Entry { index: 3, term: 3, command: Some(vec![0x04]) },
-            Entry { index: 4, term: 3, command: Some(vec![0x05]) },
+            Entry { index: 1, term: 1, command: Some(vec![0x01]), membership: None, session: None },
+            Entry { index: 2, term: 1, command: Some(vec![0x02]), membership: None, session: None },
+            Entry { index: 3, term: 3, command: Some(vec![0x04]), membership: None, session: None },
+            Entry { index: 4, term: 3, command: Some(vec![0x05]), membership: None, session: None },
         ]);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
@@ -666,6 +1110,7 @@ pub mod tests {
     fn step_replicateentries_replace_partial() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -673,20 +1118,33 @@ pub mod tests {
                 base_index: 2,
                 base_term: 1,
                 entries: vec![
-                    Entry { index: 3, term: 2, command: Some(vec![0x03]) },
-                    Entry { index: 4, term: 3, command: Some(vec![0x04]) },
+                    Entry {
+                        index: 3,
+                        term: 2,
+                        command: Some(vec![0x03]),
+                        membership: None,
+                        session: None,
+                    },
+                    Entry {
+                        index: 4,
+                        term: 3,
+                        command: Some(vec![0x04]),
+                        membership: None,
+                        session: None,
+                    },
                 ],
             },
         })?;
         assert_node(&node).is_follower().term(3).entries(vec![
-            Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-            Entry { index: 2, term: 1, command: Some(vec![0x02]) },
-            Entry { index: 3, term: 2, command: Some(vec![0x03]) },
-            Entry { index: 4, term: 3, command: Some(vec![0x04]) },
+            Entry { index: 1, term: 1, command: Some(vec![0x01]), membership: None, session: None },
+            Entry { index: 2, term: 1, command: Some(vec![0x02]), membership: None, session: None },
+            Entry { index: 3, term: 2, command: Some(vec![0x03]), membership: None, session: None },
+            Entry { index: 4, term: 3, command: Some(vec![0x04]), membership: None, session: None },
         ]);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
@@ -702,27 +1160,39 @@ pub mod tests {
     fn step_replicateentries_reject_missing_base_index() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
             event: Event::ReplicateEntries {
                 base_index: 5,
                 base_term: 2,
-                entries: vec![Entry { index: 6, term: 3, command: Some(vec![0x04]) }],
+                entries: vec![Entry {
+                    index: 6,
+                    term: 3,
+                    command: Some(vec![0x04]),
+                    membership: None,
+                    session: None,
+                }],
             },
         })?;
         assert_node(&node).is_follower().term(3).entries(vec![
-            Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-            Entry { index: 2, term: 1, command: Some(vec![0x02]) },
-            Entry { index: 3, term: 2, command: Some(vec![0x03]) },
+            Entry { index: 1, term: 1, command: Some(vec![0x01]), membership: None, session: None },
+            Entry { index: 2, term: 1, command: Some(vec![0x02]), membership: None, session: None },
+            Entry { index: 3, term: 2, command: Some(vec![0x03]), membership: None, session: None },
         ]);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
-                event: Event::RejectEntries,
+                event: Event::RejectEntries {
+                    base_index: 5,
+                    conflict_term: None,
+                    conflict_index: 4,
+                },
             }],
         );
         assert_messages(&mut state_rx, vec![]);
@@ -734,27 +1204,70 @@ pub mod tests {
     fn step_replicateentries_reject_missing_base_term() -> Result<()> {
         let (follower, mut node_rx, mut state_rx) = setup()?;
         let node = follower.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
             event: Event::ReplicateEntries {
                 base_index: 1,
                 base_term: 2,
-                entries: vec![Entry { index: 2, term: 3, command: Some(vec![0x04]) }],
+                entries: vec![Entry {
+                    index: 2,
+                    term: 3,
+                    command: Some(vec![0x04]),
+                    membership: None,
+                    session: None,
+                }],
             },
         })?;
         assert_node(&node).is_follower().term(3).entries(vec![
-            Entry { index: 1, term: 1, command: Some(vec![0x01]) },
-            Entry { index: 2, term: 1, command: Some(vec![0x02]) },
-            Entry { index: 3, term: 2, command: Some(vec![0x03]) },
+            Entry { index: 1, term: 1, command: Some(vec![0x01]), membership: None, session: None },
+            Entry { index: 2, term: 1, command: Some(vec![0x02]), membership: None, session: None },
+            Entry { index: 3, term: 2, command: Some(vec![0x03]), membership: None, session: None },
         ]);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("b".into()),
+                term: 3,
+                event: Event::RejectEntries {
+                    base_index: 1,
+                    conflict_term: Some(1),
+                    conflict_index: 1,
+                },
+            }],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // A rejection hints at the first index of our conflicting term.
+    fn step_replicateentries_reject_conflict_term() -> Result<()> {
+        let (mut follower, mut node_rx, mut state_rx) = setup()?;
+        follower.log.append(2, Some(vec![0x04]))?;
+        follower.log.append(2, Some(vec![0x05]))?;
+        follower.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::ReplicateEntries { base_index: 5, base_term: 3, entries: vec![] },
+        })?;
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
-                event: Event::RejectEntries,
+                event: Event::RejectEntries {
+                    base_index: 5,
+                    conflict_term: Some(2),
+                    conflict_index: 3,
+                },
             }],
         );
         assert_messages(&mut state_rx, vec![]);
@@ -768,6 +1281,7 @@ pub mod tests {
         let mut node = Node::Follower(follower);
 
         node = node.step(Message {
+            group: 0,
             from: Address::Client,
             to: Address::Local,
             term: 0,
@@ -782,6 +1296,7 @@ pub mod tests {
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
@@ -794,6 +1309,7 @@ pub mod tests {
         assert_messages(&mut state_rx, vec![]);
 
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -806,6 +1322,7 @@ pub mod tests {
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Client,
                 term: 3,
@@ -819,14 +1336,167 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    // Queries are executed locally once the leader has confirmed its read index, and errors and
+    // unexpected responses are passed on to the client.
+    fn step_clientrequest_query() -> Result<()> {
+        let (follower, mut node_rx, mut state_rx) = setup()?;
+        let mut node = Node::Follower(follower);
+
+        for id in [0x01, 0x02, 0x03] {
+            node = node.step(Message {
+                group: 0,
+                from: Address::Client,
+                to: Address::Local,
+                term: 0,
+                event: Event::ClientRequest { id: vec![id], request: Request::Query(vec![0xaf]) },
+            })?;
+            assert_messages(
+                &mut node_rx,
+                vec![Message {
+                    group: 0,
+                    from: Address::Local,
+                    to: Address::Peer("b".into()),
+                    term: 3,
+                    event: Event::ClientRequest { id: vec![id], request: Request::ReadIndex },
+                }],
+            );
+        }
+        assert_node(&node).is_follower().term(3).leader(Some("b")).proxied(vec![]).queued(vec![]);
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::ClientResponse { id: vec![0x01], response: Ok(Response::ReadIndex(3)) },
+        })?;
+        assert_messages(&mut node_rx, vec![]);
+        assert_messages(
+            &mut state_rx,
+            vec![Instruction::Query {
+                id: vec![0x01],
+                address: Address::Client,
+                command: vec![0xaf],
+                term: 3,
+                index: 3,
+                quorum: None,
+            }],
+        );
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::ClientResponse { id: vec![0x02], response: Err(Error::Abort) },
+        })?;
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Client,
+                term: 3,
+                event: Event::ClientResponse { id: vec![0x02], response: Err(Error::Abort) },
+            }],
+        );
+
+        let response = Response::State(vec![0xaf]);
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::ClientResponse { id: vec![0x03], response: Ok(response.clone()) },
+        })?;
+        let error = format!("Unexpected read index response {:?}", response);
+        assert_node(&node).is_follower().term(3).leader(Some("b"));
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Client,
+                term: 3,
+                event: Event::ClientResponse {
+                    id: vec![0x03],
+                    response: Err(Error::Value(error)),
+                },
+            }],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // Stale queries are executed locally within the staleness bound, and rejected otherwise.
+    fn step_clientrequest_stalequery() -> Result<()> {
+        let (mut follower, mut node_rx, mut state_rx) = setup()?;
+        follower.role.leader_commit_index = 5;
+        follower.role.leader_seen_ticks = 3;
+        let mut node = Node::Follower(follower);
+        let query = |id: u8, staleness| Message {
+            group: 0,
+            from: Address::Client,
+            to: Address::Local,
+            term: 0,
+            event: Event::ClientRequest {
+                id: vec![id],
+                request: Request::StaleQuery { command: vec![0xaf], staleness },
+            },
+        };
+
+        node = node.step(query(0x01, Staleness::Index(2)))?;
+        node = node.step(query(0x02, Staleness::Ticks(3)))?;
+        assert_messages(&mut node_rx, vec![]);
+        assert_messages(
+            &mut state_rx,
+            vec![
+                Instruction::StaleQuery {
+                    id: vec![0x01],
+                    address: Address::Client,
+                    command: vec![0xaf],
+                    index: 3,
+                },
+                Instruction::StaleQuery {
+                    id: vec![0x02],
+                    address: Address::Client,
+                    command: vec![0xaf],
+                    index: 0,
+                },
+            ],
+        );
+
+        node.step(query(0x03, Staleness::Ticks(2)))?;
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Client,
+                term: 3,
+                event: Event::ClientResponse {
+                    id: vec![0x03],
+                    response: Err(Error::Value(
+                        "Leader last seen 3 ticks ago, exceeding max staleness of 2 ticks".into(),
+                    )),
+                },
+            }],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
     #[test]
     // ClientRequest is queued when there is no leader, and forwarded when a leader appears.
     fn step_clientrequest_queued() -> Result<()> {
         let (mut follower, mut node_rx, mut state_rx) = setup()?;
-        follower.role = Follower::new(None, None);
+        follower.role = Follower::new(None, None, ELECTION_TIMEOUT_MIN);
         let mut node = Node::Follower(follower);
 
         node = node.step(Message {
+            group: 0,
             from: Address::Client,
             to: Address::Local,
             term: 0,
@@ -841,10 +1511,11 @@ pub mod tests {
 
         // When a leader appears, we will proxy the queued request to them.
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("c".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::Heartbeat { commit_index: 3, commit_term: 2 },
+            event: Event::Heartbeat { commit_index: 3, commit_term: 2, round: 0 },
         })?;
         assert_node(&node)
             .is_follower()
@@ -856,6 +1527,7 @@ pub mod tests {
             &mut node_rx,
             vec![
                 Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer("c".into()),
                     term: 0,
@@ -865,17 +1537,24 @@ pub mod tests {
                     },
                 },
                 Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer("c".into()),
                     term: 3,
-                    event: Event::ConfirmLeader { commit_index: 3, has_committed: true },
+                    event: Event::ConfirmLeader { commit_index: 3, has_committed: true, round: 0 },
                 },
             ],
         );
         assert_messages(
             &mut state_rx,
             vec![Instruction::Apply {
-                entry: Entry { index: 3, term: 2, command: Some(vec![0x03]) },
+                entry: Entry {
+                    index: 3,
+                    term: 2,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             }],
         );
         Ok(())
@@ -888,6 +1567,7 @@ pub mod tests {
         let mut node = Node::Follower(follower);
 
         node = node.step(Message {
+            group: 0,
             from: Address::Client,
             to: Address::Local,
             term: 0,
@@ -902,6 +1582,7 @@ pub mod tests {
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
@@ -915,33 +1596,42 @@ pub mod tests {
 
         // When a new leader appears, the proxied request is aborted.
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("c".into()),
             to: Address::Peer("a".into()),
             term: 4,
-            event: Event::Heartbeat { commit_index: 3, commit_term: 2 },
+            event: Event::Heartbeat { commit_index: 3, commit_term: 2, round: 0 },
         })?;
         assert_node(&node).is_follower().term(4).leader(Some("c")).proxied(vec![]).queued(vec![]);
         assert_messages(
             &mut node_rx,
             vec![
                 Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Client,
                     term: 4,
                     event: Event::ClientResponse { id: vec![0x01], response: Err(Error::Abort) },
                 },
                 Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer("c".into()),
                     term: 4,
-                    event: Event::ConfirmLeader { commit_index: 3, has_committed: true },
+                    event: Event::ConfirmLeader { commit_index: 3, has_committed: true, round: 0 },
                 },
             ],
         );
         assert_messages(
             &mut state_rx,
             vec![Instruction::Apply {
-                entry: Entry { index: 3, term: 2, command: Some(vec![0x03]) },
+                entry: Entry {
+                    index: 3,
+                    term: 2,
+                    command: Some(vec![0x03]),
+                    membership: None,
+                    session: None,
+                },
             }],
         );
         Ok(())
@@ -959,18 +1649,20 @@ pub mod tests {
             assert_node(&node).is_follower().term(3).leader(Some("b"));
             node = node.tick()?;
             node = node.step(Message {
+                group: 0,
                 from: Address::Peer("b".into()),
                 to: Address::Peer("a".into()),
                 term: 3,
-                event: Event::Heartbeat { commit_index: 2, commit_term: 1 },
+                event: Event::Heartbeat { commit_index: 2, commit_term: 1, round: 0 },
             })?;
             assert_messages(
                 &mut node_rx,
                 vec![Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer("b".into()),
                     term: 3,
-                    event: Event::ConfirmLeader { commit_index: 2, has_committed: true },
+                    event: Event::ConfirmLeader { commit_index: 2, has_committed: true, round: 0 },
                 }],
             )
         }
@@ -979,18 +1671,38 @@ pub mod tests {
             assert_node(&node).is_follower().term(3).leader(Some("b"));
             node = node.tick()?;
         }
-        assert_node(&node).is_candidate().term(4);
+        // The election timeout starts a pre-vote for the next term, without changing our term.
+        assert_node(&node).is_candidate().term(3);
 
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peers,
                 term: 4,
-                event: Event::SolicitVote { last_index: 3, last_term: 2 },
+                event: Event::PreVote { last_index: 3, last_term: 2 },
             }],
         );
         assert_messages(&mut state_rx, vec![]);
         Ok(())
     }
+
+    #[test]
+    // Learners never campaign, even without hearing from a leader.
+    fn tick_learner() -> Result<()> {
+        let (mut follower, mut node_rx, mut state_rx) = setup()?;
+        let mut membership = Membership::new(["b", "c", "d", "e"].map(String::from));
+        membership.learners.insert("a".into());
+        follower.log.append_membership(3, membership)?;
+        let mut node = Node::Follower(follower);
+
+        for _ in 0..=ELECTION_TIMEOUT_MAX {
+            node = node.tick()?;
+        }
+        assert_node(&node).is_follower().term(3).leader(Some("b"));
+        assert_messages(&mut node_rx, vec![]);
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
 }
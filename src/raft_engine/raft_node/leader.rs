@@ -1,24 +1,54 @@
 use crate::{
     error::{Error, Result},
     raft_engine::{
-        machine_state::Instruction,
-        messaging::{Address, Event, Message, Request, Response},
-        raft_node::{Follower, Node, RoleNode, HEARTBEAT_INTERVAL, Status}
+        machine_state::{Instruction, SessionEntry},
+        messaging::{Address, Event, Message, Request, Response, Staleness},
+        raft_node::{
+            random_election_timeout, Follower, Membership, Node, RoleNode, Status,
+        }
     }
 };
 
 use ::log::{debug, info, warn};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // A leader serves requests and replicates the log to followers.
 #[derive(Debug)]
 pub struct Leader {
     /// Number of ticks since last heartbeat.
     pub heartbeat_ticks: u64,
+    /// Number of ticks since becoming leader, used as the heartbeat round.
+    pub ticks: u64,
+    /// The tick at which the lease confirmed by a peer expires, i.e. the round of the peer's
+    /// latest heartbeat confirmation plus the lease timeout.
+    pub peer_lease: HashMap<String, u64>,
+    /// The tick at which a message was last received from a peer in the current term.
+    pub peer_seen: HashMap<String, u64>,
     /// The next index to replicate to a peer.
     pub peer_next_index: HashMap<String, u64>,
     /// The last index known to be replicated on a peer.
     pub peer_last_index: HashMap<String, u64>,
+    /// The messages in flight to a peer, as the base and last index of the sent entries.
+    pub peer_inflight: HashMap<String, VecDeque<(u64, u64)>>,
+    /// The tick at which a peer's in-flight messages last made progress, i.e. when the oldest
+    /// message was sent or a response acknowledged some of them.
+    pub peer_inflight_tick: HashMap<String, u64>,
+    /// The client waiting for an ongoing membership change to complete, if any.
+    pub membership_request: Option<(Address, Vec<u8>)>,
+    /// An ongoing leadership transfer, if any.
+    pub transfer: Option<Transfer>,
+}
+
+/// An ongoing leadership transfer.
+#[derive(Debug)]
+pub struct Transfer {
+    /// The node to transfer leadership to.
+    pub to: String,
+    /// Number of ticks since the transfer started.
+    pub ticks: u64,
+    /// The client waiting for the transfer, as its address and request ID.
+    pub client: (Address, Vec<u8>),
 }
 
 impl Leader {
@@ -26,12 +56,21 @@ impl Leader {
     pub fn new(peers: Vec<String>, last_index: u64) -> Self {
         let mut leader = Self {
             heartbeat_ticks: 0,
+            ticks: 0,
+            peer_lease: HashMap::new(),
+            peer_seen: HashMap::new(),
             peer_next_index: HashMap::new(),
             peer_last_index: HashMap::new(),
+            peer_inflight: HashMap::new(),
+            peer_inflight_tick: HashMap::new(),
+            membership_request: None,
+            transfer: None,
         };
         for peer in peers {
             leader.peer_next_index.insert(peer.clone(), last_index + 1);
             leader.peer_last_index.insert(peer.clone(), 0);
+            leader.peer_seen.insert(peer.clone(), 0);
+            leader.peer_inflight.insert(peer.clone(), VecDeque::new());
         }
         leader
     }
@@ -44,25 +83,121 @@ impl RoleNode<Leader> {
         self.term = term;
         self.log.save_term(term, None)?;
         self.state_tx.send(Instruction::Abort)?;
-        self.become_role(Follower::new(Some(leader), None))
+        self.abort_membership_request()?;
+        if let Some(Transfer { to, client: (address, id), .. }) = self.role.transfer.take() {
+            let response =
+                if to == leader { Ok(Response::TransferLeadership) } else { Err(Error::Abort) };
+            self.send(address, Event::ClientResponse { id, response })?;
+        }
+        let election_timeout = random_election_timeout(&self.config);
+        self.become_role(Follower::new(Some(leader), None, election_timeout))
+    }
+
+    /// Steps down after being removed from the cluster, becoming a follower without a leader.
+    fn become_removed(self) -> Result<RoleNode<Follower>> {
+        info!("Removed from cluster in term {}, stepping down", self.term);
+        self.step_down()
+    }
+
+    /// Steps down after losing contact with a quorum, becoming a follower without a leader.
+    fn become_isolated(self) -> Result<RoleNode<Follower>> {
+        warn!("Lost contact with quorum in term {}, stepping down", self.term);
+        self.step_down()
+    }
+
+    /// Steps down to a follower without a leader in the current term, aborting pending requests.
+    /// We've already voted for ourself in this term.
+    fn step_down(mut self) -> Result<RoleNode<Follower>> {
+        self.state_tx.send(Instruction::Abort)?;
+        self.abort_membership_request()?;
+        self.abort_transfer()?;
+        let election_timeout = random_election_timeout(&self.config);
+        let id = self.id.clone();
+        // We implicitly voted for ourself in this term, so record it to avoid voting again.
+        self.log.save_term(self.term, Some(&id))?;
+        self.become_role(Follower::new(None, Some(&id), election_timeout))
+    }
+
+    /// Checks if we've heard from a quorum within the maximum election timeout. If not, we may
+    /// be in a minority partition where a new leader has been elected, and should step down
+    /// rather than leave clients hanging (known as CheckQuorum).
+    fn has_quorum_contact(&self) -> bool {
+        let mut seen = self.role.peer_seen.clone();
+        seen.insert(self.id.clone(), self.role.ticks);
+        let quorum_seen = self.membership().quorum_index(&seen);
+        self.role.ticks - quorum_seen <= self.config.election_timeout_max
     }
 
     /// Appends an entry to the log and replicates it to peers.
     pub fn append(&mut self, command: Option<Vec<u8>>) -> Result<u64> {
         let entry = self.log.append(self.term, command)?;
-        for peer in self.peers.iter() {
-            self.replicate(peer)?;
+        for peer in self.peers.clone() {
+            self.replicate(&peer)?;
+        }
+        Ok(entry.index)
+    }
+
+    /// Appends a client session operation to the log and replicates it to peers.
+    fn append_session(&mut self, command: Option<Vec<u8>>, session: SessionEntry) -> Result<u64> {
+        let entry = self.log.append_session(self.term, command, session)?;
+        for peer in self.peers.clone() {
+            self.replicate(&peer)?;
         }
         Ok(entry.index)
     }
 
+    /// Sends a heartbeat to all peers, for the current round.
+    pub fn heartbeat(&mut self) -> Result<()> {
+        self.send(
+            Address::Peers,
+            Event::Heartbeat {
+                commit_index: self.log.commit_index,
+                commit_term: self.log.commit_term,
+                round: self.role.ticks,
+            },
+        )
+    }
+
+    /// Confirms leadership for pending reads at the commit index, by voting for them locally and
+    /// sending a heartbeat to collect votes from peers.
+    fn confirm_leadership(&mut self) -> Result<()> {
+        if self.is_voter(&self.id) {
+            self.state_tx.send(Instruction::Vote {
+                term: self.term,
+                index: self.log.commit_index,
+                address: Address::Peer(self.id.clone()),
+            })?;
+        }
+        if !self.peers.is_empty() {
+            self.heartbeat()?;
+        }
+        Ok(())
+    }
+
+    /// Checks if the leader holds a valid lease, allowing it to serve queries without a quorum
+    /// round-trip. The lease is valid until the lease timeout after the latest heartbeat round
+    /// confirmed by a quorum, since followers won't grant pre-votes to other candidates until
+    /// they haven't heard from the leader for an election timeout. The lease timeout is shorter
+    /// than the election timeout to allow for clock drift. Leases are only used once an entry
+    /// from the current term has been committed, and not during leadership transfers, since the
+    /// transfer target campaigns without a pre-vote.
+    fn has_lease(&self) -> bool {
+        if !self.config.lease_reads
+            || self.role.transfer.is_some()
+            || self.log.commit_term != self.term
+        {
+            return false;
+        }
+        let mut leases = self.role.peer_lease.clone();
+        leases.insert(self.id.clone(), self.role.ticks + self.config.lease_timeout());
+        self.membership().quorum_index(&leases) > self.role.ticks
+    }
+
     /// Commits any pending log entries.
     fn commit(&mut self) -> Result<u64> {
-        let mut last_indexes = vec![self.log.last_index];
-        last_indexes.extend(self.role.peer_last_index.values());
-        last_indexes.sort_unstable();
-        last_indexes.reverse();
-        let quorum_index = last_indexes[self.quorum() as usize - 1];
+        let mut last_indexes = self.role.peer_last_index.clone();
+        last_indexes.insert(self.id.clone(), self.log.last_index);
+        let quorum_index = self.membership().quorum_index(&last_indexes);
 
         // We can only safely commit up to an entry from our own term, see figure 8 in Raft paper.
         if quorum_index > self.log.commit_index {
@@ -77,53 +212,258 @@ impl RoleNode<Leader> {
                 }
             }
         }
+        self.commit_membership()?;
         Ok(self.log.commit_index)
     }
 
-    /// Replicates the log to a peer.
-    fn replicate(&self, peer: &str) -> Result<()> {
-        let peer_next = self
-            .role
-            .peer_next_index
-            .get(peer)
-            .cloned()
-            .ok_or_else(|| Error::Internal(format!("Unknown peer {}", peer)))?;
-        let base_index = if peer_next > 0 { peer_next - 1 } else { 0 };
-        let base_term = match self.log.get(base_index)? {
-            Some(base) => base.term,
-            None if base_index == 0 => 0,
-            None => return Err(Error::Internal(format!("Missing base entry {}", base_index))),
-        };
-        let entries = self.log.scan(peer_next..).collect::<Result<Vec<_>>>()?;
-        debug!("Replicating {} entries at base {} to {}", entries.len(), base_index, peer);
-        self.send(
-            Address::Peer(peer.to_string()),
-            Event::ReplicateEntries { base_index, base_term, entries },
-        )?;
+    /// Begins a membership change to the given voters and learners, by appending a joint
+    /// membership to the log. The final membership is appended once the joint membership is
+    /// committed.
+    fn change_membership(
+        &mut self,
+        voters: BTreeSet<String>,
+        learners: BTreeSet<String>,
+        added: Option<(String, String)>,
+    ) -> Result<()> {
+        let current = self.membership();
+        if current.is_joint() || self.log.membership_index > self.log.commit_index {
+            return Err(Error::Value("A membership change is already in progress".into()));
+        }
+        // A leader must commit an entry in its own term before changing the membership, otherwise
+        // an uncommitted membership change from a previous term could be overridden.
+        if self.log.commit_term != self.term {
+            return Err(Error::Abort);
+        }
+        let mut membership = current.transition(voters, learners);
+        if let Some((id, address)) = added {
+            membership.addresses.insert(id, address);
+        }
+        info!("Changing membership to {:?}", membership);
+        self.log.append_membership(self.term, membership)?;
+        self.sync_peers();
+        for peer in self.peers.clone() {
+            self.replicate(&peer)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a committed membership. A committed joint membership is followed by the final
+    /// membership, and once that is committed removed nodes are no longer replicated to.
+    fn commit_membership(&mut self) -> Result<()> {
+        if self.log.membership_index > self.log.commit_index {
+            return Ok(());
+        }
+        match self.log.membership.clone() {
+            Some(membership) if membership.is_joint() => {
+                let index = self.log.append_membership(self.term, membership.finalize())?.index;
+                if let Some((address, id)) = self.role.membership_request.take() {
+                    self.state_tx.send(Instruction::Notify { id, address, index })?;
+                }
+                for peer in self.peers.clone() {
+                    self.replicate(&peer)?;
+                }
+            }
+            Some(membership) if self.peers.iter().any(|p| !membership.nodes().contains(p)) => {
+                self.sync_peers()
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Aborts the client waiting for a membership change, if any.
+    fn abort_membership_request(&mut self) -> Result<()> {
+        if let Some((address, id)) = self.role.membership_request.take() {
+            self.send(address, Event::ClientResponse { id, response: Err(Error::Abort) })?;
+        }
+        Ok(())
+    }
+
+    /// Starts transferring leadership to the given node. Once the node has caught up with the log,
+    /// it is told to start an election via TimeoutNow.
+    fn transfer_leadership(&mut self, to: &str) -> Result<()> {
+        if self.role.transfer.is_some() {
+            return Err(Error::Value("A leadership transfer is already in progress".into()));
+        }
+        if to == self.id || !self.peers.iter().any(|p| p == to) || !self.is_voter(to) {
+            return Err(Error::Value(format!("Cannot transfer leadership to {}", to)));
+        }
+        info!("Transferring leadership to {}", to);
+        if self.role.peer_last_index.get(to) == Some(&self.log.last_index) {
+            self.send(Address::Peer(to.to_string()), Event::TimeoutNow)?;
+        } else {
+            self.replicate(to)?;
+        }
         Ok(())
     }
 
+    /// Aborts an ongoing leadership transfer, if any.
+    fn abort_transfer(&mut self) -> Result<()> {
+        if let Some(Transfer { client: (address, id), .. }) = self.role.transfer.take() {
+            self.send(address, Event::ClientResponse { id, response: Err(Error::Abort) })?;
+        }
+        Ok(())
+    }
+
+    /// Updates the peers from the log membership, tracking replication progress for new peers.
+    fn sync_peers(&mut self) {
+        self.update_peers();
+        let peers = std::mem::take(&mut self.peers);
+        self.role.peer_next_index.retain(|peer, _| peers.contains(peer));
+        self.role.peer_last_index.retain(|peer, _| peers.contains(peer));
+        self.role.peer_lease.retain(|peer, _| peers.contains(peer));
+        self.role.peer_seen.retain(|peer, _| peers.contains(peer));
+        self.role.peer_inflight.retain(|peer, _| peers.contains(peer));
+        self.role.peer_inflight_tick.retain(|peer, _| peers.contains(peer));
+        for peer in &peers {
+            if !self.role.peer_next_index.contains_key(peer) {
+                self.role.peer_next_index.insert(peer.clone(), 1);
+                self.role.peer_last_index.insert(peer.clone(), 0);
+                self.role.peer_seen.insert(peer.clone(), self.role.ticks);
+                self.role.peer_inflight.insert(peer.clone(), VecDeque::new());
+            }
+        }
+        self.peers = peers;
+    }
+
+    /// Replicates the log to a peer, in batches of at most max_append_entries entries and
+    /// max_append_bytes bytes. Up to max_inflight batches may await a response, and the peer's
+    /// next index is optimistically moved past each sent batch. If the peer's next entry has been
+    /// compacted from the log, the snapshot is sent instead. If there is nothing in flight, at
+    /// least one (possibly empty) batch is sent, to probe the peer's log.
+    fn replicate(&mut self, peer: &str) -> Result<()> {
+        let mut sent = false;
+        loop {
+            let inflight = self
+                .role
+                .peer_inflight
+                .get(peer)
+                .map(|inflight| inflight.len())
+                .ok_or_else(|| Error::Internal(format!("Unknown peer {}", peer)))?;
+            let peer_next = self.role.peer_next_index.get(peer).copied().unwrap_or(1);
+            if inflight >= self.config.max_inflight
+                || (peer_next > self.log.last_index && (sent || inflight > 0))
+            {
+                return Ok(());
+            }
+            sent = true;
+
+            if peer_next <= self.log.snapshot_index {
+                let snapshot = self.log.load_snapshot()?.ok_or_else(|| {
+                    Error::Internal(format!(
+                        "Snapshot for index {} not found",
+                        self.log.snapshot_index
+                    ))
+                })?;
+                debug!("Sending snapshot at index {} to {}", snapshot.index, peer);
+                self.track_inflight(peer, snapshot.index, snapshot.index);
+                self.send(Address::Peer(peer.to_string()), Event::InstallSnapshot { snapshot })?;
+                continue;
+            }
+
+            let base_index = if peer_next > 0 { peer_next - 1 } else { 0 };
+            let base_term = self
+                .log
+                .term(base_index)?
+                .ok_or_else(|| Error::Internal(format!("Missing base entry {}", base_index)))?;
+            let mut entries = Vec::new();
+            let mut size = 0;
+            let mut scan = self.log.scan(peer_next..);
+            while let Some(entry) = scan.next().transpose()? {
+                size += bincode::serialized_size(&entry)?;
+                if !entries.is_empty() && size > self.config.max_append_bytes {
+                    break;
+                }
+                entries.push(entry);
+                if entries.len() >= self.config.max_append_entries {
+                    break;
+                }
+            }
+            std::mem::drop(scan);
+            let last_index = entries.last().map(|e| e.index).unwrap_or(base_index);
+            debug!("Replicating {} entries at base {} to {}", entries.len(), base_index, peer);
+            self.track_inflight(peer, base_index, last_index);
+            self.send(
+                Address::Peer(peer.to_string()),
+                Event::ReplicateEntries { base_index, base_term, entries },
+            )?;
+        }
+    }
+
+    /// Determines the next index to replicate to a peer after it rejected entries at the given
+    /// base index. If we have entries in the peer's conflicting term, the logs match up to our
+    /// last entry in that term, otherwise the peer's entries in that term can all be skipped.
+    /// The next index always moves backwards, but not past the start of the log.
+    fn next_index_after_conflict(
+        &self,
+        base_index: u64,
+        conflict_term: Option<u64>,
+        conflict_index: u64,
+    ) -> Result<u64> {
+        let mut next = conflict_index;
+        if let Some(term) = conflict_term {
+            let (from, to) = (self.log.snapshot_index, base_index.min(self.log.last_index));
+            if from <= to {
+                let last = self.log.search_term(term + 1, from, to)?.saturating_sub(1);
+                if last >= from && self.log.term(last)? == Some(term) {
+                    next = last + 1;
+                }
+            }
+        }
+        Ok(next.min(base_index).max(1))
+    }
+
+    /// Tracks a message in flight to a peer, moving the peer's next index past it.
+    fn track_inflight(&mut self, peer: &str, base_index: u64, last_index: u64) {
+        let inflight = self.role.peer_inflight.entry(peer.to_string()).or_default();
+        if inflight.is_empty() {
+            self.role.peer_inflight_tick.insert(peer.to_string(), self.role.ticks);
+        }
+        inflight.push_back((base_index, last_index));
+        self.role.peer_next_index.insert(peer.to_string(), last_index + 1);
+    }
+
     /// Processes a message.
     pub fn step(mut self, msg: Message) -> Result<Node> {
         if let Err(err) = self.validate(&msg) {
             warn!("Ignoring invalid message: {}", err);
             return Ok(self.into());
         }
-        if msg.term > self.term {
+        if msg.term > self.term && !msg.is_pre_vote() {
             if let Address::Peer(from) = &msg.from {
                 return self.become_follower(msg.term, from)?.step(msg);
             }
         }
+        if let Address::Peer(from) = &msg.from {
+            if msg.term == self.term {
+                if let Some(seen) = self.role.peer_seen.get_mut(from) {
+                    *seen = self.role.ticks;
+                }
+            }
+        }
 
         match msg.event {
-            Event::ConfirmLeader { commit_index, has_committed } => {
+            Event::ConfirmLeader { commit_index, has_committed, round } => {
                 if let Address::Peer(from) = msg.from.clone() {
-                    self.state_tx.send(Instruction::Vote {
-                        term: msg.term,
-                        index: commit_index,
-                        address: msg.from,
-                    })?;
+                    let lease = self.role.peer_lease.entry(from.clone()).or_default();
+                    *lease = (*lease).max(round + self.config.lease_timeout());
+                    if self.is_voter(&from) {
+                        self.state_tx.send(Instruction::Vote {
+                            term: msg.term,
+                            index: commit_index,
+                            address: msg.from,
+                        })?;
+                    }
+                    // The peer is missing committed entries. Any batches still in flight were
+                    // likely lost, so resend them from the oldest one to make sure replication
+                    // progresses. Duplicate batches are harmless.
                     if !has_committed {
+                        if let Some(inflight) = self.role.peer_inflight.get_mut(&from) {
+                            if let Some((base_index, _)) = inflight.front() {
+                                self.role.peer_next_index.insert(from.clone(), base_index + 1);
+                            }
+                            inflight.clear();
+                        }
                         self.replicate(&from)?;
                     }
                 }
@@ -131,23 +471,64 @@ impl RoleNode<Leader> {
 
             Event::AcceptEntries { last_index } => {
                 if let Address::Peer(from) = msg.from {
-                    self.role.peer_last_index.insert(from.clone(), last_index);
-                    self.role.peer_next_index.insert(from, last_index + 1);
+                    let peer_last = self.role.peer_last_index.entry(from.clone()).or_default();
+                    *peer_last = (*peer_last).max(last_index);
+                    let peer_next = self.role.peer_next_index.entry(from.clone()).or_default();
+                    *peer_next = (*peer_next).max(last_index + 1);
+                    if let Some(inflight) = self.role.peer_inflight.get_mut(&from) {
+                        let len = inflight.len();
+                        inflight.retain(|(_, last)| *last > last_index);
+                        if inflight.len() < len {
+                            self.role.peer_inflight_tick.insert(from.clone(), self.role.ticks);
+                        }
+                    }
+                    if let Some(transfer) = &self.role.transfer {
+                        if transfer.to == from && last_index == self.log.last_index {
+                            self.send(Address::Peer(from.clone()), Event::TimeoutNow)?;
+                        }
+                    }
+                    self.commit()?;
+                    // Keep the pipeline going if there are more entries to send.
+                    if self.role.peer_next_index.get(&from) <= Some(&self.log.last_index) {
+                        self.replicate(&from)?;
+                    }
                 }
-                self.commit()?;
             }
 
-            Event::RejectEntries => {
+            // A rejection of an in-flight batch discards all later batches, which will be
+            // rejected too, and retries from the conflict hint. Rejections of batches that are
+            // no longer in flight are stale, and ignored.
+            Event::RejectEntries { base_index, conflict_term, conflict_index } => {
                 if let Address::Peer(from) = msg.from {
-                    self.role.peer_next_index.entry(from.clone()).and_modify(|i| {
-                        if *i > 1 {
-                            *i -= 1
+                    let inflight = self.role.peer_inflight.get(&from);
+                    if inflight.is_some_and(|i| i.iter().any(|(base, _)| *base == base_index)) {
+                        let next = self.next_index_after_conflict(
+                            base_index,
+                            conflict_term,
+                            conflict_index,
+                        )?;
+                        if let Some(inflight) = self.role.peer_inflight.get_mut(&from) {
+                            inflight.clear();
                         }
-                    });
-                    self.replicate(&from)?;
+                        self.role.peer_next_index.insert(from.clone(), next);
+                        self.replicate(&from)?;
+                    }
                 }
             }
 
+            // With a valid lease, queries are executed at the commit index without confirming
+            // leadership with a quorum.
+            Event::ClientRequest { id, request: Request::Query(command) } if self.has_lease() => {
+                self.state_tx.send(Instruction::Query {
+                    id,
+                    address: msg.from,
+                    command,
+                    term: self.term,
+                    index: self.log.commit_index,
+                    quorum: None,
+                })?;
+            }
+
             Event::ClientRequest { id, request: Request::Query(command) } => {
                 self.state_tx.send(Instruction::Query {
                     id,
@@ -155,22 +536,56 @@ impl RoleNode<Leader> {
                     command,
                     term: self.term,
                     index: self.log.commit_index,
-                    quorum: self.quorum(),
+                    quorum: Some(self.membership()),
                 })?;
-                self.state_tx.send(Instruction::Vote {
+                self.confirm_leadership()?;
+            }
+
+            Event::ClientRequest { id, request: Request::ReadIndex } if self.has_lease() => {
+                let response = Ok(Response::ReadIndex(self.log.commit_index));
+                self.send(msg.from, Event::ClientResponse { id, response })?;
+            }
+
+            Event::ClientRequest { id, request: Request::ReadIndex } => {
+                self.state_tx.send(Instruction::ReadIndex {
+                    id,
+                    address: msg.from,
                     term: self.term,
                     index: self.log.commit_index,
-                    address: Address::Local,
+                    quorum: Some(self.membership()),
+                })?;
+                self.confirm_leadership()?;
+            }
+
+            // We're the leader, so our state is only stale by the entries we haven't applied yet.
+            Event::ClientRequest {
+                id,
+                request: Request::StaleQuery { command, staleness },
+            } => {
+                let index = match staleness {
+                    Staleness::Index(lag) => self.log.commit_index.saturating_sub(lag),
+                    Staleness::Ticks(_) => 0,
+                };
+                self.state_tx.send(Instruction::StaleQuery {
+                    id,
+                    address: msg.from,
+                    command,
+                    index,
                 })?;
-                if !self.peers.is_empty() {
-                    self.send(
-                        Address::Peers,
-                        Event::Heartbeat {
-                            commit_index: self.log.commit_index,
-                            commit_term: self.log.commit_term,
-                        },
-                    )?;
-                }
+            }
+
+            // Mutations are rejected during leadership transfers, so the target can catch up.
+            Event::ClientRequest {
+                id,
+                request:
+                    Request::Mutate(_)
+                    | Request::OpenSession
+                    | Request::SessionMutate { .. }
+                    | Request::AddNode { .. }
+                    | Request::AddLearner { .. }
+                    | Request::RemoveNode { .. },
+            } if self.role.transfer.is_some() => {
+                self.send(msg.from, Event::ClientResponse { id, response: Err(Error::Abort) })?;
             }
 
             Event::ClientRequest { id, request: Request::Mutate(command) } => {
@@ -181,6 +596,26 @@ impl RoleNode<Leader> {
                 }
             }
 
+            Event::ClientRequest { id, request: Request::OpenSession } => {
+                let index = self.append_session(None, SessionEntry::Open { time: now() })?;
+                self.state_tx.send(Instruction::Notify { id, address: msg.from, index })?;
+                if self.peers.is_empty() {
+                    self.commit()?;
+                }
+            }
+
+            Event::ClientRequest {
+                id,
+                request: Request::SessionMutate { session, sequence, command },
+            } => {
+                let entry = SessionEntry::Mutate { id: session, sequence, time: now() };
+                let index = self.append_session(Some(command), entry)?;
+                self.state_tx.send(Instruction::Notify { id, address: msg.from, index })?;
+                if self.peers.is_empty() {
+                    self.commit()?;
+                }
+            }
+
             Event::ClientRequest { id, request: Request::Status } => {
                 let mut status = Box::new(Status {
                     server: self.id.clone(),
@@ -196,50 +631,149 @@ impl RoleNode<Leader> {
                 self.state_tx.send(Instruction::Status { id, address: msg.from, status })?
             }
 
-            Event::ClientResponse { id, mut response } => {
-                if let Ok(Response::Status(ref mut status)) = response {
-                    status.server = self.id.clone();
+            // Adding a learner as a voter promotes it.
+            Event::ClientRequest { id, request: Request::AddNode { id: node, address } } => {
+                let Membership { mut voters, mut learners, .. } = self.membership();
+                let result = if !voters.insert(node.clone()) {
+                    Err(Error::Value(format!("Node {} is already a member", node)))
+                } else {
+                    learners.remove(&node);
+                    self.change_membership(voters, learners, Some((node, address)))
+                };
+                self.respond_membership(msg.from, id, result)?;
+            }
+
+            Event::ClientRequest { id, request: Request::AddLearner { id: node, address } } => {
+                let Membership { voters, mut learners, .. } = self.membership();
+                let result = if voters.contains(&node) || !learners.insert(node.clone()) {
+                    Err(Error::Value(format!("Node {} is already a member", node)))
+                } else {
+                    self.change_membership(voters, learners, Some((node, address)))
+                };
+                self.respond_membership(msg.from, id, result)?;
+            }
+
+            Event::ClientRequest { id, request: Request::RemoveNode { id: node } } => {
+                let Membership { mut voters, mut learners, .. } = self.membership();
+                let result = if !voters.remove(&node) && !learners.remove(&node) {
+                    Err(Error::Value(format!("Node {} is not a member", node)))
+                } else if voters.is_empty() {
+                    Err(Error::Value("Cannot remove the last member".into()))
+                } else {
+                    self.change_membership(voters, learners, None)
+                };
+                self.respond_membership(msg.from, id, result)?;
+            }
+
+            Event::ClientRequest { id, request: Request::TransferLeadership { to } } => {
+                match self.transfer_leadership(&to) {
+                    Ok(()) => {
+                        self.role.transfer = Some(Transfer { to, ticks: 0, client: (msg.from, id) })
+                    }
+                    Err(error @ Error::Internal(_)) => return Err(error),
+                    Err(error) => {
+                        self.send(msg.from, Event::ClientResponse { id, response: Err(error) })?
+                    }
                 }
-                self.send(Address::Client, Event::ClientResponse { id, response })?;
             }
 
+            Event::ClientResponse { id, response } => self.proxy_response(id, response)?,
+
+            Event::Snapshot { index, data } => self.log.compact(index, data)?,
+
             // We ignore these messages, since they are typically additional votes from the previous
             // election that we won after a quorum.
             Event::SolicitVote { .. } | Event::GrantVote => {}
 
-            Event::Heartbeat { .. } | Event::ReplicateEntries { .. } => {
-                warn!("Received unexpected message {:?}", msg)
-            }
+            // We're the leader, so we never grant pre-votes, and ignore stray grants.
+            Event::PreVote { .. } | Event::PreVoteGranted => {}
+
+            Event::Heartbeat { .. }
+            | Event::ReplicateEntries { .. }
+            | Event::InstallSnapshot { .. }
+            | Event::TimeoutNow
+            | Event::Heartbeats { .. } => warn!("Received unexpected message {:?}", msg),
         }
 
+        if !self.is_voter(&self.id) && self.log.membership_index <= self.log.commit_index {
+            return Ok(self.become_removed()?.into());
+        }
         Ok(self.into())
     }
 
+    /// Tracks the client waiting for a membership change, or responds with an error if the change
+    /// could not be started.
+    fn respond_membership(&mut self, to: Address, id: Vec<u8>, result: Result<()>) -> Result<()> {
+        match result {
+            Ok(()) => self.role.membership_request = Some((to, id)),
+            Err(error @ Error::Internal(_)) => return Err(error),
+            Err(error) => self.send(to, Event::ClientResponse { id, response: Err(error) })?,
+        }
+        Ok(())
+    }
+
+    /// Resends the messages in flight to peers that haven't responded to any of them within the
+    /// replicate timeout, since they were likely lost. Otherwise, the peer's replication would
+    /// stall once max_inflight messages are lost, as long as it doesn't notice that it's missing
+    /// committed entries. Replication resumes from the oldest message in flight.
+    fn resend_lost(&mut self) -> Result<()> {
+        for peer in self.peers.clone() {
+            let Some(inflight) = self.role.peer_inflight.get_mut(&peer) else { continue };
+            let Some((base_index, _)) = inflight.front().copied() else { continue };
+            let since = self.role.peer_inflight_tick.get(&peer).copied().unwrap_or(0);
+            if self.role.ticks - since < self.config.replicate_timeout {
+                continue;
+            }
+            debug!("Replication to {} timed out, resending from {}", peer, base_index + 1);
+            inflight.clear();
+            self.role.peer_next_index.insert(peer.clone(), base_index + 1);
+            self.replicate(&peer)?;
+        }
+        Ok(())
+    }
+
     /// Processes a logical clock tick.
     pub fn tick(mut self) -> Result<Node> {
+        // Give up on a leadership transfer if the target doesn't take over within an election
+        // timeout, e.g. because it is unreachable.
+        if let Some(transfer) = &mut self.role.transfer {
+            transfer.ticks += 1;
+            if transfer.ticks >= self.config.election_timeout_max {
+                warn!("Leadership transfer to {} timed out", transfer.to);
+                self.abort_transfer()?;
+            }
+        }
+        self.role.ticks += 1;
+        if !self.has_quorum_contact() {
+            return Ok(self.become_isolated()?.into());
+        }
+        self.resend_lost()?;
         if !self.peers.is_empty() {
             self.role.heartbeat_ticks += 1;
-            if self.role.heartbeat_ticks >= HEARTBEAT_INTERVAL {
+            if self.role.heartbeat_ticks >= self.config.heartbeat_interval {
                 self.role.heartbeat_ticks = 0;
-                self.send(
-                    Address::Peers,
-                    Event::Heartbeat {
-                        commit_index: self.log.commit_index,
-                        commit_term: self.log.commit_term,
-                    },
-                )?;
+                self.heartbeat()?;
             }
         }
         Ok(self.into())
     }
 }
 
+/// Returns the current wall clock time in milliseconds, used to expire client sessions.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::super::super::{Entry, Log};
+    use crate::raft_engine::raft_log::{Entry, RaftLog, Snapshot};
+    use crate::raft_engine::raft_node::{
+        RaftConfig, ELECTION_TIMEOUT_MAX, HEARTBEAT_INTERVAL, MAX_APPEND_BYTES, MAX_APPEND_ENTRIES,
+        MAX_INFLIGHT, REPLICATE_TIMEOUT,
+    };
     use super::super::tests::{assert_messages, assert_node};
     use super::*;
-    use crate::storage::log;
+    use crate::storage_engine::log_storage::LogTest;
     use futures::FutureExt;
     use pretty_assertions::assert_eq;
     use tokio::sync::mpsc;
@@ -253,7 +787,7 @@ mod tests {
         let (node_tx, node_rx) = mpsc::unbounded_channel();
         let (state_tx, state_rx) = mpsc::unbounded_channel();
         let peers = vec!["b".into(), "c".into(), "d".into(), "e".into()];
-        let mut log = Log::new(Box::new(log::Test::new()))?;
+        let mut log = RaftLog::new(Box::new(LogTest::new()))?;
         log.append(1, Some(vec![0x01]))?;
         log.append(1, Some(vec![0x02]))?;
         log.append(2, Some(vec![0x03]))?;
@@ -271,7 +805,9 @@ mod tests {
             node_tx,
             state_tx,
             proxied_reqs: HashMap::new(),
+            read_reqs: HashMap::new(),
             queued_reqs: Vec::new(),
+            config: RaftConfig::default(),
         };
         Ok((node, node_rx, state_rx))
     }
@@ -283,10 +819,11 @@ mod tests {
         let mut node: Node = leader.into();
 
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::ConfirmLeader { commit_index: 2, has_committed: true },
+            event: Event::ConfirmLeader { commit_index: 2, has_committed: true, round: 0 },
         })?;
         assert_node(&node).is_leader().term(3).committed(2);
         assert_messages(&mut node_rx, vec![]);
@@ -304,15 +841,17 @@ mod tests {
         let mut node: Node = leader.into();
 
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::ConfirmLeader { commit_index: 2, has_committed: false },
+            event: Event::ConfirmLeader { commit_index: 2, has_committed: false, round: 0 },
         })?;
         assert_node(&node).is_leader().term(3).committed(2);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 3,
@@ -333,10 +872,11 @@ mod tests {
         let mut node: Node = leader.into();
 
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
-            event: Event::Heartbeat { commit_index: 5, commit_term: 3 },
+            event: Event::Heartbeat { commit_index: 5, commit_term: 3, round: 0 },
         })?;
         assert_node(&node).is_leader().term(3).committed(2);
         assert_messages(&mut node_rx, vec![]);
@@ -351,19 +891,21 @@ mod tests {
         let mut node: Node = leader.into();
 
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 4,
-            event: Event::Heartbeat { commit_index: 7, commit_term: 4 },
+            event: Event::Heartbeat { commit_index: 7, commit_term: 4, round: 0 },
         })?;
         assert_node(&node).is_follower().term(4).leader(Some("b")).committed(2);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 4,
-                event: Event::ConfirmLeader { commit_index: 7, has_committed: false },
+                event: Event::ConfirmLeader { commit_index: 7, has_committed: false, round: 0 },
             }],
         );
         assert_messages(&mut state_rx, vec![Instruction::Abort]);
@@ -377,10 +919,11 @@ mod tests {
         let mut node: Node = leader.into();
 
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 2,
-            event: Event::Heartbeat { commit_index: 3, commit_term: 2 },
+            event: Event::Heartbeat { commit_index: 3, commit_term: 2, round: 0 },
         })?;
         assert_node(&node).is_leader().term(3).committed(2);
         assert_messages(&mut node_rx, vec![]);
@@ -394,6 +937,7 @@ mod tests {
         let mut node: Node = leader.into();
 
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("b".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -404,6 +948,7 @@ mod tests {
         assert_messages(&mut state_rx, vec![]);
 
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("c".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -415,15 +960,28 @@ mod tests {
             &mut state_rx,
             vec![
                 Instruction::Apply {
-                    entry: Entry { index: 3, term: 2, command: Some(vec![0x03]) },
+                    entry: Entry {
+                        index: 3,
+                        term: 2,
+                        command: Some(vec![0x03]),
+                        membership: None,
+                        session: None,
+                    },
                 },
                 Instruction::Apply {
-                    entry: Entry { index: 4, term: 3, command: Some(vec![0x04]) },
+                    entry: Entry {
+                        index: 4,
+                        term: 3,
+                        command: Some(vec![0x04]),
+                        membership: None,
+                        session: None,
+                    },
                 },
             ],
         );
 
         node = node.step(Message {
+            group: 0,
             from: Address::Peer("d".into()),
             to: Address::Peer("a".into()),
             term: 3,
@@ -434,7 +992,13 @@ mod tests {
         assert_messages(
             &mut state_rx,
             vec![Instruction::Apply {
-                entry: Entry { index: 5, term: 3, command: Some(vec![0x05]) },
+                entry: Entry {
+                    index: 5,
+                    term: 3,
+                    command: Some(vec![0x05]),
+                    membership: None,
+                    session: None,
+                },
             }],
         );
 
@@ -450,6 +1014,7 @@ mod tests {
 
         for _ in 0..5 {
             node = node.step(Message {
+                group: 0,
                 from: Address::Peer("b".into()),
                 to: Address::Peer("a".into()),
                 term: 3,
@@ -471,6 +1036,7 @@ mod tests {
 
         for peer in peers.into_iter() {
             node = node.step(Message {
+                group: 0,
                 from: Address::Peer(peer),
                 to: Address::Peer("a".into()),
                 term: 3,
@@ -492,6 +1058,7 @@ mod tests {
 
         for (i, peer) in peers.into_iter().enumerate() {
             node = node.step(Message {
+                group: 0,
                 from: Address::Peer(peer),
                 to: Address::Peer("a".into()),
                 term: 3,
@@ -507,13 +1074,31 @@ mod tests {
                     &mut state_rx,
                     vec![
                         Instruction::Apply {
-                            entry: Entry { index: 3, term: 2, command: Some(vec![0x03]) },
+                            entry: Entry {
+                                index: 3,
+                                term: 2,
+                                command: Some(vec![0x03]),
+                                membership: None,
+                                session: None,
+                            },
                         },
                         Instruction::Apply {
-                            entry: Entry { index: 4, term: 3, command: Some(vec![0x04]) },
+                            entry: Entry {
+                                index: 4,
+                                term: 3,
+                                command: Some(vec![0x04]),
+                                membership: None,
+                                session: None,
+                            },
                         },
                         Instruction::Apply {
-                            entry: Entry { index: 5, term: 3, command: Some(vec![0x05]) },
+                            entry: Entry {
+                                index: 5,
+                                term: 3,
+                                command: Some(vec![0x05]),
+                                membership: None,
+                                session: None,
+                            },
                         },
                     ],
                 );
@@ -524,64 +1109,372 @@ mod tests {
         Ok(())
     }
 
+    /// Triggers replication to b via a heartbeat confirmation without the commit index, which
+    /// probes b's log with an empty batch at the end of the leader's log.
+    fn probe_b(node: Node, state_rx: &mut mpsc::UnboundedReceiver<Instruction>) -> Result<Node> {
+        let node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::ConfirmLeader { commit_index: 2, has_committed: false, round: 0 },
+        })?;
+        assert_messages(
+            state_rx,
+            vec![Instruction::Vote { term: 3, index: 2, address: Address::Peer("b".into()) }],
+        );
+        Ok(node)
+    }
+
+    #[test]
+    // Entries are replicated in bounded batches, with a bounded number of batches in flight.
+    fn replicate_pipeline() -> Result<()> {
+        let (mut leader, mut node_rx, mut state_rx) = setup()?;
+        for _ in 0..(MAX_INFLIGHT * MAX_APPEND_ENTRIES) {
+            leader.log.append(3, Some(vec![0x01]))?;
+        }
+        // A large entry is sent on its own.
+        leader.log.append(3, Some(vec![0x02; MAX_APPEND_BYTES as usize]))?;
+        leader.log.append(3, Some(vec![0x03]))?;
+        let last_index = leader.log.last_index;
+        let mut node: Node = leader.into();
+        node = probe_b(node, &mut state_rx)?;
+
+        let batches = |node_rx: &mut mpsc::UnboundedReceiver<Message>| {
+            let mut batches = Vec::new();
+            while let Ok(msg) = node_rx.try_recv() {
+                match msg.event {
+                    Event::ReplicateEntries { base_index, entries, .. } => {
+                        batches.push((base_index, entries.len()))
+                    }
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+            batches
+        };
+        let size = MAX_APPEND_ENTRIES;
+        let full: Vec<_> = (0..MAX_INFLIGHT).map(|i| ((5 + i * size) as u64, size)).collect();
+        assert_eq!(batches(&mut node_rx), full);
+
+        // Accepting the first batch frees up the window for the next one.
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::AcceptEntries { last_index: 5 + size as u64 },
+        })?;
+        assert_eq!(batches(&mut node_rx), vec![(last_index - 2, 1)]);
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::AcceptEntries { last_index: 5 + 2 * size as u64 },
+        })?;
+        assert_eq!(batches(&mut node_rx), vec![(last_index - 1, 1)]);
+
+        // Nothing is sent once the peer's next index is past the end of the log.
+        node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::AcceptEntries { last_index: 5 + 3 * size as u64 },
+        })?;
+        assert_eq!(batches(&mut node_rx), vec![]);
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // A peer missing committed entries while batches are in flight gets them resent.
+    fn replicate_resend_lost() -> Result<()> {
+        let (mut leader, mut node_rx, mut state_rx) = setup()?;
+        leader.log.append(3, Some(vec![0x01]))?;
+        let entries = leader.log.scan(6..).collect::<Result<Vec<_>>>()?;
+        let mut node: Node = leader.into();
+        let replicate = Message {
+            group: 0,
+            from: Address::Local,
+            to: Address::Peer("b".into()),
+            term: 3,
+            event: Event::ReplicateEntries { base_index: 5, base_term: 3, entries },
+        };
+        node = probe_b(node, &mut state_rx)?;
+        assert_messages(&mut node_rx, vec![replicate.clone()]);
+
+        // The batch is lost, and b reports that it is missing committed entries.
+        probe_b(node, &mut state_rx)?;
+        assert_messages(&mut node_rx, vec![replicate]);
+        Ok(())
+    }
+
+    #[test]
+    // A full window of lost batches is resent after the replicate timeout, even though the peers
+    // report that they have all committed entries, such that commits make progress.
+    fn replicate_resend_timeout() -> Result<()> {
+        let (mut leader, mut node_rx, _state_rx) = setup()?;
+        for _ in 0..(MAX_INFLIGHT * MAX_APPEND_ENTRIES) {
+            leader.log.append(3, Some(vec![0x01]))?;
+        }
+        let last_index = leader.log.last_index;
+        let mut node: Node = leader.into();
+        let step = |node: Node, from: &str, event| {
+            node.step(Message {
+                group: 0,
+                from: Address::Peer(from.into()),
+                to: Address::Peer("a".into()),
+                term: 3,
+                event,
+            })
+        };
+        let replicated = |node_rx: &mut mpsc::UnboundedReceiver<Message>| {
+            let mut batches = Vec::new();
+            while let Ok(msg) = node_rx.try_recv() {
+                if let (Address::Peer(to), Event::ReplicateEntries { base_index, entries, .. }) =
+                    (msg.to, msg.event)
+                {
+                    let last = entries.last().map_or(base_index, |entry| entry.index);
+                    batches.push((to, base_index, last));
+                }
+            }
+            batches
+        };
+
+        // Probing b and c commits index 5 and sends them a full window of batches, which is lost.
+        for peer in ["b", "c"] {
+            node = step(node, peer, Event::AcceptEntries { last_index: 5 })?;
+        }
+        assert_node(&node).is_leader().committed(5);
+        assert_eq!(replicated(&mut node_rx).len(), 2 * MAX_INFLIGHT);
+
+        // The peers confirm heartbeats without noticing the lost entries, so nothing is resent
+        // until the replicate timeout.
+        let confirm = Event::ConfirmLeader { commit_index: 5, has_committed: true, round: 0 };
+        for _ in 1..REPLICATE_TIMEOUT {
+            node = node.tick()?;
+            for peer in ["b", "c"] {
+                node = step(node, peer, confirm.clone())?;
+            }
+        }
+        assert_eq!(replicated(&mut node_rx), vec![]);
+
+        // Once it times out, the window is resent, and commits progress when it's accepted.
+        node = node.tick()?;
+        let batches = replicated(&mut node_rx);
+        assert_eq!(batches.len(), 2 * MAX_INFLIGHT);
+        assert!(batches.iter().any(|(to, base, _)| to == "b" && *base == 5));
+        assert!(batches.iter().any(|(to, base, _)| to == "c" && *base == 5));
+        for (to, _, last_index) in batches {
+            node = step(node, &to, Event::AcceptEntries { last_index })?;
+        }
+        assert_node(&node).is_leader().committed(last_index);
+        Ok(())
+    }
+
     #[test]
+    // Rejected entries are retried from the previous index, and stale rejections are ignored.
     fn step_rejectentries() -> Result<()> {
         let (leader, mut node_rx, mut state_rx) = setup()?;
         let entries = leader.log.scan(0..).collect::<Result<Vec<_>>>()?;
         let mut node: Node = leader.into();
+        node = probe_b(node, &mut state_rx)?;
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("b".into()),
+                term: 3,
+                event: Event::ReplicateEntries { base_index: 5, base_term: 3, entries: vec![] },
+            }],
+        );
 
-        for i in 0..(entries.len() + 3) {
+        for index in (0..entries.len()).rev() {
             node = node.step(Message {
+                group: 0,
                 from: Address::Peer("b".into()),
                 to: Address::Peer("a".into()),
                 term: 3,
-                event: Event::RejectEntries,
+                event: Event::RejectEntries {
+                    base_index: index as u64 + 1,
+                    conflict_term: None,
+                    conflict_index: index as u64 + 1,
+                },
             })?;
             assert_node(&node).is_leader().term(3).committed(2);
-            let index = if i >= entries.len() { 0 } else { entries.len() - i - 1 };
-            let replicate = entries.get(index..).unwrap().to_vec();
             assert_messages(
                 &mut node_rx,
                 vec![Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer("b".into()),
                     term: 3,
                     event: Event::ReplicateEntries {
                         base_index: index as u64,
-                        base_term: if index > 0 {
-                            entries.get(index - 1).map(|e| e.term).unwrap()
-                        } else {
-                            0
-                        },
-                        entries: replicate,
+                        base_term: if index > 0 { entries[index - 1].term } else { 0 },
+                        entries: entries[index..].to_vec(),
                     },
                 }],
             );
             assert_messages(&mut state_rx, vec![]);
         }
+
+        // Rejections of batches that are no longer in flight are ignored.
+        node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::RejectEntries {
+                base_index: 3,
+                conflict_term: None,
+                conflict_index: 3,
+            },
+        })?;
+        assert_messages(&mut node_rx, vec![]);
+        assert_messages(&mut state_rx, vec![]);
         Ok(())
     }
 
     #[test]
-    // Sending a client query request will pass it to the state machine and trigger heartbeats.
-    fn step_clientrequest_query() -> Result<()> {
+    // Conflict hints let the leader skip past whole terms.
+    fn step_rejectentries_conflict() -> Result<()> {
         let (leader, mut node_rx, mut state_rx) = setup()?;
-        let quorum = leader.quorum();
+        let entries = leader.log.scan(0..).collect::<Result<Vec<_>>>()?;
         let mut node: Node = leader.into();
-        node = node.step(Message {
-            from: Address::Client,
-            to: Address::Local,
-            term: 0,
-            event: Event::ClientRequest { id: vec![0x01], request: Request::Query(vec![0xaf]) },
+        node = probe_b(node, &mut state_rx)?;
+        node_rx.try_recv().unwrap();
+
+        // We have the conflicting term 2, so we resume after our last entry in it. If we don't
+        // have the term, we resume from its first index in the follower's log.
+        for (base_index, conflict_term, conflict_index, next_base) in
+            [(5, 2, 3, 3), (3, 1, 1, 2), (2, 4, 1, 0)]
+        {
+            node = node.step(Message {
+                group: 0,
+                from: Address::Peer("b".into()),
+                to: Address::Peer("a".into()),
+                term: 3,
+                event: Event::RejectEntries {
+                    base_index,
+                    conflict_term: Some(conflict_term),
+                    conflict_index,
+                },
+            })?;
+            assert_messages(
+                &mut node_rx,
+                vec![Message {
+                    group: 0,
+                    from: Address::Local,
+                    to: Address::Peer("b".into()),
+                    term: 3,
+                    event: Event::ReplicateEntries {
+                        base_index: next_base as u64,
+                        base_term: if next_base > 0 { entries[next_base - 1].term } else { 0 },
+                        entries: entries[next_base..].to_vec(),
+                    },
+                }],
+            );
+        }
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // A peer that needs compacted entries is sent the snapshot instead, followed by the
+    // remaining entries.
+    fn step_rejectentries_snapshot() -> Result<()> {
+        let (mut leader, mut node_rx, mut state_rx) = setup()?;
+        leader.log.compact(2, vec![0xaa])?;
+        let entries = leader.log.scan(3..).collect::<Result<Vec<_>>>()?;
+        let mut node: Node = leader.into();
+        node = probe_b(node, &mut state_rx)?;
+        node_rx.try_recv().unwrap();
+
+        // Rejections above the snapshot walk back through the remaining log.
+        for base_index in (2..5).rev() {
+            node = node.step(Message {
+                group: 0,
+                from: Address::Peer("b".into()),
+                to: Address::Peer("a".into()),
+                term: 3,
+                event: Event::RejectEntries {
+                    base_index: base_index + 1,
+                    conflict_term: None,
+                    conflict_index: base_index + 1,
+                },
+            })?;
+            let msg = node_rx.try_recv().unwrap();
+            assert!(matches!(msg.event, Event::ReplicateEntries { base_index: b, .. } if b == base_index));
+        }
+
+        // Once the base entry is compacted away, the snapshot is sent instead.
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::RejectEntries {
+                base_index: 2,
+                conflict_term: None,
+                conflict_index: 2,
+            },
+        })?;
+        assert_node(&node).is_leader().term(3).committed(2).last(5);
+        assert_messages(
+            &mut node_rx,
+            vec![
+                Message {
+                    group: 0,
+                    from: Address::Local,
+                    to: Address::Peer("b".into()),
+                    term: 3,
+                    event: Event::InstallSnapshot {
+                        snapshot: Snapshot {
+                            index: 2,
+                            term: 1,
+                            data: vec![0xaa],
+                            membership: None,
+                        },
+                    },
+                },
+                Message {
+                    group: 0,
+                    from: Address::Local,
+                    to: Address::Peer("b".into()),
+                    term: 3,
+                    event: Event::ReplicateEntries { base_index: 2, base_term: 1, entries },
+                },
+            ],
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // Sending a client query request will pass it to the state machine and trigger heartbeats.
+    fn step_clientrequest_query() -> Result<()> {
+        let (leader, mut node_rx, mut state_rx) = setup()?;
+        let quorum = Some(leader.membership());
+        let mut node: Node = leader.into();
+        node = node.step(Message {
+            group: 0,
+            from: Address::Client,
+            to: Address::Local,
+            term: 0,
+            event: Event::ClientRequest { id: vec![0x01], request: Request::Query(vec![0xaf]) },
         })?;
         assert_node(&node).is_leader().term(3).committed(2).last(5);
         assert_messages(
             &mut node_rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peers,
                 term: 3,
-                event: Event::Heartbeat { commit_index: 2, commit_term: 1 },
+                event: Event::Heartbeat { commit_index: 2, commit_term: 1, round: 0 },
             }],
         );
         assert_messages(
@@ -595,7 +1488,145 @@ mod tests {
                     index: 2,
                     quorum,
                 },
-                Instruction::Vote { term: 3, index: 2, address: Address::Local },
+                Instruction::Vote { term: 3, index: 2, address: Address::Peer("a".into()) },
+            ],
+        );
+        Ok(())
+    }
+
+    #[test]
+    // Read index requests from followers are confirmed by a quorum before responding.
+    fn step_clientrequest_readindex() -> Result<()> {
+        let (leader, mut node_rx, mut state_rx) = setup()?;
+        let quorum = Some(leader.membership());
+        let mut node: Node = leader.into();
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::ClientRequest { id: vec![0x01], request: Request::ReadIndex },
+        })?;
+        assert_node(&node).is_leader().term(3).committed(2).last(5);
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peers,
+                term: 3,
+                event: Event::Heartbeat { commit_index: 2, commit_term: 1, round: 0 },
+            }],
+        );
+        assert_messages(
+            &mut state_rx,
+            vec![
+                Instruction::ReadIndex {
+                    id: vec![0x01],
+                    address: Address::Peer("b".into()),
+                    term: 3,
+                    index: 2,
+                    quorum,
+                },
+                Instruction::Vote { term: 3, index: 2, address: Address::Peer("a".into()) },
+            ],
+        );
+        Ok(())
+    }
+
+    #[test]
+    // With lease reads, queries are executed locally while a quorum has confirmed a recent
+    // heartbeat, and fall back to confirming leadership otherwise.
+    fn step_clientrequest_query_lease() -> Result<()> {
+        let (mut leader, mut node_rx, mut state_rx) = setup()?;
+        leader.config.lease_reads = true;
+        leader.log.commit(5)?;
+        let quorum = Some(leader.membership());
+        let mut node: Node = leader.into();
+        let query = |id: u8| Message {
+            group: 0,
+            from: Address::Client,
+            to: Address::Local,
+            term: 0,
+            event: Event::ClientRequest { id: vec![id], request: Request::Query(vec![0xaf]) },
+        };
+        let heartbeat = |round| Message {
+            group: 0,
+            from: Address::Local,
+            to: Address::Peers,
+            term: 3,
+            event: Event::Heartbeat { commit_index: 5, commit_term: 3, round },
+        };
+
+        // Without confirmed heartbeats there is no lease.
+        node = node.step(query(0x01))?;
+        assert_messages(&mut node_rx, vec![heartbeat(0)]);
+        assert_messages(
+            &mut state_rx,
+            vec![
+                Instruction::Query {
+                    id: vec![0x01],
+                    address: Address::Client,
+                    command: vec![0xaf],
+                    term: 3,
+                    index: 5,
+                    quorum: quorum.clone(),
+                },
+                Instruction::Vote { term: 3, index: 5, address: Address::Peer("a".into()) },
+            ],
+        );
+
+        // Once a quorum confirms the heartbeat, queries are executed without a quorum.
+        for peer in ["b", "c"] {
+            node = node.step(Message {
+                group: 0,
+                from: Address::Peer(peer.into()),
+                to: Address::Peer("a".into()),
+                term: 3,
+                event: Event::ConfirmLeader { commit_index: 5, has_committed: true, round: 0 },
+            })?;
+        }
+        assert_messages(
+            &mut state_rx,
+            vec![
+                Instruction::Vote { term: 3, index: 5, address: Address::Peer("b".into()) },
+                Instruction::Vote { term: 3, index: 5, address: Address::Peer("c".into()) },
+            ],
+        );
+        node = node.step(query(0x02))?;
+        assert_messages(&mut node_rx, vec![]);
+        assert_messages(
+            &mut state_rx,
+            vec![Instruction::Query {
+                id: vec![0x02],
+                address: Address::Client,
+                command: vec![0xaf],
+                term: 3,
+                index: 5,
+                quorum: None,
+            }],
+        );
+
+        // The lease expires if no later heartbeats are confirmed.
+        let lease_timeout = RaftConfig::default().lease_timeout();
+        for round in 1..=lease_timeout {
+            node = node.tick()?;
+            assert_messages(&mut node_rx, vec![heartbeat(round)]);
+        }
+        node.step(query(0x03))?;
+        assert_messages(&mut node_rx, vec![heartbeat(lease_timeout)]);
+        assert_messages(
+            &mut state_rx,
+            vec![
+                Instruction::Query {
+                    id: vec![0x03],
+                    address: Address::Client,
+                    command: vec![0xaf],
+                    term: 3,
+                    index: 5,
+                    quorum,
+                },
+                Instruction::Vote { term: 3, index: 5, address: Address::Peer("a".into()) },
             ],
         );
         Ok(())
@@ -609,6 +1640,7 @@ mod tests {
         let mut node: Node = leader.into();
 
         node = node.step(Message {
+            group: 0,
             from: Address::Client,
             to: Address::Local,
             term: 0,
@@ -618,19 +1650,28 @@ mod tests {
             index: 6,
             term: 3,
             command: Some(vec![0xaf]),
+            membership: None,
+            session: None,
         });
 
         for peer in peers.iter().cloned() {
             assert_eq!(
                 node_rx.recv().now_or_never(),
                 Some(Some(Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peer(peer),
                     term: 3,
                     event: Event::ReplicateEntries {
                         base_index: 5,
                         base_term: 3,
-                        entries: vec![Entry { index: 6, term: 3, command: Some(vec![0xaf]) },]
+                        entries: vec![Entry {
+                            index: 6,
+                            term: 3,
+                            command: Some(vec![0xaf]),
+                            membership: None,
+                            session: None,
+                        }]
                     },
                 }))
             )
@@ -651,6 +1692,7 @@ mod tests {
         let mut node: Node = leader.into();
 
         node = node.step(Message {
+            group: 0,
             from: Address::Client,
             to: Address::Local,
             term: 0,
@@ -679,7 +1721,7 @@ mod tests {
                     commit_index: 2,
                     apply_index: 0,
                     storage: "test".into(),
-                    storage_size: 130,
+                    storage_size: 140,
                 }),
             }],
         );
@@ -687,11 +1729,440 @@ mod tests {
         Ok(())
     }
 
+    // Steps AcceptEntries for the given last index from the given peers.
+    fn accept(mut node: Node, peers: &[&str], last_index: u64) -> Result<Node> {
+        for peer in peers {
+            node = node.step(Message {
+                group: 0,
+                from: Address::Peer(peer.to_string()),
+                to: Address::Peer("a".into()),
+                term: 3,
+                event: Event::AcceptEntries { last_index },
+            })?;
+        }
+        Ok(node)
+    }
+
+    // Returns the recipients of all pending messages.
+    fn recipients(node_rx: &mut mpsc::UnboundedReceiver<Message>) -> Vec<Address> {
+        let mut to = Vec::new();
+        while let Some(Some(msg)) = node_rx.recv().now_or_never() {
+            to.push(msg.to);
+        }
+        to
+    }
+
+    #[test]
+    // Adding a node appends a joint membership, then the final membership once the joint one is
+    // committed by a quorum of both the old and new voters.
+    fn step_clientrequest_addnode() -> Result<()> {
+        let (leader, mut node_rx, mut state_rx) = setup()?;
+        let mut node = accept(leader.into(), &["b", "c"], 5)?;
+        assert_node(&node).is_leader().committed(5);
+        assert_messages(&mut node_rx, vec![]);
+        while let Some(Some(_)) = state_rx.recv().now_or_never() {}
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Client,
+            to: Address::Local,
+            term: 0,
+            event: Event::ClientRequest {
+                id: vec![0x01],
+                request: Request::AddNode { id: "f".into(), address: "f:9705".into() },
+            },
+        })?;
+        let old = Membership::new(vec!["a", "b", "c", "d", "e"].into_iter().map(String::from));
+        let mut voters = old.voters.clone();
+        voters.insert("f".into());
+        let mut joint = old.transition(voters, BTreeSet::new());
+        joint.addresses.insert("f".into(), "f:9705".into());
+        assert_node(&node).is_leader().committed(5).last(6).entry(Entry {
+            index: 6,
+            term: 3,
+            command: None,
+            membership: Some(joint.clone()),
+            session: None,
+        });
+        let peers: Vec<Address> =
+            vec!["b", "c", "d", "e", "f"].into_iter().map(|p| Address::Peer(p.into())).collect();
+        assert_eq!(recipients(&mut node_rx), peers);
+        assert_messages(&mut state_rx, vec![]);
+
+        // A majority of the old voters is not sufficient, a majority of the new ones is required.
+        node = accept(node, &["b", "c"], 6)?;
+        assert_node(&node).is_leader().committed(5).last(6);
+        node = accept(node, &["d"], 6)?;
+        assert_node(&node).is_leader().committed(6).last(7).entry(Entry {
+            index: 7,
+            term: 3,
+            command: None,
+            membership: Some(joint.finalize()),
+            session: None,
+        });
+        assert_eq!(recipients(&mut node_rx), peers);
+        assert_messages(
+            &mut state_rx,
+            vec![
+                Instruction::Apply {
+                    entry: Entry {
+                        index: 6,
+                        term: 3,
+                        command: None,
+                        membership: Some(joint.clone()),
+                        session: None,
+                    },
+                },
+                Instruction::Notify { id: vec![0x01], address: Address::Client, index: 7 },
+            ],
+        );
+
+        node = accept(node, &["b", "c", "d"], 7)?;
+        assert_node(&node).is_leader().committed(7).last(7);
+        assert_messages(
+            &mut state_rx,
+            vec![Instruction::Apply {
+                entry: Entry {
+                    index: 7,
+                    term: 3,
+                    command: None,
+                    membership: Some(joint.finalize()),
+                    session: None,
+                },
+            }],
+        );
+        Ok(())
+    }
+
+    #[test]
+    // Learners are replicated to, but don't count towards the commit quorum or read votes.
+    fn step_clientrequest_addlearner() -> Result<()> {
+        let (leader, mut node_rx, mut state_rx) = setup()?;
+        let mut node = accept(leader.into(), &["b", "c"], 5)?;
+        while let Some(Some(_)) = state_rx.recv().now_or_never() {}
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Client,
+            to: Address::Local,
+            term: 0,
+            event: Event::ClientRequest {
+                id: vec![0x01],
+                request: Request::AddLearner { id: "f".into(), address: "f:9705".into() },
+            },
+        })?;
+        let old = Membership::new(vec!["a", "b", "c", "d", "e"].into_iter().map(String::from));
+        let mut joint = old.transition(old.voters.clone(), ["f".to_string()].into());
+        joint.addresses.insert("f".into(), "f:9705".into());
+        assert_node(&node).is_leader().committed(5).last(6).entry(Entry {
+            index: 6,
+            term: 3,
+            command: None,
+            membership: Some(joint.clone()),
+            session: None,
+        });
+        let peers: Vec<Address> =
+            vec!["b", "c", "d", "e", "f"].into_iter().map(|p| Address::Peer(p.into())).collect();
+        assert_eq!(recipients(&mut node_rx), peers);
+
+        // The learner doesn't count towards the quorum.
+        node = accept(node, &["f", "b"], 6)?;
+        assert_node(&node).is_leader().committed(5).last(6);
+        node = accept(node, &["c"], 6)?;
+        assert_node(&node).is_leader().committed(6).last(7);
+        recipients(&mut node_rx);
+        while let Some(Some(_)) = state_rx.recv().now_or_never() {}
+
+        node = accept(node, &["f", "b", "c"], 7)?;
+        assert_node(&node).is_leader().committed(7).last(7);
+        match &node {
+            Node::Leader(n) => {
+                assert_eq!(n.peers, vec!["b", "c", "d", "e", "f"]);
+                assert_eq!(n.log.membership, Some(joint.finalize()));
+            }
+            _ => unreachable!(),
+        }
+        while let Some(Some(_)) = state_rx.recv().now_or_never() {}
+
+        // The learner's heartbeat confirmations are not votes.
+        node.step(Message {
+            group: 0,
+            from: Address::Peer("f".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::ConfirmLeader { commit_index: 7, has_committed: true, round: 0 },
+        })?;
+        assert_messages(&mut node_rx, vec![]);
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // A leader which removes itself steps down once the final membership is committed.
+    fn step_clientrequest_removenode_self() -> Result<()> {
+        let (leader, mut node_rx, mut state_rx) = setup()?;
+        let mut node = accept(leader.into(), &["b", "c"], 5)?;
+        while let Some(Some(_)) = state_rx.recv().now_or_never() {}
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Client,
+            to: Address::Local,
+            term: 0,
+            event: Event::ClientRequest {
+                id: vec![0x01],
+                request: Request::RemoveNode { id: "a".into() },
+            },
+        })?;
+        assert_node(&node).is_leader().committed(5).last(6);
+        assert_eq!(recipients(&mut node_rx).len(), 4);
+
+        node = accept(node, &["b", "c", "d"], 6)?;
+        assert_node(&node).is_leader().committed(6).last(7);
+        recipients(&mut node_rx);
+        while let Some(Some(_)) = state_rx.recv().now_or_never() {}
+
+        node = accept(node, &["b", "c", "d"], 7)?;
+        assert_node(&node).is_follower().leader(None).committed(7);
+        match &node {
+            Node::Follower(n) => assert_eq!(n.peers, vec!["b", "c", "d", "e"]),
+            _ => unreachable!(),
+        }
+        assert_messages(&mut node_rx, vec![]);
+        let instructions: Vec<Instruction> =
+            std::iter::from_fn(|| state_rx.recv().now_or_never().flatten()).collect();
+        assert_eq!(instructions.last(), Some(&Instruction::Abort));
+
+        // The removed node does not campaign.
+        for _ in 0..=ELECTION_TIMEOUT_MAX {
+            node = node.tick()?;
+        }
+        assert_node(&node).is_follower();
+        assert_messages(&mut node_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // Invalid membership changes are rejected.
+    fn step_clientrequest_membership_invalid() -> Result<()> {
+        let (leader, mut node_rx, mut state_rx) = setup()?;
+        let mut node: Node = leader.into();
+
+        let request = |node: Node, id: u8, request: Request| {
+            node.step(Message {
+                group: 0,
+                from: Address::Client,
+                to: Address::Local,
+                term: 0,
+                event: Event::ClientRequest { id: vec![id], request },
+            })
+        };
+
+        // The leader hasn't committed an entry in its own term yet.
+        node = request(node, 0x01, Request::RemoveNode { id: "b".into() })?;
+        node = accept(node, &["b", "c"], 5)?;
+        while let Some(Some(_)) = state_rx.recv().now_or_never() {}
+
+        node = request(node, 0x02, Request::AddNode { id: "b".into(), address: "b".into() })?;
+        node = request(node, 0x03, Request::RemoveNode { id: "f".into() })?;
+        node = request(node, 0x04, Request::RemoveNode { id: "b".into() })?;
+        node = request(node, 0x05, Request::RemoveNode { id: "c".into() })?;
+        assert_node(&node).is_leader().committed(5).last(6);
+
+        let responses: Vec<(Vec<u8>, Result<Response>)> = std::iter::from_fn(|| {
+            node_rx.recv().now_or_never().flatten()
+        })
+        .filter_map(|msg| match msg.event {
+            Event::ClientResponse { id, response } => Some((id, response)),
+            _ => None,
+        })
+        .collect();
+        assert_eq!(
+            responses,
+            vec![
+                (vec![0x01], Err(Error::Abort)),
+                (vec![0x02], Err(Error::Value("Node b is already a member".into()))),
+                (vec![0x03], Err(Error::Value("Node f is not a member".into()))),
+                (
+                    vec![0x05],
+                    Err(Error::Value("A membership change is already in progress".into()))
+                ),
+            ]
+        );
+        assert_messages(&mut state_rx, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    // Transferring leadership catches up the target, rejects mutations, and then tells the target
+    // to start an election. The client is notified once the leader steps down.
+    fn step_clientrequest_transferleadership() -> Result<()> {
+        let (leader, mut node_rx, mut state_rx) = setup()?;
+        let mut node: Node = leader.into();
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Client,
+            to: Address::Local,
+            term: 0,
+            event: Event::ClientRequest {
+                id: vec![0x01],
+                request: Request::TransferLeadership { to: "b".into() },
+            },
+        })?;
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("b".into()),
+                term: 3,
+                event: Event::ReplicateEntries { base_index: 5, base_term: 3, entries: vec![] },
+            }],
+        );
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Client,
+            to: Address::Local,
+            term: 0,
+            event: Event::ClientRequest { id: vec![0x02], request: Request::Mutate(vec![0xaf]) },
+        })?;
+        assert_node(&node).is_leader().term(3).last(5);
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Client,
+                term: 3,
+                event: Event::ClientResponse { id: vec![0x02], response: Err(Error::Abort) },
+            }],
+        );
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peer("a".into()),
+            term: 3,
+            event: Event::AcceptEntries { last_index: 5 },
+        })?;
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("b".into()),
+                term: 3,
+                event: Event::TimeoutNow,
+            }],
+        );
+
+        node = node.step(Message {
+            group: 0,
+            from: Address::Peer("b".into()),
+            to: Address::Peers,
+            term: 4,
+            event: Event::SolicitVote { last_index: 5, last_term: 3 },
+        })?;
+        assert_node(&node).is_follower().term(4).leader(Some("b"));
+        let msg = node_rx.try_recv().unwrap();
+        assert_eq!(
+            msg,
+            Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Client,
+                term: 4,
+                event: Event::ClientResponse {
+                    id: vec![0x01],
+                    response: Ok(Response::TransferLeadership)
+                },
+            }
+        );
+        assert_messages(&mut state_rx, vec![Instruction::Abort]);
+        Ok(())
+    }
+
+    #[test]
+    // A leadership transfer is aborted if the target doesn't take over within an election timeout.
+    fn step_clientrequest_transferleadership_timeout() -> Result<()> {
+        let (mut leader, mut node_rx, _) = setup()?;
+        leader.role.peer_last_index.insert("c".into(), 5);
+        let mut node: Node = leader.into();
+
+        // Invalid targets are rejected.
+        for (id, to) in [(0x01, "a"), (0x02, "x")] {
+            node = node.step(Message {
+                group: 0,
+                from: Address::Client,
+                to: Address::Local,
+                term: 0,
+                event: Event::ClientRequest {
+                    id: vec![id],
+                    request: Request::TransferLeadership { to: to.into() },
+                },
+            })?;
+            let error = Error::Value(format!("Cannot transfer leadership to {}", to));
+            assert_messages(
+                &mut node_rx,
+                vec![Message {
+                    group: 0,
+                    from: Address::Local,
+                    to: Address::Client,
+                    term: 3,
+                    event: Event::ClientResponse { id: vec![id], response: Err(error) },
+                }],
+            );
+        }
+
+        // A caught-up target is told to start an election immediately.
+        node = node.step(Message {
+            group: 0,
+            from: Address::Client,
+            to: Address::Local,
+            term: 0,
+            event: Event::ClientRequest {
+                id: vec![0x03],
+                request: Request::TransferLeadership { to: "c".into() },
+            },
+        })?;
+        assert_messages(
+            &mut node_rx,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Peer("c".into()),
+                term: 3,
+                event: Event::TimeoutNow,
+            }],
+        );
+
+        for _ in 0..ELECTION_TIMEOUT_MAX {
+            node = node.tick()?;
+        }
+        assert_node(&node).is_leader().term(3);
+        let responses: Vec<Message> = std::iter::from_fn(|| node_rx.recv().now_or_never().flatten())
+            .filter(|msg| msg.to == Address::Client)
+            .collect();
+        assert_eq!(
+            responses,
+            vec![Message {
+                group: 0,
+                from: Address::Local,
+                to: Address::Client,
+                term: 3,
+                event: Event::ClientResponse { id: vec![0x03], response: Err(Error::Abort) },
+            }]
+        );
+        Ok(())
+    }
+
     #[test]
     fn tick() -> Result<()> {
         let (leader, mut node_rx, mut state_rx) = setup()?;
         let mut node: Node = leader.into();
-        for _ in 0..5 {
+        for i in 1..=5 {
             for _ in 0..HEARTBEAT_INTERVAL {
                 assert_messages(&mut node_rx, vec![]);
                 assert_messages(&mut state_rx, vec![]);
@@ -702,13 +2173,61 @@ mod tests {
             assert_eq!(
                 node_rx.recv().now_or_never(),
                 Some(Some(Message {
+                    group: 0,
                     from: Address::Local,
                     to: Address::Peers,
                     term: 3,
-                    event: Event::Heartbeat { commit_index: 2, commit_term: 1 },
+                    event: Event::Heartbeat {
+                        commit_index: 2,
+                        commit_term: 1,
+                        round: i * HEARTBEAT_INTERVAL,
+                    },
                 }))
             );
         }
         Ok(())
     }
+
+    #[test]
+    // The leader steps down if it doesn't hear from a quorum within an election timeout.
+    fn tick_check_quorum() -> Result<()> {
+        let (leader, mut node_rx, mut state_rx) = setup()?;
+        let mut node: Node = leader.into();
+
+        // Hearing from b and c is sufficient for a quorum of 5.
+        for _ in 0..(2 * ELECTION_TIMEOUT_MAX) {
+            node = node.tick()?;
+            for peer in ["b", "c"] {
+                node = node.step(Message {
+                    group: 0,
+                    from: Address::Peer(peer.into()),
+                    to: Address::Peer("a".into()),
+                    term: 3,
+                    event: Event::ConfirmLeader { commit_index: 2, has_committed: true, round: 0 },
+                })?;
+            }
+        }
+        assert_node(&node).is_leader().term(3);
+
+        // Only hearing from b is not.
+        for _ in 0..ELECTION_TIMEOUT_MAX {
+            node = node.tick()?;
+            node = node.step(Message {
+                group: 0,
+                from: Address::Peer("b".into()),
+                to: Address::Peer("a".into()),
+                term: 3,
+                event: Event::ConfirmLeader { commit_index: 2, has_committed: true, round: 0 },
+            })?;
+        }
+        assert_node(&node).is_leader().term(3);
+        while let Some(Some(_)) = node_rx.recv().now_or_never() {}
+        while let Some(Some(_)) = state_rx.recv().now_or_never() {}
+
+        node = node.tick()?;
+        assert_node(&node).is_follower().term(3).leader(None).voted_for(Some("a"));
+        assert_messages(&mut node_rx, vec![]);
+        assert_messages(&mut state_rx, vec![Instruction::Abort]);
+        Ok(())
+    }
 }
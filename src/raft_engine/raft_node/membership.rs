@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// The cluster membership, i.e. the set of voting nodes and non-voting learners. Learners receive
+/// the replicated log, but don't count towards quorums and never campaign. Membership is changed
+/// via joint consensus: the leader first appends a joint membership, where elections and commits
+/// require a quorum of both the old and new voters, and once that is committed it appends the new
+/// membership.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Membership {
+    /// The voting nodes.
+    pub voters: BTreeSet<String>,
+    /// The previous voting nodes during a joint consensus transition.
+    pub outgoing: Option<BTreeSet<String>>,
+    /// The non-voting learners.
+    pub learners: BTreeSet<String>,
+    /// The network addresses of nodes added after the cluster was bootstrapped. Addresses of the
+    /// initial nodes are given by the server configuration.
+    pub addresses: BTreeMap<String, String>,
+}
+
+impl Membership {
+    /// Creates a new membership with the given voters.
+    pub fn new(voters: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            voters: voters.into_iter().collect(),
+            outgoing: None,
+            learners: BTreeSet::new(),
+            addresses: BTreeMap::new(),
+        }
+    }
+
+    /// Returns a joint membership transitioning from the current voters to the given voters,
+    /// with the given learners.
+    pub fn transition(&self, voters: BTreeSet<String>, learners: BTreeSet<String>) -> Self {
+        Self {
+            voters,
+            outgoing: Some(self.voters.clone()),
+            learners,
+            addresses: self.addresses.clone(),
+        }
+    }
+
+    /// Returns the final membership of a joint consensus transition.
+    pub fn finalize(&self) -> Self {
+        let mut addresses = self.addresses.clone();
+        addresses.retain(|id, _| self.voters.contains(id) || self.learners.contains(id));
+        Self {
+            voters: self.voters.clone(),
+            outgoing: None,
+            learners: self.learners.clone(),
+            addresses,
+        }
+    }
+
+    /// Checks if the membership is in a joint consensus transition.
+    pub fn is_joint(&self) -> bool {
+        self.outgoing.is_some()
+    }
+
+    /// Checks if a node is a voter, in either the old or new voters when joint.
+    pub fn is_voter(&self, id: &str) -> bool {
+        self.voters.contains(id) || self.outgoing.as_ref().is_some_and(|o| o.contains(id))
+    }
+
+    /// Returns all nodes: the voters, in both the old and new voters when joint, and the learners.
+    pub fn nodes(&self) -> BTreeSet<String> {
+        let mut nodes = self.voters.clone();
+        if let Some(outgoing) = &self.outgoing {
+            nodes.extend(outgoing.iter().cloned());
+        }
+        nodes.extend(self.learners.iter().cloned());
+        nodes
+    }
+
+    /// Checks if the given nodes form a quorum: a majority of the voters, and when joint also a
+    /// majority of the old voters. Learners and unknown nodes don't count.
+    pub fn has_quorum(&self, votes: &HashSet<String>) -> bool {
+        let majority = |voters: &BTreeSet<String>| {
+            voters.iter().filter(|id| votes.contains(*id)).count() > voters.len() / 2
+        };
+        majority(&self.voters) && self.outgoing.as_ref().is_none_or(majority)
+    }
+
+    /// Returns the highest log index replicated to a quorum, given the last replicated index of
+    /// each node. Learners are ignored. When joint, the index must be replicated to a majority of
+    /// both the old and the new voters.
+    pub fn quorum_index(&self, last_indexes: &HashMap<String, u64>) -> u64 {
+        let majority_index = |voters: &BTreeSet<String>| {
+            let mut indexes: Vec<u64> =
+                voters.iter().map(|id| last_indexes.get(id).copied().unwrap_or(0)).collect();
+            indexes.sort_unstable();
+            indexes.reverse();
+            indexes.get(voters.len() / 2).copied().unwrap_or(0)
+        };
+        match &self.outgoing {
+            None => majority_index(&self.voters),
+            Some(outgoing) => min(majority_index(&self.voters), majority_index(outgoing)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn voters(ids: &[&str]) -> BTreeSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn votes(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn has_quorum() {
+        let membership = Membership::new(voters(&["a", "b", "c"]));
+        assert!(!membership.has_quorum(&votes(&[])));
+        assert!(!membership.has_quorum(&votes(&["a"])));
+        assert!(membership.has_quorum(&votes(&["a", "b"])));
+        assert!(membership.has_quorum(&votes(&["b", "c"])));
+
+        // Unknown nodes don't count.
+        assert!(!membership.has_quorum(&votes(&["a", "x", "y"])));
+
+        // An even number of voters requires more than half.
+        let membership = Membership::new(voters(&["a", "b", "c", "d"]));
+        assert!(!membership.has_quorum(&votes(&["a", "b"])));
+        assert!(membership.has_quorum(&votes(&["a", "b", "d"])));
+    }
+
+    #[test]
+    fn has_quorum_joint() {
+        // a,b,d is a majority of both a-c and a-e, which is less than any conservative count
+        // of 4 distinct voters.
+        let membership = Membership::new(voters(&["a", "b", "c"]));
+        let joint = membership.transition(voters(&["a", "b", "c", "d", "e"]), BTreeSet::new());
+        assert!(joint.has_quorum(&votes(&["a", "b", "d"])));
+        assert!(!joint.has_quorum(&votes(&["a", "d", "e"])));
+        assert!(!joint.has_quorum(&votes(&["a", "b"])));
+
+        // Replacing c with d: c,d is a majority of neither.
+        let joint = membership.transition(voters(&["a", "b", "d"]), BTreeSet::new());
+        assert!(!joint.has_quorum(&votes(&["c", "d"])));
+        assert!(joint.has_quorum(&votes(&["a", "c", "d"])));
+
+        // Disjoint voters require a majority of each.
+        let joint = membership.transition(voters(&["d", "e", "f"]), BTreeSet::new());
+        assert!(!joint.has_quorum(&votes(&["a", "b", "c", "d"])));
+        assert!(joint.has_quorum(&votes(&["a", "b", "d", "e"])));
+    }
+
+    #[test]
+    fn quorum_index() {
+        let indexes: HashMap<String, u64> = vec![("a", 5), ("b", 4), ("c", 3), ("d", 1)]
+            .into_iter()
+            .map(|(id, index)| (id.into(), index))
+            .collect();
+
+        assert_eq!(Membership::new(voters(&["a", "b", "c"])).quorum_index(&indexes), 4);
+        assert_eq!(Membership::new(voters(&["a", "b", "c", "d"])).quorum_index(&indexes), 3);
+        assert_eq!(Membership::new(voters(&["c", "d", "e"])).quorum_index(&indexes), 1);
+
+        let joint = Membership::new(voters(&["a", "b", "c"]))
+            .transition(voters(&["b", "c", "d"]), BTreeSet::new());
+        assert_eq!(joint.quorum_index(&indexes), 3);
+        assert_eq!(joint.finalize().quorum_index(&indexes), 3);
+        assert_eq!(joint.finalize(), Membership::new(voters(&["b", "c", "d"])));
+
+        // Learners don't count towards the quorum, and are retained when finalized.
+        let learners = Membership::new(voters(&["c", "d", "e"]))
+            .transition(voters(&["c", "d", "e"]), voters(&["a", "b"]));
+        assert!(!learners.has_quorum(&votes(&["a", "b", "c"])));
+        assert!(learners.has_quorum(&votes(&["c", "d"])));
+        assert_eq!(learners.quorum_index(&indexes), 1);
+        assert_eq!(learners.finalize().learners, voters(&["a", "b"]));
+        assert_eq!(learners.finalize().nodes(), voters(&["a", "b", "c", "d", "e"]));
+    }
+}
@@ -1,37 +1,102 @@
 mod candidate;
+mod config;
 mod follower;
 mod leader;
+mod membership;
 mod node;
 mod role_node;
+#[cfg(test)]
+mod simulation;
 mod status;
 
-
+use rand::{rngs::StdRng, Rng as _};
+use std::cell::RefCell;
+use std::time::Duration;
 
 pub use candidate::*;
+pub use config::*;
 pub use follower::*;
 pub use leader::*;
+pub use membership::*;
 pub use node::*;
 pub use role_node::*;
 pub use status::*;
 
-/// The interval between leader heartbeats, in ticks.
+/// The default duration of a logical clock tick, the unit of time for e.g. heartbeats and
+/// elections.
+pub const TICK: Duration = Duration::from_millis(100);
+
+/// The default interval between leader heartbeats, in ticks.
 pub const HEARTBEAT_INTERVAL: u64 = 1;
 
-/// The minimum election timeout, in ticks.
+/// The default minimum election timeout, in ticks.
 pub const ELECTION_TIMEOUT_MIN: u64 = 8 * HEARTBEAT_INTERVAL;
 
-/// The maximum election timeout, in ticks.
+/// The default maximum election timeout, in ticks.
 pub const ELECTION_TIMEOUT_MAX: u64 = 15 * HEARTBEAT_INTERVAL;
 
-/*
+/// The default minimum delay before reconnecting to an unreachable peer.
+pub const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(1000);
+
+/// The default maximum delay before reconnecting to an unreachable peer.
+pub const RECONNECT_BACKOFF_MAX: Duration = Duration::from_millis(8000);
+
+/// The default number of outbound messages buffered per peer.
+pub const PEER_BUFFER: usize = 1000;
+
+/// The default maximum number of entries in a single ReplicateEntries message.
+pub const MAX_APPEND_ENTRIES: usize = 64;
+
+/// The default maximum size of the entries in a single ReplicateEntries message, in bytes. An
+/// entry larger than this is still sent, on its own.
+pub const MAX_APPEND_BYTES: u64 = 1024 * 1024;
+
+/// The default maximum number of ReplicateEntries messages in flight to a peer, awaiting a
+/// response.
+pub const MAX_INFLIGHT: usize = 4;
+
+/// The default number of ticks without a response from a peer after which the ReplicateEntries
+/// messages in flight to it are considered lost, and resent.
+pub const REPLICATE_TIMEOUT: u64 = 4 * HEARTBEAT_INTERVAL;
+
+thread_local! {
+    /// A seeded random number generator for election timeouts on this thread, if any. Used by
+    /// deterministic simulations, otherwise timeouts are drawn from the thread's entropy source.
+    static ELECTION_RNG: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+/// Returns a random election timeout within the configured range.
+pub fn random_election_timeout(config: &RaftConfig) -> u64 {
+    let range = config.election_timeout_min..=config.election_timeout_max;
+    ELECTION_RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(rng) => rng.gen_range(range),
+        None => rand::thread_rng().gen_range(range),
+    })
+}
+
+/// Seeds the election timeouts of nodes on the current thread, making them deterministic.
 #[cfg(test)]
-mod tests {
-    pub use super::super::state::tests::TestState;
-    use super::super::Entry;
+pub fn seed_election_timeouts(seed: u64) {
+    use rand::SeedableRng as _;
+    ELECTION_RNG.with(|rng| *rng.borrow_mut() = Some(StdRng::seed_from_u64(seed)))
+}
+
+#[cfg(test)]
+pub mod tests {
+    pub use crate::raft_engine::machine_state::tests::TestState;
     use super::follower::tests::{follower_leader, follower_voted_for};
     use super::*;
-    use crate::storage::log;
+    use crate::{
+        error::{Error, Result},
+        raft_engine::{
+            machine_state::MachineState,
+            messaging::{Address, Event, Message},
+            raft_log::{Entry, RaftLog}
+        },
+        storage_engine::log_storage::LogTest
+    };
     use futures::FutureExt;
+    use std::collections::{HashMap, HashSet};
     use pretty_assertions::assert_eq;
     use tokio::sync::mpsc;
 
@@ -55,7 +120,7 @@ mod tests {
             Self { node }
         }
 
-        fn log(&self) -> &'a Log {
+        fn log(&self) -> &'a RaftLog {
             match self.node {
                 Node::Candidate(n) => &n.log,
                 Node::Follower(n) => &n.log,
@@ -188,7 +253,7 @@ mod tests {
         }
     }
 
-    pub fn assert_node(node: &Node) -> NodeAsserter {
+    pub fn assert_node(node: &Node) -> NodeAsserter<'_> {
         NodeAsserter::new(node)
     }
 
@@ -206,11 +271,13 @@ mod tests {
             id: "a".into(),
             peers,
             term: 1,
-            log: Log::new(Box::new(log::Test::new()))?,
+            log: RaftLog::new(Box::new(LogTest::new()))?,
             node_tx,
             state_tx,
             proxied_reqs: HashMap::new(),
+            read_reqs: HashMap::new(),
             queued_reqs: Vec::new(),
+            config: RaftConfig::default(),
         };
         Ok((node, node_rx))
     }
@@ -221,9 +288,10 @@ mod tests {
         let node = Node::new(
             "a",
             vec!["b".into(), "c".into()],
-            Log::new(Box::new(log::Test::new()))?,
+            RaftLog::new(Box::new(LogTest::new()))?,
             Box::new(TestState::new(0)),
             node_tx,
+            RaftConfig::default(),
         )
         .await?;
         match node {
@@ -240,14 +308,15 @@ mod tests {
     #[tokio::test]
     async fn new_loads_term() -> Result<()> {
         let (node_tx, _) = mpsc::unbounded_channel();
-        let store = Box::new(log::Test::new());
-        Log::new(store.clone())?.save_term(3, Some("c"))?;
+        let store = Box::new(LogTest::new());
+        RaftLog::new(store.clone())?.save_term(3, Some("c"))?;
         let node = Node::new(
             "a",
             vec!["b".into(), "c".into()],
-            Log::new(store)?,
+            RaftLog::new(store)?,
             Box::new(TestState::new(0)),
             node_tx,
+            RaftConfig::default(),
         )
         .await?;
         match node {
@@ -260,7 +329,7 @@ mod tests {
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn new_state_apply_all() -> Result<()> {
         let (node_tx, _) = mpsc::unbounded_channel();
-        let mut log = Log::new(Box::new(log::Test::new()))?;
+        let mut log = RaftLog::new(Box::new(LogTest::new()))?;
         log.append(1, Some(vec![0x01]))?;
         log.append(2, None)?;
         log.append(2, Some(vec![0x02]))?;
@@ -268,7 +337,8 @@ mod tests {
         log.append(2, Some(vec![0x03]))?;
         let state = Box::new(TestState::new(0));
 
-        Node::new("a", vec!["b".into(), "c".into()], log, state.clone(), node_tx).await?;
+        let peers = vec!["b".into(), "c".into()];
+        Node::new("a", peers, log, state.clone(), node_tx, RaftConfig::default()).await?;
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         assert_eq!(state.list(), vec![vec![0x01], vec![0x02]]);
         assert_eq!(state.applied_index(), 3);
@@ -278,7 +348,7 @@ mod tests {
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn new_state_apply_partial() -> Result<()> {
         let (node_tx, _) = mpsc::unbounded_channel();
-        let mut log = Log::new(Box::new(log::Test::new()))?;
+        let mut log = RaftLog::new(Box::new(LogTest::new()))?;
         log.append(1, Some(vec![0x01]))?;
         log.append(2, None)?;
         log.append(2, Some(vec![0x02]))?;
@@ -286,7 +356,8 @@ mod tests {
         log.append(2, Some(vec![0x03]))?;
         let state = Box::new(TestState::new(2));
 
-        Node::new("a", vec!["b".into(), "c".into()], log, state.clone(), node_tx).await?;
+        let peers = vec!["b".into(), "c".into()];
+        Node::new("a", peers, log, state.clone(), node_tx, RaftConfig::default()).await?;
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         assert_eq!(state.list(), vec![vec![0x02]]);
         assert_eq!(state.applied_index(), 3);
@@ -296,7 +367,7 @@ mod tests {
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn new_state_apply_missing() -> Result<()> {
         let (node_tx, _) = mpsc::unbounded_channel();
-        let mut log = Log::new(Box::new(log::Test::new()))?;
+        let mut log = RaftLog::new(Box::new(LogTest::new()))?;
         log.append(1, Some(vec![0x01]))?;
         log.append(2, None)?;
         log.append(2, Some(vec![0x02]))?;
@@ -304,8 +375,9 @@ mod tests {
         log.append(2, Some(vec![0x03]))?;
         let state = Box::new(TestState::new(4));
 
+        let peers = vec!["b".into(), "c".into()];
         assert_eq!(
-            Node::new("a", vec!["b".into(), "c".into()], log, state.clone(), node_tx).await.err(),
+            Node::new("a", peers, log, state.clone(), node_tx, RaftConfig::default()).await.err(),
             Some(Error::Internal(
                 "State machine applied index 4 greater than log committed index 3".into()
             ))
@@ -313,15 +385,33 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn new_invalid_config() -> Result<()> {
+        let (node_tx, _) = mpsc::unbounded_channel();
+        let config = RaftConfig { election_timeout_min: 1, ..RaftConfig::default() };
+        let result = Node::new(
+            "a",
+            vec!["b".into(), "c".into()],
+            RaftLog::new(Box::new(LogTest::new()))?,
+            Box::new(TestState::new(0)),
+            node_tx,
+            config,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Config(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn new_single() -> Result<()> {
         let (node_tx, _) = mpsc::unbounded_channel();
         let node = Node::new(
             "a",
             vec![],
-            Log::new(Box::new(log::Test::new()))?,
+            RaftLog::new(Box::new(LogTest::new()))?,
             Box::new(TestState::new(0)),
             node_tx,
+            RaftConfig::default(),
         )
         .await?;
         match node {
@@ -353,8 +443,11 @@ mod tests {
             let peers: Vec<String> =
                 (0..(size as u8 - 1)).map(|i| (i as char).to_string()).collect();
             assert_eq!(peers.len(), size as usize - 1);
-            let (node, _) = setup_rolenode_peers(peers)?;
-            assert_eq!(node.quorum(), quorum);
+            let (node, _) = setup_rolenode_peers(peers.clone())?;
+            let mut votes: HashSet<String> = peers.into_iter().take(quorum - 1).collect();
+            assert!(!node.has_quorum(&votes));
+            votes.insert(node.id.clone());
+            assert!(node.has_quorum(&votes));
         }
         Ok(())
     }
@@ -362,17 +455,20 @@ mod tests {
     #[test]
     fn send() -> Result<()> {
         let (node, mut rx) = setup_rolenode()?;
-        node.send(Address::Peer("b".into()), Event::Heartbeat { commit_index: 1, commit_term: 1 })?;
+        node.send(
+            Address::Peer("b".into()),
+            Event::Heartbeat { commit_index: 1, commit_term: 1, round: 0 },
+        )?;
         assert_messages(
             &mut rx,
             vec![Message {
+                group: 0,
                 from: Address::Local,
                 to: Address::Peer("b".into()),
                 term: 1,
-                event: Event::Heartbeat { commit_index: 1, commit_term: 1 },
+                event: Event::Heartbeat { commit_index: 1, commit_term: 1, round: 0 },
             }],
         );
         Ok(())
     }
 }
-*/
\ No newline at end of file
@@ -3,7 +3,9 @@ use crate::{
     raft_engine::{
         machine_state::{Driver, MachineState},
         messaging::Message,
-        raft_node::{Candidate, Follower, Leader, RoleNode},
+        raft_node::{
+            random_election_timeout, Candidate, Follower, Leader, Membership, RaftConfig, RoleNode,
+        },
         raft_log::RaftLog
     }
 };
@@ -16,6 +18,7 @@ use tokio::sync::mpsc;
 
 
 /// The local Raft node state machine.
+#[allow(clippy::large_enum_variant)]
 pub enum Node {
     Candidate(RoleNode<Candidate>),
     Follower(RoleNode<Follower>),
@@ -30,8 +33,10 @@ impl Node {
         log: RaftLog,
         mut state: Box<dyn MachineState>,
         node_tx: mpsc::UnboundedSender<Message>,
+        config: RaftConfig,
     ) -> Result<Self> {
-        let applied_index = state.applied_index();
+        config.validate()?;
+        let mut applied_index = state.applied_index();
         if applied_index > log.commit_index {
             return Err(Error::Internal(format!(
                 "State machine applied index {} greater than log committed index {}",
@@ -41,6 +46,19 @@ impl Node {
 
         let (state_tx, state_rx) = mpsc::unbounded_channel();
         let mut driver = Driver::new(state_rx, node_tx.clone());
+        if applied_index < log.snapshot_index {
+            let snapshot = log.load_snapshot()?.ok_or_else(|| {
+                Error::Internal(format!("Snapshot for index {} not found", log.snapshot_index))
+            })?;
+            info!("Restoring state machine from snapshot at index {}", snapshot.index);
+            driver.restore(&mut *state, snapshot.index, snapshot.data)?;
+            applied_index = snapshot.index;
+        } else if applied_index > 0 {
+            let sessions_index = driver.load_sessions(&*state, log.load_snapshot()?)?;
+            driver.replay_sessions(log.scan((sessions_index + 1)..=applied_index))?;
+        }
+        driver.applied_index = applied_index;
+        driver.snapshot_index = log.snapshot_index;
         if log.commit_index > applied_index {
             info!("Replaying log entries {} to {}", applied_index + 1, log.commit_index);
             driver.replay(&mut *state, log.scan((applied_index + 1)..=log.commit_index))?;
@@ -48,7 +66,8 @@ impl Node {
         tokio::spawn(driver.drive(state));
 
         let (term, voted_for) = log.load_term()?;
-        let node = RoleNode {
+        let role = Follower::new(None, voted_for.as_deref(), random_election_timeout(&config));
+        let mut node = RoleNode {
             id: id.to_owned(),
             peers,
             term,
@@ -57,9 +76,12 @@ impl Node {
             state_tx,
             queued_reqs: Vec::new(),
             proxied_reqs: HashMap::new(),
-            role: Follower::new(None, voted_for.as_deref()),
+            read_reqs: HashMap::new(),
+            config,
+            role,
         };
-        if node.peers.is_empty() {
+        node.update_peers();
+        if node.peers.is_empty() && node.is_voter(id) {
             info!("No peers specified, starting as leader");
             let last_index = node.log.last_index;
             Ok(node.become_role(Leader::new(vec![], last_index))?.into())
@@ -77,6 +99,15 @@ impl Node {
         }
     }
 
+    /// Returns the latest membership in the log, if it was changed from the initial peers.
+    pub fn membership(&self) -> Option<&Membership> {
+        match self {
+            Node::Candidate(n) => n.log.membership.as_ref(),
+            Node::Follower(n) => n.log.membership.as_ref(),
+            Node::Leader(n) => n.log.membership.as_ref(),
+        }
+    }
+
     /// Processes a message.
     pub fn step(self, msg: Message) -> Result<Self> {
         debug!("Stepping {:?}", msg);
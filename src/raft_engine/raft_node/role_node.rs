@@ -2,13 +2,14 @@ use crate::{
     error::{Error, Result},
     raft_engine::{
         machine_state::Instruction,
-        messaging::{Address, Event, Message},
-        raft_log::RaftLog
+        messaging::{Address, Event, Message, Request, Response},
+        raft_log::RaftLog,
+        raft_node::{Membership, RaftConfig}
     },
 };
 
 use log::debug;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio::sync::mpsc;
 
 
@@ -24,6 +25,11 @@ pub struct RoleNode<R> {
     pub queued_reqs: Vec<(Address, Event)>,
     /// Keeps track of proxied client requests, to abort on new leader election.
     pub proxied_reqs: HashMap<Vec<u8>, Address>,
+    /// Keeps track of local queries awaiting the leader's read index, as the client address and
+    /// query command.
+    pub read_reqs: HashMap<Vec<u8>, (Address, Vec<u8>)>,
+    /// Raft timing and tuning parameters.
+    pub config: RaftConfig,
     pub role: R,
 }
 
@@ -39,24 +45,77 @@ impl<R> RoleNode<R> {
             state_tx: self.state_tx,
             queued_reqs: self.queued_reqs,
             proxied_reqs: self.proxied_reqs,
+            read_reqs: self.read_reqs,
+            config: self.config,
             role,
         })
     }
 
-    /// Aborts any proxied requests.
+    /// Aborts any proxied requests, including local queries awaiting a read index.
     pub fn abort_proxied(&mut self) -> Result<()> {
         for (id, address) in std::mem::take(&mut self.proxied_reqs) {
             self.send(address, Event::ClientResponse { id, response: Err(Error::Abort) })?;
         }
+        for (id, (address, _)) in std::mem::take(&mut self.read_reqs) {
+            self.send(address, Event::ClientResponse { id, response: Err(Error::Abort) })?;
+        }
         Ok(())
     }
 
+    /// Requests the leader's read index for a query, to execute it locally once the local state
+    /// machine has applied the index.
+    pub fn read_index(
+        &mut self,
+        leader: &str,
+        id: Vec<u8>,
+        address: Address,
+        command: Vec<u8>,
+    ) -> Result<()> {
+        self.read_reqs.insert(id.clone(), (address, command));
+        self.send(
+            Address::Peer(leader.to_string()),
+            Event::ClientRequest { id, request: Request::ReadIndex },
+        )
+    }
+
+    /// Handles a response to a proxied request. Read index responses for local queries submit
+    /// the query to the state machine, while other responses are passed on to the client. An
+    /// unexpected response to a read index request is returned to the client as an error.
+    pub fn proxy_response(&mut self, id: Vec<u8>, mut response: Result<Response>) -> Result<()> {
+        if let Some((address, command)) = self.read_reqs.remove(&id) {
+            return match response {
+                Ok(Response::ReadIndex(index)) => Ok(self.state_tx.send(Instruction::Query {
+                    id,
+                    address,
+                    command,
+                    term: self.term,
+                    index,
+                    quorum: None,
+                })?),
+                Ok(response) => {
+                    let error = format!("Unexpected read index response {:?}", response);
+                    let response = Err(Error::Value(error));
+                    self.send(address, Event::ClientResponse { id, response })
+                }
+                Err(error) => {
+                    self.send(address, Event::ClientResponse { id, response: Err(error) })
+                }
+            };
+        }
+        if let Ok(Response::Status(ref mut status)) = response {
+            status.server = self.id.clone();
+        }
+        self.proxied_reqs.remove(&id);
+        self.send(Address::Client, Event::ClientResponse { id, response })
+    }
+
     /// Sends any queued requests to the given leader.
     pub fn forward_queued(&mut self, leader: Address) -> Result<()> {
         for (from, event) in std::mem::take(&mut self.queued_reqs) {
             if let Event::ClientRequest { id, .. } = &event {
                 self.proxied_reqs.insert(id.clone(), from.clone());
                 self.node_tx.send(Message {
+                    group: 0,
                     from: match from {
                         Address::Client => Address::Local,
                         address => address,
@@ -70,32 +129,73 @@ impl<R> RoleNode<R> {
         Ok(())
     }
 
-    /// Returns the quorum size of the cluster.
-    pub fn quorum(&self) -> u64 {
-        (self.peers.len() as u64 + 1) / 2 + 1
+    /// Returns the cluster membership: the latest membership in the log, or the initial peers.
+    pub fn membership(&self) -> Membership {
+        match &self.log.membership {
+            Some(membership) => membership.clone(),
+            None => Membership::new(self.peers.iter().chain(std::iter::once(&self.id)).cloned()),
+        }
+    }
+
+    /// Updates the peers from the latest membership in the log, if any.
+    pub fn update_peers(&mut self) {
+        if let Some(membership) = &self.log.membership {
+            self.peers = membership.nodes().into_iter().filter(|id| id != &self.id).collect();
+        }
+    }
+
+    /// Checks if a node is a voter in the current membership.
+    pub fn is_voter(&self, id: &str) -> bool {
+        match &self.log.membership {
+            Some(membership) => membership.is_voter(id),
+            None => id == self.id || self.peers.iter().any(|p| p == id),
+        }
+    }
+
+    /// Checks if the given nodes form a quorum of the cluster, accounting for joint consensus.
+    pub fn has_quorum(&self, votes: &HashSet<String>) -> bool {
+        self.membership().has_quorum(votes)
     }
 
     /// Sends an event
     pub fn send(&self, to: Address, event: Event) -> Result<()> {
-        let msg = Message { term: self.term, from: Address::Local, to, event };
+        self.send_term(to, self.term, event)
+    }
+
+    /// Sends an event for a given term, used for pre-votes which don't change the current term.
+    pub fn send_term(&self, to: Address, term: u64, event: Event) -> Result<()> {
+        let msg = Message { group: 0, term, from: Address::Local, to, event };
         debug!("Sending {:?}", msg);
         Ok(self.node_tx.send(msg)?)
     }
 
+    /// Checks if a candidate's log is at least as up-to-date as ours, as required to vote for it.
+    pub fn is_log_current(&self, last_index: u64, last_term: u64) -> bool {
+        last_term > self.log.last_term
+            || (last_term == self.log.last_term && last_index >= self.log.last_index)
+    }
+
     /// Validates a message
     pub fn validate(&self, msg: &Message) -> Result<()> {
         match msg.from {
             Address::Peers => return Err(Error::Internal("Message from broadcast address".into())),
-            Address::Local => return Err(Error::Internal("Message from local node".into())),
+            Address::Local if !matches!(msg.event, Event::Snapshot { .. }) => {
+                return Err(Error::Internal("Message from local node".into()));
+            }
             Address::Client if !matches!(msg.event, Event::ClientRequest { .. }) => {
                 return Err(Error::Internal("Non-request message from client".into()));
             }
             _ => {}
         }
 
-        // Allowing requests and responses form past terms is fine, since they don't rely on it
+        // Allowing requests and responses form past terms is fine, since they don't rely on it.
+        // Local snapshots are not tied to a term either, and pre-votes are checked by the roles.
         if msg.term < self.term
-            && !matches!(msg.event, Event::ClientRequest { .. } | Event::ClientResponse { .. })
+            && !msg.is_pre_vote()
+            && !matches!(
+                msg.event,
+                Event::ClientRequest { .. } | Event::ClientResponse { .. } | Event::Snapshot { .. }
+            )
         {
             return Err(Error::Internal(format!("Message from past term {}", msg.term)));
         }
@@ -0,0 +1,455 @@
+//! A deterministic, in-process simulation of a Raft cluster. Nodes are driven synchronously via
+//! step and tick, their outbound messages are routed through a simulated network that can drop,
+//! delay, duplicate, reorder and partition messages, and Raft safety invariants are checked after
+//! every step. All randomness is derived from a single seed, so a failing run can be reproduced.
+//!
+//! Client requests are only submitted to leaders: the state machine drivers run as asynchronous
+//! tasks, and responses they send to peers (e.g. for proxied requests) would otherwise enter the
+//! network at nondeterministic points.
+
+use super::{seed_election_timeouts, Node, RaftConfig};
+use crate::{
+    error::{Error, Result},
+    raft_engine::{
+        machine_state::tests::TestState,
+        messaging::{Address, Event, Message, Request},
+        raft_log::{Entry, RaftLog},
+    },
+    storage_engine::log_storage::LogMemory,
+};
+
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tokio::sync::mpsc;
+
+/// Simulated network faults.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// The probability of dropping a message.
+    pub drop: f64,
+    /// The probability of delivering a message twice.
+    pub duplicate: f64,
+    /// The maximum message delay, in ticks. Delays are uniformly distributed, so messages sent
+    /// with a nonzero maximum delay are also reordered.
+    pub max_delay: u64,
+}
+
+/// A simulated Raft cluster.
+pub struct Cluster {
+    /// The node IDs, in order.
+    ids: Vec<String>,
+    /// The nodes, by ID.
+    nodes: HashMap<String, Node>,
+    /// The outbound message receivers of each node, by ID.
+    node_rxs: HashMap<String, mpsc::UnboundedReceiver<Message>>,
+    /// The network faults.
+    faults: Faults,
+    /// Partitioned links, as (from, to) pairs. Messages across them are dropped.
+    partitions: HashSet<(String, String)>,
+    /// The random number generator for network faults.
+    rng: StdRng,
+    /// The current simulated time, in ticks.
+    now: u64,
+    /// Messages in flight, keyed by delivery time and a random tie-breaker, with the recipient.
+    network: BTreeMap<(u64, u64), (String, Message)>,
+    /// The leader of each term seen so far.
+    leaders: HashMap<u64, String>,
+    /// The log of each node as of its last check.
+    views: HashMap<String, LogView>,
+    /// Committed entries seen so far, by index, with the lowest term of any node observing them.
+    committed: BTreeMap<u64, (Entry, u64)>,
+}
+
+/// A snapshot of a node's log, used when checking invariants.
+struct LogView {
+    term: u64,
+    is_leader: bool,
+    snapshot_index: u64,
+    commit_index: u64,
+    entries: Vec<Entry>,
+}
+
+impl LogView {
+    /// Returns the entry at the given index, if present and not compacted.
+    fn get(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot_index - 1) as usize)
+    }
+}
+
+impl Cluster {
+    /// Creates a new cluster with the given number of nodes, named a, b, c, and so on.
+    pub async fn new(size: usize, seed: u64) -> Result<Self> {
+        seed_election_timeouts(seed);
+        let ids: Vec<String> = (0..size).map(|i| ((b'a' + i as u8) as char).to_string()).collect();
+        let mut nodes = HashMap::new();
+        let mut node_rxs = HashMap::new();
+        for id in &ids {
+            let peers = ids.iter().filter(|p| *p != id).cloned().collect();
+            let (node_tx, node_rx) = mpsc::unbounded_channel();
+            let log = RaftLog::new(Box::new(LogMemory::new()))?;
+            let state = Box::new(TestState::new(0));
+            let node = Node::new(id, peers, log, state, node_tx, RaftConfig::default()).await?;
+            nodes.insert(id.clone(), node);
+            node_rxs.insert(id.clone(), node_rx);
+        }
+        let mut cluster = Self {
+            ids,
+            nodes,
+            node_rxs,
+            faults: Faults::default(),
+            partitions: HashSet::new(),
+            rng: StdRng::seed_from_u64(seed),
+            now: 0,
+            network: BTreeMap::new(),
+            leaders: HashMap::new(),
+            views: HashMap::new(),
+            committed: BTreeMap::new(),
+        };
+        for id in cluster.ids.clone() {
+            cluster.check(&id)?;
+        }
+        Ok(cluster)
+    }
+
+    /// Sets the network faults.
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.faults = faults
+    }
+
+    /// Partitions the given nodes from the rest of the cluster, in both directions.
+    pub fn partition(&mut self, group: &[&str]) {
+        for a in group {
+            for b in self.ids.iter().filter(|b| !group.contains(&b.as_str())) {
+                self.partitions.insert((a.to_string(), b.clone()));
+                self.partitions.insert((b.clone(), a.to_string()));
+            }
+        }
+    }
+
+    /// Heals all network partitions.
+    pub fn heal(&mut self) {
+        self.partitions.clear()
+    }
+
+    /// Returns the node IDs.
+    pub fn ids(&self) -> &[String] {
+        &self.ids
+    }
+
+    /// Returns the node with the given ID.
+    fn node(&self, id: &str) -> &Node {
+        &self.nodes[id]
+    }
+
+    /// Returns a node's log, term, and whether it is a leader.
+    fn log(&self, id: &str) -> (&RaftLog, u64, bool) {
+        match self.node(id) {
+            Node::Candidate(n) => (&n.log, n.term, false),
+            Node::Follower(n) => (&n.log, n.term, false),
+            Node::Leader(n) => (&n.log, n.term, true),
+        }
+    }
+
+    /// Returns the leaders, i.e. nodes that currently consider themselves leader, with their term.
+    pub fn leaders(&self) -> Vec<(String, u64)> {
+        self.ids
+            .iter()
+            .filter_map(|id| match self.node(id) {
+                Node::Leader(n) => Some((id.clone(), n.term)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the leader with the highest term, if any.
+    pub fn leader(&self) -> Option<String> {
+        self.leaders().into_iter().max_by_key(|(_, term)| *term).map(|(id, _)| id)
+    }
+
+    /// Returns a node's log view.
+    fn view(&self, id: &str) -> Result<LogView> {
+        let (log, term, is_leader) = self.log(id);
+        Ok(LogView {
+            term,
+            is_leader,
+            snapshot_index: log.snapshot_index,
+            commit_index: log.commit_index,
+            entries: log.scan((log.snapshot_index + 1)..).collect::<Result<_>>()?,
+        })
+    }
+
+    /// Returns a node's last log index and commit index.
+    pub fn indexes(&self, id: &str) -> (u64, u64) {
+        let (log, _, _) = self.log(id);
+        (log.last_index, log.commit_index)
+    }
+
+    /// Submits a client request to a node, which must be a leader.
+    pub fn request(&mut self, id: &str, request: Request) -> Result<()> {
+        if !matches!(self.node(id), Node::Leader(_)) {
+            return Err(Error::Value(format!("Node {} is not a leader", id)));
+        }
+        let request_id = self.rng.gen::<u64>().to_be_bytes().to_vec();
+        self.step(
+            id,
+            Message {
+                group: 0,
+                from: Address::Client,
+                to: Address::Local,
+                term: 0,
+                event: Event::ClientRequest { id: request_id, request },
+            },
+        )
+    }
+
+    /// Steps a message on a node, routes its outbound messages and checks invariants.
+    fn step(&mut self, id: &str, msg: Message) -> Result<()> {
+        let node = self.nodes.remove(id).expect("unknown node");
+        self.nodes.insert(id.to_string(), node.step(msg)?);
+        self.route(id)?;
+        self.check(id)
+    }
+
+    /// Delivers the next message that is due, if any. Returns true if a message was delivered.
+    pub fn deliver(&mut self) -> Result<bool> {
+        let key = match self.network.keys().next() {
+            Some(key) if key.0 <= self.now => *key,
+            _ => return Ok(false),
+        };
+        let (to, msg) = self.network.remove(&key).expect("message not found");
+        self.step(&to, msg)?;
+        Ok(true)
+    }
+
+    /// Delivers all due messages, then advances time by one tick on every node.
+    pub fn tick(&mut self) -> Result<()> {
+        while self.deliver()? {}
+        self.now += 1;
+        for id in self.ids.clone() {
+            let node = self.nodes.remove(&id).expect("unknown node");
+            self.nodes.insert(id.clone(), node.tick()?);
+            self.route(&id)?;
+            self.check(&id)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the cluster for the given number of ticks.
+    pub fn run(&mut self, ticks: u64) -> Result<()> {
+        for _ in 0..ticks {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    /// Routes a node's outbound messages into the network, applying faults and partitions.
+    /// Local messages are delivered to the node itself without faults, and client responses are
+    /// discarded.
+    fn route(&mut self, id: &str) -> Result<()> {
+        let node_rx = self.node_rxs.get_mut(id).expect("unknown node");
+        let mut msgs = Vec::new();
+        while let Ok(msg) = node_rx.try_recv() {
+            msgs.push(msg);
+        }
+        for mut msg in msgs {
+            let to = match &msg.to {
+                Address::Client => continue,
+                Address::Local => {
+                    let key = (self.now, self.rng.gen());
+                    self.network.insert(key, (id.to_string(), msg));
+                    continue;
+                }
+                Address::Peer(peer) => vec![peer.clone()],
+                Address::Peers => self.ids.iter().filter(|p| *p != id).cloned().collect(),
+            };
+            if msg.from == Address::Local {
+                msg.from = Address::Peer(id.to_string());
+            }
+            for peer in to {
+                if self.partitions.contains(&(id.to_string(), peer.clone()))
+                    || self.rng.gen_bool(self.faults.drop)
+                {
+                    continue;
+                }
+                let copies = if self.rng.gen_bool(self.faults.duplicate) { 2 } else { 1 };
+                for _ in 0..copies {
+                    let delay = self.rng.gen_range(0..=self.faults.max_delay);
+                    let key = (self.now + delay, self.rng.gen());
+                    self.network.insert(key, (peer.clone(), msg.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks Raft safety invariants, returning an error if any are violated:
+    ///
+    /// * Election safety: at most one leader per term.
+    /// * Log matching: if two logs contain an entry with the same index and term, the logs are
+    ///   identical up to that index.
+    /// * State machine safety: a committed entry is never changed or removed.
+    /// * Leader completeness: a leader contains all entries committed in earlier terms.
+    ///
+    /// Only the given node has changed since the last check, so only its log is reloaded.
+    fn check(&mut self, id: &str) -> Result<()> {
+        let view = self.view(id)?;
+        self.views.insert(id.to_string(), view);
+        let view = &self.views[id];
+
+        if view.is_leader {
+            let leader = self.leaders.entry(view.term).or_insert_with(|| id.to_string());
+            if leader != id {
+                return Err(Error::Internal(format!(
+                    "Multiple leaders {} and {} in term {}",
+                    leader, id, view.term
+                )));
+            }
+        }
+
+        for (peer, other) in self.views.iter().filter(|(peer, _)| *peer != id) {
+            let from = view.snapshot_index.max(other.snapshot_index) + 1;
+            let to = (view.snapshot_index + view.entries.len() as u64)
+                .min(other.snapshot_index + other.entries.len() as u64);
+            let Some(matched) = (from..=to).rev().find(|i| view.get(*i) == other.get(*i)) else {
+                continue;
+            };
+            if let Some(index) = (from..=matched).find(|i| view.get(*i) != other.get(*i)) {
+                return Err(Error::Internal(format!(
+                    "Logs of {} and {} match at index {} but differ at index {}",
+                    id, peer, matched, index
+                )));
+            }
+        }
+
+        for index in (view.snapshot_index + 1)..=view.commit_index {
+            let entry = view.get(index).expect("committed entry missing");
+            let (committed, term) =
+                self.committed.entry(index).or_insert_with(|| (entry.clone(), view.term));
+            if committed != entry {
+                return Err(Error::Internal(format!(
+                    "Node {} committed {:?}, but {:?} was already committed",
+                    id, entry, committed
+                )));
+            }
+            *term = (*term).min(view.term);
+        }
+
+        for (id, view) in self.views.iter().filter(|(_, v)| v.is_leader) {
+            for (index, (entry, term)) in self.committed.range((view.snapshot_index + 1)..) {
+                if view.term > *term && view.get(*index) != Some(entry) {
+                    return Err(Error::Internal(format!(
+                        "Leader {} in term {} is missing committed entry {:?}",
+                        id, view.term, entry
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the cluster until a leader is elected, up to the given number of ticks.
+    fn elect(cluster: &mut Cluster, ticks: u64) -> Result<String> {
+        for _ in 0..ticks {
+            if let Some(leader) = cluster.leader() {
+                return Ok(leader);
+            }
+            cluster.tick()?;
+        }
+        cluster.leader().ok_or_else(|| Error::Internal("No leader elected".into()))
+    }
+
+    /// Asserts that all nodes have committed everything in the leader's log.
+    fn assert_converged(cluster: &Cluster) {
+        let leader = cluster.leader().expect("no leader");
+        let (last_index, commit_index) = cluster.indexes(&leader);
+        assert_eq!(last_index, commit_index, "leader {} has uncommitted entries", leader);
+        for id in cluster.ids() {
+            assert_eq!(cluster.indexes(id), (last_index, commit_index), "node {} lags", id);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn election() -> Result<()> {
+        for seed in 0..10 {
+            let mut cluster = Cluster::new(3, seed).await?;
+            elect(&mut cluster, 50)?;
+            cluster.run(20)?;
+            assert_eq!(cluster.leaders().len(), 1, "seed {}", seed);
+            assert_converged(&cluster);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn faults() -> Result<()> {
+        for seed in 0..10 {
+            let mut cluster = Cluster::new(5, seed).await?;
+            let mut rng = StdRng::seed_from_u64(seed);
+            cluster.set_faults(Faults { drop: 0.1, duplicate: 0.1, max_delay: 2 });
+            for tick in 0..300 {
+                if tick % 50 == 0 {
+                    cluster.heal();
+                    let id = cluster.ids()[rng.gen_range(0..5)].clone();
+                    cluster.partition(&[&id]);
+                }
+                if tick % 3 == 0 {
+                    if let Some(leader) = cluster.leader() {
+                        cluster.request(&leader, Request::Mutate(vec![tick as u8]))?;
+                    }
+                }
+                cluster.tick()?;
+            }
+
+            // Once the network recovers, the cluster must converge.
+            cluster.heal();
+            cluster.set_faults(Faults::default());
+            cluster.run(50)?;
+            let leader = elect(&mut cluster, 50)?;
+            cluster.request(&leader, Request::Mutate(vec![0xff]))?;
+            cluster.run(10)?;
+            assert_converged(&cluster);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn partition_leader() -> Result<()> {
+        let mut cluster = Cluster::new(5, 0).await?;
+        let old = elect(&mut cluster, 50)?;
+        cluster.run(5)?;
+        let (old_last, _) = cluster.indexes(&old);
+
+        // An isolated leader can't commit writes, and the majority elects a new leader. The old
+        // leader steps down once it notices that it has lost contact with the quorum.
+        cluster.partition(&[&old]);
+        cluster.request(&old, Request::Mutate(vec![0x01]))?;
+        cluster.run(50)?;
+        assert_eq!(cluster.indexes(&old), (old_last + 1, old_last));
+        let leaders = cluster.leaders();
+        assert_eq!(leaders.len(), 1);
+        let new = leaders[0].0.clone();
+        assert_ne!(new, old);
+        cluster.request(&new, Request::Mutate(vec![0x02]))?;
+        cluster.run(5)?;
+
+        // When the partition heals, the old leader's write is discarded.
+        cluster.heal();
+        cluster.run(20)?;
+        assert_eq!(cluster.leaders().len(), 1);
+        assert_converged(&cluster);
+        let (last_index, _) = cluster.indexes(&old);
+        let entries = cluster.view(&old)?.entries;
+        assert_eq!(entries.len() as u64, last_index);
+        assert!(entries.iter().all(|e| e.command != Some(vec![0x01])));
+        assert!(entries.iter().any(|e| e.command == Some(vec![0x02])));
+        Ok(())
+    }
+}
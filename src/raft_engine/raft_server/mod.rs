@@ -1,10 +1,15 @@
 
+mod tls;
+mod transport;
+pub use tls::*;
+pub use transport::*;
+
 use crate::{
     error::{Error, Result},
     raft_engine::{
         machine_state::MachineState,
-        messaging::{Address, Event, Message, Request, Response},
-        raft_node::Node,
+        messaging::{Address, Event, GroupHeartbeat, Message, Request, Response},
+        raft_node::{Node, RaftConfig},
         raft_log::RaftLog
     }
 };
@@ -12,169 +17,372 @@ use crate::{
 use log::{debug, error};
 use futures::{sink::SinkExt as _, FutureExt as _};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream, UnboundedReceiverStream};
-use tokio_stream::StreamExt as _;
+use tokio_stream::{StreamExt as _, StreamMap};
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use uuid::Uuid;
 
-/// The duration of a Raft tick, the unit of time for e.g. heartbeats and elections.
-const TICK: Duration = Duration::from_millis(100);
-
-/// A Raft server.
+/// A Raft server. It hosts one or more Raft groups, each with its own node, log and state
+/// machine, replicated across the same set of peers. Messages for all groups are multiplexed over
+/// shared peer connections by their group ID, and all groups are ticked together. The periodic
+/// heartbeats emitted by a tick are coalesced into a single message per peer, and fanned out to
+/// the groups by the receiving server. Heartbeats emitted outside of a tick (on election or for
+/// read quorums) are sent immediately.
 pub struct Server {
-    pub node: Node,
+    pub id: String,
+    pub nodes: HashMap<u64, Node>,
     pub peers: HashMap<String, String>,
-    pub node_rx: mpsc::UnboundedReceiver<Message>,
+    pub node_rxs: HashMap<u64, mpsc::UnboundedReceiver<Message>>,
+    pub config: RaftConfig,
 }
 
 impl Server {
-    /// Creates a new Raft cluster
+    /// Creates a new Raft server, hosting group 0.
     pub async fn new(
         id: &str,
         peers: HashMap<String, String>,
         log: RaftLog,
         state: Box<dyn MachineState>,
+        config: RaftConfig,
     ) -> Result<Self> {
-        let (node_tx, node_rx) = mpsc::unbounded_channel();
-        Ok(Self {
-            node: Node::new(
-                id,
-                peers.iter().map(|(k, _)| k.to_string()).collect(),
-                log,
-                state,
-                node_tx,
-            )
-            .await?,
+        let mut server = Self {
+            id: id.to_string(),
+            nodes: HashMap::new(),
             peers,
-            node_rx,
-        })
+            node_rxs: HashMap::new(),
+            config,
+        };
+        server.add_group(0, log, state).await?;
+        Ok(server)
+    }
+
+    /// Adds a Raft group to the server, with its own log and state machine. Groups can only be
+    /// added before serve() is called, since the running event loop owns the nodes, so all
+    /// groups must be provisioned up front (e.g. a pool of groups for range splits).
+    pub async fn add_group(
+        &mut self,
+        group: u64,
+        log: RaftLog,
+        state: Box<dyn MachineState>,
+    ) -> Result<()> {
+        if self.nodes.contains_key(&group) {
+            return Err(Error::Value(format!("Raft group {} already exists", group)));
+        }
+        let (node_tx, node_rx) = mpsc::unbounded_channel();
+        let node = Node::new(
+            &self.id,
+            self.peers.keys().map(|k| k.to_string()).collect(),
+            log,
+            state,
+            node_tx,
+            self.config.clone(),
+        )
+        .await?;
+        self.nodes.insert(group, node);
+        self.node_rxs.insert(group, node_rx);
+        Ok(())
     }
 
-    /// Connects to peers and serves requests.
+    /// Connects to peers via the given transport and serves requests. Client requests are
+    /// given with the ID of the Raft group to execute them in.
     pub async fn serve(
         self,
-        listener: TcpListener,
-        client_rx: mpsc::UnboundedReceiver<(Request, oneshot::Sender<Result<Response>>)>,
+        transport: impl Transport,
+        client_rx: mpsc::UnboundedReceiver<(u64, Request, oneshot::Sender<Result<Response>>)>,
     ) -> Result<()> {
-        let (tcp_in_tx, tcp_in_rx) = mpsc::unbounded_channel::<Message>();
-        let (tcp_out_tx, tcp_out_rx) = mpsc::unbounded_channel::<Message>();
-        let (task, tcp_receiver) = Self::tcp_receive(listener, tcp_in_tx).remote_handle();
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let (in_tx, in_rx) = mpsc::unbounded_channel::<Message>();
+        let (out_tx, out_rx) = mpsc::unbounded_channel::<Message>();
+        let (peer_tx, peer_rx) = mpsc::unbounded_channel::<(String, String)>();
+        let (task, receiver) = transport.receive(in_tx).remote_handle();
         tokio::spawn(task);
-        let (task, tcp_sender) =
-            Self::tcp_send(self.node.id(), self.peers, tcp_out_rx).remote_handle();
+        let (task, sender) = Self::send(
+            self.id,
+            self.peers.clone(),
+            transport,
+            out_rx,
+            peer_rx,
+            self.config.clone(),
+        )
+        .remote_handle();
         tokio::spawn(task);
-        let (task, eventloop) =
-            Self::eventloop(self.node, self.node_rx, client_rx, tcp_in_rx, tcp_out_tx)
-                .remote_handle();
+        let (task, eventloop) = Self::eventloop(
+            self.nodes,
+            self.peers,
+            self.node_rxs,
+            client_rx,
+            in_rx,
+            out_tx,
+            peer_tx,
+            self.config.tick(),
+        )
+        .remote_handle();
         tokio::spawn(task);
 
-        tokio::try_join!(tcp_receiver, tcp_sender, eventloop)?;
+        tokio::try_join!(receiver, sender, eventloop)?;
         Ok(())
     }
 
     /// Runs the event loop.
+    #[allow(clippy::too_many_arguments)]
     async fn eventloop(
-        mut node: Node,
-        node_rx: mpsc::UnboundedReceiver<Message>,
-        client_rx: mpsc::UnboundedReceiver<(Request, oneshot::Sender<Result<Response>>)>,
+        mut nodes: HashMap<u64, Node>,
+        mut peers: HashMap<String, String>,
+        node_rxs: HashMap<u64, mpsc::UnboundedReceiver<Message>>,
+        client_rx: mpsc::UnboundedReceiver<(u64, Request, oneshot::Sender<Result<Response>>)>,
         tcp_rx: mpsc::UnboundedReceiver<Message>,
         tcp_tx: mpsc::UnboundedSender<Message>,
+        peer_tx: mpsc::UnboundedSender<(String, String)>,
+        tick: Duration,
     ) -> Result<()> {
-        let mut node_rx = UnboundedReceiverStream::new(node_rx);
+        let mut node_rx = StreamMap::new();
+        for (group, rx) in node_rxs {
+            node_rx.insert(group, UnboundedReceiverStream::new(rx));
+        }
         let mut tcp_rx = UnboundedReceiverStream::new(tcp_rx);
         let mut client_rx = UnboundedReceiverStream::new(client_rx);
 
-        let mut ticker = tokio::time::interval(TICK);
+        let mut ticker = tokio::time::interval(tick);
         let mut requests = HashMap::<Vec<u8>, oneshot::Sender<Result<Response>>>::new();
         loop {
             tokio::select! {
-                _ = ticker.tick() => node = node.tick()?,
-
-                Some(msg) = tcp_rx.next() => node = node.step(msg)?,
-
-                Some(msg) = node_rx.next() => {
-                    match msg {
-                        Message{to: Address::Peer(_), ..} => tcp_tx.send(msg)?,
-                        Message{to: Address::Peers, ..} => tcp_tx.send(msg)?,
-                        Message{to: Address::Client, event: Event::ClientResponse{ id, response }, ..} => {
-                            if let Some(response_tx) = requests.remove(&id) {
-                                response_tx
-                                    .send(response)
-                                    .map_err(|e| Error::Internal(format!("Failed to send response {:?}", e)))?;
+                _ = ticker.tick() => {
+                    for group in nodes.keys().copied().collect::<Vec<_>>() {
+                        Self::transition(&mut nodes, group, |node| node.tick())?;
+                    }
+                    // Route the messages emitted by the tick, coalescing the groups' heartbeats
+                    // into a single message per peer.
+                    let mut heartbeats = Vec::new();
+                    while let Some(Some((group, mut msg))) = node_rx.next().now_or_never() {
+                        msg.group = group;
+                        match msg {
+                            Message {
+                                to: Address::Peers,
+                                term,
+                                event: Event::Heartbeat { commit_index, commit_term, round },
+                                ..
+                            } => {
+                                Self::add_peers(&nodes[&group], &mut peers, &peer_tx)?;
+                                heartbeats.push(GroupHeartbeat {
+                                    group,
+                                    term,
+                                    commit_index,
+                                    commit_term,
+                                    round,
+                                });
                             }
+                            msg => Self::route(
+                                msg,
+                                &mut nodes,
+                                &mut peers,
+                                &mut requests,
+                                &tcp_tx,
+                                &peer_tx,
+                            )?,
                         }
-                        _ => return Err(Error::Internal(format!("Unexpected message {:?}", msg))),
+                    }
+                    if !heartbeats.is_empty() {
+                        tcp_tx.send(Message {
+                            group: 0,
+                            term: 0,
+                            from: Address::Local,
+                            to: Address::Peers,
+                            event: Event::Heartbeats { heartbeats },
+                        })?;
                     }
                 }
 
-                Some((request, response_tx)) = client_rx.next() => {
+                Some(msg) = tcp_rx.next() => match msg.event {
+                    Event::Heartbeats { heartbeats } => {
+                        for GroupHeartbeat { group, term, commit_index, commit_term, round }
+                            in heartbeats
+                        {
+                            Self::deliver(&mut nodes, Message {
+                                group,
+                                term,
+                                from: msg.from.clone(),
+                                to: msg.to.clone(),
+                                event: Event::Heartbeat { commit_index, commit_term, round },
+                            })?;
+                        }
+                    }
+                    _ => Self::deliver(&mut nodes, msg)?,
+                },
+
+                Some((group, mut msg)) = node_rx.next() => {
+                    msg.group = group;
+                    Self::route(msg, &mut nodes, &mut peers, &mut requests, &tcp_tx, &peer_tx)?;
+                }
+
+                Some((group, request, response_tx)) = client_rx.next() => {
+                    if !nodes.contains_key(&group) {
+                        let error = Error::Value(format!("Unknown Raft group {}", group));
+                        let _ = response_tx.send(Err(error));
+                        continue;
+                    }
                     let id = Uuid::new_v4().as_bytes().to_vec();
                     requests.insert(id.clone(), response_tx);
-                    node = node.step(Message{
+                    let msg = Message{
+                        group,
                         from: Address::Client,
                         to: Address::Local,
                         term: 0,
                         event: Event::ClientRequest{id, request},
+                    };
+                    Self::transition(&mut nodes, group, |node| node.step(msg))?;
+                }
+            }
+        }
+    }
+
+    /// Routes a message emitted by the node of the message's group.
+    fn route(
+        msg: Message,
+        nodes: &mut HashMap<u64, Node>,
+        peers: &mut HashMap<String, String>,
+        requests: &mut HashMap<Vec<u8>, oneshot::Sender<Result<Response>>>,
+        tcp_tx: &mpsc::UnboundedSender<Message>,
+        peer_tx: &mpsc::UnboundedSender<(String, String)>,
+    ) -> Result<()> {
+        let group = msg.group;
+        if matches!(msg.to, Address::Peer(_) | Address::Peers) {
+            Self::add_peers(&nodes[&group], peers, peer_tx)?;
+        }
+        match msg {
+            Message { to: Address::Peer(_), .. } => tcp_tx.send(msg)?,
+            Message { to: Address::Peers, .. } => tcp_tx.send(msg)?,
+            Message { to: Address::Local, .. } => {
+                Self::transition(nodes, group, |node| node.step(msg))?
+            }
+            Message { to: Address::Client, event: Event::ClientResponse { id, response }, .. } => {
+                if let Some(response_tx) = requests.remove(&id) {
+                    response_tx.send(response).map_err(|e| {
+                        Error::Internal(format!("Failed to send response {:?}", e))
                     })?;
                 }
             }
+            _ => return Err(Error::Internal(format!("Unexpected message {:?}", msg))),
+        }
+        Ok(())
+    }
+
+    /// Delivers a message received from a peer to the node of its group, if any.
+    fn deliver(nodes: &mut HashMap<u64, Node>, msg: Message) -> Result<()> {
+        if nodes.contains_key(&msg.group) {
+            Self::transition(nodes, msg.group, |node| node.step(msg))
+        } else {
+            debug!("Discarding message for unknown Raft group {}", msg.group);
+            Ok(())
+        }
+    }
+
+    /// Transitions the node of the given group, which must exist, by ticking or stepping it.
+    fn transition(
+        nodes: &mut HashMap<u64, Node>,
+        group: u64,
+        f: impl FnOnce(Node) -> Result<Node>,
+    ) -> Result<()> {
+        let node = nodes
+            .remove(&group)
+            .ok_or_else(|| Error::Internal(format!("Unknown Raft group {}", group)))?;
+        nodes.insert(group, f(node)?);
+        Ok(())
+    }
+
+    /// Passes on the addresses of any new peers added via membership changes to the TCP sender.
+    fn add_peers(
+        node: &Node,
+        peers: &mut HashMap<String, String>,
+        peer_tx: &mpsc::UnboundedSender<(String, String)>,
+    ) -> Result<()> {
+        if let Some(membership) = node.membership() {
+            let node_id = node.id();
+            for (id, address) in &membership.addresses {
+                if id != &node_id && peers.get(id) != Some(address) {
+                    peers.insert(id.clone(), address.clone());
+                    peer_tx.send((id.clone(), address.clone()))?;
+                }
+            }
         }
+        Ok(())
     }
 
-    /// Receives inbound messages from peers via TCP.
-    async fn tcp_receive(
+    /// Serves remote clients via TCP, passing their requests on to the client channel of serve().
+    /// Remote clients use Raft group 0.
+    pub async fn serve_clients(
         listener: TcpListener,
-        in_tx: mpsc::UnboundedSender<Message>,
+        request_tx: mpsc::UnboundedSender<(u64, Request, oneshot::Sender<Result<Response>>)>,
     ) -> Result<()> {
         let mut listener = TcpListenerStream::new(listener);
         while let Some(socket) = listener.try_next().await? {
             let peer = socket.peer_addr()?;
-            let peer_in_tx = in_tx.clone();
+            let request_tx = request_tx.clone();
             tokio::spawn(async move {
-                debug!("Raft peer {} connected", peer);
-                match Self::tcp_receive_peer(socket, peer_in_tx).await {
-                    Ok(()) => debug!("Raft peer {} disconnected", peer),
-                    Err(err) => error!("Raft peer {} error: {}", peer, err.to_string()),
+                debug!("Client {} connected", peer);
+                match Self::serve_client(socket, request_tx).await {
+                    Ok(()) => debug!("Client {} disconnected", peer),
+                    Err(err) => error!("Client {} error: {}", peer, err),
                 };
             });
         }
         Ok(())
     }
 
-    /// Receives inbound messages from a peer via TCP.
-    async fn tcp_receive_peer(
+    /// Serves a remote client via TCP, executing one request at a time.
+    async fn serve_client(
         socket: TcpStream,
-        in_tx: mpsc::UnboundedSender<Message>,
+        request_tx: mpsc::UnboundedSender<(u64, Request, oneshot::Sender<Result<Response>>)>,
     ) -> Result<()> {
-        let mut stream = tokio_serde::SymmetricallyFramed::<_, Message, _>::new(
+        let mut stream = tokio_serde::Framed::<_, Request, Result<Response>, _>::new(
             Framed::new(socket, LengthDelimitedCodec::new()),
-            tokio_serde::formats::SymmetricalBincode::<Message>::default(),
+            tokio_serde::formats::Bincode::<Request, Result<Response>>::default(),
         );
-        while let Some(message) = stream.try_next().await? {
-            in_tx.send(message)?;
+        while let Some(request) = stream.try_next().await? {
+            let (response_tx, response_rx) = oneshot::channel();
+            request_tx.send((0, request, response_tx))?;
+            stream.send(response_rx.await?).await?;
         }
         Ok(())
     }
 
-    /// Sends outbound messages to peers via TCP.
-    async fn tcp_send(
+    /// Sends outbound messages to peers via the transport. Peers added later are received via
+    /// peer_rx.
+    async fn send(
         node_id: String,
         peers: HashMap<String, String>,
+        transport: Arc<dyn Transport>,
         out_rx: mpsc::UnboundedReceiver<Message>,
+        peer_rx: mpsc::UnboundedReceiver<(String, String)>,
+        config: RaftConfig,
     ) -> Result<()> {
         let mut out_rx = UnboundedReceiverStream::new(out_rx);
+        let mut peer_rx = UnboundedReceiverStream::new(peer_rx);
         let mut peer_txs: HashMap<String, mpsc::Sender<Message>> = HashMap::new();
 
         for (id, addr) in peers.into_iter() {
-            let (tx, rx) = mpsc::channel::<Message>(1000);
+            let (tx, rx) = mpsc::channel::<Message>(config.peer_buffer);
+            let (transport, config) = (transport.clone(), config.clone());
+            tokio::spawn(Self::send_peer(transport, id.clone(), addr, rx, config));
             peer_txs.insert(id, tx);
-            tokio::spawn(Self::tcp_send_peer(addr, rx));
         }
 
-        while let Some(mut message) = out_rx.next().await {
+        loop {
+            let mut message = tokio::select! {
+                Some((id, addr)) = peer_rx.next() => {
+                    debug!("Adding Raft peer {} at {}", id, addr);
+                    let (tx, rx) = mpsc::channel::<Message>(config.peer_buffer);
+                    let (transport, config) = (transport.clone(), config.clone());
+                    tokio::spawn(Self::send_peer(transport, id.clone(), addr, rx, config));
+                    peer_txs.insert(id, tx);
+                    continue;
+                }
+                Some(message) = out_rx.next() => message,
+                else => break,
+            };
             if message.from == Address::Local {
                 message.from = Address::Peer(node_id.clone())
             }
@@ -182,7 +390,7 @@ impl Server {
                 Address::Peers => peer_txs.keys().cloned().collect(),
                 Address::Peer(peer) => vec![peer.to_string()],
                 addr => {
-                    error!("Received outbound message for non-TCP address {:?}", addr);
+                    error!("Received outbound message for non-peer address {:?}", addr);
                     continue;
                 }
             };
@@ -202,37 +410,255 @@ impl Server {
         Ok(())
     }
 
-    /// Sends outbound messages to a peer, continuously reconnecting.
-    async fn tcp_send_peer(addr: String, out_rx: mpsc::Receiver<Message>) {
+    /// Sends outbound messages to a peer, continuously reconnecting with exponential backoff.
+    async fn send_peer(
+        transport: Arc<dyn Transport>,
+        id: String,
+        addr: String,
+        out_rx: mpsc::Receiver<Message>,
+        config: RaftConfig,
+    ) {
         let mut out_rx = ReceiverStream::new(out_rx);
+        let mut backoff = config.reconnect_backoff_min_ms;
         loop {
-            match TcpStream::connect(&addr).await {
-                Ok(socket) => {
+            match transport.connect(&id, &addr).await {
+                Ok(sink) => {
                     debug!("Connected to Raft peer {}", addr);
-                    match Self::tcp_send_peer_session(socket, &mut out_rx).await {
+                    backoff = config.reconnect_backoff_min_ms;
+                    match Self::send_peer_session(sink, &mut out_rx).await {
                         Ok(()) => break,
                         Err(err) => error!("Failed sending to Raft peer {}: {}", addr, err),
                     }
                 }
                 Err(err) => error!("Failed connecting to Raft peer {}: {}", addr, err),
             }
-            tokio::time::sleep(Duration::from_millis(1000)).await;
+            tokio::time::sleep(Duration::from_millis(backoff)).await;
+            backoff = (backoff * 2).min(config.reconnect_backoff_max_ms);
         }
         debug!("Disconnected from Raft peer {}", addr);
     }
 
-    /// Sends outbound messages to a peer via a TCP session.
-    async fn tcp_send_peer_session(
-        socket: TcpStream,
+    /// Sends outbound messages to a peer via a transport connection. Messages that are queued
+    /// together, e.g. heartbeats for all Raft groups after a tick, are flushed together.
+    async fn send_peer_session(
+        mut sink: MessageSink,
         out_rx: &mut ReceiverStream<Message>,
     ) -> Result<()> {
-        let mut stream = tokio_serde::SymmetricallyFramed::<_, Message, _>::new(
-            Framed::new(socket, LengthDelimitedCodec::new()),
-            tokio_serde::formats::SymmetricalBincode::<Message>::default(),
-        );
         while let Some(message) = out_rx.next().await {
-            stream.send(message).await?;
+            sink.feed(message).await?;
+            while let Some(Some(message)) = out_rx.next().now_or_never() {
+                sink.feed(message).await?;
+            }
+            sink.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_engine::{
+        machine_state::{KvMutation, KvState},
+        messaging::Handshake,
+        raft_client::{Client, RetryPolicy}
+    };
+    use crate::storage_engine::{key_value_storage::KvMemory, log_storage::LogMemory};
+    use pretty_assertions::assert_eq;
+    use std::collections::HashSet;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    // A remote client can mutate and query a single-node cluster via the client port.
+    async fn serve_clients() -> Result<()> {
+        let log = RaftLog::new(Box::new(LogMemory::new()))?;
+        let state = Box::new(KvState::new(Box::new(KvMemory::new()))?);
+        let server = Server::new("a", HashMap::new(), log, state, RaftConfig::default()).await?;
+        let raft_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client_addr = client_listener.local_addr()?;
+        let (client_tx, client_rx) = mpsc::unbounded_channel();
+        let transport = TcpTransport::new(raft_listener, Handshake::new("test", "a"));
+        tokio::spawn(server.serve(transport, client_rx));
+        tokio::spawn(Server::serve_clients(client_listener, client_tx));
+
+        let client = Client::connect(&client_addr.to_string()).await?;
+        let set = KvMutation::Set { key: b"a".to_vec(), value: vec![0x01] };
+        assert_eq!(client.mutate(bincode::serialize(&set)?).await?, Vec::<u8>::new());
+        let value: Option<Vec<u8>> = bincode::deserialize(&client.query(b"a".to_vec()).await?)?;
+        assert_eq!(value, Some(vec![0x01]));
+        assert!(matches!(client.mutate(vec![0xff]).await, Err(Error::Value(_))));
+
+        let status = client.status().await?;
+        assert_eq!(status.server, "a");
+        assert_eq!(status.leader, "a");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    // A cluster client connects to any reachable node, tracks the leader via status requests, and
+    // sends mutations in a session.
+    async fn serve_clients_cluster() -> Result<()> {
+        let log = RaftLog::new(Box::new(LogMemory::new()))?;
+        let state = Box::new(KvState::new(Box::new(KvMemory::new()))?);
+        let config = RaftConfig { tick_ms: 10, ..RaftConfig::default() };
+        let server = Server::new("b", HashMap::new(), log, state, config).await?;
+        let raft_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client_addr = client_listener.local_addr()?;
+        let (client_tx, client_rx) = mpsc::unbounded_channel();
+        let transport = TcpTransport::new(raft_listener, Handshake::new("test", "b"));
+        tokio::spawn(server.serve(transport, client_rx));
+        tokio::spawn(Server::serve_clients(client_listener, client_tx));
+
+        // Node a is unreachable, and is sorted first.
+        let unreachable = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let nodes = vec![("a", unreachable.to_string()), ("b", client_addr.to_string())]
+            .into_iter()
+            .map(|(id, addr)| (id.to_string(), addr))
+            .collect();
+        let retry = RetryPolicy { mutations: true, ..RetryPolicy::default() };
+        let client = Client::connect_cluster(nodes, retry).await?;
+        let status = client.status().await?;
+        assert_eq!(status.server, "b");
+        assert_eq!(status.leader, "b");
+
+        let set = KvMutation::Set { key: b"a".to_vec(), value: vec![0x01] };
+        client.mutate(bincode::serialize(&set)?).await?;
+        let value: Option<Vec<u8>> = bincode::deserialize(&client.query(b"a".to_vec()).await?)?;
+        assert_eq!(value, Some(vec![0x01]));
+
+        // A mutation retried in an explicit session is not reapplied.
+        let set = KvMutation::Set { key: b"a".to_vec(), value: vec![0x02] };
+        let session = client.open_session().await?;
+        client.session_mutate(session, 1, bincode::serialize(&set)?).await?;
+        let set = KvMutation::Set { key: b"a".to_vec(), value: vec![0x03] };
+        client.session_mutate(session, 1, bincode::serialize(&set)?).await?;
+        let value: Option<Vec<u8>> = bincode::deserialize(&client.query(b"a".to_vec()).await?)?;
+        assert_eq!(value, Some(vec![0x02]));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    // A cluster can run over an in-memory transport, with requests proxied to the leader.
+    async fn serve_memory_transport() -> Result<()> {
+        let network = MemoryNetwork::new();
+        let ids = ["a", "b", "c"];
+        let mut clients = HashMap::new();
+        for id in ids {
+            let peers = ids
+                .iter()
+                .filter(|peer| **peer != id)
+                .map(|peer| (peer.to_string(), format!("mem://{}", peer)))
+                .collect();
+            let log = RaftLog::new(Box::new(LogMemory::new()))?;
+            let state = Box::new(KvState::new(Box::new(KvMemory::new()))?);
+            let config = RaftConfig { tick_ms: 10, ..RaftConfig::default() };
+            let server = Server::new(id, peers, log, state, config).await?;
+            let (client_tx, client_rx) = mpsc::unbounded_channel();
+            let transport = network.transport(&format!("mem://{}", id));
+            tokio::spawn(server.serve(transport, client_rx));
+            clients.insert(id, Client::new(client_tx));
+        }
+
+        let set = KvMutation::Set { key: b"a".to_vec(), value: vec![0x01] };
+        clients["a"].mutate(bincode::serialize(&set)?).await?;
+        let value: Option<Vec<u8>> =
+            bincode::deserialize(&clients["c"].query(b"a".to_vec()).await?)?;
+        assert_eq!(value, Some(vec![0x01]));
+
+        let status = clients["b"].status().await?;
+        assert!(ids.contains(&status.leader.as_str()));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    // A server can host several Raft groups with separate state, sharing peer connections.
+    async fn serve_groups() -> Result<()> {
+        let network = MemoryNetwork::new();
+        let ids = ["a", "b", "c"];
+        let mut clients = HashMap::new();
+        for id in ids {
+            let peers: HashMap<String, String> = ids
+                .iter()
+                .filter(|peer| **peer != id)
+                .map(|peer| (peer.to_string(), peer.to_string()))
+                .collect();
+            let config = RaftConfig { tick_ms: 10, ..RaftConfig::default() };
+            let log = RaftLog::new(Box::new(LogMemory::new()))?;
+            let state = Box::new(KvState::new(Box::new(KvMemory::new()))?);
+            let mut server = Server::new(id, peers, log, state, config).await?;
+            for group in [1, 2] {
+                let log = RaftLog::new(Box::new(LogMemory::new()))?;
+                let state = Box::new(KvState::new(Box::new(KvMemory::new()))?);
+                server.add_group(group, log, state).await?;
+            }
+            let log = RaftLog::new(Box::new(LogMemory::new()))?;
+            let state = Box::new(KvState::new(Box::new(KvMemory::new()))?);
+            assert!(matches!(server.add_group(1, log, state).await, Err(Error::Value(_))));
+
+            let (client_tx, client_rx) = mpsc::unbounded_channel();
+            tokio::spawn(server.serve(network.transport(id), client_rx));
+            clients.insert(id, client_tx);
+        }
+
+        for group in [0, 1, 2] {
+            let client = Client::new_group(clients["a"].clone(), group);
+            let set = KvMutation::Set { key: b"group".to_vec(), value: vec![group as u8] };
+            client.mutate(bincode::serialize(&set)?).await?;
+        }
+        for group in [0, 1, 2] {
+            let client = Client::new_group(clients["c"].clone(), group);
+            let value: Option<Vec<u8>> =
+                bincode::deserialize(&client.query(b"group".to_vec()).await?)?;
+            assert_eq!(value, Some(vec![group as u8]));
+        }
+
+        let client = Client::new_group(clients["b"].clone(), 3);
+        assert!(matches!(client.status().await, Err(Error::Value(_))));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    // Heartbeats from all groups are coalesced into a single message per tick, and fanned out to
+    // the groups by the receiving server.
+    async fn serve_coalesced_heartbeats() -> Result<()> {
+        let network = MemoryNetwork::new();
+        let ids = ["a", "b", "c"];
+        for id in ["a", "b"] {
+            let peers: HashMap<String, String> = ids
+                .iter()
+                .filter(|peer| **peer != id)
+                .map(|peer| (peer.to_string(), peer.to_string()))
+                .collect();
+            let config = RaftConfig { tick_ms: 10, ..RaftConfig::default() };
+            let log = RaftLog::new(Box::new(LogMemory::new()))?;
+            let state = Box::new(KvState::new(Box::new(KvMemory::new()))?);
+            let mut server = Server::new(id, peers, log, state, config).await?;
+            for group in [1, 2] {
+                let log = RaftLog::new(Box::new(LogMemory::new()))?;
+                let state = Box::new(KvState::new(Box::new(KvMemory::new()))?);
+                server.add_group(group, log, state).await?;
+            }
+            let (_client_tx, client_rx) = mpsc::unbounded_channel();
+            tokio::spawn(server.serve(network.transport(id), client_rx));
+        }
+
+        // Node c never responds, but records the messages it receives. Once all groups have a
+        // leader, it should see a coalesced heartbeat covering every group. Heartbeats sent
+        // outside of ticks, e.g. on election, are not coalesced.
+        let (c_tx, mut c_rx) = mpsc::unbounded_channel();
+        tokio::spawn(network.transport("c").receive(c_tx));
+        let mut seen = HashSet::new();
+        while seen.len() < 3 {
+            let msg = tokio::time::timeout(Duration::from_secs(10), c_rx.recv())
+                .await
+                .map_err(|_| Error::Internal("Timed out waiting for heartbeats".into()))?
+                .ok_or_else(|| Error::Internal("Transport closed".into()))?;
+            if let Event::Heartbeats { heartbeats } = msg.event {
+                seen.extend(heartbeats.iter().map(|heartbeat| heartbeat.group));
+            }
         }
+        assert_eq!(seen, HashSet::from([0, 1, 2]));
         Ok(())
     }
 }
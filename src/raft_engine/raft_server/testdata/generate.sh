@@ -0,0 +1,29 @@
+#!/usr/bin/env bash
+#
+# Generates self-signed test certificates for Raft peer TLS: a CA, node certificates for a and b
+# signed by it, and a certificate for b signed by an untrusted rogue CA.
+
+set -euo pipefail
+cd "$(dirname "$0")"
+
+ca() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1.key"
+    openssl req -x509 -new -key "$1.key" -out "$1.pem" -days 36500 -subj "/CN=$1" \
+        -addext "basicConstraints=critical,CA:TRUE" \
+        -addext "keyUsage=critical,keyCertSign,cRLSign"
+}
+
+node() {
+    local ext="subjectAltName=DNS:$2\nextendedKeyUsage=serverAuth,clientAuth\n"
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1.key"
+    openssl req -new -key "$1.key" -subj "/CN=$2" |
+        openssl x509 -req -CA "$3.pem" -CAkey "$3.key" -CAcreateserial -out "$1.pem" -days 36500 \
+            -extfile <(printf "$ext")
+}
+
+ca ca
+ca rogue-ca
+node a a ca
+node b b ca
+node rogue b rogue-ca
+rm -f ./*.srl ca.key rogue-ca.key rogue-ca.pem
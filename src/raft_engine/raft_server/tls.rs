@@ -0,0 +1,242 @@
+use super::transport::{connect_peer, receive_peer, MessageSink, Transport};
+use crate::{
+    error::{Error, Result},
+    raft_engine::messaging::{Handshake, Message},
+};
+
+use futures::future::BoxFuture;
+use futures::FutureExt as _;
+use log::{debug, error};
+use serde::Deserialize;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// TLS settings for Raft peer connections, given as paths to PEM files. Node certificates must
+/// be valid for their node ID as a DNS name, which peers use to authenticate them.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct TlsConfig {
+    /// The node's certificate chain.
+    pub cert: String,
+    /// The node's private key.
+    pub key: String,
+    /// The CA certificates used to verify peers.
+    pub ca: String,
+}
+
+/// A TCP transport secured with mutually authenticated TLS. Peers must identify themselves in the
+/// handshake with the node ID that their certificate is valid for.
+pub struct TlsTransport {
+    listener: Arc<TcpListener>,
+    handshake: Handshake,
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+impl TlsTransport {
+    /// Creates a new TLS transport, receiving peer messages via the given listener and
+    /// identifying the node to peers with the given handshake.
+    pub fn new(listener: TcpListener, handshake: Handshake, config: &TlsConfig) -> Result<Self> {
+        let certs = load_certs(&config.cert)?;
+        let key = load_key(&config.key)?;
+        authenticate(&certs[0], &handshake.node_id)
+            .map_err(|err| Error::Config(format!("Invalid node certificate: {}", err)))?;
+
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&config.ca)? {
+            roots.add(cert).map_err(|err| Error::Config(format!("Invalid CA: {}", err)))?;
+        }
+        let roots = Arc::new(roots);
+
+        let verifier = WebPkiClientVerifier::builder(roots.clone())
+            .build()
+            .map_err(|err| Error::Config(format!("Invalid CA: {}", err)))?;
+        let server = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs.clone(), key.clone_key())
+            .map_err(|err| Error::Config(format!("Invalid node certificate: {}", err)))?;
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)
+            .map_err(|err| Error::Config(format!("Invalid node certificate: {}", err)))?;
+
+        Ok(Self {
+            listener: Arc::new(listener),
+            handshake,
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            connector: TlsConnector::from(Arc::new(client)),
+        })
+    }
+
+    /// Receives inbound messages from a peer via a TLS session, checking that the peer's
+    /// certificate is valid for the node ID in its handshake.
+    async fn receive_peer(
+        acceptor: TlsAcceptor,
+        socket: TcpStream,
+        handshake: Handshake,
+        in_tx: mpsc::UnboundedSender<Message>,
+    ) -> Result<()> {
+        let stream = acceptor.accept(socket).await?;
+        let cert = match stream.get_ref().1.peer_certificates() {
+            Some([cert, ..]) => cert.clone(),
+            _ => return Err(Error::Value("Peer did not present a certificate".into())),
+        };
+        receive_peer(stream, &handshake, in_tx, |peer| {
+            authenticate(&cert, &peer.node_id)?;
+            debug!("Authenticated Raft peer {}", peer.node_id);
+            Ok(())
+        })
+        .await
+    }
+}
+
+impl Transport for TlsTransport {
+    fn receive(&self, in_tx: mpsc::UnboundedSender<Message>) -> BoxFuture<'static, Result<()>> {
+        let listener = self.listener.clone();
+        let acceptor = self.acceptor.clone();
+        let handshake = self.handshake.clone();
+        async move {
+            loop {
+                let (socket, peer) = listener.accept().await?;
+                let (acceptor, in_tx) = (acceptor.clone(), in_tx.clone());
+                let handshake = handshake.clone();
+                tokio::spawn(async move {
+                    debug!("Raft peer {} connected", peer);
+                    match Self::receive_peer(acceptor, socket, handshake, in_tx).await {
+                        Ok(()) => debug!("Raft peer {} disconnected", peer),
+                        Err(err) => error!("Raft peer {} error: {}", peer, err),
+                    };
+                });
+            }
+        }
+        .boxed()
+    }
+
+    fn connect(&self, id: &str, addr: &str) -> BoxFuture<'static, Result<MessageSink>> {
+        let connector = self.connector.clone();
+        let name = server_name(id);
+        let (id, addr, handshake) = (id.to_string(), addr.to_string(), self.handshake.clone());
+        async move {
+            let name = name?;
+            let socket = TcpStream::connect(addr).await?;
+            connect_peer(connector.connect(name, socket).await?, &handshake, &id).await
+        }
+        .boxed()
+    }
+}
+
+/// Checks that a peer certificate is valid for the given node ID. The certificate chain itself
+/// has already been verified during the TLS handshake.
+fn authenticate(cert: &CertificateDer, id: &str) -> Result<()> {
+    let name = server_name(id)?;
+    webpki::EndEntityCert::try_from(cert)
+        .and_then(|cert| cert.verify_is_valid_for_subject_name(&name))
+        .map_err(|err| Error::Value(format!("Certificate is not valid for node {}: {}", id, err)))
+}
+
+/// Returns the TLS server name for a node ID.
+fn server_name(id: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(id.to_string())
+        .map_err(|_| Error::Value(format!("Node ID {} is not a valid DNS name", id)))
+}
+
+/// Loads PEM certificates from a file.
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| Error::Config(format!("Failed to read {}: {}", path, err)))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(Error::Config(format!("No certificates found in {}", path)));
+    }
+    Ok(certs)
+}
+
+/// Loads a PEM private key from a file.
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| Error::Config(format!("Failed to read {}: {}", path, err)))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))?
+        .ok_or_else(|| Error::Config(format!("No private key found in {}", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_engine::messaging::{Address, Event};
+    use futures::sink::SinkExt as _;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    const TESTDATA: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/src/raft_engine/raft_server/testdata");
+
+    fn config(name: &str) -> TlsConfig {
+        TlsConfig {
+            cert: format!("{}/{}.pem", TESTDATA, name),
+            key: format!("{}/{}.key", TESTDATA, name),
+            ca: format!("{}/ca.pem", TESTDATA),
+        }
+    }
+
+    async fn transport(id: &str, name: &str) -> Result<(TlsTransport, String)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let handshake = Handshake::new("test", id);
+        Ok((TlsTransport::new(listener, handshake, &config(name))?, addr))
+    }
+
+    fn heartbeat(from: &str) -> Message {
+        Message {
+            group: 0,
+            from: Address::Peer(from.into()),
+            to: Address::Peer("a".into()),
+            term: 1,
+            event: Event::Heartbeat { commit_index: 0, commit_term: 0, round: 0 },
+        }
+    }
+
+    async fn recv(in_rx: &mut mpsc::UnboundedReceiver<Message>) -> Option<Message> {
+        tokio::time::timeout(Duration::from_millis(500), in_rx.recv()).await.ok().flatten()
+    }
+
+    #[tokio::test]
+    // The node certificate must be valid for the node ID.
+    async fn new_invalid() {
+        assert!(matches!(transport("b", "a").await, Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    // Peers authenticate each other, and can only send messages as themselves.
+    async fn authenticate() -> Result<()> {
+        let (a, a_addr) = transport("a", "a").await?;
+        let (b, _) = transport("b", "b").await?;
+        let (in_tx, mut in_rx) = mpsc::unbounded_channel();
+        tokio::spawn(a.receive(in_tx));
+
+        let mut sink = b.connect("a", &a_addr).await?;
+        sink.send(heartbeat("b")).await?;
+        assert_eq!(recv(&mut in_rx).await, Some(heartbeat("b")));
+
+        // Impersonating another node closes the connection.
+        sink.send(heartbeat("c")).await?;
+        let _ = sink.send(heartbeat("b")).await;
+        assert_eq!(recv(&mut in_rx).await, None);
+
+        // The peer name must match the server certificate.
+        assert!(b.connect("c", &a_addr).await.is_err());
+
+        // A certificate from an untrusted CA is rejected.
+        let (rogue, _) = transport("b", "rogue").await?;
+        if let Ok(mut sink) = rogue.connect("a", &a_addr).await {
+            let _ = sink.send(heartbeat("b")).await;
+        }
+        assert_eq!(recv(&mut in_rx).await, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,463 @@
+use crate::{
+    error::{Error, Result},
+    raft_engine::messaging::{Address, Event, Handshake, Message},
+};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::sink::{Sink, SinkExt};
+use futures::FutureExt as _;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt as _;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// An outbound message stream to a single peer.
+pub type MessageSink = Pin<Box<dyn Sink<Message, Error = Error> + Send>>;
+
+/// A transport carries Raft messages between peers. The server takes care of buffering,
+/// broadcasts, and reconnecting to peers, so a transport only needs to accept inbound connections
+/// and open outbound ones. Socket transports exchange handshakes when connecting, rejecting peers
+/// from other clusters or with incompatible protocol versions.
+pub trait Transport: Send + Sync + 'static {
+    /// Receives inbound messages from peers and passes them on to in_tx, until the transport fails
+    /// or in_tx is closed.
+    fn receive(&self, in_tx: mpsc::UnboundedSender<Message>) -> BoxFuture<'static, Result<()>>;
+
+    /// Opens an outbound connection to the given peer at the given address. If the sink fails,
+    /// the server discards it and reconnects.
+    fn connect(&self, id: &str, addr: &str) -> BoxFuture<'static, Result<MessageSink>>;
+}
+
+impl Transport for Box<dyn Transport> {
+    fn receive(&self, in_tx: mpsc::UnboundedSender<Message>) -> BoxFuture<'static, Result<()>> {
+        self.as_ref().receive(in_tx)
+    }
+
+    fn connect(&self, id: &str, addr: &str) -> BoxFuture<'static, Result<MessageSink>> {
+        self.as_ref().connect(id, addr)
+    }
+}
+
+/// A TCP transport, using length-delimited Bincode frames.
+pub struct TcpTransport {
+    listener: Arc<TcpListener>,
+    handshake: Handshake,
+}
+
+impl TcpTransport {
+    /// Creates a new TCP transport, receiving peer messages via the given listener and
+    /// identifying the node to peers with the given handshake.
+    pub fn new(listener: TcpListener, handshake: Handshake) -> Self {
+        Self { listener: Arc::new(listener), handshake }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn receive(&self, in_tx: mpsc::UnboundedSender<Message>) -> BoxFuture<'static, Result<()>> {
+        let (listener, handshake) = (self.listener.clone(), self.handshake.clone());
+        async move {
+            loop {
+                let (socket, peer) = listener.accept().await?;
+                spawn_receive_peer(socket, peer.to_string(), handshake.clone(), in_tx.clone());
+            }
+        }
+        .boxed()
+    }
+
+    fn connect(&self, id: &str, addr: &str) -> BoxFuture<'static, Result<MessageSink>> {
+        let (id, addr, handshake) = (id.to_string(), addr.to_string(), self.handshake.clone());
+        async move { connect_peer(TcpStream::connect(addr).await?, &handshake, &id).await }.boxed()
+    }
+}
+
+/// A Unix domain socket transport for co-located nodes, using length-delimited Bincode frames.
+/// Peer addresses are socket paths.
+#[cfg(unix)]
+pub struct UnixTransport {
+    listener: Arc<tokio::net::UnixListener>,
+    handshake: Handshake,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    /// Creates a new Unix transport, receiving peer messages via the given listener and
+    /// identifying the node to peers with the given handshake.
+    pub fn new(listener: tokio::net::UnixListener, handshake: Handshake) -> Self {
+        Self { listener: Arc::new(listener), handshake }
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    fn receive(&self, in_tx: mpsc::UnboundedSender<Message>) -> BoxFuture<'static, Result<()>> {
+        let (listener, handshake) = (self.listener.clone(), self.handshake.clone());
+        async move {
+            loop {
+                let (socket, peer) = listener.accept().await?;
+                let peer = format!("{:?}", peer);
+                spawn_receive_peer(socket, peer, handshake.clone(), in_tx.clone());
+            }
+        }
+        .boxed()
+    }
+
+    fn connect(&self, id: &str, addr: &str) -> BoxFuture<'static, Result<MessageSink>> {
+        let (id, addr, handshake) = (id.to_string(), addr.to_string(), self.handshake.clone());
+        async move {
+            let socket = tokio::net::UnixStream::connect(addr).await?;
+            connect_peer(socket, &handshake, &id).await
+        }
+        .boxed()
+    }
+}
+
+/// An in-memory network of channel transports, keyed by address. Mostly useful for tests.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inboxes: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Message>>>>,
+}
+
+impl MemoryNetwork {
+    /// Creates a new, empty in-memory network.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a transport that receives messages sent to the given address.
+    pub fn transport(&self, addr: &str) -> MemoryTransport {
+        MemoryTransport { addr: addr.to_string(), network: self.clone() }
+    }
+
+    /// Registers the inbox for the given address.
+    fn register(&self, addr: &str, inbox: mpsc::UnboundedSender<Message>) -> Result<()> {
+        self.inboxes.lock()?.insert(addr.to_string(), inbox);
+        Ok(())
+    }
+
+    /// Looks up the inbox for the given address.
+    fn inbox(&self, addr: &str) -> Result<mpsc::UnboundedSender<Message>> {
+        self.inboxes
+            .lock()?
+            .get(addr)
+            .cloned()
+            .ok_or_else(|| Error::Internal(format!("Unknown address {}", addr)))
+    }
+
+    /// Disconnects the given address, such that messages to it fail until it receives again.
+    pub fn disconnect(&self, addr: &str) -> Result<()> {
+        self.inboxes.lock()?.remove(addr);
+        Ok(())
+    }
+}
+
+/// An in-memory transport, passing messages over channels in a MemoryNetwork.
+pub struct MemoryTransport {
+    addr: String,
+    network: MemoryNetwork,
+}
+
+impl Transport for MemoryTransport {
+    fn receive(&self, in_tx: mpsc::UnboundedSender<Message>) -> BoxFuture<'static, Result<()>> {
+        let result = self.network.register(&self.addr, in_tx.clone());
+        async move {
+            result?;
+            in_tx.closed().await;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn connect(&self, _id: &str, addr: &str) -> BoxFuture<'static, Result<MessageSink>> {
+        let inbox = self.network.inbox(addr);
+        async move {
+            let inbox = inbox?;
+            let sink = futures::sink::unfold(inbox, |inbox, message: Message| async move {
+                inbox.send(message)?;
+                Ok::<_, Error>(inbox)
+            });
+            Ok(Box::pin(sink) as MessageSink)
+        }
+        .boxed()
+    }
+}
+
+/// A fault-injecting transport wrapper, which randomly drops outbound messages. Raft recovers
+/// from lost messages, but proxied client requests are not retried and may hang.
+pub struct FaultyTransport {
+    inner: Box<dyn Transport>,
+    drop: f64,
+}
+
+impl FaultyTransport {
+    /// Wraps a transport, dropping outbound messages with the given probability (0.0 to 1.0).
+    pub fn new(inner: impl Transport, drop: f64) -> Self {
+        Self { inner: Box::new(inner), drop }
+    }
+}
+
+impl Transport for FaultyTransport {
+    fn receive(&self, in_tx: mpsc::UnboundedSender<Message>) -> BoxFuture<'static, Result<()>> {
+        self.inner.receive(in_tx)
+    }
+
+    fn connect(&self, id: &str, addr: &str) -> BoxFuture<'static, Result<MessageSink>> {
+        let connect = self.inner.connect(id, addr);
+        let drop = self.drop;
+        async move {
+            let sink = futures::sink::unfold(connect.await?, move |mut sink, message| async move {
+                if rand::random::<f64>() >= drop {
+                    sink.send(message).await?;
+                }
+                Ok::<_, Error>(sink)
+            });
+            Ok(Box::pin(sink) as MessageSink)
+        }
+        .boxed()
+    }
+}
+
+/// A peer connection, carrying length-delimited frames.
+type Connection<S> = Framed<S, LengthDelimitedCodec>;
+
+/// A message as encoded on the wire. The event is encoded separately as its tag and fields, such
+/// that a message with an event this node doesn't know, e.g. a new event sent by an upgraded node
+/// during a rolling upgrade, is skipped instead of failing the connection. See Event for the
+/// rules on changing events.
+#[derive(Serialize, Deserialize)]
+struct Frame {
+    group: u64,
+    term: u64,
+    from: Address,
+    to: Address,
+    tag: u32,
+    event: Vec<u8>,
+}
+
+impl Frame {
+    /// Encodes a message as a frame.
+    fn encode(message: Message) -> Result<Bytes> {
+        let Message { group, term, from, to, event } = message;
+        let (tag, event) = event.encode()?;
+        Ok(bincode::serialize(&Frame { group, term, from, to, tag, event })?.into())
+    }
+
+    /// Decodes a message from a frame, or returns None if the event is unknown.
+    fn decode(bytes: &[u8]) -> Result<Option<Message>> {
+        let Frame { group, term, from, to, tag, event } = bincode::deserialize(bytes)?;
+        let Some(event) = Event::decode(tag, &event)? else {
+            warn!("Skipping message from {:?} with unknown event tag {}", from, tag);
+            return Ok(None);
+        };
+        Ok(Some(Message { group, term, from, to, event }))
+    }
+}
+
+/// Opens a peer connection on a socket by exchanging handshakes, optionally expecting the given
+/// peer ID. Returns the connection and the peer's handshake.
+pub(super) async fn handshake<S>(
+    socket: S,
+    local: &Handshake,
+    peer_id: Option<&str>,
+) -> Result<(Connection<S>, Handshake)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection = Framed::new(socket, LengthDelimitedCodec::new());
+    connection.send(Bytes::from(bincode::serialize(local)?)).await?;
+    let peer: Handshake = match connection.try_next().await? {
+        Some(frame) => bincode::deserialize(&frame)?,
+        None => return Err(Error::Value("Peer closed connection during handshake".into())),
+    };
+    let version = local.check(&peer, peer_id)?;
+    debug!("Raft peer {} uses protocol version {}", peer.node_id, version);
+    Ok((connection, peer))
+}
+
+/// Spawns a task receiving inbound messages from a connected peer socket.
+fn spawn_receive_peer<S>(
+    socket: S,
+    peer: String,
+    handshake: Handshake,
+    in_tx: mpsc::UnboundedSender<Message>,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    tokio::spawn(async move {
+        debug!("Raft peer {} connected", peer);
+        match receive_peer(socket, &handshake, in_tx, |_| Ok(())).await {
+            Ok(()) => debug!("Raft peer {} disconnected", peer),
+            Err(err) => error!("Raft peer {} error: {}", peer, err),
+        };
+    });
+}
+
+/// Receives inbound messages from a peer socket. After exchanging handshakes, the peer is passed
+/// through the given authentication check, and may then only send messages as itself. A failed
+/// check closes the connection.
+pub(super) async fn receive_peer<S>(
+    socket: S,
+    local: &Handshake,
+    in_tx: mpsc::UnboundedSender<Message>,
+    authenticate: impl FnOnce(&Handshake) -> Result<()>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut connection, peer) = handshake(socket, local, None).await?;
+    authenticate(&peer)?;
+    let from = Address::Peer(peer.node_id);
+    while let Some(frame) = connection.try_next().await? {
+        let Some(message) = Frame::decode(&frame)? else {
+            continue;
+        };
+        if message.from != from {
+            return Err(Error::Value(format!(
+                "Invalid peer message sender {:?}, expected {:?}",
+                message.from, from
+            )));
+        }
+        in_tx.send(message)?;
+    }
+    Ok(())
+}
+
+/// Opens an outbound peer connection on a socket, exchanging handshakes with the given peer and
+/// returning a sink of messages.
+pub(super) async fn connect_peer<S>(socket: S, local: &Handshake, id: &str) -> Result<MessageSink>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (connection, _) = handshake(socket, local, Some(id)).await?;
+    let sink = SinkExt::<Bytes>::sink_map_err(connection, Error::from)
+        .with(|message: Message| futures::future::ready(Frame::encode(message)));
+    Ok(Box::pin(sink))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_engine::messaging::PROTOCOL_VERSION;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn heartbeat(from: &str) -> Message {
+        Message {
+            group: 0,
+            from: Address::Peer(from.into()),
+            to: Address::Peer("a".into()),
+            term: 1,
+            event: Event::Heartbeat { commit_index: 0, commit_term: 0, round: 0 },
+        }
+    }
+
+    /// Asserts that the peer closes the connection.
+    async fn assert_closed<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<S>) {
+        let next = tokio::time::timeout(Duration::from_secs(1), connection.try_next()).await;
+        assert!(matches!(next, Ok(Ok(None)) | Ok(Err(_))), "connection not closed");
+    }
+
+    #[tokio::test]
+    // Messages are passed across a memory network, and dropped by a faulty transport.
+    async fn memory_faulty() -> Result<()> {
+        let network = MemoryNetwork::new();
+        let (in_tx, mut in_rx) = mpsc::unbounded_channel();
+        tokio::spawn(network.transport("a").receive(in_tx));
+        tokio::task::yield_now().await;
+        let msg = heartbeat("b");
+
+        let mut sink = network.transport("b").connect("a", "a").await?;
+        sink.send(msg.clone()).await?;
+        assert_eq!(in_rx.recv().await, Some(msg.clone()));
+
+        let mut sink = FaultyTransport::new(network.transport("b"), 1.0).connect("a", "a").await?;
+        sink.send(msg.clone()).await?;
+        assert!(in_rx.try_recv().is_err());
+
+        assert!(network.transport("b").connect("c", "c").await.is_err());
+        network.disconnect("a")?;
+        assert!(network.transport("b").connect("a", "a").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    // Peers exchange handshakes, and reject other clusters, node IDs, and protocol versions.
+    async fn tcp_handshake() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let (in_tx, mut in_rx) = mpsc::unbounded_channel();
+        tokio::spawn(TcpTransport::new(listener, Handshake::new("test", "a")).receive(in_tx));
+        let msg = heartbeat("b");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let b = TcpTransport::new(listener, Handshake::new("test", "b"));
+        let mut sink = b.connect("a", &addr).await?;
+        sink.send(msg.clone()).await?;
+        assert_eq!(in_rx.recv().await, Some(msg.clone()));
+
+        // The peer must have the expected node ID.
+        assert!(b.connect("c", &addr).await.is_err());
+
+        // Nodes from other clusters or with incompatible protocol versions are rejected.
+        let other = Handshake::new("other", "b");
+        let socket = TcpStream::connect(&addr).await?;
+        assert!(handshake(socket, &other, Some("a")).await.is_err());
+        let newer = Handshake { min_version: PROTOCOL_VERSION + 1, ..Handshake::new("test", "b") };
+        let socket = TcpStream::connect(&addr).await?;
+        assert!(handshake(socket, &newer, Some("a")).await.is_err());
+
+        // Messages must be sent by the node that connected, otherwise the connection is closed.
+        let socket = TcpStream::connect(&addr).await?;
+        let (mut connection, _) = handshake(socket, &Handshake::new("test", "b"), None).await?;
+        connection.send(Frame::encode(heartbeat("c"))?).await?;
+        assert_closed(&mut connection).await;
+        assert!(in_rx.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    // A node talks to a peer at the next protocol version, skipping its unknown events and
+    // ignoring fields appended to known events. Events that can't be decoded close the connection.
+    async fn tcp_newer_version() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let (in_tx, mut in_rx) = mpsc::unbounded_channel();
+        tokio::spawn(TcpTransport::new(listener, Handshake::new("test", "a")).receive(in_tx));
+        let msg = heartbeat("b");
+
+        let newer = Handshake {
+            version: PROTOCOL_VERSION + 1,
+            min_version: PROTOCOL_VERSION,
+            ..Handshake::new("test", "b")
+        };
+        let socket = TcpStream::connect(&addr).await?;
+        let (mut connection, _) = handshake(socket, &newer, Some("a")).await?;
+        let (tag, fields) = msg.event.encode()?;
+        let frame = |tag, event| -> Result<Bytes> {
+            let (from, to) = (Address::Peer("b".into()), Address::Peer("a".into()));
+            Ok(bincode::serialize(&Frame { group: 0, term: 1, from, to, tag, event })?.into())
+        };
+
+        // A new event, with an unused tag, is skipped.
+        connection.send(frame(u32::MAX, bincode::serialize(&(1u64, Some(2u64)))?)?).await?;
+
+        // A field appended to a known event is ignored.
+        let appended = [fields.clone(), bincode::serialize(&Some(2u64))?].concat();
+        connection.send(frame(tag, appended)?).await?;
+        assert_eq!(in_rx.recv().await, Some(msg.clone()));
+        connection.send(Frame::encode(msg.clone())?).await?;
+        assert_eq!(in_rx.recv().await, Some(msg));
+
+        // A known event that can't be decoded closes the connection.
+        connection.send(frame(tag, fields[..1].to_vec())?).await?;
+        assert_closed(&mut connection).await;
+        assert!(in_rx.try_recv().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,471 @@
+use super::{RangeDescriptor, RangeMutation, RangeQuery, RangeResponse, RangeTable};
+use crate::{
+    error::{Error, Result},
+    raft_engine::{
+        messaging::{Request, Response},
+        raft_client::Client,
+    },
+    storage_engine::key_value_storage::Range,
+};
+
+use log::{debug, error, info};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// The number of times a request is retried after refreshing a stale routing table.
+const MAX_REROUTES: u32 = 5;
+/// The delay before retrying a request after refreshing the routing table.
+const REROUTE_DELAY: Duration = Duration::from_millis(50);
+/// The approximate size of the data chunks returned by scans and handed over between groups,
+/// in bytes. This bounds the size of Raft responses and log entries.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Thresholds for splitting and merging ranges, by the size of their keys and values in bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeThresholds {
+    /// Ranges larger than this are split in half.
+    pub split: u64,
+    /// Adjacent ranges whose combined size is below this are merged.
+    pub merge: u64,
+}
+
+impl Default for RangeThresholds {
+    fn default() -> Self {
+        Self { split: 64 * 1024 * 1024, merge: 16 * 1024 * 1024 }
+    }
+}
+
+/// A client for a range-sharded keyspace on a local server, hosting a Raft group per range.
+/// Requests are routed via a cached routing table, which is refreshed from group 0 when a
+/// range reports that a key doesn't belong to it. Keys are encoded MVCC keys.
+///
+/// The client is asynchronous, so it is not a KvStore and the MVCC layer can't run on top of
+/// it directly. It provides the key/value operations that MVCC needs, including scans across
+/// ranges, for an asynchronous MVCC layer to build on.
+#[derive(Clone)]
+pub struct RangeClient {
+    request_tx: mpsc::UnboundedSender<(u64, Request, oneshot::Sender<Result<Response>>)>,
+    /// The Raft groups available to ranges.
+    groups: Vec<u64>,
+    /// The cached routing table.
+    table: Arc<Mutex<RangeTable>>,
+    /// The size of data chunks.
+    chunk_size: u64,
+}
+
+impl RangeClient {
+    /// Creates a new range client, using the given Raft groups for ranges. Group 0 is always
+    /// used, and stores the routing table.
+    pub fn new(
+        request_tx: mpsc::UnboundedSender<(u64, Request, oneshot::Sender<Result<Response>>)>,
+        mut groups: Vec<u64>,
+    ) -> Self {
+        groups.push(0);
+        groups.sort_unstable();
+        groups.dedup();
+        let table = Arc::new(Mutex::new(RangeTable::new()));
+        Self { request_tx, groups, table, chunk_size: CHUNK_SIZE }
+    }
+
+    /// Returns the cached routing table.
+    pub fn table(&self) -> Result<RangeTable> {
+        Ok(self.table.lock()?.clone())
+    }
+
+    /// Gets the value of a key.
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let query = RangeQuery::Get { key: key.to_vec() };
+        match self.route(key, |group| self.query(group, query.clone())).await? {
+            RangeResponse::Value(value) => Ok(value),
+            resp => Err(Error::Internal(format!("Unexpected range response {:?}", resp))),
+        }
+    }
+
+    /// Sets the value of a key.
+    pub async fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let mutation = RangeMutation::Set { key: key.to_vec(), value };
+        self.route(key, |group| self.mutate(group, mutation.clone())).await.map(|_| ())
+    }
+
+    /// Deletes a key.
+    pub async fn delete(&self, key: &[u8]) -> Result<()> {
+        let mutation = RangeMutation::Delete { key: key.to_vec() };
+        self.route(key, |group| self.mutate(group, mutation.clone())).await.map(|_| ())
+    }
+
+    /// Scans the key/value pairs in the given range of keys, in key order. Each range is
+    /// scanned in chunks, and the scan is restarted if a range changes while scanning. Scans
+    /// are not atomic across ranges, which is fine for MVCC since records are versioned.
+    pub async fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range = Range::from(range);
+        let mut reroutes = 0;
+        'restart: loop {
+            let table = self.table()?;
+            let mut data = Vec::new();
+            for descriptor in table.ranges() {
+                let mut start = range.start.clone();
+                loop {
+                    let query = RangeQuery::Scan {
+                        start: start.clone(),
+                        end: range.end.clone(),
+                        generation: descriptor.generation,
+                        limit: self.chunk_size,
+                    };
+                    match self.query(descriptor.group, query).await? {
+                        RangeResponse::Data(chunk) => {
+                            let Some((last, _)) = chunk.last() else { break };
+                            start = Bound::Excluded(last.clone());
+                            data.extend(chunk);
+                        }
+                        RangeResponse::Mismatch(range) => {
+                            debug!("Range of group {} changed, now {:?}", descriptor.group, range);
+                            self.reroute(&mut reroutes).await?;
+                            continue 'restart;
+                        }
+                        resp => {
+                            return Err(Error::Internal(format!(
+                                "Unexpected range response {:?}",
+                                resp
+                            )))
+                        }
+                    }
+                }
+            }
+            data.sort_by(|(a, _), (b, _)| a.cmp(b));
+            return Ok(data);
+        }
+    }
+
+    /// Refreshes the cached routing table from group 0.
+    pub async fn refresh(&self) -> Result<RangeTable> {
+        match self.query(0, RangeQuery::Table).await? {
+            RangeResponse::Table(table) => {
+                *self.table.lock()? = table.clone();
+                Ok(table)
+            }
+            resp => Err(Error::Internal(format!("Unexpected range response {:?}", resp))),
+        }
+    }
+
+    /// Splits ranges above the split threshold and merges adjacent ranges below the merge
+    /// threshold. Returns true if any ranges were changed.
+    pub async fn rebalance(&self, thresholds: &RangeThresholds) -> Result<bool> {
+        if self.resume_all().await? {
+            return Ok(true);
+        }
+        let table = self.refresh().await?;
+        let mut sizes = Vec::new();
+        for range in table.ranges() {
+            sizes.push((range.clone(), self.size(range.group).await?));
+        }
+        for (range, size) in &sizes {
+            if *size > thresholds.split {
+                if let Some(group) = self.free_group(&table) {
+                    if self.split(range, group).await? {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        for pair in sizes.windows(2) {
+            let ((left, left_size), (right, right_size)) = (&pair[0], &pair[1]);
+            if left_size + right_size < thresholds.merge
+                && right.group != 0
+                && self.merge(left, right).await?
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Runs rebalance() at the given interval, until the server shuts down.
+    pub async fn run(self, interval: Duration, thresholds: RangeThresholds) -> Result<()> {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.rebalance(&thresholds).await {
+                Ok(_) => {}
+                Err(Error::Internal(err)) => return Err(Error::Internal(err)),
+                Err(err) => error!("Failed to rebalance ranges: {}", err),
+            }
+        }
+    }
+
+    /// Splits a range roughly in half by size, handing over the upper half to the given group.
+    /// Returns false if the range can't be split, e.g. because it only has a single key.
+    async fn split(&self, range: &RangeDescriptor, group: u64) -> Result<bool> {
+        let key = match self.query(range.group, RangeQuery::SplitKey).await? {
+            RangeResponse::SplitKey(Some(key)) => key,
+            RangeResponse::SplitKey(None) => return Ok(false),
+            resp => return Err(Error::Internal(format!("Unexpected range response {:?}", resp))),
+        };
+        info!("Splitting range of group {} at {:x?} into group {}", range.group, key, group);
+        match self.mutate(range.group, RangeMutation::Split { key, group }).await? {
+            RangeResponse::Ok => {}
+            resp => return Err(Error::Internal(format!("Unexpected range response {:?}", resp))),
+        }
+        self.resume(range.group).await?;
+        Ok(true)
+    }
+
+    /// Merges a range into its left neighbor. Returns false if the left neighbor has changed
+    /// since the routing table was refreshed, or has a pending handover.
+    async fn merge(&self, left: &RangeDescriptor, right: &RangeDescriptor) -> Result<bool> {
+        match self.query(left.group, RangeQuery::Descriptor).await? {
+            RangeResponse::Descriptor(Some(range)) if &range == left => {}
+            RangeResponse::Descriptor(range) => {
+                debug!("Range of group {} changed, now {:?}", left.group, range);
+                return Ok(false);
+            }
+            resp => return Err(Error::Internal(format!("Unexpected range response {:?}", resp))),
+        }
+        match self.query(left.group, RangeQuery::Handover).await? {
+            RangeResponse::Handover(None) => {}
+            RangeResponse::Handover(Some(_)) => return Ok(false),
+            resp => return Err(Error::Internal(format!("Unexpected range response {:?}", resp))),
+        }
+        info!("Merging range of group {} into group {}", right.group, left.group);
+        let range = RangeDescriptor {
+            group: left.group,
+            start: left.start.clone(),
+            end: right.end.clone(),
+            generation: left.generation.max(right.generation) + 1,
+        };
+        let left = left.clone();
+        match self.mutate(right.group, RangeMutation::Retire { range, left }).await? {
+            RangeResponse::Ok => {}
+            resp => return Err(Error::Internal(format!("Unexpected range response {:?}", resp))),
+        }
+        self.resume(right.group).await?;
+        Ok(true)
+    }
+
+    /// Completes a group's pending handover from a split or merge, if any: the data is handed
+    /// over in chunks, the receiving group takes over the range, the routing table is updated
+    /// and the handed over data is cleaned up. Every step is idempotent, so an interrupted
+    /// handover can be resumed at any point. If the receiving group's range has changed since
+    /// the handover started, it refuses the data and the takeover, and an error is returned
+    /// before the routing table is updated or the data cleaned up, so the data is kept in the
+    /// handing over group. Returns true if there was a pending handover.
+    async fn resume(&self, group: u64) -> Result<bool> {
+        let handover = match self.query(group, RangeQuery::Handover).await? {
+            RangeResponse::Handover(Some(handover)) => handover,
+            RangeResponse::Handover(None) => return Ok(false),
+            resp => return Err(Error::Internal(format!("Unexpected range response {:?}", resp))),
+        };
+        debug!("Handing over range {:?} from group {}", handover.range, group);
+        let target = handover.range.group;
+        let mut after = None;
+        loop {
+            let query = RangeQuery::HandoverData { after, limit: self.chunk_size };
+            let data = match self.query(group, query).await? {
+                RangeResponse::Data(data) => data,
+                resp => {
+                    return Err(Error::Internal(format!("Unexpected range response {:?}", resp)))
+                }
+            };
+            let Some((last, _)) = data.last() else { break };
+            after = Some(last.clone());
+            let (range, recipient) = (handover.range.clone(), handover.recipient.clone());
+            let response = self.mutate(target, RangeMutation::Ingest { range, recipient, data });
+            Self::check_handover(target, response.await?)?;
+        }
+        let (range, recipient, size) = (handover.range, handover.recipient, handover.size);
+        let response = self.mutate(target, RangeMutation::Takeover { range, recipient, size });
+        Self::check_handover(target, response.await?)?;
+        self.mutate(0, RangeMutation::UpdateTable { ranges: handover.ranges }).await?;
+        self.mutate(group, RangeMutation::Cleanup).await?;
+        Ok(true)
+    }
+
+    /// Checks the response of a group receiving a handover.
+    fn check_handover(group: u64, response: RangeResponse) -> Result<()> {
+        match response {
+            RangeResponse::Ok => Ok(()),
+            RangeResponse::Mismatch(range) => Err(Error::Value(format!(
+                "Group {} refused handover, its range changed to {:?}",
+                group, range
+            ))),
+            resp => Err(Error::Internal(format!("Unexpected range response {:?}", resp))),
+        }
+    }
+
+    /// Resumes the pending handovers of all groups. Returns true if there were any.
+    async fn resume_all(&self) -> Result<bool> {
+        let mut resumed = false;
+        for group in &self.groups {
+            resumed |= self.resume(*group).await?;
+        }
+        Ok(resumed)
+    }
+
+    /// Returns a group that doesn't own a range, if any.
+    fn free_group(&self, table: &RangeTable) -> Option<u64> {
+        self.groups.iter().copied().find(|group| table.get(*group).is_none())
+    }
+
+    /// Returns the size of a group's range.
+    async fn size(&self, group: u64) -> Result<u64> {
+        match self.query(group, RangeQuery::Size).await? {
+            RangeResponse::Size(size) => Ok(size),
+            resp => Err(Error::Internal(format!("Unexpected range response {:?}", resp))),
+        }
+    }
+
+    /// Executes a request for a key in the group that owns it, refreshing the routing table and
+    /// retrying if the range has moved.
+    async fn route<F, Fut>(&self, key: &[u8], f: F) -> Result<RangeResponse>
+    where
+        F: Fn(u64) -> Fut,
+        Fut: std::future::Future<Output = Result<RangeResponse>>,
+    {
+        let user_key = super::route_key(key)?;
+        let mut reroutes = 0;
+        loop {
+            let group = self.table.lock()?.lookup(&user_key).map(|range| range.group);
+            if let Some(group) = group {
+                match f(group).await? {
+                    RangeResponse::Mismatch(range) => {
+                        debug!("Range of group {} no longer owns key, now {:?}", group, range)
+                    }
+                    response => return Ok(response),
+                }
+            }
+            self.reroute(&mut reroutes).await?;
+        }
+    }
+
+    /// Refreshes the routing table before retrying a request, up to MAX_REROUTES times. If the
+    /// table is still stale after a refresh, a split or merge may have been interrupted, so
+    /// pending handovers are resumed.
+    async fn reroute(&self, reroutes: &mut u32) -> Result<()> {
+        if *reroutes >= MAX_REROUTES {
+            return Err(Error::Abort);
+        }
+        if *reroutes > 0 {
+            tokio::time::sleep(REROUTE_DELAY).await;
+            self.resume_all().await?;
+        }
+        *reroutes += 1;
+        self.refresh().await?;
+        Ok(())
+    }
+
+    /// Executes a mutation in a group.
+    async fn mutate(&self, group: u64, mutation: RangeMutation) -> Result<RangeResponse> {
+        let client = Client::new_group(self.request_tx.clone(), group);
+        Ok(bincode::deserialize(&client.mutate(bincode::serialize(&mutation)?).await?)?)
+    }
+
+    /// Executes a query in a group.
+    async fn query(&self, group: u64, query: RangeQuery) -> Result<RangeResponse> {
+        let client = Client::new_group(self.request_tx.clone(), group);
+        Ok(bincode::deserialize(&client.query(bincode::serialize(&query)?).await?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_engine::{
+        raft_log::RaftLog,
+        raft_node::RaftConfig,
+        raft_server::{MemoryNetwork, Server},
+        sharding::RangeState,
+    };
+    use crate::storage_engine::{
+        key_value_storage::KvMemory, log_storage::LogMemory, mvcc_storage::Key,
+    };
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    fn key(i: u64) -> Vec<u8> {
+        Key::Record(format!("key{:03}", i).into_bytes().into(), 1).encode()
+    }
+
+    /// Sets up a single-node server with 4 range groups, returning its request sender.
+    async fn setup(
+    ) -> Result<mpsc::UnboundedSender<(u64, Request, oneshot::Sender<Result<Response>>)>> {
+        let config = RaftConfig { tick_ms: 10, ..RaftConfig::default() };
+        let log = RaftLog::new(Box::new(LogMemory::new()))?;
+        let state = Box::new(RangeState::new(Box::new(KvMemory::new()), 0)?);
+        let mut server = Server::new("a", HashMap::new(), log, state, config).await?;
+        for group in 1..4 {
+            let log = RaftLog::new(Box::new(LogMemory::new()))?;
+            let state = Box::new(RangeState::new(Box::new(KvMemory::new()), group)?);
+            server.add_group(group, log, state).await?;
+        }
+        let (request_tx, request_rx) = mpsc::unbounded_channel();
+        tokio::spawn(server.serve(MemoryNetwork::new().transport("a"), request_rx));
+        Ok(request_tx)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    // Ranges are split as they grow and merged as they shrink, with requests routed to them.
+    async fn rebalance() -> Result<()> {
+        let request_tx = setup().await?;
+        let mut client = RangeClient::new(request_tx.clone(), vec![1, 2, 3]);
+        client.chunk_size = 100;
+        let thresholds = RangeThresholds { split: 1000, merge: 500 };
+        for i in 0..100 {
+            client.set(&key(i), vec![0x01; 20]).await?;
+        }
+
+        // Split into all 4 groups, after which there are no more free groups.
+        while client.rebalance(&thresholds).await? {}
+        let table = client.table()?;
+        let mut groups: Vec<_> = table.ranges().map(|r| r.group).collect();
+        groups.sort();
+        assert_eq!(groups, vec![0, 1, 2, 3]);
+
+        // Requests are routed to the right group, also by a client with a stale table.
+        let stale = RangeClient::new(request_tx, vec![1, 2, 3]);
+        for i in 0..100 {
+            assert_eq!(client.get(&key(i)).await?, Some(vec![0x01; 20]));
+            assert_eq!(stale.get(&key(i)).await?, Some(vec![0x01; 20]));
+        }
+        assert_eq!(stale.table()?, table);
+
+        // Scans span all ranges, in chunks.
+        let scan = stale.scan(key(5)..key(95)).await?;
+        assert_eq!(scan, (5..95).map(|i| (key(i), vec![0x01; 20])).collect::<Vec<_>>());
+        let scan = client.scan(..).await?;
+        assert_eq!(scan, (0..100).map(|i| (key(i), vec![0x01; 20])).collect::<Vec<_>>());
+
+        // Deleting most keys merges the ranges again.
+        for i in 10..100 {
+            client.delete(&key(i)).await?;
+        }
+        while client.rebalance(&thresholds).await? {}
+        assert_eq!(client.table()?.ranges().count(), 1);
+        for i in 0..100 {
+            let expect = if i < 10 { Some(vec![0x01; 20]) } else { None };
+            assert_eq!(stale.get(&key(i)).await?, expect);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    // A split that is interrupted after the Split mutation is resumed by the next request.
+    async fn resume_split() -> Result<()> {
+        let request_tx = setup().await?;
+        let client = RangeClient::new(request_tx.clone(), vec![1, 2, 3]);
+        for i in 0..10 {
+            client.set(&key(i), vec![0x01]).await?;
+        }
+        let split_key = b"key005".to_vec();
+        client.mutate(0, RangeMutation::Split { key: split_key, group: 1 }).await?;
+
+        // A new client routes the request via the stale table to group 0, which no longer
+        // owns the key. It resumes the split, and finds the key in group 1.
+        let client = RangeClient::new(request_tx, vec![1, 2, 3]);
+        assert_eq!(client.get(&key(7)).await?, Some(vec![0x01]));
+        assert_eq!(client.table()?.lookup(b"key007").map(|r| r.group), Some(1));
+        assert_eq!(client.resume_all().await?, false);
+        assert_eq!(client.scan(..).await?.len(), 10);
+        Ok(())
+    }
+}
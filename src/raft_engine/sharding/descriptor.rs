@@ -0,0 +1,71 @@
+use crate::error::Result;
+use crate::storage_engine::mvcc_storage::Key;
+
+use serde::{Deserialize, Serialize};
+
+/// A contiguous range of the MVCC keyspace, owned by a Raft group. Ranges are defined over the
+/// user keys of MVCC records, such that all versions of a key belong to the same range. Other
+/// MVCC keys, e.g. transaction metadata, belong to the first range.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RangeDescriptor {
+    /// The Raft group that owns the range.
+    pub group: u64,
+    /// The first user key in the range.
+    pub start: Vec<u8>,
+    /// The user key after the last key in the range, or None if unbounded.
+    pub end: Option<Vec<u8>>,
+    /// The range generation, incremented on every split and merge.
+    pub generation: u64,
+}
+
+impl RangeDescriptor {
+    /// Creates a descriptor spanning the entire keyspace.
+    pub fn full(group: u64) -> Self {
+        Self { group, start: Vec::new(), end: None, generation: 0 }
+    }
+
+    /// Checks if the range contains the given user key.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.start.as_slice() <= key && self.end.as_ref().is_none_or(|end| key < end.as_slice())
+    }
+
+    /// Checks if the range contains the given encoded MVCC key.
+    pub fn contains_encoded(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.contains(&route_key(key)?))
+    }
+}
+
+/// Returns the user key to route an encoded MVCC key by: the user key for records, and the
+/// empty key for anything else.
+pub fn route_key(key: &[u8]) -> Result<Vec<u8>> {
+    Ok(match Key::decode(key)? {
+        Key::Record(key, _) => key.into_owned(),
+        _ => Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains() -> Result<()> {
+        let range = RangeDescriptor {
+            group: 1,
+            start: b"b".to_vec(),
+            end: Some(b"d".to_vec()),
+            generation: 1,
+        };
+        assert!(!range.contains(b"a"));
+        assert!(range.contains(b"b"));
+        assert!(range.contains(b"c\xff"));
+        assert!(!range.contains(b"d"));
+        assert!(RangeDescriptor::full(0).contains(b"\xff\xff"));
+
+        assert!(range.contains_encoded(&Key::Record(b"c".as_slice().into(), 7).encode())?);
+        assert!(!range.contains_encoded(&Key::Record(b"d".as_slice().into(), 7).encode())?);
+        assert!(!range.contains_encoded(&Key::TxnNext.encode())?);
+        assert!(RangeDescriptor::full(0).contains_encoded(&Key::TxnActive(1).encode())?);
+        Ok(())
+    }
+}
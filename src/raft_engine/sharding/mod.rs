@@ -0,0 +1,9 @@
+mod client;
+mod descriptor;
+mod state;
+mod table;
+
+pub use client::*;
+pub use descriptor::*;
+pub use state::*;
+pub use table::*;
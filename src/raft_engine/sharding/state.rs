@@ -0,0 +1,800 @@
+use super::{route_key, RangeDescriptor, RangeTable};
+use crate::{
+    error::{Error, Result},
+    raft_engine::machine_state::{MachineState, ReadOnlyState, StateView},
+    storage_engine::{
+        key_value_storage::{KvCow, KvStore, Range},
+        mvcc_storage::Key,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+
+/// The key under which the applied index is stored.
+const APPLIED_INDEX_KEY: &[u8] = &[0x00];
+/// The key under which the range descriptor is stored.
+const DESCRIPTOR_KEY: &[u8] = &[0x00, 0x01];
+/// The key under which the routing table is stored, in group 0.
+const TABLE_KEY: &[u8] = &[0x00, 0x02];
+/// The key under which the driver's client sessions are stored, along with their index.
+const SESSIONS_KEY: &[u8] = &[0x00, 0x03];
+/// The key under which a pending handover is stored.
+const HANDOVER_KEY: &[u8] = &[0x00, 0x04];
+/// The key under which the size of the range's data is stored.
+const SIZE_KEY: &[u8] = &[0x00, 0x05];
+/// The prefix of data keys, to keep them separate from metadata.
+const DATA_PREFIX: u8 = 0x01;
+
+/// A range state machine mutation, the command of a Raft mutate request. Keys are encoded MVCC
+/// keys.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RangeMutation {
+    /// Sets a key, if it belongs to the range.
+    Set { key: Vec<u8>, value: Vec<u8> },
+    /// Deletes a key, if it belongs to the range.
+    Delete { key: Vec<u8> },
+    /// Splits the range at the given user key, handing the upper half over to a new group. The
+    /// handover is recorded in the range state, and the data is kept until Cleanup.
+    Split { key: Vec<u8>, group: u64 },
+    /// Gives up the range to be merged into its left neighbor, as the given merged range. The
+    /// left neighbor must still have the given range when taking over the merged range. The
+    /// handover is recorded in the range state, and the data is kept until Cleanup.
+    Retire { range: RangeDescriptor, left: RangeDescriptor },
+    /// Writes a chunk of data handed over for the given range, from a split or merge. The data
+    /// doesn't belong to the group's range until Takeover. Returns a mismatch unless the group
+    /// still has the handover's recipient range.
+    Ingest {
+        range: RangeDescriptor,
+        recipient: Option<RangeDescriptor>,
+        data: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    /// Takes over a range whose data has been ingested, from a split or merge, adding the
+    /// size of the handed over data. Returns a mismatch unless the group still has the
+    /// handover's recipient range.
+    Takeover { range: RangeDescriptor, recipient: Option<RangeDescriptor>, size: u64 },
+    /// Deletes the data of a completed handover, and clears it.
+    Cleanup,
+    /// Updates the routing table in group 0 with the given descriptors.
+    UpdateTable { ranges: Vec<RangeDescriptor> },
+}
+
+/// A range state machine query, the command of a Raft query request.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RangeQuery {
+    /// Gets the value of a key, if it belongs to the range.
+    Get { key: Vec<u8> },
+    /// Scans the range's key/value pairs between the given keys, in key order, up to roughly
+    /// limit bytes. Returns a mismatch unless the range has the given generation.
+    Scan { start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, generation: u64, limit: u64 },
+    /// Returns the range descriptor.
+    Descriptor,
+    /// Returns the total size of the range's keys and values, in bytes.
+    Size,
+    /// Returns a user key that splits the range's data roughly in half, if any.
+    SplitKey,
+    /// Returns the pending handover, if any.
+    Handover,
+    /// Returns the pending handover's data after the given key, in key order, up to roughly
+    /// limit bytes.
+    HandoverData { after: Option<Vec<u8>>, limit: u64 },
+    /// Returns the routing table, from group 0.
+    Table,
+}
+
+/// A range state machine response.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RangeResponse {
+    /// The key does not belong to the range (anymore), which is given if it still has one. The
+    /// client should refresh its routing table and retry.
+    Mismatch(Option<RangeDescriptor>),
+    Ok,
+    Value(Option<Vec<u8>>),
+    Data(Vec<(Vec<u8>, Vec<u8>)>),
+    Descriptor(Option<RangeDescriptor>),
+    Size(u64),
+    SplitKey(Option<Vec<u8>>),
+    Handover(Option<Handover>),
+    Table(RangeTable),
+}
+
+/// A handover of data from a split or merge, recorded by the group handing over the data until
+/// it is cleaned up. This allows an interrupted split or merge to be resumed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Handover {
+    /// The range taken over by the receiving group, which contains the handed over data.
+    pub range: RangeDescriptor,
+    /// The receiving group's range before the takeover, if any. The takeover is refused if the
+    /// group's range has changed since, keeping the handover pending along with its data.
+    pub recipient: Option<RangeDescriptor>,
+    /// The range descriptors to update the routing table with.
+    pub ranges: Vec<RangeDescriptor>,
+    /// The size of the handed over data, in bytes.
+    pub size: u64,
+}
+
+/// A Raft state machine for a range of the MVCC keyspace, backed by a key/value store. A group
+/// starts out without a range, except group 0 which initially owns the entire keyspace, and
+/// takes over ranges via splits and merges. Group 0 also stores the routing table. The store is
+/// wrapped in a copy-on-write store, whose snapshots back the read-only views.
+pub struct RangeState {
+    store: Box<dyn KvStore>,
+    applied_index: u64,
+    range: Option<RangeDescriptor>,
+    /// The pending handover, if any.
+    handover: Option<Handover>,
+    /// The size of the range's keys and values, in bytes.
+    size: u64,
+}
+
+impl RangeState {
+    /// Creates a new range state machine for the given group, resuming from the store.
+    pub fn new(store: Box<dyn KvStore>, group: u64) -> Result<Self> {
+        let applied_index = match store.get(APPLIED_INDEX_KEY)? {
+            Some(v) => bincode::deserialize(&v)?,
+            None => 0,
+        };
+        let range = match store.get(DESCRIPTOR_KEY)? {
+            Some(v) => Some(bincode::deserialize(&v)?),
+            None if applied_index == 0 && group == 0 => Some(RangeDescriptor::full(0)),
+            None => None,
+        };
+        let handover = store.get(HANDOVER_KEY)?.map(|v| bincode::deserialize(&v)).transpose()?;
+        let size = match store.get(SIZE_KEY)? {
+            Some(v) => bincode::deserialize(&v)?,
+            None => 0,
+        };
+        let store = Box::new(KvCow::new(store));
+        Ok(Self { store, applied_index, range, handover, size })
+    }
+
+    /// Returns the storage key of a data key.
+    fn data_key(key: &[u8]) -> Vec<u8> {
+        let mut data_key = Vec::with_capacity(key.len() + 1);
+        data_key.push(DATA_PREFIX);
+        data_key.extend_from_slice(key);
+        data_key
+    }
+
+    /// Iterates over the data between the given data keys, as key/value pairs in key order.
+    fn scan_data(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+        let start = match start {
+            Bound::Included(key) => Bound::Included(Self::data_key(&key)),
+            Bound::Excluded(key) => Bound::Excluded(Self::data_key(&key)),
+            Bound::Unbounded => Bound::Included(vec![DATA_PREFIX]),
+        };
+        let end = match end {
+            Bound::Included(key) => Bound::Included(Self::data_key(&key)),
+            Bound::Excluded(key) => Bound::Excluded(Self::data_key(&key)),
+            Bound::Unbounded => Bound::Excluded(vec![DATA_PREFIX + 1]),
+        };
+        self.store
+            .scan(Range::from((start, end)))
+            .map(|r| r.map(|(key, value)| (key[1..].to_vec(), value)))
+    }
+
+    /// Returns all data, as key/value pairs in key order.
+    fn data(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_data(Bound::Unbounded, Bound::Unbounded).collect()
+    }
+
+    /// Iterates over the data that may belong to the given range, in key order. It includes
+    /// data outside of the range, which must be filtered out by the caller.
+    fn scan_range(
+        &self,
+        range: &RangeDescriptor,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+        // Only the first range contains non-record keys, so other ranges start at their first
+        // record. Records are ordered by user key, then version.
+        let start = match range.start.is_empty() {
+            true => Bound::Unbounded,
+            false => Bound::Included(Key::Record(range.start.as_slice().into(), 0).encode()),
+        };
+        self.scan_data(start, Bound::Unbounded)
+    }
+
+    /// Returns the data matching the predicate between the given data keys, in key order, up
+    /// to roughly limit bytes (at least one pair).
+    fn data_chunk(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: u64,
+        predicate: impl Fn(&[u8]) -> Result<bool>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut data = Vec::new();
+        let mut size = 0;
+        for result in self.scan_data(start, end) {
+            let (key, value) = result?;
+            if !predicate(&key)? {
+                continue;
+            }
+            size += (key.len() + value.len()) as u64;
+            data.push((key, value));
+            if size >= limit {
+                break;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Checks that a key belongs to the range, returning a mismatch response if it doesn't.
+    fn check_key(&self, key: &[u8]) -> Result<Option<RangeResponse>> {
+        match &self.range {
+            Some(range) if range.contains_encoded(key)? => Ok(None),
+            range => Ok(Some(RangeResponse::Mismatch(range.clone()))),
+        }
+    }
+
+    /// Checks if a key belongs to the pending handover's data.
+    fn is_handed_over(&self, key: &[u8]) -> Result<bool> {
+        let Some(handover) = &self.handover else { return Ok(false) };
+        let key = route_key(key)?;
+        Ok(handover.range.contains(&key) && !self.range.as_ref().is_some_and(|r| r.contains(&key)))
+    }
+
+    /// Returns the range descriptor, or an error if the group has no range.
+    fn range(&self) -> Result<&RangeDescriptor> {
+        self.range.as_ref().ok_or_else(|| Error::Value("Group has no range".into()))
+    }
+
+    /// Stores the range descriptor.
+    fn set_range(&mut self, range: Option<RangeDescriptor>) -> Result<()> {
+        match &range {
+            Some(range) => self.store.set(DESCRIPTOR_KEY, bincode::serialize(range)?)?,
+            None => self.store.delete(DESCRIPTOR_KEY)?,
+        }
+        self.range = range;
+        Ok(())
+    }
+
+    /// Checks whether the group can ingest data for or take over a handed over range, given the
+    /// handover's recipient range. Returns Ok if the range was already taken over, making
+    /// retries noops, and a mismatch if the group's range is no longer the recipient range.
+    fn check_takeover(
+        &self,
+        range: &RangeDescriptor,
+        recipient: &Option<RangeDescriptor>,
+    ) -> Option<RangeResponse> {
+        if self.range.as_ref() == Some(range) {
+            return Some(RangeResponse::Ok);
+        }
+        if &self.range != recipient {
+            return Some(RangeResponse::Mismatch(self.range.clone()));
+        }
+        None
+    }
+
+    /// Stores the pending handover.
+    fn set_handover(&mut self, handover: Option<Handover>) -> Result<()> {
+        match &handover {
+            Some(handover) => self.store.set(HANDOVER_KEY, bincode::serialize(handover)?)?,
+            None => self.store.delete(HANDOVER_KEY)?,
+        }
+        self.handover = handover;
+        Ok(())
+    }
+
+    /// Stores the size of the range's data.
+    fn set_size(&mut self, size: u64) -> Result<()> {
+        self.store.set(SIZE_KEY, bincode::serialize(&size)?)?;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Returns the routing table.
+    fn table(&self) -> Result<RangeTable> {
+        match self.store.get(TABLE_KEY)? {
+            Some(v) => Ok(bincode::deserialize(&v)?),
+            None => Ok(RangeTable::new()),
+        }
+    }
+
+    /// Applies a serialized mutation command without flushing the store.
+    fn apply_command(&mut self, index: u64, command: Vec<u8>) -> Result<Vec<u8>> {
+        // Invalid commands are still applied, returning the error to the client.
+        let result = match bincode::deserialize(&command) {
+            Ok(mutation) => self.apply(mutation),
+            Err(err) => Err(Error::Value(format!("Invalid range mutation: {}", err))),
+        };
+        self.store.set(APPLIED_INDEX_KEY, bincode::serialize(&index)?)?;
+        self.applied_index = index;
+        Ok(bincode::serialize(&result?)?)
+    }
+
+    /// Applies a mutation.
+    fn apply(&mut self, mutation: RangeMutation) -> Result<RangeResponse> {
+        match mutation {
+            RangeMutation::Set { key, value } => {
+                if let Some(mismatch) = self.check_key(&key)? {
+                    return Ok(mismatch);
+                }
+                let data_key = Self::data_key(&key);
+                let old = self.store.get(&data_key)?.map_or(0, |v| key.len() + v.len());
+                let size = self.size + (key.len() + value.len()) as u64 - old as u64;
+                self.store.set(&data_key, value)?;
+                self.set_size(size)?;
+                Ok(RangeResponse::Ok)
+            }
+
+            RangeMutation::Delete { key } => {
+                if let Some(mismatch) = self.check_key(&key)? {
+                    return Ok(mismatch);
+                }
+                let data_key = Self::data_key(&key);
+                if let Some(value) = self.store.get(&data_key)? {
+                    self.store.delete(&data_key)?;
+                    self.set_size(self.size - (key.len() + value.len()) as u64)?;
+                }
+                Ok(RangeResponse::Ok)
+            }
+
+            RangeMutation::Split { key, group } => {
+                if self.handover.is_some() {
+                    return Err(Error::Value("Range has a pending handover".into()));
+                }
+                let left = self.range()?.clone();
+                if key <= left.start || !left.contains(&key) {
+                    return Err(Error::Value(format!("Invalid split key {:x?}", key)));
+                }
+                let generation = left.generation + 1;
+                let right =
+                    RangeDescriptor { group, start: key.clone(), end: left.end, generation };
+                let left = RangeDescriptor { end: Some(key), generation, ..left };
+                let mut size = 0;
+                for result in self.scan_range(&right) {
+                    let (key, value) = result?;
+                    if right.contains_encoded(&key)? {
+                        size += (key.len() + value.len()) as u64;
+                    }
+                }
+                self.set_range(Some(left.clone()))?;
+                self.set_size(self.size - size)?;
+                self.set_handover(Some(Handover {
+                    range: right.clone(),
+                    recipient: None,
+                    ranges: vec![left, right],
+                    size,
+                }))?;
+                Ok(RangeResponse::Ok)
+            }
+
+            RangeMutation::Retire { range, left } => {
+                if self.handover.is_some() {
+                    return Err(Error::Value("Range has a pending handover".into()));
+                }
+                let current = self.range()?;
+                if range.group == current.group
+                    || range.group != left.group
+                    || range.start != left.start
+                    || range.end != current.end
+                    || left.end.as_ref() != Some(&current.start)
+                    || range.generation <= current.generation.max(left.generation)
+                {
+                    return Err(Error::Value(format!("Invalid merged range {:?}", range)));
+                }
+                let size = self.size;
+                self.set_range(None)?;
+                self.set_size(0)?;
+                self.set_handover(Some(Handover {
+                    range: range.clone(),
+                    recipient: Some(left),
+                    ranges: vec![range],
+                    size,
+                }))?;
+                Ok(RangeResponse::Ok)
+            }
+
+            RangeMutation::Ingest { range, recipient, data } => {
+                if let Some(response) = self.check_takeover(&range, &recipient) {
+                    return Ok(response);
+                }
+                for (key, value) in data {
+                    if range.contains_encoded(&key)? {
+                        self.store.set(&Self::data_key(&key), value)?;
+                    }
+                }
+                Ok(RangeResponse::Ok)
+            }
+
+            RangeMutation::Takeover { range, recipient, size } => {
+                if let Some(response) = self.check_takeover(&range, &recipient) {
+                    return Ok(response);
+                }
+                let size = self.range.as_ref().map_or(0, |_| self.size) + size;
+                self.set_range(Some(range))?;
+                self.set_size(size)?;
+                Ok(RangeResponse::Ok)
+            }
+
+            RangeMutation::Cleanup => {
+                let Some(handover) = self.handover.clone() else { return Ok(RangeResponse::Ok) };
+                let mut keys = Vec::new();
+                for result in self.scan_range(&handover.range) {
+                    let (key, _) = result?;
+                    if self.is_handed_over(&key)? {
+                        keys.push(key);
+                    }
+                }
+                for key in keys {
+                    self.store.delete(&Self::data_key(&key))?;
+                }
+                self.set_handover(None)?;
+                Ok(RangeResponse::Ok)
+            }
+
+            RangeMutation::UpdateTable { ranges } => {
+                let mut table = self.table()?;
+                for range in ranges {
+                    table.update(range);
+                }
+                self.store.set(TABLE_KEY, bincode::serialize(&table)?)?;
+                Ok(RangeResponse::Ok)
+            }
+        }
+    }
+
+    /// Finds a user key that splits the range's data roughly in half by size. It scans the
+    /// range until it has seen half of the range's size.
+    fn split_key(&self) -> Result<Option<Vec<u8>>> {
+        let range = self.range()?;
+        let mut size = 0;
+        for result in self.scan_range(range) {
+            let (key, value) = result?;
+            if !range.contains_encoded(&key)? {
+                continue;
+            }
+            size += (key.len() + value.len()) as u64;
+            let key = route_key(&key)?;
+            if size * 2 >= self.size && key > range.start {
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Scans the range's data between the given keys, up to roughly limit bytes.
+    fn scan(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        generation: u64,
+        limit: u64,
+    ) -> Result<RangeResponse> {
+        let range = match &self.range {
+            Some(range) if range.generation == generation => range,
+            range => return Ok(RangeResponse::Mismatch(range.clone())),
+        };
+        Ok(RangeResponse::Data(self.data_chunk(start, end, limit, |key| {
+            range.contains_encoded(key)
+        })?))
+    }
+
+    /// Returns the pending handover's data after the given key, up to roughly limit bytes.
+    fn handover_data(&self, after: Option<Vec<u8>>, limit: u64) -> Result<RangeResponse> {
+        let Some(handover) = &self.handover else { return Ok(RangeResponse::Data(Vec::new())) };
+        let start = match after {
+            Some(key) => Bound::Excluded(key),
+            None if handover.range.start.is_empty() => Bound::Unbounded,
+            None => {
+                Bound::Included(Key::Record(handover.range.start.as_slice().into(), 0).encode())
+            }
+        };
+        Ok(RangeResponse::Data(self.data_chunk(start, Bound::Unbounded, limit, |key| {
+            self.is_handed_over(key)
+        })?))
+    }
+}
+
+impl MachineState for RangeState {
+    fn applied_index(&self) -> u64 {
+        self.applied_index
+    }
+
+    fn mutate(&mut self, index: u64, command: Vec<u8>) -> Result<Vec<u8>> {
+        let result = self.apply_command(index, command);
+        self.store.flush()?;
+        result
+    }
+
+    fn mutate_batch(&mut self, commands: Vec<(u64, Vec<u8>)>) -> Result<Vec<Result<Vec<u8>>>> {
+        let mut results = Vec::with_capacity(commands.len());
+        for (index, command) in commands {
+            match self.apply_command(index, command) {
+                Err(error @ Error::Internal(_)) => return Err(error),
+                result => results.push(result),
+            }
+        }
+        self.store.flush()?;
+        Ok(results)
+    }
+
+    fn query(&self, command: Vec<u8>) -> Result<Vec<u8>> {
+        let response = match bincode::deserialize(&command)? {
+            RangeQuery::Get { key } => match self.check_key(&key)? {
+                Some(mismatch) => mismatch,
+                None => RangeResponse::Value(self.store.get(&Self::data_key(&key))?),
+            },
+            RangeQuery::Scan { start, end, generation, limit } => {
+                self.scan(start, end, generation, limit)?
+            }
+            RangeQuery::Descriptor => RangeResponse::Descriptor(self.range.clone()),
+            RangeQuery::Size => RangeResponse::Size(self.size),
+            RangeQuery::SplitKey => RangeResponse::SplitKey(self.split_key()?),
+            RangeQuery::Handover => RangeResponse::Handover(self.handover.clone()),
+            RangeQuery::HandoverData { after, limit } => self.handover_data(after, limit)?,
+            RangeQuery::Table => RangeResponse::Table(self.table()?),
+        };
+        Ok(bincode::serialize(&response)?)
+    }
+
+    fn view(&self) -> Result<Option<Box<dyn StateView>>> {
+        let Some(store) = self.store.snapshot()? else { return Ok(None) };
+        Ok(Some(Box::new(ReadOnlyState(Self {
+            store,
+            applied_index: self.applied_index,
+            range: self.range.clone(),
+            handover: self.handover.clone(),
+            size: self.size,
+        }))))
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        let table = self.store.get(TABLE_KEY)?;
+        Ok(bincode::serialize(&(&self.range, table, &self.handover, self.size, self.data()?))?)
+    }
+
+    fn restore(&mut self, index: u64, snapshot: Vec<u8>) -> Result<()> {
+        type Snapshot = (
+            Option<RangeDescriptor>,
+            Option<Vec<u8>>,
+            Option<Handover>,
+            u64,
+            Vec<(Vec<u8>, Vec<u8>)>,
+        );
+        let (range, table, handover, size, data): Snapshot = bincode::deserialize(&snapshot)?;
+        // Clear the applied index before touching the data, and only write it back once the
+        // snapshot has been fully restored, such that a crash halfway restores it again.
+        self.store.delete(APPLIED_INDEX_KEY)?;
+        self.store.flush()?;
+        self.applied_index = 0;
+        for (key, _) in self.data()? {
+            self.store.delete(&Self::data_key(&key))?;
+        }
+        for (key, value) in data {
+            self.store.set(&Self::data_key(&key), value)?;
+        }
+        match table {
+            Some(table) => self.store.set(TABLE_KEY, table)?,
+            None => self.store.delete(TABLE_KEY)?,
+        }
+        self.set_range(range)?;
+        self.set_handover(handover)?;
+        self.set_size(size)?;
+        self.store.set(APPLIED_INDEX_KEY, bincode::serialize(&index)?)?;
+        self.applied_index = index;
+        self.store.flush()
+    }
+
+    fn save_sessions(&mut self, index: u64, sessions: Vec<u8>) -> Result<()> {
+        self.store.set(SESSIONS_KEY, bincode::serialize(&(index, sessions))?)?;
+        self.store.flush()
+    }
+
+    fn load_sessions(&self) -> Result<Option<(u64, Vec<u8>)>> {
+        self.store.get(SESSIONS_KEY)?.map(|v| Ok(bincode::deserialize(&v)?)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_engine::{key_value_storage::KvMemory, mvcc_storage::Key};
+    use pretty_assertions::assert_eq;
+
+    fn key(key: &[u8]) -> Vec<u8> {
+        Key::Record(key.into(), 1).encode()
+    }
+
+    fn mutate(state: &mut RangeState, mutation: RangeMutation) -> Result<RangeResponse> {
+        let index = state.applied_index() + 1;
+        Ok(bincode::deserialize(&state.mutate(index, bincode::serialize(&mutation)?)?)?)
+    }
+
+    fn query(state: &RangeState, query: RangeQuery) -> Result<RangeResponse> {
+        Ok(bincode::deserialize(&state.query(bincode::serialize(&query)?)?)?)
+    }
+
+    /// Hands over the pending handover's data from one state to another in chunks of the given
+    /// size, and takes over the range. Returns the number of chunks.
+    fn hand_over(from: &mut RangeState, to: &mut RangeState, limit: u64) -> Result<usize> {
+        let RangeResponse::Handover(Some(handover)) = query(from, RangeQuery::Handover)? else {
+            panic!("expected handover")
+        };
+        let mut after = None;
+        let mut chunks = 0;
+        loop {
+            let RangeResponse::Data(data) =
+                query(from, RangeQuery::HandoverData { after, limit })?
+            else {
+                panic!("expected data")
+            };
+            let Some((last, _)) = data.last() else { break };
+            after = Some(last.clone());
+            chunks += 1;
+            let (range, recipient) = (handover.range.clone(), handover.recipient.clone());
+            let ingest = RangeMutation::Ingest { range, recipient, data };
+            assert_eq!(mutate(to, ingest)?, RangeResponse::Ok);
+        }
+        let Handover { range, recipient, size, .. } = handover;
+        let takeover = RangeMutation::Takeover { range, recipient, size };
+        assert_eq!(mutate(to, takeover)?, RangeResponse::Ok);
+        assert_eq!(mutate(from, RangeMutation::Cleanup)?, RangeResponse::Ok);
+        assert_eq!(query(from, RangeQuery::Handover)?, RangeResponse::Handover(None));
+        Ok(chunks)
+    }
+
+    #[test]
+    // Splitting a range hands over its upper half to another group, and merging hands it back.
+    fn split_merge() -> Result<()> {
+        let mut left = RangeState::new(Box::new(KvMemory::new()), 0)?;
+        let mut right = RangeState::new(Box::new(KvMemory::new()), 1)?;
+        assert_eq!(query(&right, RangeQuery::Descriptor)?, RangeResponse::Descriptor(None));
+        for k in [b"a", b"b", b"c", b"d"] {
+            mutate(&mut left, RangeMutation::Set { key: key(k), value: vec![0x01] })?;
+        }
+        mutate(&mut left, RangeMutation::Set { key: key(b"d"), value: vec![0x02] })?;
+        let RangeResponse::Size(size) = query(&left, RangeQuery::Size)? else { panic!() };
+        assert_eq!(size, 4 * (key(b"a").len() + 1) as u64);
+        assert_eq!(query(&left, RangeQuery::SplitKey)?, RangeResponse::SplitKey(Some(b"b".into())));
+
+        assert_eq!(
+            mutate(&mut left, RangeMutation::Split { key: b"c".to_vec(), group: 1 })?,
+            RangeResponse::Ok
+        );
+        let RangeResponse::Handover(Some(handover)) = query(&left, RangeQuery::Handover)? else {
+            panic!("expected handover")
+        };
+        let expect = RangeDescriptor { group: 1, start: b"c".into(), end: None, generation: 1 };
+        assert_eq!(handover.range, expect);
+        assert_eq!(handover.size, size / 2);
+
+        // A split survives a restart, and its data is handed over in chunks.
+        let store = std::mem::replace(&mut left.store, Box::new(KvMemory::new()));
+        let mut left = RangeState::new(store, 0)?;
+        assert_eq!(hand_over(&mut left, &mut right, 1)?, 2);
+
+        assert_eq!(
+            query(&left, RangeQuery::Get { key: key(b"a") })?,
+            RangeResponse::Value(Some(vec![0x01]))
+        );
+        assert!(matches!(
+            query(&left, RangeQuery::Get { key: key(b"c") })?,
+            RangeResponse::Mismatch(Some(_))
+        ));
+        assert_eq!(
+            query(&right, RangeQuery::Get { key: key(b"d") })?,
+            RangeResponse::Value(Some(vec![0x02]))
+        );
+        assert_eq!(query(&left, RangeQuery::Size)?, query(&right, RangeQuery::Size)?);
+        assert_eq!(left.data()?.len(), 2);
+
+        // Merge the right range back into the left. The merged range must cover both ranges.
+        let RangeResponse::Descriptor(Some(planned)) = query(&left, RangeQuery::Descriptor)? else {
+            panic!("expected descriptor")
+        };
+        let merged = RangeDescriptor { group: 0, start: vec![], end: None, generation: 2 };
+        let short = RangeDescriptor { end: Some(b"d".to_vec()), ..merged.clone() };
+        let retire = RangeMutation::Retire { range: short, left: planned.clone() };
+        assert!(mutate(&mut right, retire).is_err());
+        mutate(&mut right, RangeMutation::Retire { range: merged, left: planned })?;
+        assert!(matches!(
+            query(&right, RangeQuery::Get { key: key(b"c") })?,
+            RangeResponse::Mismatch(None)
+        ));
+        assert_eq!(hand_over(&mut right, &mut left, 1000)?, 1);
+        assert_eq!(query(&right, RangeQuery::Size)?, RangeResponse::Size(0));
+        assert_eq!(query(&left, RangeQuery::Size)?, RangeResponse::Size(size));
+        assert!(right.data()?.is_empty());
+        assert_eq!(
+            query(&left, RangeQuery::Get { key: key(b"d") })?,
+            RangeResponse::Value(Some(vec![0x02]))
+        );
+
+        // The routing table is updated separately.
+        let ranges = vec![RangeDescriptor { generation: 3, ..RangeDescriptor::full(0) }];
+        mutate(&mut left, RangeMutation::UpdateTable { ranges })?;
+        let RangeResponse::Table(table) = query(&left, RangeQuery::Table)? else {
+            panic!("expected table")
+        };
+        assert_eq!(table.lookup(b"x").map(|r| r.generation), Some(3));
+        Ok(())
+    }
+
+    #[test]
+    // A merge is refused if the left range has changed since the merge was planned, and the
+    // retired range keeps its data.
+    fn merge_refused() -> Result<()> {
+        let mut left = RangeState::new(Box::new(KvMemory::new()), 0)?;
+        let mut right = RangeState::new(Box::new(KvMemory::new()), 1)?;
+        for k in [b"a", b"b", b"c", b"d"] {
+            mutate(&mut left, RangeMutation::Set { key: key(k), value: vec![0x01] })?;
+        }
+        mutate(&mut left, RangeMutation::Split { key: b"c".to_vec(), group: 1 })?;
+        hand_over(&mut left, &mut right, 1000)?;
+        let RangeResponse::Descriptor(Some(planned)) = query(&left, RangeQuery::Descriptor)? else {
+            panic!("expected descriptor")
+        };
+
+        // The left range is split again, with the generation of the planned merge.
+        mutate(&mut left, RangeMutation::Split { key: b"b".to_vec(), group: 2 })?;
+        let merged = RangeDescriptor { group: 0, start: vec![], end: None, generation: 2 };
+        let retire = RangeMutation::Retire { range: merged.clone(), left: planned.clone() };
+        mutate(&mut right, retire)?;
+
+        let (range, recipient) = (merged.clone(), Some(planned.clone()));
+        let data = vec![(key(b"c"), vec![0x01])];
+        let ingest = RangeMutation::Ingest { range, recipient, data };
+        assert!(matches!(mutate(&mut left, ingest)?, RangeResponse::Mismatch(Some(_))));
+        let takeover = RangeMutation::Takeover { range: merged, recipient: Some(planned), size: 1 };
+        assert!(matches!(mutate(&mut left, takeover)?, RangeResponse::Mismatch(Some(_))));
+        assert!(matches!(
+            query(&left, RangeQuery::Get { key: key(b"c") })?,
+            RangeResponse::Mismatch(Some(_))
+        ));
+        assert!(matches!(query(&right, RangeQuery::Handover)?, RangeResponse::Handover(Some(_))));
+        assert_eq!(right.data()?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    // Scans only return the range's data, in chunks, for the expected range generation.
+    fn scan() -> Result<()> {
+        let mut state = RangeState::new(Box::new(KvMemory::new()), 0)?;
+        for k in [b"a", b"b", b"c", b"d"] {
+            mutate(&mut state, RangeMutation::Set { key: key(k), value: vec![0x01] })?;
+        }
+        mutate(&mut state, RangeMutation::Split { key: b"c".to_vec(), group: 1 })?;
+
+        let scan = |start, generation, limit| {
+            query(&state, RangeQuery::Scan { start, end: Bound::Unbounded, generation, limit })
+        };
+        let RangeResponse::Data(data) = scan(Bound::Unbounded, 1, 1000)? else { panic!() };
+        assert_eq!(data, vec![(key(b"a"), vec![0x01]), (key(b"b"), vec![0x01])]);
+        let RangeResponse::Data(data) = scan(Bound::Unbounded, 1, 1)? else { panic!() };
+        assert_eq!(data, vec![(key(b"a"), vec![0x01])]);
+        let RangeResponse::Data(data) = scan(Bound::Excluded(key(b"a")), 1, 1)? else { panic!() };
+        assert_eq!(data, vec![(key(b"b"), vec![0x01])]);
+        assert!(matches!(scan(Bound::Unbounded, 0, 1000)?, RangeResponse::Mismatch(Some(_))));
+        Ok(())
+    }
+
+    #[test]
+    // Restoring a snapshot replaces the data and the pending handover.
+    fn snapshot_restore() -> Result<()> {
+        let mut state = RangeState::new(Box::new(KvMemory::new()), 0)?;
+        for k in [b"a", b"b", b"c"] {
+            mutate(&mut state, RangeMutation::Set { key: key(k), value: vec![0x01] })?;
+        }
+        mutate(&mut state, RangeMutation::Split { key: b"b".to_vec(), group: 1 })?;
+        let snapshot = state.snapshot()?;
+
+        let mut restored = RangeState::new(Box::new(KvMemory::new()), 0)?;
+        mutate(&mut restored, RangeMutation::Set { key: key(b"x"), value: vec![0x02] })?;
+        restored.restore(7, snapshot)?;
+        assert_eq!(restored.applied_index(), 7);
+        assert_eq!(restored.data()?, state.data()?);
+        for q in [RangeQuery::Descriptor, RangeQuery::Size, RangeQuery::Handover] {
+            assert_eq!(query(&restored, q.clone())?, query(&state, q)?);
+        }
+
+        let restored = RangeState::new(restored.store, 0)?;
+        assert_eq!(restored.applied_index(), 7);
+        assert_eq!(query(&restored, RangeQuery::Handover)?, query(&state, RangeQuery::Handover)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use super::RangeDescriptor;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A routing table mapping user keys to the ranges that own them. The authoritative table is
+/// stored in Raft group 0, and clients cache a copy which they refresh when it is stale.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RangeTable {
+    /// Ranges by start key.
+    ranges: BTreeMap<Vec<u8>, RangeDescriptor>,
+}
+
+impl Default for RangeTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RangeTable {
+    /// Creates a routing table with a single range owned by group 0.
+    pub fn new() -> Self {
+        let range = RangeDescriptor::full(0);
+        Self { ranges: BTreeMap::from([(range.start.clone(), range)]) }
+    }
+
+    /// Looks up the range containing a user key. Returns None if the table has a gap there,
+    /// which can only happen while a stale table is being updated.
+    pub fn lookup(&self, key: &[u8]) -> Option<&RangeDescriptor> {
+        self.ranges
+            .range(..=key.to_vec())
+            .next_back()
+            .map(|(_, range)| range)
+            .filter(|range| range.contains(key))
+    }
+
+    /// Looks up the range owned by a group.
+    pub fn get(&self, group: u64) -> Option<&RangeDescriptor> {
+        self.ranges.values().find(|range| range.group == group)
+    }
+
+    /// Returns the ranges in key order.
+    pub fn ranges(&self) -> impl Iterator<Item = &RangeDescriptor> {
+        self.ranges.values()
+    }
+
+    /// Updates the table with a range descriptor, replacing any ranges that overlap it or are
+    /// owned by the same group. Older generations than the existing ones are ignored.
+    pub fn update(&mut self, range: RangeDescriptor) {
+        let overlaps = |other: &RangeDescriptor| {
+            other.group == range.group
+                || (range.end.as_ref().is_none_or(|end| &other.start < end)
+                    && other.end.as_ref().is_none_or(|end| &range.start < end))
+        };
+        let replaced: Vec<_> =
+            self.ranges.values().filter(|other| overlaps(other)).cloned().collect();
+        let is_stale = replaced
+            .iter()
+            .any(|other| other.group == range.group && other.generation > range.generation);
+        if is_stale {
+            return;
+        }
+        for other in replaced {
+            self.ranges.remove(&other.start);
+        }
+        self.ranges.insert(range.start.clone(), range);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn range(group: u64, start: &[u8], end: Option<&[u8]>, generation: u64) -> RangeDescriptor {
+        RangeDescriptor { group, start: start.to_vec(), end: end.map(|e| e.to_vec()), generation }
+    }
+
+    #[test]
+    fn lookup_update() {
+        let mut table = RangeTable::new();
+        assert_eq!(table.lookup(b"x"), Some(&RangeDescriptor::full(0)));
+
+        // Split group 0 at m into group 1.
+        table.update(range(0, b"", Some(b"m"), 1));
+        assert_eq!(table.lookup(b"a").map(|r| r.group), Some(0));
+        assert_eq!(table.lookup(b"x"), None);
+        table.update(range(1, b"m", None, 1));
+        assert_eq!(table.lookup(b"m").map(|r| r.group), Some(1));
+        assert_eq!(table.get(1), Some(&range(1, b"m", None, 1)));
+
+        // Stale descriptors are ignored.
+        table.update(range(0, b"", None, 0));
+        assert_eq!(table.lookup(b"x").map(|r| r.group), Some(1));
+
+        // Merge group 1 into group 0.
+        table.update(range(0, b"", None, 2));
+        assert_eq!(table.lookup(b"x").map(|r| r.group), Some(0));
+        assert_eq!(table.ranges().count(), 1);
+        assert_eq!(table.get(1), None);
+    }
+}
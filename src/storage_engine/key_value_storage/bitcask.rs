@@ -0,0 +1,570 @@
+use crate::{
+    error::{Error, Result},
+    storage_engine::key_value_storage::{
+        KvStore, Range, Scan
+    }
+};
+
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek as _, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The length of a record header: a u32 key length followed by an i32 value length.
+const HEADER_SIZE: u64 = 8;
+
+/// The minimum log file size at which a running store is compacted, to avoid repeatedly
+/// rewriting small files.
+const COMPACT_MIN_SIZE: u64 = 1 << 20;
+
+/// A persistent key-value store, using an append-only log file with an in-memory key directory.
+/// This is a simplified variant of BitCask: https://riak.com/assets/bitcask-intro.pdf
+///
+/// Every write appends a record to the end of the log file, and deletes append a tombstone
+/// record. Each record consists of a big-endian u32 key length, a big-endian i32 value length
+/// (-1 for tombstones), the key bytes, and the value bytes.
+///
+/// The key directory maps every live key to the position and length of its latest value in the
+/// log file, so a read is a single seek. It is rebuilt on startup by scanning the log file, and any
+/// incomplete record at the end of the file (e.g. after a crash during a write) is truncated.
+///
+/// Overwritten values and tombstones remain in the log file as garbage until it is compacted,
+/// which rewrites the live keys into a new file and atomically replaces the old one. Compaction can
+/// be run online via compact(), offline via compact_dir(), or automatically via new_compact() when
+/// the garbage ratio exceeds a threshold, both on open and on flush() while the store is in use.
+pub struct KvBitCask {
+    /// The path to the log file.
+    path: PathBuf,
+    /// The append-only log file. Protected by a mutex for interior mutability (i.e. read seeks).
+    file: Mutex<File>,
+    /// Maps keys to the position and length of their latest value in the log file.
+    keydir: BTreeMap<Vec<u8>, (u64, u32)>,
+    /// The size of the log file.
+    size: u64,
+    /// The size of the live records in the log file.
+    live_size: u64,
+    /// The garbage ratio at which to compact the store on flush, if any.
+    compact_threshold: Option<f64>,
+}
+
+impl Display for KvBitCask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bitcask")
+    }
+}
+
+impl KvBitCask {
+    /// Creates or opens a BitCask store, with its log file in the given directory.
+    pub fn new(dir: &Path) -> Result<Self> {
+        create_dir_all(dir)?;
+        let path = dir.join("kv-log");
+        let mut file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let keydir = Self::build_keydir(&mut file)?;
+        let size = file.metadata()?.len();
+        let live_size = keydir.iter().map(|(key, (_, len))| Self::record_size(key, *len)).sum();
+        Ok(Self { path, file: Mutex::new(file), keydir, size, live_size, compact_threshold: None })
+    }
+
+    /// Creates or opens a BitCask store, compacting it if the fraction of garbage in the log file
+    /// is at or above the given threshold (between 0.0 and 1.0). The store is also compacted on
+    /// flush() once the threshold is reached again, if the log file is at least COMPACT_MIN_SIZE.
+    pub fn new_compact(dir: &Path, garbage_ratio_threshold: f64) -> Result<Self> {
+        if !(0.0..=1.0).contains(&garbage_ratio_threshold) {
+            return Err(Error::Config(format!(
+                "Garbage ratio threshold must be between 0.0 and 1.0, got {}",
+                garbage_ratio_threshold
+            )));
+        }
+        let mut store = Self::new(dir)?;
+        let garbage_ratio = store.garbage_ratio()?;
+        if garbage_ratio > 0.0 && garbage_ratio >= garbage_ratio_threshold {
+            info!(
+                "Compacting {} with garbage ratio {:.2} (threshold {:.2})",
+                store.path.display(),
+                garbage_ratio,
+                garbage_ratio_threshold
+            );
+            store.compact()?;
+        }
+        store.compact_threshold = Some(garbage_ratio_threshold);
+        Ok(store)
+    }
+
+    /// Compacts the BitCask store in the given directory while it is not in use, returning the
+    /// number of bytes reclaimed.
+    pub fn compact_dir(dir: &Path) -> Result<u64> {
+        Self::new(dir)?.compact()
+    }
+
+    /// Compacts the log file by writing the live keys to a new file and atomically replacing the
+    /// old one with it. Returns the number of bytes reclaimed.
+    pub fn compact(&mut self) -> Result<u64> {
+        let mut file = self.file.lock()?;
+        let old_size = file.metadata()?.len();
+
+        let new_path = self.path.with_extension("new");
+        let mut new_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&new_path)?;
+        let mut new_keydir = BTreeMap::new();
+        let mut bufwriter = BufWriter::new(&mut new_file);
+        let mut pos = 0;
+        for (key, (value_pos, value_len)) in self.keydir.iter() {
+            let value = Self::read_value(&mut file, *value_pos, *value_len)?;
+            let record = Self::encode_record(key, Some(&value))?;
+            bufwriter.write_all(&record)?;
+            pos += record.len() as u64;
+            new_keydir.insert(key.clone(), (pos - *value_len as u64, *value_len));
+        }
+        bufwriter.flush()?;
+        drop(bufwriter);
+        new_file.sync_all()?;
+
+        // The rename atomically replaces the old log file, and the directory is fsynced to make
+        // the rename itself durable.
+        std::fs::rename(&new_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        *file = new_file;
+        drop(file);
+        self.keydir = new_keydir;
+        self.size = pos;
+        self.live_size = pos;
+
+        let reclaimed = old_size - pos;
+        info!("Compacted {}, reclaimed {} bytes", self.path.display(), reclaimed);
+        Ok(reclaimed)
+    }
+
+    /// Returns the fraction of the log file which is garbage, i.e. overwritten values and
+    /// tombstones, between 0.0 and 1.0.
+    pub fn garbage_ratio(&self) -> Result<f64> {
+        if self.size == 0 {
+            return Ok(0.0);
+        }
+        Ok((self.size - self.live_size) as f64 / self.size as f64)
+    }
+
+    /// Returns the size of a live record in the log file.
+    fn record_size(key: &[u8], value_len: u32) -> u64 {
+        HEADER_SIZE + key.len() as u64 + value_len as u64
+    }
+
+    /// Builds the key directory by scanning the log file, truncating any incomplete trailing
+    /// record.
+    fn build_keydir(file: &mut File) -> Result<BTreeMap<Vec<u8>, (u64, u32)>> {
+        let filesize = file.metadata()?.len();
+        let mut bufreader = BufReader::new(&mut *file);
+        let mut keydir = BTreeMap::new();
+        let mut headerbuf = [0; HEADER_SIZE as usize];
+        let mut pos = 0;
+        while pos < filesize {
+            let result = || -> std::io::Result<(Vec<u8>, u64, Option<u32>)> {
+                bufreader.read_exact(&mut headerbuf)?;
+                let key_len = u32::from_be_bytes(headerbuf[0..4].try_into().unwrap());
+                let value_len = i32::from_be_bytes(headerbuf[4..8].try_into().unwrap());
+                let value_len = if value_len >= 0 { Some(value_len as u32) } else { None };
+                let value_pos = pos + HEADER_SIZE + key_len as u64;
+                // Check the record bounds before allocating the key, since a torn or corrupt
+                // header may contain arbitrary lengths.
+                if value_pos + value_len.unwrap_or(0) as u64 > filesize {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                let mut key = vec![0; key_len as usize];
+                bufreader.read_exact(&mut key)?;
+                if let Some(value_len) = value_len {
+                    bufreader.seek_relative(value_len as i64)?;
+                }
+                Ok((key, value_pos, value_len))
+            }();
+            match result {
+                Ok((key, value_pos, Some(value_len))) => {
+                    keydir.insert(key, (value_pos, value_len));
+                    pos = value_pos + value_len as u64;
+                }
+                Ok((key, value_pos, None)) => {
+                    keydir.remove(&key);
+                    pos = value_pos;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    warn!("Found incomplete record at offset {}, truncating log file", pos);
+                    drop(bufreader);
+                    file.set_len(pos)?;
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(keydir)
+    }
+
+    /// Encodes a record for the log file. A None value encodes a tombstone.
+    fn encode_record(key: &[u8], value: Option<&[u8]>) -> Result<Vec<u8>> {
+        let key_len = u32::try_from(key.len())
+            .map_err(|_| Error::Value(format!("Key of {} bytes is too large", key.len())))?;
+        let value_len = match value {
+            Some(value) => i32::try_from(value.len())
+                .map_err(|_| Error::Value(format!("Value of {} bytes is too large", value.len())))?,
+            None => -1,
+        };
+        let mut record = Vec::with_capacity(
+            HEADER_SIZE as usize + key.len() + value.map(|v| v.len()).unwrap_or(0),
+        );
+        record.extend_from_slice(&key_len.to_be_bytes());
+        record.extend_from_slice(&value_len.to_be_bytes());
+        record.extend_from_slice(key);
+        record.extend_from_slice(value.unwrap_or_default());
+        Ok(record)
+    }
+
+    /// Appends a record to the end of the log file, returning the position and length of the
+    /// value (if any).
+    fn write_record(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(u64, u32)> {
+        let record = Self::encode_record(key, value)?;
+        let mut file = self.file.lock()?;
+        let pos = file.seek(SeekFrom::End(0))?;
+        file.write_all(&record)?;
+        self.size = pos + record.len() as u64;
+        Ok((pos + HEADER_SIZE + key.len() as u64, value.map(|v| v.len()).unwrap_or(0) as u32))
+    }
+
+    /// Reads a value from the log file at the given position.
+    fn read_value(file: &mut File, pos: u64, len: u32) -> Result<Vec<u8>> {
+        let mut value = vec![0; len as usize];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut value)?;
+        Ok(value)
+    }
+}
+
+impl KvStore for KvBitCask {
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        if self.keydir.contains_key(key) {
+            self.write_record(key, None)?;
+            if let Some((_, len)) = self.keydir.remove(key) {
+                self.live_size -= Self::record_size(key, len);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.lock()?.sync_all()?;
+        if let Some(threshold) = self.compact_threshold {
+            if self.size >= COMPACT_MIN_SIZE && self.garbage_ratio()? >= threshold {
+                self.compact()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.keydir.get(key) {
+            Some((pos, len)) => Ok(Some(Self::read_value(&mut *self.file.lock()?, *pos, *len)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        // The values are read via the shared file handle, which would require holding the lock
+        // for the duration of the iteration. We buffer the entire iteration instead, as is done
+        // for StdMemory.
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(err) => return Box::new(std::iter::once(Err(err.into()))),
+        };
+        Box::new(
+            self.keydir
+                .range(range)
+                .map(|(key, (pos, len))| {
+                    Ok((key.clone(), Self::read_value(&mut file, *pos, *len)?))
+                })
+                .collect::<Vec<_>>()
+                .into_iter(),
+        )
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let (pos, len) = self.write_record(key, Some(&value))?;
+        if let Some((_, old_len)) = self.keydir.insert(key.to_vec(), (pos, len)) {
+            self.live_size -= Self::record_size(key, old_len);
+        }
+        self.live_size += Self::record_size(key, len);
+        Ok(())
+    }
+}
+
+impl Drop for KvBitCask {
+    /// Attempt to fsync data on drop, in case the store was not flushed.
+    fn drop(&mut self) {
+        self.file.lock().map(|f| f.sync_all()).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_engine::mvcc_storage::MVCC;
+    use pretty_assertions::assert_eq;
+
+    fn setup() -> Result<(KvBitCask, tempdir::TempDir)> {
+        let dir = tempdir::TempDir::new("boula")?;
+        Ok((KvBitCask::new(dir.path())?, dir))
+    }
+
+    #[test]
+    fn get_set_delete() -> Result<()> {
+        let (mut s, _dir) = setup()?;
+        assert_eq!(None, s.get(b"a")?);
+
+        s.set(b"a", vec![0x01])?;
+        assert_eq!(Some(vec![0x01]), s.get(b"a")?);
+
+        s.set(b"a", vec![0x02])?;
+        assert_eq!(Some(vec![0x02]), s.get(b"a")?);
+
+        s.set(b"b", vec![])?;
+        assert_eq!(Some(vec![]), s.get(b"b")?);
+
+        s.delete(b"a")?;
+        assert_eq!(None, s.get(b"a")?);
+        s.delete(b"a")?;
+        assert_eq!(None, s.get(b"a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn scan() -> Result<()> {
+        let (mut s, _dir) = setup()?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+        s.set(b"ba", vec![0x03])?;
+        s.set(b"c", vec![0x04])?;
+        s.delete(b"b")?;
+
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), vec![0x01]),
+                (b"ba".to_vec(), vec![0x03]),
+                (b"c".to_vec(), vec![0x04]),
+            ],
+            s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(
+            vec![(b"c".to_vec(), vec![0x04]), (b"ba".to_vec(), vec![0x03])],
+            s.scan(Range::from(b"b".to_vec()..)).rev().collect::<Result<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn persistent() -> Result<()> {
+        let dir = tempdir::TempDir::new("boula")?;
+        let mut s = KvBitCask::new(dir.path())?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+        s.set(b"c", vec![0x03])?;
+        s.set(b"b", vec![0x20])?;
+        s.delete(b"c")?;
+        s.flush()?;
+        drop(s);
+
+        let s = KvBitCask::new(dir.path())?;
+        assert_eq!(
+            vec![(b"a".to_vec(), vec![0x01]), (b"b".to_vec(), vec![0x20])],
+            s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn truncates_incomplete_record() -> Result<()> {
+        let dir = tempdir::TempDir::new("boula")?;
+        let mut s = KvBitCask::new(dir.path())?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02, 0x03])?;
+        drop(s);
+
+        // Chop off the last byte of the final record, as if we crashed while writing it.
+        let path = dir.path().join("kv-log");
+        let size = std::fs::metadata(&path)?.len();
+        OpenOptions::new().write(true).open(&path)?.set_len(size - 1)?;
+
+        let mut s = KvBitCask::new(dir.path())?;
+        assert_eq!(Some(vec![0x01]), s.get(b"a")?);
+        assert_eq!(None, s.get(b"b")?);
+        assert_eq!(size - 11, std::fs::metadata(&path)?.len());
+
+        // New writes should go after the last complete record.
+        s.set(b"c", vec![0x04])?;
+        drop(s);
+        let s = KvBitCask::new(dir.path())?;
+        assert_eq!(
+            vec![(b"a".to_vec(), vec![0x01]), (b"c".to_vec(), vec![0x04])],
+            s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn truncates_corrupt_header() -> Result<()> {
+        let dir = tempdir::TempDir::new("boula")?;
+        let mut s = KvBitCask::new(dir.path())?;
+        s.set(b"a", vec![0x01])?;
+        drop(s);
+
+        // Append a header claiming a huge key, as if it was torn or corrupted. It must be
+        // treated as an incomplete record rather than allocated.
+        let path = dir.path().join("kv-log");
+        let size = std::fs::metadata(&path)?.len();
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&u32::MAX.to_be_bytes())?;
+        file.write_all(&1_i32.to_be_bytes())?;
+        file.write_all(&[0x02])?;
+        drop(file);
+
+        let s = KvBitCask::new(dir.path())?;
+        assert_eq!(Some(vec![0x01]), s.get(b"a")?);
+        assert_eq!(size, std::fs::metadata(&path)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn mvcc() -> Result<()> {
+        let dir = tempdir::TempDir::new("boula")?;
+        let mvcc = MVCC::new(Box::new(KvBitCask::new(dir.path())?));
+        let mut txn = mvcc.begin()?;
+        txn.set(b"key", vec![0x01])?;
+        txn.commit()?;
+        drop(mvcc);
+
+        let mvcc = MVCC::new(Box::new(KvBitCask::new(dir.path())?));
+        let txn = mvcc.begin()?;
+        assert_eq!(2, txn.id());
+        assert_eq!(Some(vec![0x01]), txn.get(b"key")?);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn compact() -> Result<()> {
+        let dir = tempdir::TempDir::new("boula")?;
+        let mut s = KvBitCask::new(dir.path())?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+        s.set(b"b", vec![0x20, 0x21])?;
+        s.set(b"c", vec![0x03])?;
+        s.delete(b"c")?;
+        assert_eq!(29.0 / 50.0, s.garbage_ratio()?);
+
+        // Live records are a=10 and b=11 bytes, out of 50 bytes in total.
+        assert_eq!(29, s.compact()?);
+        assert_eq!(0.0, s.garbage_ratio()?);
+        assert_eq!(21, std::fs::metadata(dir.path().join("kv-log"))?.len());
+        assert!(!dir.path().join("kv-log.new").exists());
+
+        // The store should remain usable after compaction, and survive a reopen.
+        s.set(b"d", vec![0x04])?;
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), vec![0x01]),
+                (b"b".to_vec(), vec![0x20, 0x21]),
+                (b"d".to_vec(), vec![0x04]),
+            ],
+            s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+        );
+        drop(s);
+
+        let s = KvBitCask::new(dir.path())?;
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), vec![0x01]),
+                (b"b".to_vec(), vec![0x20, 0x21]),
+                (b"d".to_vec(), vec![0x04]),
+            ],
+            s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compact_dir() -> Result<()> {
+        let dir = tempdir::TempDir::new("boula")?;
+        let mut s = KvBitCask::new(dir.path())?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"a", vec![0x02])?;
+        drop(s);
+
+        assert_eq!(10, KvBitCask::compact_dir(dir.path())?);
+        assert_eq!(0, KvBitCask::compact_dir(dir.path())?);
+        assert_eq!(Some(vec![0x02]), KvBitCask::new(dir.path())?.get(b"a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn new_compact() -> Result<()> {
+        let dir = tempdir::TempDir::new("boula")?;
+        let path = dir.path().join("kv-log");
+        let mut s = KvBitCask::new(dir.path())?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"a", vec![0x02])?;
+        s.set(b"b", vec![0x03])?;
+        s.set(b"c", vec![0x04])?;
+        drop(s);
+
+        // 10 out of 40 bytes are garbage, so a ratio of 0.3 should not trigger compaction.
+        let s = KvBitCask::new_compact(dir.path(), 0.3)?;
+        assert_eq!(0.25, s.garbage_ratio()?);
+        drop(s);
+        assert_eq!(40, std::fs::metadata(&path)?.len());
+
+        // A ratio of 0.25 should.
+        let s = KvBitCask::new_compact(dir.path(), 0.25)?;
+        assert_eq!(0.0, s.garbage_ratio()?);
+        assert_eq!(Some(vec![0x02]), s.get(b"a")?);
+        drop(s);
+        assert_eq!(30, std::fs::metadata(&path)?.len());
+
+        assert_eq!(
+            Some(Error::Config("Garbage ratio threshold must be between 0.0 and 1.0, got 1.5".into())),
+            KvBitCask::new_compact(dir.path(), 1.5).err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    // A running store opened via new_compact() is compacted on flush once the threshold is
+    // reached, including when used via a boxed KvStore.
+    fn compact_flush() -> Result<()> {
+        let dir = tempdir::TempDir::new("boula")?;
+        let path = dir.path().join("kv-log");
+        let mut s: Box<dyn KvStore> = Box::new(KvBitCask::new_compact(dir.path(), 0.5)?);
+        let value = vec![0xff; 64 * 1024];
+        for _ in 0..8 {
+            s.set(b"a", value.clone())?;
+        }
+        s.set(b"b", vec![0x01])?;
+        // The file is below COMPACT_MIN_SIZE, so it isn't compacted yet.
+        s.flush()?;
+        assert!(std::fs::metadata(&path)?.len() > 8 * 64 * 1024);
+
+        for _ in 0..16 {
+            s.set(b"a", value.clone())?;
+        }
+        s.flush()?;
+        assert_eq!(2 * 8 + 2 + 64 * 1024 + 1, std::fs::metadata(&path)?.len());
+        assert_eq!(Some(value), s.get(b"a")?);
+        assert_eq!(Some(vec![0x01]), s.get(b"b")?);
+        drop(s);
+        assert_eq!(Some(vec![0x01]), KvBitCask::new(dir.path())?.get(b"b")?);
+        Ok(())
+    }
+}
@@ -60,7 +60,7 @@ impl Children {
         child.delete(key);
 
         // If the child does not underflow, or it has no siblings, we're done.
-        if child.size() >= (child.order() + 1) / 2 || self.len() == 1 {
+        if child.size() >= child.order().div_ceil(2) || self.len() == 1 {
             return;
         }
 
@@ -71,9 +71,9 @@ impl Children {
         let (rsize, rorder) =
             if i < self.len() - 1 { (self[i + 1].size(), self[i + 1].order()) } else { (0, 0) };
 
-        if lsize > (lorder + 1) / 2 {
+        if lsize > lorder.div_ceil(2) {
             self.rotate_right(i - 1);
-        } else if rsize > (rorder + 1) / 2 {
+        } else if rsize > rorder.div_ceil(2) {
             self.rotate_left(i + 1);
         } else if lsize + size <= lorder {
             self.merge(i - 1);
@@ -140,7 +140,7 @@ impl Children {
     /// Looks up the child responsible for a given key. This can only be called on non-empty
     /// child sets, which should be all child sets except for the initial root node.
     pub fn lookup(&self, key: &[u8]) -> (usize, &Node) {
-        let i = self.keys.iter().position(|k| k.deref() > key).unwrap_or_else(|| self.keys.len());
+        let i = self.keys.iter().position(|k| k.deref() > key).unwrap_or(self.keys.len());
         (i, &self[i])
     }
 
@@ -148,7 +148,7 @@ impl Children {
     /// can only be called on non-empty child sets, which should be all child sets except for the
     /// initial root node.
     pub fn lookup_mut(&mut self, key: &[u8]) -> (usize, &mut Node) {
-        let i = self.keys.iter().position(|k| k.deref() > key).unwrap_or_else(|| self.keys.len());
+        let i = self.keys.iter().position(|k| k.deref() > key).unwrap_or(self.keys.len());
         (i, &mut self[i])
     }
 
@@ -0,0 +1,408 @@
+use crate::storage_engine::key_value_storage::{KvStore, Range, Scan};
+use crate::error::{Error, Result};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+/// A key/value store wrapper which provides read-only point-in-time snapshots via copy-on-write.
+/// While snapshots are open, every write first saves the key's previous value for them, so
+/// snapshots never see later writes. Snapshots only hold the store lock for individual reads,
+/// never for their lifetime, so writes don't wait for snapshot readers to finish.
+pub struct KvCow {
+    shared: Arc<RwLock<Shared>>,
+}
+
+/// State shared by a KvCow store and its snapshots.
+struct Shared {
+    /// The underlying store.
+    store: Box<dyn KvStore>,
+    /// The current epoch, incremented by every snapshot. Writes belong to the current epoch, and
+    /// a snapshot sees all writes from earlier epochs.
+    epoch: u64,
+    /// The epochs of open snapshots, with the number of snapshots at each.
+    snapshots: BTreeMap<u64, usize>,
+    /// Previous values of keys written while snapshots were open, by key and the epoch of the
+    /// write. None if the key didn't exist. Only the first write in each epoch is recorded.
+    undo: BTreeMap<Vec<u8>, BTreeMap<u64, Option<Vec<u8>>>>,
+}
+
+impl Shared {
+    /// Returns the value of a key as seen by a snapshot at the given epoch, if it was changed
+    /// since. The outer Option is None if the key wasn't changed.
+    fn undo(&self, key: &[u8], epoch: u64) -> Option<Option<Vec<u8>>> {
+        let (_, value) = self.undo.get(key)?.range(epoch + 1..).next()?;
+        Some(value.clone())
+    }
+
+    /// Saves the previous value of a key before writing it, if any snapshots are open.
+    fn save(&mut self, key: &[u8]) -> Result<()> {
+        if self.snapshots.is_empty() {
+            return Ok(());
+        }
+        let epoch = self.epoch;
+        if self.undo.get(key).is_some_and(|versions| versions.contains_key(&epoch)) {
+            return Ok(());
+        }
+        let value = self.store.get(key)?;
+        self.undo.entry(key.to_vec()).or_default().insert(epoch, value);
+        Ok(())
+    }
+
+    /// Releases a snapshot at the given epoch, removing previous values no longer needed.
+    fn release(&mut self, epoch: u64) {
+        if let Some(count) = self.snapshots.get_mut(&epoch) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&epoch);
+            }
+        }
+        // Writes at or before the oldest snapshot's epoch are not needed by any snapshot.
+        match self.snapshots.keys().next().copied() {
+            None => self.undo.clear(),
+            Some(oldest) => self.undo.retain(|_, versions| {
+                versions.retain(|epoch, _| *epoch > oldest);
+                !versions.is_empty()
+            }),
+        }
+    }
+}
+
+impl KvCow {
+    /// Wraps a key/value store.
+    pub fn new(store: Box<dyn KvStore>) -> Self {
+        let shared = Shared { store, epoch: 0, snapshots: BTreeMap::new(), undo: BTreeMap::new() };
+        Self { shared: Arc::new(RwLock::new(shared)) }
+    }
+}
+
+impl Display for KvCow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.shared.read() {
+            Ok(shared) => write!(f, "cow({})", shared.store),
+            Err(_) => write!(f, "cow"),
+        }
+    }
+}
+
+impl KvStore for KvCow {
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let mut shared = self.shared.write()?;
+        shared.save(key)?;
+        shared.store.delete(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.shared.write()?.store.flush()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.shared.read()?.store.get(key)
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        match self.shared.read() {
+            Ok(shared) => shared.store.scan(range),
+            Err(err) => Box::new(std::iter::once(Err(err.into()))),
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let mut shared = self.shared.write()?;
+        shared.save(key)?;
+        shared.store.set(key, value)
+    }
+
+    fn snapshot(&self) -> Result<Option<Box<dyn KvStore>>> {
+        let mut shared = self.shared.write()?;
+        let epoch = shared.epoch;
+        shared.epoch += 1;
+        *shared.snapshots.entry(epoch).or_default() += 1;
+        Ok(Some(Box::new(KvCowSnapshot { shared: self.shared.clone(), epoch })))
+    }
+}
+
+/// A read-only point-in-time snapshot of a KvCow store. Writes return Error::ReadOnly.
+pub struct KvCowSnapshot {
+    shared: Arc<RwLock<Shared>>,
+    epoch: u64,
+}
+
+impl Drop for KvCowSnapshot {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.write() {
+            shared.release(self.epoch)
+        }
+    }
+}
+
+impl Display for KvCowSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "snapshot@{}", self.epoch)
+    }
+}
+
+impl KvStore for KvCowSnapshot {
+    fn delete(&mut self, _: &[u8]) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let shared = self.shared.read()?;
+        match shared.undo(key, self.epoch) {
+            Some(value) => Ok(value),
+            None => shared.store.get(key),
+        }
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        match SnapshotScan::new(self.shared.clone(), self.epoch, range) {
+            Ok(scan) => Box::new(scan),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    }
+
+    fn set(&mut self, _: &[u8], _: Vec<u8>) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+}
+
+/// A snapshot scan. It iterates over the underlying store without holding the lock, and
+/// corrects each key/value pair from the previous values saved for the snapshot.
+///
+/// A key seen by the underlying scan has its snapshot value if it hasn't been written since
+/// the snapshot, and its saved previous value otherwise. Keys that are skipped by the
+/// underlying scan didn't exist when the scan moved past them, so they are only visible to
+/// the snapshot if they were deleted since, in which case their previous value was saved.
+struct SnapshotScan {
+    shared: Arc<RwLock<Shared>>,
+    epoch: u64,
+    /// The underlying store scan, or None once it's exhausted.
+    inner: Option<Scan>,
+    /// The lower bound of the keys not yet returned by the underlying scan.
+    front: Bound<Vec<u8>>,
+    /// The upper bound of the keys not yet returned by the underlying scan.
+    back: Bound<Vec<u8>>,
+    /// Buffered key/value pairs from the front, in order.
+    front_buffer: VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// Buffered key/value pairs from the back, in order.
+    back_buffer: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl SnapshotScan {
+    /// Creates a new snapshot scan.
+    fn new(shared: Arc<RwLock<Shared>>, epoch: u64, range: Range) -> Result<Self> {
+        let bounds = (range.start.clone(), range.end.clone());
+        let inner = shared.read()?.store.scan(Range::from(bounds));
+        Ok(Self {
+            shared,
+            epoch,
+            inner: Some(inner),
+            front: range.start,
+            back: range.end,
+            front_buffer: VecDeque::new(),
+            back_buffer: VecDeque::new(),
+        })
+    }
+
+    /// Returns the snapshot's key/value pairs between the given bounds that are not in the
+    /// underlying store, i.e. those deleted since the snapshot, in order.
+    fn deleted(
+        &self,
+        shared: &Shared,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        // BTreeMap::range() panics on inverted or empty exclusive ranges.
+        let is_empty = match (&start, &end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start >= end,
+            _ => false,
+        };
+        if is_empty {
+            return Vec::new();
+        }
+        shared
+            .undo
+            .range((start, end))
+            .filter_map(|(key, _)| Some((key.clone(), shared.undo(key, self.epoch)??)))
+            .collect()
+    }
+
+    /// Returns a key/value pair from the underlying scan as seen by the snapshot, if visible.
+    fn visible(
+        &self,
+        shared: &Shared,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Option<(Vec<u8>, Vec<u8>)> {
+        match shared.undo(&key, self.epoch) {
+            Some(value) => Some((key, value?)),
+            None => Some((key, value)),
+        }
+    }
+
+    /// Steps the underlying scan from the front, buffering the results. Returns false if the
+    /// underlying scan is exhausted.
+    fn step_front(&mut self) -> Result<bool> {
+        let Some(inner) = self.inner.as_mut() else { return Ok(false) };
+        let next = inner.next().transpose()?;
+        let shared = self.shared.read()?;
+        let Some((key, value)) = next else {
+            let deleted = self.deleted(&shared, self.front.clone(), self.back.clone());
+            self.front_buffer.extend(deleted);
+            self.inner = None;
+            return Ok(false);
+        };
+        let deleted = self.deleted(&shared, self.front.clone(), Bound::Excluded(key.clone()));
+        self.front_buffer.extend(deleted);
+        self.front = Bound::Excluded(key.clone());
+        self.front_buffer.extend(self.visible(&shared, key, value));
+        Ok(true)
+    }
+
+    /// Steps the underlying scan from the back, buffering the results. Returns false if the
+    /// underlying scan is exhausted.
+    fn step_back(&mut self) -> Result<bool> {
+        let Some(inner) = self.inner.as_mut() else { return Ok(false) };
+        let next = inner.next_back().transpose()?;
+        let shared = self.shared.read()?;
+        let Some((key, value)) = next else {
+            let deleted = self.deleted(&shared, self.front.clone(), self.back.clone());
+            for item in deleted.into_iter().rev() {
+                self.back_buffer.push_front(item);
+            }
+            self.inner = None;
+            return Ok(false);
+        };
+        let deleted = self.deleted(&shared, Bound::Excluded(key.clone()), self.back.clone());
+        self.back = Bound::Excluded(key.clone());
+        for item in self.visible(&shared, key, value).into_iter().chain(deleted).rev() {
+            self.back_buffer.push_front(item);
+        }
+        Ok(true)
+    }
+
+    /// next() with error handling.
+    fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while self.front_buffer.is_empty() && self.step_front()? {}
+        Ok(self.front_buffer.pop_front().or_else(|| self.back_buffer.pop_front()))
+    }
+
+    /// next_back() with error handling.
+    fn try_next_back(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while self.back_buffer.is_empty() && self.step_back()? {}
+        Ok(self.back_buffer.pop_back().or_else(|| self.front_buffer.pop_back()))
+    }
+}
+
+impl Iterator for SnapshotScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+impl DoubleEndedIterator for SnapshotScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.try_next_back().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_engine::key_value_storage::KvMemory;
+    use pretty_assertions::assert_eq;
+
+    fn scan(store: &dyn KvStore, range: Range) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        store.scan(range).collect()
+    }
+
+    #[test]
+    // Snapshots see the store as of when they were taken, in gets and scans in both directions,
+    // including while writes happen during a scan.
+    fn snapshot() -> Result<()> {
+        let mut store = KvCow::new(Box::new(KvMemory::new()));
+        for key in [b"a", b"b", b"c", b"d"] {
+            store.set(key, vec![0x01])?;
+        }
+        let first = store.snapshot()?.expect("snapshot");
+        store.set(b"b", vec![0x02])?;
+        store.set(b"b", vec![0x03])?;
+        store.delete(b"c")?;
+        store.set(b"e", vec![0x02])?;
+        let second = store.snapshot()?.expect("snapshot");
+        store.delete(b"a")?;
+        store.set(b"c", vec![0x03])?;
+
+        assert_eq!(first.get(b"a")?, Some(vec![0x01]));
+        assert_eq!(first.get(b"b")?, Some(vec![0x01]));
+        assert_eq!(first.get(b"e")?, None);
+        assert_eq!(second.get(b"a")?, Some(vec![0x01]));
+        assert_eq!(second.get(b"c")?, None);
+        assert_eq!(store.get(b"b")?, Some(vec![0x03]));
+
+        let expect = |pairs: &[(&[u8], u8)]| -> Vec<(Vec<u8>, Vec<u8>)> {
+            pairs.iter().map(|(k, v)| (k.to_vec(), vec![*v])).collect()
+        };
+        let all = expect(&[(b"a", 1), (b"b", 1), (b"c", 1), (b"d", 1)]);
+        assert_eq!(scan(first.as_ref(), Range::from(..))?, all);
+        assert_eq!(
+            first.scan(Range::from(..)).rev().collect::<Result<Vec<_>>>()?,
+            all.iter().rev().cloned().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            scan(second.as_ref(), Range::from(b"b".to_vec()..))?,
+            expect(&[(b"b", 3), (b"d", 1), (b"e", 2)])
+        );
+        assert_eq!(scan(&store, Range::from(..))?.len(), 4);
+
+        // Interleave both ends, and write during the scan.
+        let mut iter = first.scan(Range::from(b"a".to_vec()..=b"d".to_vec()));
+        assert_eq!(iter.next().transpose()?, Some(all[0].clone()));
+        store.delete(b"b")?;
+        store.delete(b"d")?;
+        assert_eq!(iter.next_back().transpose()?, Some(all[3].clone()));
+        store.set(b"bb", vec![0x04])?;
+        assert_eq!(iter.next().transpose()?, Some(all[1].clone()));
+        assert_eq!(iter.next_back().transpose()?, Some(all[2].clone()));
+        assert!(iter.next().is_none());
+        assert!(iter.next_back().is_none());
+        Ok(())
+    }
+
+    #[test]
+    // Snapshots are read-only, and previous values are only kept while snapshots need them.
+    fn snapshot_release() -> Result<()> {
+        let mut store = KvCow::new(Box::new(KvMemory::new()));
+        store.set(b"a", vec![0x01])?;
+        let mut first = store.snapshot()?.expect("snapshot");
+        assert!(matches!(first.set(b"a", vec![0x02]), Err(Error::ReadOnly)));
+        assert!(matches!(first.delete(b"a"), Err(Error::ReadOnly)));
+
+        store.set(b"a", vec![0x02])?;
+        let second = store.snapshot()?.expect("snapshot");
+        store.set(b"a", vec![0x03])?;
+        assert_eq!(store.shared.read()?.undo[b"a".as_slice()].len(), 2);
+
+        std::mem::drop(first);
+        assert_eq!(second.get(b"a")?, Some(vec![0x02]));
+        assert_eq!(store.shared.read()?.undo[b"a".as_slice()].len(), 1);
+        std::mem::drop(second);
+        assert!(store.shared.read()?.undo.is_empty());
+
+        store.set(b"a", vec![0x04])?;
+        assert!(store.shared.read()?.undo.is_empty());
+        Ok(())
+    }
+}
@@ -44,6 +44,12 @@ impl KvMemory {
     }
 }
 
+impl Default for KvMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl KvStore for KvMemory {
     fn delete(&mut self, key: &[u8]) -> Result<()> {
         self.root.write()?.delete(key);
@@ -1,4 +1,6 @@
+mod bitcask;
 mod children;
+mod cow;
 mod iterator;
 mod memory;
 mod node;
@@ -7,7 +9,9 @@ mod scan;
 mod store;
 mod value;
 
+pub use bitcask::*;
 pub use children::*;
+pub use cow::*;
 pub use iterator::*;
 pub use memory::*;
 pub use node::*;
@@ -28,7 +32,6 @@ pub use test::Test;
 mod key_value_storage_tests {
     use super::{
         children::*,
-        memory::*,
         node::*,
         value::*
     };
@@ -20,4 +20,10 @@ pub trait KvStore: Display + Send + Sync {
 
     /// Sets a value for a key, replacing the existing value if any.
     fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()>;
+
+    /// Returns a read-only point-in-time snapshot of the store, which doesn't see later writes,
+    /// or None if the store doesn't support snapshots.
+    fn snapshot(&self) -> Result<Option<Box<dyn KvStore>>> {
+        Ok(None)
+    }
 }
\ No newline at end of file
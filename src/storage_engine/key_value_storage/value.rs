@@ -29,7 +29,7 @@ impl Values {
     /// Deletes a key from the set, if it exists.
     pub fn delete(&mut self, key: &[u8]) {
         for (i, (k, _)) in self.iter().enumerate() {
-            match (&**k).cmp(key) {
+            match (**k).cmp(key) {
                 Ordering::Greater => break,
                 Ordering::Equal => {
                     self.remove(i);
@@ -43,7 +43,7 @@ impl Values {
     /// Fetches a value from the set, if the key exists.
     pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
         self.iter()
-            .find_map(|(k, v)| match (&**k).cmp(key) {
+            .find_map(|(k, v)| match (**k).cmp(key) {
                 Ordering::Greater => Some(None),
                 Ordering::Equal => Some(Some(v.to_vec())),
                 Ordering::Less => None,
@@ -64,7 +64,7 @@ impl Values {
     /// Fetches the next value after the given key, if it exists.
     pub fn get_next(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
         self.iter()
-            .find_map(|(k, v)| match (&**k).cmp(key) {
+            .find_map(|(k, v)| match (**k).cmp(key) {
                 Ordering::Greater => Some(Some((k.to_vec(), v.to_vec()))),
                 Ordering::Equal => None,
                 Ordering::Less => None,
@@ -76,7 +76,7 @@ impl Values {
     pub fn get_prev(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
         self.iter()
             .rev()
-            .find_map(|(k, v)| match (&**k).cmp(key) {
+            .find_map(|(k, v)| match (**k).cmp(key) {
                 Ordering::Less => Some(Some((k.to_vec(), v.to_vec()))),
                 Ordering::Equal => None,
                 Ordering::Greater => None,
@@ -90,7 +90,7 @@ impl Values {
         // Find position to insert at, or if the key already exists just update it.
         let mut insert_at = self.len();
         for (i, (k, v)) in self.iter_mut().enumerate() {
-            match (&**k).cmp(key) {
+            match (**k).cmp(key) {
                 Ordering::Greater => {
                     insert_at = i;
                     break;
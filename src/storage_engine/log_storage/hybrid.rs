@@ -11,11 +11,19 @@ use std::fmt::Display;
 use std::fs::{create_dir_all, File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek as _, SeekFrom, Write};
 use std::ops::Bound;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
+/// The length prefix of the marker record at the start of a compacted log file.
+const COMPACTED_MARKER: u32 = u32::MAX;
+
+/// Index of entry positions and sizes in the log file.
+type Index = BTreeMap<u64, (u64, u32)>;
+
 /// A hybrid log store, storing committed entries in an append-only file, uncommitted entries
-/// in memory, and metadata in a separate file (should be an on-disk key-value store).
+/// in memory, metadata in a separate file (should be an on-disk key-value store), and the state
+/// machine snapshot in a file of its own. The metadata and snapshot files are replaced
+/// atomically, by writing a new file and renaming it over the old one.
 ///
 /// The log file contains sequential binary log entries, length-prefixed with a big-endian u32.
 /// Entries are only flushed to disk when they are committed and permanent, thus the file is
@@ -25,18 +33,28 @@ use std::sync::Mutex;
 /// scanning the file, since maintaining the index in a separate file requires additional fsyncing
 /// which is expensive. Since datasets are expected to be small, scanning the file on startup is
 /// reasonably cheap.
+///
+/// When the log is compacted, the remaining committed entries are rewritten to a new file which
+/// atomically replaces the old one. A compacted file begins with a marker record, whose length
+/// prefix is u32::MAX, followed by the big-endian u64 index of the last compacted entry.
 pub struct Hybrid {
+    /// The path to the log file.
+    path: PathBuf,
     /// The append-only log file. Protected by a mutex for interior mutability (i.e. read seeks).
     file: Mutex<File>,
     /// Index of entry locations and sizes in the log file.
-    index: BTreeMap<u64, (u64, u32)>,
+    index: Index,
+    /// The index of the last compacted entry, or 0 if none.
+    compacted: u64,
     /// Uncommitted log entries.
     uncommitted: VecDeque<Vec<u8>>,
     /// Metadata cache. Flushed to disk on changes.
     metadata: HashMap<Vec<u8>, Vec<u8>>,
-    /// The file used to store metadata.
+    /// The path to the file used to store metadata.
     /// FIXME Should be an on-disk B-tree key-value store.
-    metadata_file: File,
+    metadata_path: PathBuf,
+    /// The path to the file used to store the state machine snapshot.
+    snapshot_path: PathBuf,
     /// If true, fsync writes.
     sync: bool,
 }
@@ -52,49 +70,63 @@ impl Hybrid {
     pub fn new(dir: &Path, sync: bool) -> Result<Self> {
         create_dir_all(dir)?;
 
+        let path = dir.join("raft-log");
         let file =
-            OpenOptions::new().read(true).write(true).create(true).open(dir.join("raft-log"))?;
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
 
-        let metadata_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(dir.join("raft-metadata"))?;
+        let metadata_path = dir.join("raft-metadata");
+        let metadata = Self::load_metadata(&metadata_path)?;
 
+        let (compacted, index) = Self::build_index(&file)?;
         Ok(Self {
-            index: Self::build_index(&file)?,
+            path,
+            index,
+            compacted,
             file: Mutex::new(file),
             uncommitted: VecDeque::new(),
-            metadata: Self::load_metadata(&metadata_file)?,
-            metadata_file,
+            metadata,
+            metadata_path,
+            snapshot_path: dir.join("raft-snapshot"),
             sync,
         })
     }
 
-    /// Builds the index by scanning the log file.
-    fn build_index(file: &File) -> Result<BTreeMap<u64, (u64, u32)>> {
+    /// Builds the index by scanning the log file, returning it along with the compacted index.
+    fn build_index(file: &File) -> Result<(u64, Index)> {
         let filesize = file.metadata()?.len();
         let mut bufreader = BufReader::new(file);
         let mut index = BTreeMap::new();
         let mut sizebuf = [0; 4];
         let mut pos = 0;
-        let mut i = 1;
+        let mut compacted = 0;
         while pos < filesize {
             bufreader.read_exact(&mut sizebuf)?;
             pos += 4;
             let size = u32::from_be_bytes(sizebuf);
+            if pos == 4 && size == COMPACTED_MARKER {
+                let mut compactedbuf = [0; 8];
+                bufreader.read_exact(&mut compactedbuf)?;
+                pos += 8;
+                compacted = u64::from_be_bytes(compactedbuf);
+                continue;
+            }
+            let i = compacted + index.len() as u64 + 1;
             index.insert(i, (pos, size));
             let mut buf = vec![0; size as usize];
             bufreader.read_exact(&mut buf)?;
             pos += size as u64;
-            i += 1;
         }
-        Ok(index)
+        Ok((compacted, index))
     }
 
-    /// Loads metadata from a file.
-    fn load_metadata(file: &File) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
-        match bincode::deserialize_from(file) {
+    /// Loads metadata from a file, if it exists.
+    fn load_metadata(path: &Path) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(err) => return Err(err.into()),
+        };
+        match bincode::deserialize_from(BufReader::new(file)) {
             Ok(metadata) => Ok(metadata),
             Err(err) => {
                 if let bincode::ErrorKind::Io(err) = &*err {
@@ -106,6 +138,22 @@ impl Hybrid {
             }
         }
     }
+
+    /// Atomically replaces a file with the given contents, by writing them to a new file and
+    /// renaming it over the old one. If sync is true, the data and rename are made durable.
+    fn replace_file(path: &Path, data: &[u8], sync: bool) -> Result<()> {
+        let new_path = path.with_extension("new");
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&new_path)?;
+        file.write_all(data)?;
+        if sync {
+            file.sync_all()?;
+        }
+        std::fs::rename(&new_path, path)?;
+        if let (true, Some(dir)) = (sync, path.parent()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
 }
 
 impl LogStore for Hybrid {
@@ -118,20 +166,20 @@ impl LogStore for Hybrid {
         if index > self.len() {
             return Err(Error::Internal(format!("Cannot commit non-existant index {}", index)));
         }
-        if index < self.index.len() as u64 {
+        if index < self.committed() {
             return Err(Error::Internal(format!(
                 "Cannot commit below current committed index {}",
-                self.index.len() as u64
+                self.committed()
             )));
         }
-        if index == self.index.len() as u64 {
+        if index == self.committed() {
             return Ok(());
         }
 
         let mut file = self.file.lock()?;
         let mut pos = file.seek(SeekFrom::End(0))?;
         let mut bufwriter = BufWriter::new(&mut *file);
-        for i in (self.index.len() as u64 + 1)..=index {
+        for i in (self.committed() + 1)..=index {
             let entry = self
                 .uncommitted
                 .pop_front()
@@ -151,13 +199,67 @@ impl LogStore for Hybrid {
     }
 
     fn committed(&self) -> u64 {
-        self.index.len() as u64
+        self.compacted + self.index.len() as u64
+    }
+
+    fn compact(&mut self, index: u64) -> Result<()> {
+        if index <= self.compacted {
+            return Ok(());
+        }
+        // If compacting beyond the committed entries, discard the covered uncommitted entries.
+        let committed = self.committed();
+        if index > committed {
+            self.uncommitted.drain(..min(index - committed, self.uncommitted.len() as u64) as usize);
+        }
+
+        // Write the remaining committed entries to a new file, behind a compaction marker, and
+        // atomically replace the old file with it.
+        let mut file = self.file.lock()?;
+        let new_path = self.path.with_extension("new");
+        let mut new_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&new_path)?;
+        let mut new_index = BTreeMap::new();
+        let mut bufwriter = BufWriter::new(&mut new_file);
+        bufwriter.write_all(&COMPACTED_MARKER.to_be_bytes())?;
+        bufwriter.write_all(&index.to_be_bytes())?;
+        let mut pos = 12;
+        for (i, (entry_pos, size)) in self.index.range((index + 1)..) {
+            let mut entry = vec![0; *size as usize];
+            file.seek(SeekFrom::Start(*entry_pos))?;
+            file.read_exact(&mut entry)?;
+            bufwriter.write_all(&size.to_be_bytes())?;
+            pos += 4;
+            new_index.insert(*i, (pos, *size));
+            bufwriter.write_all(&entry)?;
+            pos += *size as u64;
+        }
+        bufwriter.flush()?;
+        drop(bufwriter);
+        new_file.sync_all()?;
+        std::fs::rename(&new_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        *file = new_file;
+        drop(file);
+
+        self.index = new_index;
+        self.compacted = index;
+        Ok(())
+    }
+
+    fn compacted(&self) -> u64 {
+        self.compacted
     }
 
     fn get(&self, index: u64) -> Result<Option<Vec<u8>>> {
         match index {
-            0 => Ok(None),
-            i if i <= self.index.len() as u64 => {
+            i if i <= self.compacted => Ok(None),
+            i if i <= self.committed() => {
                 let (pos, size) = self.index.get(&i).copied().ok_or_else(|| {
                     Error::Internal(format!("Indexed position not found for entry {}", i))
                 })?;
@@ -167,21 +269,23 @@ impl LogStore for Hybrid {
                 file.read_exact(&mut entry)?;
                 Ok(Some(entry))
             }
-            i => Ok(self.uncommitted.get(i as usize - self.index.len() - 1).cloned()),
+            i => Ok(self.uncommitted.get((i - self.committed()) as usize - 1).cloned()),
         }
     }
 
     fn len(&self) -> u64 {
-        self.index.len() as u64 + self.uncommitted.len() as u64
+        self.committed() + self.uncommitted.len() as u64
     }
 
-    fn scan(&self, range: Range) -> Scan {
-        let start = match range.start {
-            Bound::Included(0) => 1,
-            Bound::Included(n) => n,
-            Bound::Excluded(n) => n + 1,
-            Bound::Unbounded => 1,
-        };
+    fn scan(&self, range: Range) -> Scan<'_> {
+        let start = max(
+            match range.start {
+                Bound::Included(n) => n,
+                Bound::Excluded(n) => n + 1,
+                Bound::Unbounded => 0,
+            },
+            self.compacted + 1,
+        );
         let end = match range.end {
             Bound::Included(n) => n,
             Bound::Excluded(0) => 0,
@@ -210,13 +314,14 @@ impl LogStore for Hybrid {
         }
 
         // Scan uncommitted entries in memory
-        if end > self.index.len() as u64 {
+        let committed = self.committed();
+        if end > committed {
             scan = Box::new(
                 scan.chain(
                     self.uncommitted
                         .iter()
-                        .skip(start as usize - min(start as usize, self.index.len() + 1))
-                        .take(end as usize - max(start as usize, self.index.len()) + 1)
+                        .skip(start.saturating_sub(committed + 1) as usize)
+                        .take((end - max(start, committed + 1) + 1) as usize)
                         .cloned()
                         .map(Ok),
                 ),
@@ -231,13 +336,13 @@ impl LogStore for Hybrid {
     }
 
     fn truncate(&mut self, index: u64) -> Result<u64> {
-        if index < self.index.len() as u64 {
+        if index < self.committed() {
             return Err(Error::Internal(format!(
                 "Cannot truncate below committed index {}",
-                self.index.len() as u64
+                self.committed()
             )));
         }
-        self.uncommitted.truncate(index as usize - self.index.len());
+        self.uncommitted.truncate((index - self.committed()) as usize);
         Ok(self.len())
     }
 
@@ -247,30 +352,35 @@ impl LogStore for Hybrid {
 
     fn set_metadata(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
         self.metadata.insert(key.to_vec(), value);
-        self.metadata_file.set_len(0)?;
-        self.metadata_file.seek(SeekFrom::Start(0))?;
-        bincode::serialize_into(&mut self.metadata_file, &self.metadata)?;
-        if self.sync {
-            self.metadata_file.sync_data()?;
+        Self::replace_file(&self.metadata_path, &bincode::serialize(&self.metadata)?, self.sync)
+    }
+
+    fn get_snapshot(&self) -> Result<Option<Vec<u8>>> {
+        match std::fs::read(&self.snapshot_path) {
+            Ok(snapshot) => Ok(Some(snapshot)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
         }
-        Ok(())
+    }
+
+    fn set_snapshot(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        // The snapshot replaces compacted entries, so it's always synced.
+        Self::replace_file(&self.snapshot_path, &snapshot, true)
     }
 }
 
 impl Drop for Hybrid {
     /// Attempt to fsync data on drop, in case we're running without sync.
     fn drop(&mut self) {
-        self.metadata_file.sync_all().ok();
         self.file.lock().map(|f| f.sync_all()).ok();
     }
 }
 
-/*
 #[cfg(test)]
 impl super::TestSuite<Hybrid> for Hybrid {
-    fn setup() -> Result<Self> {
+    fn setup() -> Result<(Self, Option<tempdir::TempDir>)> {
         let dir = tempdir::TempDir::new("toydb")?;
-        Hybrid::new(dir.as_ref(), false)
+        Ok((Hybrid::new(dir.path(), false)?, Some(dir)))
     }
 }
 
@@ -301,4 +411,34 @@ fn test_persistent() -> Result<()> {
 
     Ok(())
 }
-*/
\ No newline at end of file
+
+#[test]
+fn test_persistent_compacted() -> Result<()> {
+    let dir = tempdir::TempDir::new("toydb")?;
+    let mut l = Hybrid::new(dir.as_ref(), true)?;
+
+    l.append(vec![0x01])?;
+    l.append(vec![0x02])?;
+    l.append(vec![0x03])?;
+    l.append(vec![0x04])?;
+    l.commit(3)?;
+    l.compact(2)?;
+    l.append(vec![0x05])?;
+    l.commit(4)?;
+
+    let mut l = Hybrid::new(dir.as_ref(), true)?;
+    assert_eq!(2, l.compacted());
+    assert_eq!(4, l.committed());
+    assert_eq!(4, l.len());
+    assert_eq!(vec![vec![3], vec![4]], l.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+    assert!(!dir.path().join("raft-log.new").exists());
+
+    // Compacting beyond the end of the log should persist the new base index.
+    l.compact(6)?;
+    let l = Hybrid::new(dir.as_ref(), true)?;
+    assert_eq!(6, l.compacted());
+    assert_eq!(6, l.len());
+    assert!(l.scan(Range::from(..)).collect::<Result<Vec<_>>>()?.is_empty());
+
+    Ok(())
+}
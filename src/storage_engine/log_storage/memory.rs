@@ -5,6 +5,7 @@ use crate::{
     }
 };
 
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::ops::Bound;
@@ -13,13 +14,27 @@ use std::ops::Bound;
 pub struct LogMemory {
     log: Vec<Vec<u8>>,
     committed: u64,
+    compacted: u64,
     metadata: HashMap<Vec<u8>, Vec<u8>>,
+    snapshot: Option<Vec<u8>>,
 }
 
 impl LogMemory {
     /// Creates a new in-memory log.
     pub fn new() -> Self {
-        Self { log: Vec::new(), committed: 0, metadata: HashMap::new() }
+        Self {
+            log: Vec::new(),
+            committed: 0,
+            compacted: 0,
+            metadata: HashMap::new(),
+            snapshot: None,
+        }
+    }
+}
+
+impl Default for LogMemory {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -32,7 +47,7 @@ impl Display for LogMemory {
 impl LogStore for LogMemory {
     fn append(&mut self, entry: Vec<u8>) -> Result<u64> {
         self.log.push(entry);
-        Ok(self.log.len() as u64)
+        Ok(self.len())
     }
 
     fn commit(&mut self, index: u64) -> Result<()> {
@@ -53,31 +68,43 @@ impl LogStore for LogMemory {
         self.committed
     }
 
+    fn compact(&mut self, index: u64) -> Result<()> {
+        if index <= self.compacted {
+            return Ok(());
+        }
+        self.log.drain(..min(index - self.compacted, self.log.len() as u64) as usize);
+        self.compacted = index;
+        self.committed = max(self.committed, index);
+        Ok(())
+    }
+
+    fn compacted(&self) -> u64 {
+        self.compacted
+    }
+
     fn get(&self, index: u64) -> Result<Option<Vec<u8>>> {
         match index {
-            0 => Ok(None),
-            i => Ok(self.log.get(i as usize - 1).cloned()),
+            i if i <= self.compacted => Ok(None),
+            i => Ok(self.log.get((i - self.compacted) as usize - 1).cloned()),
         }
     }
 
     fn len(&self) -> u64 {
-        self.log.len() as u64
+        self.compacted + self.log.len() as u64
     }
 
-    fn scan(&self, range: Range) -> Scan {
+    fn scan(&self, range: Range) -> Scan<'_> {
         Box::new(
             self.log
                 .iter()
                 .take(match range.end {
-                    Bound::Included(n) => n as usize,
-                    Bound::Excluded(0) => 0,
-                    Bound::Excluded(n) => n as usize - 1,
-                    Bound::Unbounded => std::usize::MAX,
+                    Bound::Included(n) => n.saturating_sub(self.compacted) as usize,
+                    Bound::Excluded(n) => n.saturating_sub(self.compacted + 1) as usize,
+                    Bound::Unbounded => usize::MAX,
                 })
                 .skip(match range.start {
-                    Bound::Included(0) => 0,
-                    Bound::Included(n) => n as usize - 1,
-                    Bound::Excluded(n) => n as usize,
+                    Bound::Included(n) => n.saturating_sub(self.compacted + 1) as usize,
+                    Bound::Excluded(n) => n.saturating_sub(self.compacted) as usize,
                     Bound::Unbounded => 0,
                 })
                 .cloned()
@@ -96,8 +123,8 @@ impl LogStore for LogMemory {
                 self.committed
             )));
         }
-        self.log.truncate(index as usize);
-        Ok(self.log.len() as u64)
+        self.log.truncate((index - self.compacted) as usize);
+        Ok(self.len())
     }
 
     fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
@@ -108,19 +135,26 @@ impl LogStore for LogMemory {
         self.metadata.insert(key.to_vec(), value);
         Ok(())
     }
+
+    fn get_snapshot(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.snapshot.clone())
+    }
+
+    fn set_snapshot(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
 }
 
-/*
 #[cfg(test)]
-impl super::TestSuite<Memory> for Memory {
-    fn setup() -> Result<Self> {
-        Ok(Memory::new())
+impl super::TestSuite<LogMemory> for LogMemory {
+    fn setup() -> Result<(Self, Option<tempdir::TempDir>)> {
+        Ok((LogMemory::new(), None))
     }
 }
 
 #[test]
 fn tests() -> Result<()> {
     use super::TestSuite;
-    Memory::test()
-}
-*/
\ No newline at end of file
+    LogMemory::test()
+}
\ No newline at end of file
@@ -4,6 +4,8 @@ mod mutex_reader;
 mod range;
 mod scan;
 mod store;
+#[cfg(test)]
+mod test;
 
 pub use hybrid::*;
 pub use memory::*;
@@ -11,27 +13,32 @@ pub use mutex_reader::*;
 pub use range::*;
 pub use scan::*;
 pub use store::*;
+#[cfg(test)]
+pub use test::*;
 
 
-/*
 #[cfg(test)]
-use crate::error::Error;
+use crate::error::{Error, Result};
 
 #[cfg(test)]
-trait TestSuite<S: Store> {
-    fn setup() -> Result<S>;
+trait TestSuite<S: LogStore> {
+    /// Sets up a store, along with its temporary directory if any, which must be kept alive for
+    /// the duration of the test.
+    fn setup() -> Result<(S, Option<tempdir::TempDir>)>;
 
     fn test() -> Result<()> {
         Self::test_append()?;
         Self::test_commit_truncate()?;
+        Self::test_compact()?;
         Self::test_get()?;
         Self::test_metadata()?;
         Self::test_scan()?;
+        Self::test_snapshot()?;
         Ok(())
     }
 
     fn test_append() -> Result<()> {
-        let mut s = Self::setup()?;
+        let (mut s, _dir) = Self::setup()?;
         assert_eq!(0, s.len());
         assert_eq!(1, s.append(vec![0x01])?);
         assert_eq!(2, s.append(vec![0x02])?);
@@ -45,7 +52,7 @@ trait TestSuite<S: Store> {
     }
 
     fn test_commit_truncate() -> Result<()> {
-        let mut s = Self::setup()?;
+        let (mut s, _dir) = Self::setup()?;
 
         assert_eq!(0, s.committed());
 
@@ -78,8 +85,53 @@ trait TestSuite<S: Store> {
         Ok(())
     }
 
+    fn test_compact() -> Result<()> {
+        let (mut s, _dir) = Self::setup()?;
+        assert_eq!(0, s.compacted());
+
+        s.append(vec![0x01])?;
+        s.append(vec![0x02])?;
+        s.append(vec![0x03])?;
+        s.append(vec![0x04])?;
+        s.commit(3)?;
+
+        // Compacting a prefix should discard those entries, but keep the indexes.
+        s.compact(2)?;
+        assert_eq!(2, s.compacted());
+        assert_eq!(3, s.committed());
+        assert_eq!(4, s.len());
+        assert_eq!(None, s.get(1)?);
+        assert_eq!(None, s.get(2)?);
+        assert_eq!(Some(vec![0x03]), s.get(3)?);
+        assert_eq!(Some(vec![0x04]), s.get(4)?);
+        assert_eq!(vec![vec![3], vec![4]], s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+        assert_eq!(vec![vec![3]], s.scan(Range::from(1..=3)).collect::<Result<Vec<_>>>()?);
+
+        // Compacting at or below the compacted index is a noop.
+        s.compact(1)?;
+        assert_eq!(2, s.compacted());
+
+        // Appends, commits and truncation should continue from the last index.
+        assert_eq!(5, s.append(vec![0x05])?);
+        s.commit(4)?;
+        assert_eq!(4, s.truncate(4)?);
+        assert_eq!(vec![vec![3], vec![4]], s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+
+        // Compacting beyond the end of the log empties it.
+        s.compact(7)?;
+        assert_eq!(7, s.compacted());
+        assert_eq!(7, s.committed());
+        assert_eq!(7, s.len());
+        assert!(s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?.is_empty());
+        assert_eq!(8, s.append(vec![0x08])?);
+        assert_eq!(Some(vec![0x08]), s.get(8)?);
+        assert_eq!(vec![vec![8]], s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+
+        Ok(())
+    }
+
     fn test_get() -> Result<()> {
-        let mut s = Self::setup()?;
+        let (mut s, _dir) = Self::setup()?;
         s.append(vec![0x01])?;
         s.append(vec![0x02])?;
         s.append(vec![0x03])?;
@@ -90,16 +142,25 @@ trait TestSuite<S: Store> {
     }
 
     fn test_metadata() -> Result<()> {
-        let mut s = Self::setup()?;
+        let (mut s, _dir) = Self::setup()?;
         s.set_metadata(b"a", vec![0x01])?;
         assert_eq!(Some(vec![0x01]), s.get_metadata(b"a")?);
         assert_eq!(None, s.get_metadata(b"b")?);
         Ok(())
     }
 
+    fn test_snapshot() -> Result<()> {
+        let (mut s, _dir) = Self::setup()?;
+        assert_eq!(None, s.get_snapshot()?);
+        s.set_snapshot(vec![0x01])?;
+        s.set_snapshot(vec![0x02, 0x03])?;
+        assert_eq!(Some(vec![0x02, 0x03]), s.get_snapshot()?);
+        Ok(())
+    }
+
     #[allow(clippy::reversed_empty_ranges)]
     fn test_scan() -> Result<()> {
-        let mut s = Self::setup()?;
+        let (mut s, _dir) = Self::setup()?;
         s.append(vec![0x01])?;
         s.append(vec![0x02])?;
         s.append(vec![0x03])?;
@@ -132,4 +193,3 @@ trait TestSuite<S: Store> {
         Ok(())
     }
 }
-*/
\ No newline at end of file
@@ -19,14 +19,22 @@ pub trait LogStore: Display + Sync + Send {
     /// Returns the committed index, if any.
     fn committed(&self) -> u64;
 
-    /// Fetches a log entry, if it exists.
+    /// Discards all entries up to and including the given index, e.g. once they are covered by a
+    /// state machine snapshot. The index may be beyond the end of the log, in which case the log is
+    /// emptied and the next appended entry gets index+1. Compacted entries count as committed.
+    fn compact(&mut self, index: u64) -> Result<()>;
+
+    /// Returns the index of the last compacted entry, or 0 if none.
+    fn compacted(&self) -> u64;
+
+    /// Fetches a log entry, if it exists and has not been compacted.
     fn get(&self, index: u64) -> Result<Option<Vec<u8>>>;
 
-    /// Returns the number of entries in the log.
+    /// Returns the index of the last entry in the log, including compacted entries.
     fn len(&self) -> u64;
 
     /// Scans the log between the given indexes.
-    fn scan(&self, range: Range) -> Scan;
+    fn scan(&self, range: Range) -> Scan<'_>;
 
     /// Returns the size of the log, in bytes.
     fn size(&self) -> u64;
@@ -41,6 +49,14 @@ pub trait LogStore: Display + Sync + Send {
     /// Sets a metadata value.
     fn set_metadata(&mut self, key: &[u8], value: Vec<u8>) -> Result<()>;
 
+    /// Gets the state machine snapshot, if any.
+    fn get_snapshot(&self) -> Result<Option<Vec<u8>>>;
+
+    /// Replaces the state machine snapshot. This must be atomic and durable, since the snapshot
+    /// is the only copy of compacted entries. Snapshots can be large, so unlike metadata they
+    /// are only written when replaced.
+    fn set_snapshot(&mut self, snapshot: Vec<u8>) -> Result<()>;
+
     /// Returns true if the log has no entries.
     fn is_empty(&self) -> bool {
         self.len() == 0
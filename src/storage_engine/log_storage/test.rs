@@ -0,0 +1,94 @@
+use crate::{
+    error::Result,
+    storage_engine::log_storage::{
+        LogMemory, LogStore, Range, Scan
+    }
+};
+
+use std::fmt::Display;
+use std::sync::{Arc, RwLock};
+
+/// Log storage backend for testing. Protects an inner LogMemory backend using a mutex, so it can
+/// be cloned and inspected.
+#[derive(Clone)]
+pub struct LogTest {
+    log: Arc<RwLock<LogMemory>>,
+}
+
+impl LogTest {
+    /// Creates a new Test log store.
+    pub fn new() -> Self {
+        Self { log: Arc::new(RwLock::new(LogMemory::new())) }
+    }
+}
+
+impl Default for LogTest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for LogTest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "test")
+    }
+}
+
+impl LogStore for LogTest {
+    fn append(&mut self, entry: Vec<u8>) -> Result<u64> {
+        self.log.write()?.append(entry)
+    }
+
+    fn commit(&mut self, index: u64) -> Result<()> {
+        self.log.write()?.commit(index)
+    }
+
+    fn committed(&self) -> u64 {
+        self.log.read().unwrap().committed()
+    }
+
+    fn compact(&mut self, index: u64) -> Result<()> {
+        self.log.write()?.compact(index)
+    }
+
+    fn compacted(&self) -> u64 {
+        self.log.read().unwrap().compacted()
+    }
+
+    fn get(&self, index: u64) -> Result<Option<Vec<u8>>> {
+        self.log.read()?.get(index)
+    }
+
+    fn len(&self) -> u64 {
+        self.log.read().unwrap().len()
+    }
+
+    fn scan(&self, range: Range) -> Scan<'_> {
+        // Since the mutex guard is scoped to this method, we simply buffer the result.
+        Box::new(self.log.read().unwrap().scan(range).collect::<Vec<Result<_>>>().into_iter())
+    }
+
+    fn size(&self) -> u64 {
+        self.log.read().unwrap().size()
+    }
+
+    fn truncate(&mut self, index: u64) -> Result<u64> {
+        self.log.write()?.truncate(index)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.log.read()?.get_metadata(key)
+    }
+
+    fn set_metadata(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.log.write()?.set_metadata(key, value)
+    }
+
+    fn get_snapshot(&self) -> Result<Option<Vec<u8>>> {
+        self.log.read()?.get_snapshot()
+    }
+
+    fn set_snapshot(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        self.log.write()?.set_snapshot(snapshot)
+    }
+}
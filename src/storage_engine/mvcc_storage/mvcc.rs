@@ -69,7 +69,7 @@ impl MVCC {
             } - 1,
             txns_active: store
                 .scan(Range::from(
-                    Key::TxnActive(0).encode()..Key::TxnActive(std::u64::MAX).encode(),
+                    Key::TxnActive(0).encode()..Key::TxnActive(u64::MAX).encode(),
                 ))
                 .try_fold(0, |count, r| r.map(|_| count + 1))?,
             storage: store.to_string(),
@@ -48,6 +48,6 @@ impl Snapshot {
 
     /// Checks whether the given version is visible in this snapshot.
     pub fn is_visible(&self, version: u64) -> bool {
-        version <= self.version && self.invisible.get(&version).is_none()
+        version <= self.version && !self.invisible.contains(&version)
     }
 }
\ No newline at end of file
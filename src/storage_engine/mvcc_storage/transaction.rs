@@ -132,13 +132,13 @@ impl Transaction {
     /// Scans a key range.
     pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Scan> {
         let start = match range.start_bound() {
-            Bound::Excluded(k) => Bound::Excluded(Key::Record(k.into(), std::u64::MAX).encode()),
+            Bound::Excluded(k) => Bound::Excluded(Key::Record(k.into(), u64::MAX).encode()),
             Bound::Included(k) => Bound::Included(Key::Record(k.into(), 0).encode()),
             Bound::Unbounded => Bound::Included(Key::Record(vec![].into(), 0).encode()),
         };
         let end = match range.end_bound() {
             Bound::Excluded(k) => Bound::Excluded(Key::Record(k.into(), 0).encode()),
-            Bound::Included(k) => Bound::Included(Key::Record(k.into(), std::u64::MAX).encode()),
+            Bound::Included(k) => Bound::Included(Key::Record(k.into(), u64::MAX).encode()),
             Bound::Unbounded => Bound::Unbounded,
         };
         let scan = self.store.read()?.scan(Range::from((start, end)));
@@ -187,7 +187,7 @@ impl Transaction {
         let mut scan = session
             .scan(Range::from(
                 Key::Record(key.into(), min).encode()
-                    ..=Key::Record(key.into(), std::u64::MAX).encode(),
+                    ..=Key::Record(key.into(), u64::MAX).encode(),
             ))
             .rev();
         while let Some((k, _)) = scan.next().transpose()? {
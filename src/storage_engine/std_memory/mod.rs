@@ -19,6 +19,12 @@ impl StdMemory {
     }
 }
 
+impl Default for StdMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Display for StdMemory {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "stdmemory")